@@ -0,0 +1,178 @@
+// Copyright 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Implements comparing two FDTs node by node
+
+use anyhow::{anyhow, Result};
+use libfdt::{Fdt, FdtNode};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::CStr;
+use std::fmt;
+
+/// A difference between two FDTs
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FdtDiff {
+    /// The node at the path is only in the new FDT, with all its descendants
+    NodeAdded(String),
+    /// The node at the path is only in the old FDT, with all its descendants
+    NodeRemoved(String),
+    /// The property is only in the new FDT
+    PropertyAdded { node: String, name: String, value: Vec<u8> },
+    /// The property is only in the old FDT
+    PropertyRemoved { node: String, name: String, value: Vec<u8> },
+    /// The property has different values in the two FDTs
+    PropertyChanged { node: String, name: String, old: Vec<u8>, new: Vec<u8> },
+}
+
+impl fmt::Display for FdtDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NodeAdded(path) => write!(f, "+ {path}"),
+            Self::NodeRemoved(path) => write!(f, "- {path}"),
+            Self::PropertyAdded { node, name, value } => {
+                write!(f, "+ {node}:{name} = {}", format_value(value))
+            }
+            Self::PropertyRemoved { node, name, value } => {
+                write!(f, "- {node}:{name} = {}", format_value(value))
+            }
+            Self::PropertyChanged { node, name, old, new } => {
+                write!(f, "~ {node}:{name} = {} -> {}", format_value(old), format_value(new))
+            }
+        }
+    }
+}
+
+/// Compares the `old` and `new` FDTs node by node, and returns their differences sorted by path.
+/// Nodes are matched by their path, so a node which is only in one of the FDTs is reported alone,
+/// without its properties and subnodes.
+pub fn diff(old: &Fdt, new: &Fdt) -> Result<Vec<FdtDiff>> {
+    let mut diffs = vec![];
+    diff_nodes(&old.root(), &new.root(), "/", &mut diffs)?;
+    Ok(diffs)
+}
+
+fn diff_nodes(old: &FdtNode, new: &FdtNode, path: &str, diffs: &mut Vec<FdtDiff>) -> Result<()> {
+    let old_properties = properties(old)?;
+    let new_properties = properties(new)?;
+    let names: BTreeSet<_> = old_properties.keys().chain(new_properties.keys()).collect();
+    for name in names {
+        let node = path.to_owned();
+        let name_str = name.to_string_lossy().into_owned();
+        match (old_properties.get(name), new_properties.get(name)) {
+            (Some(old), Some(new)) if old != new => diffs.push(FdtDiff::PropertyChanged {
+                node,
+                name: name_str,
+                old: old.to_vec(),
+                new: new.to_vec(),
+            }),
+            (Some(old), None) => {
+                diffs.push(FdtDiff::PropertyRemoved { node, name: name_str, value: old.to_vec() })
+            }
+            (None, Some(new)) => {
+                diffs.push(FdtDiff::PropertyAdded { node, name: name_str, value: new.to_vec() })
+            }
+            _ => {}
+        }
+    }
+
+    let old_subnodes = subnodes(old)?;
+    let new_subnodes = subnodes(new)?;
+    let names: BTreeSet<_> = old_subnodes.keys().chain(new_subnodes.keys()).collect();
+    for name in names {
+        let subnode_path = if path == "/" {
+            format!("/{}", name.to_string_lossy())
+        } else {
+            format!("{path}/{}", name.to_string_lossy())
+        };
+        match (old_subnodes.get(name), new_subnodes.get(name)) {
+            (Some(old), Some(new)) => diff_nodes(old, new, &subnode_path, diffs)?,
+            (Some(_), None) => diffs.push(FdtDiff::NodeRemoved(subnode_path)),
+            (None, Some(_)) => diffs.push(FdtDiff::NodeAdded(subnode_path)),
+            (None, None) => unreachable!(),
+        }
+    }
+    Ok(())
+}
+
+fn properties<'a>(node: &'a FdtNode<'a>) -> Result<BTreeMap<&'a CStr, &'a [u8]>> {
+    let properties =
+        node.properties().map_err(|e| anyhow!("Failed to read FDT properties, {e:?}"))?;
+    properties
+        .map(|property| {
+            let name =
+                property.name().map_err(|e| anyhow!("Failed to read property name, {e:?}"))?;
+            let value =
+                property.value().map_err(|e| anyhow!("Failed to read value of {name:?}, {e:?}"))?;
+            Ok((name, value))
+        })
+        .collect()
+}
+
+fn subnodes<'a>(node: &FdtNode<'a>) -> Result<BTreeMap<&'a CStr, FdtNode<'a>>> {
+    let subnodes = node.subnodes().map_err(|e| anyhow!("Failed to read FDT subnodes, {e:?}"))?;
+    subnodes
+        .map(|subnode| {
+            let name = subnode.name().map_err(|e| anyhow!("Failed to read node name, {e:?}"))?;
+            Ok((name, subnode))
+        })
+        .collect()
+}
+
+/// Formats a property value like dtc does: as strings if it is a list of printable
+/// NUL-terminated strings, as cells if its size is a multiple of 4 bytes, or as bytes otherwise.
+fn format_value(value: &[u8]) -> String {
+    if value.is_empty() {
+        return "<empty>".to_owned();
+    }
+    if let Some(strings) = as_strings(value) {
+        let strings: Vec<_> = strings.iter().map(|s| format!("{s:?}")).collect();
+        return strings.join(", ");
+    }
+    let cells = value.chunks_exact(4);
+    if cells.remainder().is_empty() {
+        let cells: Vec<_> = cells
+            .map(|cell| format!("{:#x}", u32::from_be_bytes(cell.try_into().unwrap())))
+            .collect();
+        return format!("<{}>", cells.join(" "));
+    }
+    let bytes: Vec<_> = value.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!("[{}]", bytes.join(" "))
+}
+
+fn as_strings(value: &[u8]) -> Option<Vec<&str>> {
+    let value = value.strip_suffix(b"\0")?;
+    value
+        .split(|byte| *byte == b'\0')
+        .map(|s| {
+            let s = std::str::from_utf8(s).ok()?;
+            (!s.is_empty() && s.chars().all(|c| c.is_ascii_graphic() || c == ' ')).then_some(s)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_value() {
+        assert_eq!(format_value(b""), "<empty>");
+        assert_eq!(format_value(b"okay\0"), "\"okay\"");
+        assert_eq!(format_value(b"arm,pl011\0arm,primecell\0"), "\"arm,pl011\", \"arm,primecell\"");
+        assert_eq!(format_value(&[0, 0, 0, 1, 0, 0, 0x10, 0]), "<0x1 0x1000>");
+        assert_eq!(format_value(&[1, 2, 3]), "[01 02 03]");
+        // Not a string, as an empty string isn't printable.
+        assert_eq!(format_value(b"\0\0\0\0"), "<0x0>");
+    }
+}
@@ -12,11 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! Implements converting file system to FDT blob
+//! Implements converting file system to FDT blob, and back
 
-use anyhow::{anyhow, Context, Result};
+mod diff;
+
+pub use diff::{diff, FdtDiff};
+
+use anyhow::{anyhow, bail, Context, Result};
 use libfdt::Fdt;
-use std::ffi::{CStr, CString};
+use std::ffi::{CStr, CString, OsStr};
 use std::fs;
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
@@ -28,6 +32,10 @@ pub trait FsFdt<'a> {
 
     /// Overlay an FDT from /proc/device-tree style directory at the given node path
     fn overlay_onto(&mut self, fdt_node_path: &CStr, fs_path: &Path) -> Result<()>;
+
+    /// Writes the FDT out as a /proc/device-tree style directory at the given path, with a
+    /// directory for each node and a file for each property
+    fn to_fs(&self, fs_path: &Path) -> Result<()>;
 }
 
 impl<'a> FsFdt<'a> for Fdt {
@@ -109,6 +117,47 @@ impl<'a> FsFdt<'a> for Fdt {
 
         Ok(())
     }
+
+    fn to_fs(&self, fs_path: &Path) -> Result<()> {
+        fs::create_dir_all(fs_path).with_context(|| format!("Failed to create {fs_path:?}"))?;
+
+        // Recursively traverse the FDT with DFS algorithm.
+        let mut stack = vec![(self.root(), fs_path.to_path_buf())];
+        while let Some((node, dir_path)) = stack.pop() {
+            let properties =
+                node.properties().map_err(|e| anyhow!("Failed to read FDT properties, {e:?}"))?;
+            for property in properties {
+                let name =
+                    property.name().map_err(|e| anyhow!("Failed to read property name, {e:?}"))?;
+                let value = property
+                    .value()
+                    .map_err(|e| anyhow!("Failed to read value of {name:?}, {e:?}"))?;
+                let path = dir_path.join(entry_name(name)?);
+                fs::write(&path, value).with_context(|| format!("Failed to write {path:?}"))?;
+            }
+
+            let subnodes =
+                node.subnodes().map_err(|e| anyhow!("Failed to read FDT subnodes, {e:?}"))?;
+            for subnode in subnodes {
+                let name =
+                    subnode.name().map_err(|e| anyhow!("Failed to read node name, {e:?}"))?;
+                let path = dir_path.join(entry_name(name)?);
+                fs::create_dir(&path).with_context(|| format!("Failed to create {path:?}"))?;
+                stack.push((subnode, path));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the name of the file or directory for an FDT property or node named `name`.
+fn entry_name(name: &CStr) -> Result<&OsStr> {
+    let name = name.to_bytes();
+    if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') {
+        bail!("Unsupported FDT name for file system, {:?}", OsStr::from_bytes(name));
+    }
+    Ok(OsStr::from_bytes(name))
 }
 
 #[cfg(test)]
@@ -134,4 +183,64 @@ mod test {
         // subnode are already present.
         fdt.overlay_onto(&CString::new("/").unwrap(), fs_path).unwrap();
     }
+
+    #[test]
+    fn test_to_fs() {
+        let fs_path = Path::new(TEST_FS_FDT_ROOT_PATH);
+
+        let mut data = vec![0_u8; BUF_SIZE_MAX];
+        let fdt = Fdt::from_fs(fs_path, &mut data).unwrap();
+        let out_dir = tempfile::tempdir().unwrap();
+        fdt.to_fs(out_dir.path()).unwrap();
+
+        let expected = Dts::from_fs(fs_path).unwrap();
+        let actual = Dts::from_fs(out_dir.path()).unwrap();
+
+        assert_eq!(&expected, &actual);
+    }
+
+    #[test]
+    fn test_diff() {
+        let fs_path = Path::new(TEST_FS_FDT_ROOT_PATH);
+        let node_path = |path: &str| CString::new(path).unwrap();
+        let name = |name: &str| CString::new(name).unwrap();
+
+        let mut old_data = vec![0_u8; BUF_SIZE_MAX];
+        let old = Fdt::from_fs(fs_path, &mut old_data).unwrap();
+        let mut new_data = vec![0_u8; BUF_SIZE_MAX * 2];
+        let new = Fdt::from_fs(fs_path, &mut new_data).unwrap();
+        assert_eq!(diff(old, new).unwrap(), vec![]);
+
+        let mut node = new.node_mut(&node_path("/avf/reference")).unwrap().unwrap();
+        node.setprop(&name("vendor_image_key"), b"new_key\0").unwrap();
+        new.node_mut(&node_path("/avf/reference/oem")).unwrap().unwrap().nop().unwrap();
+        let mut node = new.node_mut(&node_path("/avf/reference/vendor")).unwrap().unwrap();
+        node.setprop(&name("new_flag"), &[0, 0, 0, 1]).unwrap();
+        let node = new.node_mut(&node_path("/avf")).unwrap().unwrap();
+        node.add_subnode(&name("added")).unwrap();
+
+        let diffs = diff(old, new).unwrap();
+        assert_eq!(
+            diffs,
+            vec![
+                FdtDiff::NodeAdded("/avf/added".to_owned()),
+                FdtDiff::PropertyChanged {
+                    node: "/avf/reference".to_owned(),
+                    name: "vendor_image_key".to_owned(),
+                    old: b"this_is_not_real\0".to_vec(),
+                    new: b"new_key\0".to_vec(),
+                },
+                FdtDiff::NodeRemoved("/avf/reference/oem".to_owned()),
+                FdtDiff::PropertyAdded {
+                    node: "/avf/reference/vendor".to_owned(),
+                    name: "new_flag".to_owned(),
+                    value: vec![0, 0, 0, 1],
+                },
+            ]
+        );
+        assert_eq!(
+            diffs[1].to_string(),
+            "~ /avf/reference:vendor_image_key = \"this_is_not_real\" -> \"new_key\""
+        );
+    }
 }
@@ -12,31 +12,97 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! CLI for converting file system to FDT
+//! CLI for converting file system to FDT, and back
 
-use clap::Parser;
-use fsfdt::FsFdt;
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, Subcommand};
+use fsfdt::{diff, FsFdt};
 use libfdt::Fdt;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
 const FDT_MAX_SIZE: usize = 1_000_000_usize;
 
 /// Option parser
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Opt {
     /// File system path (directory path) to parse from
-    fs_path: PathBuf,
+    #[arg(required = true)]
+    fs_path: Option<PathBuf>,
 
     /// FDT file path for writing
-    fdt_file_path: PathBuf,
+    #[arg(required = true)]
+    fdt_file_path: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Writes an FDT file out as a file system (directory path)
+    ToFs {
+        /// FDT file path to parse from
+        fdt_file_path: PathBuf,
+
+        /// File system path (directory path) for writing. Must not exist or be empty.
+        fs_path: PathBuf,
+    },
+    /// Compares two device trees node by node and prints their differences. Exits with 1 if they
+    /// differ.
+    Diff {
+        /// Old device tree, as an FDT file path or a file system path (directory path)
+        old: PathBuf,
+
+        /// New device tree, as an FDT file path or a file system path (directory path)
+        new: PathBuf,
+    },
 }
 
-fn main() {
+fn main() -> Result<ExitCode> {
     let opt = Opt::parse();
 
+    match opt.command {
+        None => {
+            // Both are required without a subcommand.
+            let (fs_path, fdt_file_path) = (opt.fs_path.unwrap(), opt.fdt_file_path.unwrap());
+            fs::write(fdt_file_path, read_fdt(&fs_path)?)?;
+        }
+        Some(Command::ToFs { fdt_file_path, fs_path }) => {
+            if fs_path.exists() && fs::read_dir(&fs_path)?.next().is_some() {
+                return Err(anyhow!("{fs_path:?} is not empty"));
+            }
+            let data = read_fdt(&fdt_file_path)?;
+            as_fdt(&data)?.to_fs(&fs_path)?;
+        }
+        Some(Command::Diff { old, new }) => {
+            let old = read_fdt(&old)?;
+            let new = read_fdt(&new)?;
+            let diffs = diff(as_fdt(&old)?, as_fdt(&new)?)?;
+            for diff in &diffs {
+                println!("{diff}");
+            }
+            if !diffs.is_empty() {
+                return Ok(ExitCode::from(1));
+            }
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Reads an FDT blob from an FDT file, or builds it from a file system if the path is a directory.
+fn read_fdt(path: &Path) -> Result<Vec<u8>> {
+    if !path.is_dir() {
+        return fs::read(path).with_context(|| format!("Failed to read {path:?}"));
+    }
     let mut data = vec![0_u8; FDT_MAX_SIZE];
-    let fdt = Fdt::from_fs(&opt.fs_path, &mut data).unwrap();
-    fdt.pack().unwrap();
-    fs::write(&opt.fdt_file_path, fdt.as_slice()).unwrap();
+    let fdt = Fdt::from_fs(path, &mut data)?;
+    fdt.pack().map_err(|e| anyhow!("Failed to pack FDT, {e:?}"))?;
+    Ok(fdt.as_slice().to_vec())
+}
+
+fn as_fdt(data: &[u8]) -> Result<&Fdt> {
+    Fdt::from_slice(data).map_err(|e| anyhow!("Invalid FDT, {e:?}"))
 }
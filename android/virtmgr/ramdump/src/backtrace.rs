@@ -0,0 +1,123 @@
+// Copyright 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Backtraces of the CPUs of the crashed kernel, from the registers saved in its core
+
+use crate::elf::{u32_at, u64_at, Core, Machine, VmcoreInfo};
+use std::io::{Read, Seek};
+
+/// Name and type of the notes holding the registers of each CPU.
+const NT_PRSTATUS: (&str, u32) = ("CORE", 1);
+/// Offsets in `struct elf_prstatus` of 64-bit kernels.
+const PR_PID_OFFSET: usize = 32;
+const PR_REG_OFFSET: usize = 112;
+
+/// The most frames of a backtrace, in case the frame pointers loop.
+const MAX_FRAMES: usize = 32;
+
+/// The registers of a CPU which are needed to unwind its stack.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Registers {
+    pc: u64,
+    sp: u64,
+    fp: u64,
+}
+
+impl Registers {
+    /// Reads the registers from the `pr_reg` field of `struct elf_prstatus`.
+    fn from_pr_reg(machine: Machine, pr_reg: &[u8]) -> Option<Self> {
+        let (pc, sp, fp) = match machine {
+            // struct user_pt_regs: x0-x30, sp, pc, pstate
+            Machine::Aarch64 => (32, 31, 29),
+            // struct user_regs_struct: r15, r14, r13, r12, rbp, ..., rip, cs, eflags, rsp, ss, ...
+            Machine::X86_64 => (16, 19, 4),
+        };
+        let reg = |index: usize| {
+            let offset = index * 8;
+            (offset + 8 <= pr_reg.len()).then(|| u64_at(pr_reg, offset))
+        };
+        Some(Self { pc: reg(pc)?, sp: reg(sp)?, fp: reg(fp)? })
+    }
+}
+
+/// The backtrace of a CPU at the time of the crash.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CpuBacktrace {
+    /// The index of the CPU, in the order the kernel saved them.
+    pub cpu: usize,
+    /// The PID of the task which was running on the CPU.
+    pub pid: u32,
+    pub sp: u64,
+    /// The program counter, followed by the return addresses found by following frame pointers.
+    pub frames: Vec<u64>,
+}
+
+/// Returns the backtraces of all of the CPUs whose registers were saved in the core.
+pub fn backtraces<R: Read + Seek>(core: &mut Core<R>, info: &VmcoreInfo) -> Vec<CpuBacktrace> {
+    let va_bits = info.number("VA_BITS").ok();
+    let machine = core.machine;
+    let registers: Vec<(u32, Registers)> = core
+        .notes(NT_PRSTATUS.0, NT_PRSTATUS.1)
+        .filter(|note| note.desc.len() >= PR_REG_OFFSET)
+        .filter_map(|note| {
+            let pid = u32_at(&note.desc, PR_PID_OFFSET);
+            Some((pid, Registers::from_pr_reg(machine, &note.desc[PR_REG_OFFSET..])?))
+        })
+        .collect();
+    registers
+        .into_iter()
+        .enumerate()
+        .map(|(cpu, (pid, regs))| CpuBacktrace {
+            cpu,
+            pid,
+            sp: regs.sp,
+            frames: unwind(core, regs, va_bits),
+        })
+        .collect()
+}
+
+/// Follows the chain of frame records, which on both architectures are a pointer to the previous
+/// frame record followed by the return address.
+fn unwind<R: Read + Seek>(core: &mut Core<R>, regs: Registers, va_bits: Option<u64>) -> Vec<u64> {
+    let mut frames = vec![regs.pc];
+    let mut fp = regs.fp;
+    while fp != 0 && frames.len() < MAX_FRAMES {
+        let Some(ret_addr) = fp.checked_add(8) else {
+            break;
+        };
+        let (Ok(next_fp), Ok(ret)) = (core.read_u64(fp), core.read_u64(ret_addr)) else {
+            break;
+        };
+        if ret == 0 {
+            break;
+        }
+        frames.push(strip_pac(ret, core.machine, va_bits));
+        // Stacks grow down, so the frame of each caller must be above that of its callee.
+        if next_fp <= fp {
+            break;
+        }
+        fp = next_fp;
+    }
+    frames
+}
+
+/// Removes the pointer authentication code from a kernel return address on arm64.
+fn strip_pac(addr: u64, machine: Machine, va_bits: Option<u64>) -> u64 {
+    match (machine, va_bits) {
+        (Machine::Aarch64, Some(va_bits)) if va_bits < 64 && addr & (1 << 55) != 0 => {
+            addr | !((1 << va_bits) - 1)
+        }
+        _ => addr,
+    }
+}
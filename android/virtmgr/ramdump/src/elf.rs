@@ -0,0 +1,275 @@
+// Copyright 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reading of the ELF core files produced by the kernel as /proc/vmcore
+
+use anyhow::{bail, ensure, Context, Result};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+/// Upper bound of the number of program headers, to reject corrupt files early.
+const MAX_PHNUM: usize = 4096;
+/// Upper bound of the size of a note segment, to reject corrupt files early.
+const MAX_NOTES_SIZE: u64 = 16 << 20;
+
+/// Returns whether the data starts like an ELF file.
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(ELF_MAGIC)
+}
+
+/// The architecture of the machine the core was taken from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Machine {
+    Aarch64,
+    X86_64,
+}
+
+impl Machine {
+    /// Returns the name of the architecture, as in `uname -m`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Aarch64 => "aarch64",
+            Self::X86_64 => "x86_64",
+        }
+    }
+}
+
+/// A note of the core.
+#[derive(Debug)]
+pub struct Note {
+    pub name: String,
+    pub kind: u32,
+    pub desc: Vec<u8>,
+}
+
+/// A segment of memory contained in the core.
+#[derive(Debug)]
+struct Segment {
+    offset: u64,
+    vaddr: u64,
+    size: u64,
+}
+
+/// An ELF core file, whose memory can be read by virtual address.
+pub struct Core<R> {
+    reader: R,
+    /// The offset in `reader` at which the core starts.
+    base: u64,
+    pub machine: Machine,
+    pub notes: Vec<Note>,
+    segments: Vec<Segment>,
+}
+
+impl<R: Read + Seek> Core<R> {
+    /// Parses the headers and notes of the ELF core starting at `base` in `reader`.
+    pub fn new(mut reader: R, base: u64) -> Result<Self> {
+        let mut ehdr = [0u8; EHDR_SIZE];
+        reader.seek(SeekFrom::Start(base))?;
+        reader.read_exact(&mut ehdr).context("Failed to read the ELF header")?;
+        ensure!(is_elf(&ehdr), "Not an ELF file");
+        ensure!(ehdr[4] == ELFCLASS64, "Only 64-bit cores are supported");
+        ensure!(ehdr[5] == ELFDATA2LSB, "Only little-endian cores are supported");
+        ensure!(u16_at(&ehdr, 16) == ET_CORE, "Not a core file");
+        let machine = match u16_at(&ehdr, 18) {
+            EM_AARCH64 => Machine::Aarch64,
+            EM_X86_64 => Machine::X86_64,
+            machine => bail!("Unsupported machine {machine}"),
+        };
+        let phoff = u64_at(&ehdr, 32);
+        let phentsize = u16_at(&ehdr, 54) as usize;
+        let phnum = u16_at(&ehdr, 56) as usize;
+        ensure!(phentsize == PHDR_SIZE, "Unexpected program header size {phentsize}");
+        ensure!(phnum <= MAX_PHNUM, "Too many program headers: {phnum}");
+
+        let mut phdrs = vec![0u8; phnum * PHDR_SIZE];
+        reader.seek(SeekFrom::Start(address(base, &[phoff])?))?;
+        reader.read_exact(&mut phdrs).context("Failed to read the program headers")?;
+
+        let mut core = Self { reader, base, machine, notes: Vec::new(), segments: Vec::new() };
+        for phdr in phdrs.chunks_exact(PHDR_SIZE) {
+            let offset = u64_at(phdr, 8);
+            let filesz = u64_at(phdr, 32);
+            match u32_at(phdr, 0) {
+                PT_NOTE => {
+                    ensure!(filesz <= MAX_NOTES_SIZE, "Note segment too large: {filesz}");
+                    let mut notes = vec![0u8; filesz as usize];
+                    core.reader.seek(SeekFrom::Start(address(base, &[offset])?))?;
+                    core.reader.read_exact(&mut notes).context("Failed to read notes")?;
+                    core.notes.extend(parse_notes(&notes)?);
+                }
+                PT_LOAD => {
+                    core.segments.push(Segment { offset, vaddr: u64_at(phdr, 16), size: filesz })
+                }
+                _ => {}
+            }
+        }
+        Ok(core)
+    }
+
+    /// Returns the notes with the given name and type.
+    pub fn notes(&self, name: &str, kind: u32) -> impl Iterator<Item = &Note> {
+        let name = name.to_owned();
+        self.notes.iter().filter(move |note| note.name == name && note.kind == kind)
+    }
+
+    /// Reads the memory at the given virtual address.
+    pub fn read(&mut self, mut vaddr: u64, mut buf: &mut [u8]) -> Result<()> {
+        while !buf.is_empty() {
+            let Some(segment) =
+                self.segments.iter().find(|s| s.vaddr <= vaddr && vaddr - s.vaddr < s.size)
+            else {
+                bail!("Address {vaddr:#x} is not in the core");
+            };
+            let skip = vaddr - segment.vaddr;
+            let len = buf.len().min((segment.size - skip).try_into().unwrap_or(usize::MAX));
+            self.reader.seek(SeekFrom::Start(address(self.base, &[segment.offset, skip])?))?;
+            self.reader
+                .read_exact(&mut buf[..len])
+                .with_context(|| format!("Failed to read {len} bytes at {vaddr:#x}"))?;
+            buf = &mut buf[len..];
+            if !buf.is_empty() {
+                vaddr = address(vaddr, &[len as u64])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the 64-bit value at the given virtual address.
+    pub fn read_u64(&mut self, vaddr: u64) -> Result<u64> {
+        let mut buf = [0u8; 8];
+        self.read(vaddr, &mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    /// Reads the 32-bit value at the given virtual address.
+    pub fn read_u32(&mut self, vaddr: u64) -> Result<u32> {
+        let mut buf = [0u8; 4];
+        self.read(vaddr, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    /// Reads the 16-bit value at the given virtual address.
+    pub fn read_u16(&mut self, vaddr: u64) -> Result<u16> {
+        let mut buf = [0u8; 2];
+        self.read(vaddr, &mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+}
+
+fn parse_notes(mut data: &[u8]) -> Result<Vec<Note>> {
+    let mut notes = Vec::new();
+    while data.len() >= 12 {
+        let namesz = u32_at(data, 0) as usize;
+        let descsz = u32_at(data, 4) as usize;
+        let kind = u32_at(data, 8);
+        let name_end = 12 + namesz;
+        let desc_start = 12 + namesz.next_multiple_of(4);
+        let desc_end = desc_start + descsz;
+        ensure!(desc_end <= data.len(), "Truncated note");
+        let name = &data[12..name_end];
+        let name = String::from_utf8_lossy(name.strip_suffix(b"\0").unwrap_or(name)).into_owned();
+        notes.push(Note { name, kind, desc: data[desc_start..desc_end].to_vec() });
+        data = &data[desc_end.next_multiple_of(4).min(data.len())..];
+    }
+    Ok(notes)
+}
+
+/// The `VMCOREINFO` note, which describes the layout of the crashed kernel.
+#[derive(Debug, Default)]
+pub struct VmcoreInfo(HashMap<String, String>);
+
+impl VmcoreInfo {
+    /// Name and type of the note.
+    pub const NOTE: (&'static str, u32) = ("VMCOREINFO", 0);
+
+    /// Parses the `KEY=VALUE` lines of the note.
+    pub fn parse(desc: &[u8]) -> Self {
+        let text = String::from_utf8_lossy(desc);
+        Self(
+            text.lines()
+                .filter_map(|line| line.split_once('='))
+                .map(|(key, value)| (key.to_owned(), value.trim_end_matches('\0').to_owned()))
+                .collect(),
+        )
+    }
+
+    /// Returns the raw value of the key.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    /// Returns the address of the kernel symbol, `SYMBOL(name)`.
+    pub fn symbol(&self, name: &str) -> Result<u64> {
+        let key = format!("SYMBOL({name})");
+        let value = self.get(&key).with_context(|| format!("No {key} in VMCOREINFO"))?;
+        u64::from_str_radix(value, 16).with_context(|| format!("Invalid {key}: {value}"))
+    }
+
+    /// Returns the offset of the field of a kernel struct, `OFFSET(struct.field)`.
+    pub fn offset(&self, field: &str) -> Result<u64> {
+        self.decimal(&format!("OFFSET({field})"))
+    }
+
+    /// Returns the size of a kernel type, `SIZE(type)`.
+    pub fn size(&self, name: &str) -> Result<u64> {
+        self.decimal(&format!("SIZE({name})"))
+    }
+
+    /// Returns a kernel constant, `NUMBER(name)`.
+    pub fn number(&self, name: &str) -> Result<u64> {
+        self.decimal(&format!("NUMBER({name})"))
+    }
+
+    /// Returns the KASLR offset of the kernel, or 0 if it isn't randomized.
+    pub fn kernel_offset(&self) -> u64 {
+        self.get("KERNELOFFSET").and_then(|v| u64::from_str_radix(v, 16).ok()).unwrap_or(0)
+    }
+
+    fn decimal(&self, key: &str) -> Result<u64> {
+        let value = self.get(key).with_context(|| format!("No {key} in VMCOREINFO"))?;
+        value.parse().with_context(|| format!("Invalid {key}: {value}"))
+    }
+}
+
+/// Returns `base` plus each of `offsets`, which come from the core. Fails rather than overflowing
+/// with corrupt cores.
+pub(crate) fn address(base: u64, offsets: &[u64]) -> Result<u64> {
+    offsets.iter().try_fold(base, |address, &offset| {
+        address.checked_add(offset).with_context(|| format!("{address:#x} + {offset:#x} overflows"))
+    })
+}
+
+pub(crate) fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+pub(crate) fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+pub(crate) fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
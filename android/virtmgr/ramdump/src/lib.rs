@@ -0,0 +1,471 @@
+// Copyright 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Summarizes the ramdumps of crashed VMs: the ELF cores which the crash kernel of the guest
+//! copies out of /proc/vmcore.
+//!
+//! A ramdump is sent to tombstoned as a summary followed by the raw core as an attachment, see
+//! [`write_tombstone`]. The summary can be computed again offline from either the raw core or
+//! such a tombstone.
+
+mod backtrace;
+mod elf;
+mod printk;
+mod symbols;
+
+pub use backtrace::CpuBacktrace;
+pub use elf::Machine;
+pub use printk::LogRecord;
+pub use symbols::SymbolMap;
+
+use anyhow::{Context, Result};
+use elf::{Core, VmcoreInfo};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// The line separating the summary in a tombstone from the raw core attached after it.
+pub const ATTACHMENT_MARKER: &str = "--- ramdump core attached below ---\n";
+
+/// How far into a tombstone to look for the attached core.
+const MAX_SUMMARY_SIZE: u64 = 1 << 20;
+
+/// How many of the last lines of the kernel log are included in a summary by default.
+pub const DEFAULT_LOG_LINES: usize = 64;
+
+/// What is known about the crash of a kernel from its core.
+#[derive(Debug)]
+pub struct Summary {
+    pub machine: Machine,
+    /// The release of the kernel, as in `uname -r`.
+    pub release: Option<String>,
+    /// The KASLR offset of the kernel, to subtract from its addresses to look them up in its
+    /// `System.map`.
+    pub kernel_offset: u64,
+    /// When the kernel crashed, in seconds since epoch.
+    pub crash_time: Option<String>,
+    /// The message of the panic or oops, found in the kernel log.
+    pub panic: Option<String>,
+    pub backtraces: Vec<CpuBacktrace>,
+    /// The records of the kernel log, oldest first.
+    pub log: Vec<LogRecord>,
+    /// Why the kernel log couldn't be read, if it couldn't.
+    pub log_error: Option<String>,
+}
+
+impl Summary {
+    /// Writes the summary out as text, with symbols from `symbols` if given and the last
+    /// `log_lines` lines of the kernel log.
+    pub fn write(
+        &self,
+        out: &mut dyn Write,
+        symbols: Option<&SymbolMap>,
+        log_lines: usize,
+    ) -> io::Result<()> {
+        writeln!(out, "Ramdump summary")?;
+        writeln!(out, "Architecture: {}", self.machine.name())?;
+        writeln!(out, "Kernel release: {}", self.release.as_deref().unwrap_or("unknown"))?;
+        writeln!(out, "Kernel offset: {:#x}", self.kernel_offset)?;
+        if let Some(crash_time) = &self.crash_time {
+            writeln!(out, "Crash time: {crash_time}")?;
+        }
+        writeln!(out, "Panic: {}", self.panic.as_deref().unwrap_or("not found in the kernel log"))?;
+
+        for backtrace in &self.backtraces {
+            writeln!(out)?;
+            writeln!(
+                out,
+                "CPU {} (pid {}, sp {:#018x}):",
+                backtrace.cpu, backtrace.pid, backtrace.sp
+            )?;
+            for (index, addr) in backtrace.frames.iter().enumerate() {
+                let symbol = symbols.and_then(|s| s.lookup(addr.wrapping_sub(self.kernel_offset)));
+                match symbol {
+                    Some((name, offset)) => {
+                        writeln!(out, "  #{index:02} {addr:#018x} {name}+{offset:#x}")?
+                    }
+                    None => writeln!(out, "  #{index:02} {addr:#018x}")?,
+                }
+            }
+        }
+
+        writeln!(out)?;
+        if let Some(error) = &self.log_error {
+            writeln!(out, "Kernel log unavailable: {error}")?;
+        } else {
+            let lines: Vec<String> = self
+                .log
+                .iter()
+                .flat_map(|record| {
+                    let prefix = LogRecord { text: String::new(), ..*record }.to_string();
+                    record.text.lines().map(move |line| format!("{prefix}{line}"))
+                })
+                .collect();
+            let skip = lines.len().saturating_sub(log_lines);
+            writeln!(out, "Kernel log (last {} of {} lines):", lines.len() - skip, lines.len())?;
+            for line in &lines[skip..] {
+                writeln!(out, "{line}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Summarizes the ramdump in `reader`, which is either a raw core or a tombstone with the core
+/// attached.
+pub fn summarize<R: Read + Seek>(mut reader: R) -> Result<Summary> {
+    let base = find_core(&mut reader)?;
+    let mut core = Core::new(reader, base).context("Failed to parse the ELF core")?;
+    let info = core
+        .notes(VmcoreInfo::NOTE.0, VmcoreInfo::NOTE.1)
+        .next()
+        .map(|note| VmcoreInfo::parse(&note.desc))
+        .unwrap_or_default();
+
+    let backtraces = backtrace::backtraces(&mut core, &info);
+    let (log, log_error) = match printk::read_log(&mut core, &info) {
+        Ok(log) => (log, None),
+        Err(e) => (Vec::new(), Some(format!("{e:#}"))),
+    };
+    Ok(Summary {
+        machine: core.machine,
+        release: info.get("OSRELEASE").map(str::to_owned),
+        kernel_offset: info.kernel_offset(),
+        crash_time: info.get("CRASHTIME").map(str::to_owned),
+        panic: find_panic(&log),
+        backtraces,
+        log,
+        log_error,
+    })
+}
+
+/// Returns the message of the panic or, failing that, of the first oops in the kernel log.
+fn find_panic(log: &[LogRecord]) -> Option<String> {
+    const OOPS_MARKERS: [&str; 4] =
+        ["Unable to handle kernel", "Internal error:", "BUG:", "general protection fault"];
+    let lines = || log.iter().flat_map(|record| record.text.lines());
+    lines()
+        .rfind(|line| line.contains("Kernel panic - not syncing"))
+        .or_else(|| lines().find(|line| OOPS_MARKERS.iter().any(|marker| line.contains(marker))))
+        .map(|line| line.trim().to_owned())
+}
+
+/// Returns the offset of the ELF core in `reader`: either 0 for a raw core, or just after the
+/// [`ATTACHMENT_MARKER`] of a tombstone.
+pub fn find_core<R: Read + Seek>(reader: &mut R) -> Result<u64> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut head = Vec::new();
+    reader.take(MAX_SUMMARY_SIZE).read_to_end(&mut head)?;
+    if elf::is_elf(&head) {
+        return Ok(0);
+    }
+    let marker = ATTACHMENT_MARKER.as_bytes();
+    let position = head
+        .windows(marker.len())
+        .position(|window| window == marker)
+        .context("Neither an ELF core nor a tombstone with a ramdump attached")?;
+    Ok((position + marker.len()) as u64)
+}
+
+/// Writes a summary of the ramdump in `core` to `out`, followed by the raw core as an attachment.
+/// If the ramdump can't be summarized, only the reason is written before the attachment.
+pub fn write_tombstone<R: Read + Seek>(mut core: R, out: &mut dyn Write) -> Result<()> {
+    match summarize(&mut core) {
+        Ok(summary) => summary.write(out, None, DEFAULT_LOG_LINES)?,
+        Err(e) => writeln!(out, "Failed to summarize the ramdump: {e:#}")?,
+    }
+    out.write_all(ATTACHMENT_MARKER.as_bytes())?;
+    core.seek(SeekFrom::Start(0))?;
+    io::copy(&mut core, out).context("Failed to attach the ramdump")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Where the memory of the test core is mapped.
+    const BASE: u64 = 0xffff_0000_0000_0000;
+    const MEMORY_SIZE: usize = 0x5000;
+    const PRB: u64 = BASE + 0x100;
+    const DESCS: u64 = BASE + 0x1000;
+    const INFOS: u64 = BASE + 0x2000;
+    const DATA: u64 = BASE + 0x3000;
+    const DATA_SIZE_BITS: u32 = 8;
+    const FRAME: u64 = BASE + 0x4000;
+    const KERNEL_OFFSET: u64 = 0x1000_0000;
+    const PC: u64 = 0xffff_8000_1000_0010;
+
+    const VMCOREINFO: &str = "OSRELEASE=6.6.0-test
+KERNELOFFSET=10000000
+CRASHTIME=1700000000
+NUMBER(VA_BITS)=48
+SYMBOL(prb)=ffff000000000000
+OFFSET(printk_ringbuffer.desc_ring)=0
+OFFSET(printk_ringbuffer.text_data_ring)=64
+OFFSET(prb_desc_ring.count_bits)=0
+OFFSET(prb_desc_ring.descs)=8
+OFFSET(prb_desc_ring.infos)=16
+OFFSET(prb_desc_ring.head_id)=24
+OFFSET(prb_desc_ring.tail_id)=32
+SIZE(prb_desc)=24
+OFFSET(prb_desc.state_var)=0
+OFFSET(prb_desc.text_blk_lpos)=8
+OFFSET(prb_data_blk_lpos.begin)=0
+OFFSET(prb_data_blk_lpos.next)=8
+SIZE(printk_info)=16
+OFFSET(printk_info.ts_nsec)=0
+OFFSET(printk_info.text_len)=8
+OFFSET(prb_data_ring.size_bits)=0
+OFFSET(prb_data_ring.data)=8
+OFFSET(atomic_long_t.counter)=0
+";
+
+    struct Memory(Vec<u8>);
+
+    impl Memory {
+        fn write(&mut self, vaddr: u64, data: &[u8]) {
+            let offset = (vaddr - BASE) as usize;
+            self.0[offset..offset + data.len()].copy_from_slice(data);
+        }
+
+        fn write_u64(&mut self, vaddr: u64, value: u64) {
+            self.write(vaddr, &value.to_le_bytes());
+        }
+
+        /// Writes a finalized record of the printk ringbuffer with a 4-entry descriptor ring.
+        fn write_record(&mut self, id: u64, begin: u64, next: u64, ts_nsec: u64, text: &str) {
+            let index = id % 4;
+            let desc = DESCS + index * 24;
+            self.write_u64(desc, (2 << 62) | id);
+            self.write_u64(desc + 8, begin);
+            self.write_u64(desc + 16, next);
+            let info = INFOS + index * 16;
+            self.write_u64(info, ts_nsec);
+            self.write(info + 8, &(text.len() as u16).to_le_bytes());
+            let data_size = 1 << DATA_SIZE_BITS;
+            let start = if begin / data_size == next / data_size { begin % data_size } else { 0 };
+            self.write_u64(DATA + start, id);
+            self.write(DATA + start + 8, text.as_bytes());
+        }
+    }
+
+    fn note(name: &str, kind: u32, desc: &[u8]) -> Vec<u8> {
+        let mut note = Vec::new();
+        note.extend_from_slice(&(name.len() as u32 + 1).to_le_bytes());
+        note.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+        note.extend_from_slice(&kind.to_le_bytes());
+        note.extend_from_slice(name.as_bytes());
+        note.push(0);
+        note.resize(note.len().next_multiple_of(4), 0);
+        note.extend_from_slice(desc);
+        note.resize(note.len().next_multiple_of(4), 0);
+        note
+    }
+
+    fn prstatus(pid: u32, regs: &[(usize, u64)]) -> Vec<u8> {
+        let mut desc = vec![0u8; 112 + 34 * 8 + 8];
+        desc[32..36].copy_from_slice(&pid.to_le_bytes());
+        for (index, value) in regs {
+            let offset = 112 + index * 8;
+            desc[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+        }
+        desc
+    }
+
+    fn phdr(kind: u32, offset: u64, vaddr: u64, size: u64) -> Vec<u8> {
+        let mut phdr = vec![0u8; 56];
+        phdr[0..4].copy_from_slice(&kind.to_le_bytes());
+        phdr[8..16].copy_from_slice(&offset.to_le_bytes());
+        phdr[16..24].copy_from_slice(&vaddr.to_le_bytes());
+        phdr[32..40].copy_from_slice(&size.to_le_bytes());
+        phdr[40..48].copy_from_slice(&size.to_le_bytes());
+        phdr
+    }
+
+    fn make_core(notes: &[u8], memory: &Memory) -> Vec<u8> {
+        let notes_offset = 64 + 2 * 56;
+        let memory_offset = notes_offset + notes.len() as u64;
+        let mut core = vec![0u8; 64];
+        core[0..4].copy_from_slice(b"\x7fELF");
+        core[4] = 2;
+        core[5] = 1;
+        core[6] = 1;
+        core[16..18].copy_from_slice(&4u16.to_le_bytes());
+        core[18..20].copy_from_slice(&183u16.to_le_bytes());
+        core[32..40].copy_from_slice(&64u64.to_le_bytes());
+        core[52..54].copy_from_slice(&64u16.to_le_bytes());
+        core[54..56].copy_from_slice(&56u16.to_le_bytes());
+        core[56..58].copy_from_slice(&2u16.to_le_bytes());
+        core.extend(phdr(4, notes_offset, 0, notes.len() as u64));
+        core.extend(phdr(1, memory_offset, BASE, memory.0.len() as u64));
+        core.extend_from_slice(notes);
+        core.extend_from_slice(&memory.0);
+        core
+    }
+
+    fn test_core() -> Vec<u8> {
+        let mut memory = Memory(vec![0u8; MEMORY_SIZE]);
+        // The ringbuffer.
+        memory.write_u64(BASE, PRB);
+        memory.write(PRB, &2u32.to_le_bytes());
+        memory.write_u64(PRB + 8, DESCS);
+        memory.write_u64(PRB + 16, INFOS);
+        memory.write_u64(PRB + 24, 6);
+        memory.write_u64(PRB + 32, 3);
+        memory.write(PRB + 64, &DATA_SIZE_BITS.to_le_bytes());
+        memory.write_u64(PRB + 64 + 8, DATA);
+        // Record 3 is still being written.
+        memory.write_record(3, 256 + 100, 256 + 120, 500_000, "reserved");
+        memory.write_u64(DESCS + 3 * 24, 3);
+        memory.write_record(4, 256 + 200, 256 + 224, 1_000_000, "first");
+        // Record 5 doesn't fit at the end of the ring so wraps to its start.
+        memory.write_record(5, 256 + 240, 512 + 24, 2_500_000_000, "second\nthird");
+        memory.write_record(
+            6,
+            512 + 24,
+            512 + 72,
+            3_000_000_000,
+            "Kernel panic - not syncing: test",
+        );
+
+        // Two frame records, the first of which has a return address signed with PAC.
+        memory.write_u64(FRAME, FRAME + 0x10);
+        memory.write_u64(FRAME + 8, 0x12ab_8000_1000_0200);
+        memory.write_u64(FRAME + 0x10, 0);
+        memory.write_u64(FRAME + 0x18, 0xffff_8000_1000_0300);
+
+        let mut notes =
+            note("CORE", 1, &prstatus(42, &[(29, FRAME), (31, FRAME - 0x100), (32, PC)]));
+        notes.extend(note("CORE", 1, &prstatus(0, &[(31, BASE + 0x3f00), (32, PC + 4)])));
+        notes.extend(note("VMCOREINFO", 0, VMCOREINFO.as_bytes()));
+        make_core(&notes, &memory)
+    }
+
+    #[test]
+    fn summarize_core() {
+        let summary = summarize(Cursor::new(test_core())).unwrap();
+        assert_eq!(Machine::Aarch64, summary.machine);
+        assert_eq!(Some("6.6.0-test".to_owned()), summary.release);
+        assert_eq!(KERNEL_OFFSET, summary.kernel_offset);
+        assert_eq!(Some("Kernel panic - not syncing: test".to_owned()), summary.panic);
+        assert_eq!(None, summary.log_error);
+        let texts: Vec<&str> = summary.log.iter().map(|r| r.text.as_str()).collect();
+        assert_eq!(vec!["first", "second\nthird", "Kernel panic - not syncing: test"], texts);
+
+        assert_eq!(2, summary.backtraces.len());
+        assert_eq!(42, summary.backtraces[0].pid);
+        assert_eq!(
+            vec![PC, 0xffff_8000_1000_0200, 0xffff_8000_1000_0300],
+            summary.backtraces[0].frames
+        );
+        assert_eq!(vec![PC + 4], summary.backtraces[1].frames);
+    }
+
+    #[test]
+    fn write_summary_with_symbols() {
+        let summary = summarize(Cursor::new(test_core())).unwrap();
+        let symbols = SymbolMap::parse(
+            "ffff800000000000 T _text\n\
+             ffff800000000000 t panic\n\
+             ffff800000000180 T do_exit\n\
+             ffff800000000190 d some_data\n",
+        )
+        .unwrap();
+        let mut out = Vec::new();
+        summary.write(&mut out, Some(&symbols), 2).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("Panic: Kernel panic - not syncing: test\n"), "{text}");
+        assert!(text.contains("CPU 0 (pid 42, sp 0xffff000000003f00):\n"), "{text}");
+        assert!(text.contains("  #00 0xffff800010000010 panic+0x10\n"), "{text}");
+        assert!(text.contains("  #01 0xffff800010000200 do_exit+0x80\n"), "{text}");
+        assert!(text.contains("Kernel log (last 2 of 4 lines):\n"), "{text}");
+        assert!(text.contains("[    2.500000] third\n[    3.000000] Kernel panic"), "{text}");
+        assert!(!text.contains("first"), "{text}");
+    }
+
+    #[test]
+    fn summarize_tombstone() {
+        let core = test_core();
+        let mut tombstone = Vec::new();
+        write_tombstone(Cursor::new(&core), &mut tombstone).unwrap();
+        let text = String::from_utf8_lossy(&tombstone);
+        assert!(text.starts_with("Ramdump summary\n"), "{text}");
+        assert!(tombstone.ends_with(&core));
+
+        let mut reader = Cursor::new(&tombstone);
+        assert_eq!((tombstone.len() - core.len()) as u64, find_core(&mut reader).unwrap());
+        let summary = summarize(reader).unwrap();
+        assert_eq!(3, summary.log.len());
+    }
+
+    #[test]
+    fn summarize_without_log() {
+        let memory = Memory(vec![0u8; 0x100]);
+        let core = make_core(&note("VMCOREINFO", 0, b"OSRELEASE=6.6.0-test\n"), &memory);
+        let summary = summarize(Cursor::new(core)).unwrap();
+        assert!(summary.log.is_empty());
+        assert!(summary.log_error.is_some());
+        assert_eq!(None, summary.panic);
+        assert!(summary.backtraces.is_empty());
+    }
+
+    #[test]
+    fn summarize_malformed_core() {
+        // Program headers and notes past the end of the file, in tombstones so that their offsets
+        // overflow once added to the offset of the core.
+        for field in [32..40, 64 + 8..64 + 16] {
+            let mut core = test_core();
+            core[field].copy_from_slice(&u64::MAX.to_le_bytes());
+            let mut tombstone = ATTACHMENT_MARKER.as_bytes().to_vec();
+            tombstone.extend(core);
+            let e = summarize(Cursor::new(tombstone)).unwrap_err();
+            assert!(format!("{e:#}").contains("overflows"), "{e:#}");
+        }
+
+        // Memory at the end of the address space, read past it.
+        let mut core = test_core();
+        let top = u64::MAX - MEMORY_SIZE as u64 + 1;
+        core[64 + 56 + 16..64 + 56 + 24].copy_from_slice(&top.to_le_bytes());
+        let mut core = Core::new(Cursor::new(core), 0).unwrap();
+        assert!(core.read_u64(u64::MAX - 7).is_ok());
+        assert!(core.read_u64(u64::MAX - 3).is_err());
+
+        // A descriptor ring and a frame record whose addresses overflow.
+        let mut memory = Memory(vec![0u8; MEMORY_SIZE]);
+        memory.write_u64(BASE, PRB);
+        memory.write(PRB, &2u32.to_le_bytes());
+        memory.write_u64(PRB + 8, DESCS);
+        memory.write_u64(PRB + 24, 3);
+        memory.write_u64(PRB + 32, 3);
+        memory.write(PRB + 64, &DATA_SIZE_BITS.to_le_bytes());
+        let vmcoreinfo =
+            VMCOREINFO.replace("SIZE(prb_desc)=24", &format!("SIZE(prb_desc)={}", u64::MAX));
+        let mut notes = note("CORE", 1, &prstatus(42, &[(29, u64::MAX - 3), (32, PC)]));
+        notes.extend(note("VMCOREINFO", 0, vmcoreinfo.as_bytes()));
+        let summary = summarize(Cursor::new(make_core(&notes, &memory))).unwrap();
+        assert!(summary.log.is_empty());
+        let log_error = summary.log_error.unwrap();
+        assert!(log_error.contains("overflows"), "{log_error}");
+        assert_eq!(vec![PC], summary.backtraces[0].frames);
+    }
+
+    #[test]
+    fn tombstone_of_invalid_core() {
+        let mut tombstone = Vec::new();
+        write_tombstone(Cursor::new(b"not a core"), &mut tombstone).unwrap();
+        let text = String::from_utf8(tombstone).unwrap();
+        assert!(text.starts_with("Failed to summarize the ramdump: "), "{text}");
+        assert!(text.ends_with(&format!("{ATTACHMENT_MARKER}not a core")), "{text}");
+    }
+}
@@ -0,0 +1,67 @@
+// Copyright 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! CLI for summarizing the ramdump of a crashed VM offline
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use ramdump::{find_core, summarize, SymbolMap, DEFAULT_LOG_LINES};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Seek, SeekFrom};
+use std::path::PathBuf;
+
+/// Option parser
+#[derive(Parser, Debug)]
+struct Opt {
+    /// Ramdump to summarize: either the raw ELF core, or a tombstone with the core attached
+    dump: PathBuf,
+
+    /// System.map of the crashed kernel, to symbolize the backtraces with
+    #[arg(long)]
+    system_map: Option<PathBuf>,
+
+    /// Number of lines of the kernel log to print, from the end
+    #[arg(long, default_value_t = DEFAULT_LOG_LINES)]
+    log_lines: usize,
+
+    /// Write the raw ELF core out to this file, e.g. to load it in a debugger
+    #[arg(long)]
+    extract_core: Option<PathBuf>,
+}
+
+fn main() -> Result<()> {
+    let opt = Opt::parse();
+
+    let symbols = opt
+        .system_map
+        .map(|path| {
+            let text =
+                fs::read_to_string(&path).with_context(|| format!("Failed to read {path:?}"))?;
+            SymbolMap::parse(&text).with_context(|| format!("Failed to parse {path:?}"))
+        })
+        .transpose()?;
+    let file = File::open(&opt.dump).with_context(|| format!("Failed to open {:?}", opt.dump))?;
+    let mut reader = BufReader::new(file);
+
+    let summary = summarize(&mut reader)?;
+    summary.write(&mut io::stdout().lock(), symbols.as_ref(), opt.log_lines)?;
+
+    if let Some(path) = opt.extract_core {
+        let base = find_core(&mut reader)?;
+        reader.seek(SeekFrom::Start(base))?;
+        let mut out = File::create(&path).with_context(|| format!("Failed to create {path:?}"))?;
+        io::copy(&mut reader, &mut out).with_context(|| format!("Failed to write {path:?}"))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,175 @@
+// Copyright 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Extraction of the kernel log from the printk ringbuffer of the crashed kernel
+
+use crate::elf::{address, Core, VmcoreInfo};
+use anyhow::{ensure, Context, Result};
+use std::fmt;
+use std::io::{Read, Seek};
+
+/// Bits of `prb_desc.state_var` holding the ID of the descriptor.
+const DESC_ID_MASK: u64 = !(3 << 62);
+/// The states of a descriptor whose record can be read, see `enum desc_state` in the kernel.
+const DESC_COMMITTED: u64 = 1;
+const DESC_FINALIZED: u64 = 2;
+
+/// Upper bound of the number of descriptors, to reject corrupt ringbuffers early.
+const MAX_COUNT_BITS: u32 = 24;
+
+/// A record of the kernel log.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LogRecord {
+    /// The time of the record, in nanoseconds since boot.
+    pub ts_nsec: u64,
+    pub text: String,
+}
+
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.ts_nsec / 1_000_000_000;
+        let usecs = self.ts_nsec % 1_000_000_000 / 1000;
+        write!(f, "[{secs:5}.{usecs:06}] {}", self.text)
+    }
+}
+
+/// Layout of the printk ringbuffer structs, from VMCOREINFO.
+struct Layout {
+    desc_ring: u64,
+    text_data_ring: u64,
+    count_bits: u64,
+    descs: u64,
+    infos: u64,
+    head_id: u64,
+    tail_id: u64,
+    desc_size: u64,
+    state_var: u64,
+    text_blk_lpos: u64,
+    lpos_begin: u64,
+    lpos_next: u64,
+    info_size: u64,
+    info_ts_nsec: u64,
+    info_text_len: u64,
+    size_bits: u64,
+    data: u64,
+    counter: u64,
+}
+
+impl Layout {
+    fn new(info: &VmcoreInfo) -> Result<Self> {
+        Ok(Self {
+            desc_ring: info.offset("printk_ringbuffer.desc_ring")?,
+            text_data_ring: info.offset("printk_ringbuffer.text_data_ring")?,
+            count_bits: info.offset("prb_desc_ring.count_bits")?,
+            descs: info.offset("prb_desc_ring.descs")?,
+            infos: info.offset("prb_desc_ring.infos")?,
+            head_id: info.offset("prb_desc_ring.head_id")?,
+            tail_id: info.offset("prb_desc_ring.tail_id")?,
+            desc_size: info.size("prb_desc")?,
+            state_var: info.offset("prb_desc.state_var")?,
+            text_blk_lpos: info.offset("prb_desc.text_blk_lpos")?,
+            lpos_begin: info.offset("prb_data_blk_lpos.begin")?,
+            lpos_next: info.offset("prb_data_blk_lpos.next")?,
+            info_size: info.size("printk_info")?,
+            info_ts_nsec: info.offset("printk_info.ts_nsec")?,
+            info_text_len: info.offset("printk_info.text_len")?,
+            size_bits: info.offset("prb_data_ring.size_bits")?,
+            data: info.offset("prb_data_ring.data")?,
+            counter: info.offset("atomic_long_t.counter")?,
+        })
+    }
+}
+
+/// Reads the records of the kernel log, oldest first. Records which are being written or have
+/// been overwritten are skipped.
+pub fn read_log<R: Read + Seek>(core: &mut Core<R>, info: &VmcoreInfo) -> Result<Vec<LogRecord>> {
+    let layout = Layout::new(info).context("Kernel without a printk ringbuffer")?;
+    let prb = core.read_u64(info.symbol("prb")?).context("Failed to read prb")?;
+
+    let desc_ring = address(prb, &[layout.desc_ring])?;
+    let count_bits = core.read_u32(address(desc_ring, &[layout.count_bits])?)?;
+    ensure!(count_bits <= MAX_COUNT_BITS, "Invalid descriptor count bits {count_bits}");
+    let count = 1u64 << count_bits;
+    let descs = core.read_u64(address(desc_ring, &[layout.descs])?)?;
+    let infos = core.read_u64(address(desc_ring, &[layout.infos])?)?;
+    let head_id =
+        core.read_u64(address(desc_ring, &[layout.head_id, layout.counter])?)? & DESC_ID_MASK;
+    let tail_id =
+        core.read_u64(address(desc_ring, &[layout.tail_id, layout.counter])?)? & DESC_ID_MASK;
+
+    let data_ring = address(prb, &[layout.text_data_ring])?;
+    let size_bits = core.read_u32(address(data_ring, &[layout.size_bits])?)?;
+    ensure!(size_bits < 32, "Invalid data ring size bits {size_bits}");
+    let data = core.read_u64(address(data_ring, &[layout.data])?)?;
+
+    let mut records = Vec::new();
+    let mut id = tail_id;
+    for _ in 0..count {
+        let index = id % count;
+        let desc = address(descs, &[element_offset(index, layout.desc_size)?])?;
+        let state_var = core.read_u64(address(desc, &[layout.state_var, layout.counter])?)?;
+        let state = state_var >> 62;
+        if state_var & DESC_ID_MASK == id && (state == DESC_COMMITTED || state == DESC_FINALIZED) {
+            let lpos = address(desc, &[layout.text_blk_lpos])?;
+            let begin = core.read_u64(address(lpos, &[layout.lpos_begin])?)?;
+            let next = core.read_u64(address(lpos, &[layout.lpos_next])?)?;
+            let info = address(infos, &[element_offset(index, layout.info_size)?])?;
+            let ts_nsec = core.read_u64(address(info, &[layout.info_ts_nsec])?)?;
+            let text_len = core.read_u16(address(info, &[layout.info_text_len])?)?;
+            if let Some(text) = read_text(core, data, size_bits, begin, next, text_len)? {
+                records.push(LogRecord { ts_nsec, text });
+            }
+        }
+        if id == head_id {
+            break;
+        }
+        id = id.wrapping_add(1) & DESC_ID_MASK;
+    }
+    Ok(records)
+}
+
+/// Returns the offset of the element `index` of an array of elements of `size` bytes.
+fn element_offset(index: u64, size: u64) -> Result<u64> {
+    index.checked_mul(size).with_context(|| format!("Element {index} of size {size} overflows"))
+}
+
+/// Reads the text of a data block of the ringbuffer, see `get_data()` in the kernel.
+fn read_text<R: Read + Seek>(
+    core: &mut Core<R>,
+    data: u64,
+    size_bits: u32,
+    begin: u64,
+    next: u64,
+    text_len: u16,
+) -> Result<Option<String>> {
+    // Data-less blocks have the lowest bit set in both positions.
+    if begin & 1 == 1 && next & 1 == 1 {
+        return Ok(None);
+    }
+    let wraps = |lpos: u64| lpos >> size_bits;
+    let data_size = 1u64 << size_bits;
+    let (start, block_len) = if wraps(begin) == wraps(next) && begin < next {
+        (begin % data_size, next - begin)
+    } else if wraps(begin.wrapping_add(data_size)) == wraps(next) {
+        // The block didn't fit at the end of the ring, so it was put at its start.
+        (0, next % data_size)
+    } else {
+        return Ok(None);
+    };
+    // Each block starts with the ID of its descriptor.
+    let len = block_len.saturating_sub(8).min(text_len.into()) as usize;
+    let mut text = vec![0u8; len];
+    core.read(address(data, &[start, 8])?, &mut text)?;
+    Ok(Some(String::from_utf8_lossy(&text).into_owned()))
+}
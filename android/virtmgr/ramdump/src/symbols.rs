@@ -0,0 +1,53 @@
+// Copyright 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Symbolization of kernel addresses with a `System.map`
+
+use anyhow::{Context, Result};
+
+/// The text symbols of a kernel, sorted by address.
+#[derive(Debug, Default)]
+pub struct SymbolMap {
+    symbols: Vec<(u64, String)>,
+}
+
+impl SymbolMap {
+    /// Parses the `<address> <type> <name>` lines of a `System.map` or `/proc/kallsyms`.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut symbols = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let mut fields = line.split_whitespace();
+            let (Some(addr), Some(kind), Some(name)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            if !matches!(kind, "t" | "T" | "w" | "W") {
+                continue;
+            }
+            let addr = u64::from_str_radix(addr, 16)
+                .with_context(|| format!("Invalid address at line {}", number + 1))?;
+            symbols.push((addr, name.to_owned()));
+        }
+        symbols.sort();
+        Ok(Self { symbols })
+    }
+
+    /// Returns the symbol containing the address, and the offset of the address in it.
+    pub fn lookup(&self, addr: u64) -> Option<(&str, u64)> {
+        let index = self.symbols.partition_point(|(start, _)| *start <= addr).checked_sub(1)?;
+        let (start, name) = &self.symbols[index];
+        Some((name, addr - start))
+    }
+}
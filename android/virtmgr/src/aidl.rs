@@ -14,19 +14,23 @@
 
 //! Implementation of the AIDL interface of the VirtualizationService.
 
-use crate::{get_calling_pid, get_calling_uid, get_this_pid};
+use crate::{get_calling_pid, get_calling_uid};
 use crate::atom::{write_vm_booted_stats, write_vm_creation_stats};
+use crate::balloon::BalloonPolicy;
 use crate::composite::make_composite_image;
-use crate::crosvm::{AudioConfig, CrosvmConfig, DiskFile, DisplayConfig, GpuConfig, InputDeviceOption, PayloadState, UsbConfig, VmContext, VmInstance, VmState};
+use crate::crosvm::{AudioConfig, BOOT_HANGUP_TIMEOUT, CrosvmConfig, DiskFile, DisplayConfig, GpuConfig, InputDeviceOption, MAX_NETWORK_INTERFACES, PayloadState, TapDevice, UsbConfig, VmContext, VmInstance, VmState};
 use crate::debug_config::DebugConfig;
 use crate::dt_overlay::{create_device_tree_overlay, VM_DT_OVERLAY_MAX_SIZE, VM_DT_OVERLAY_PATH};
+use crate::metrics::VmMetrics;
 use crate::payload::{add_microdroid_payload_images, add_microdroid_system_images, add_microdroid_vendor_image};
+use crate::restart::RestartPolicy;
 use crate::selinux::{getfilecon, SeContext};
 use android_os_permissions_aidl::aidl::android::os::IPermissionController;
 use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::{
     Certificate::Certificate,
     DeathReason::DeathReason,
     ErrorCode::ErrorCode,
+    PayloadTaskStatus::PayloadTaskStatus,
 };
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
     AssignableDevice::AssignableDevice,
@@ -35,12 +39,18 @@ use android_system_virtualizationservice::aidl::android::system::virtualizations
     InputDevice::InputDevice,
     IVirtualMachine::{BnVirtualMachine, IVirtualMachine},
     IVirtualMachineCallback::IVirtualMachineCallback,
+    IVirtualMachineMetricsCallback::IVirtualMachineMetricsCallback,
     IVirtualizationService::IVirtualizationService,
+    NetworkInterface::NetworkInterface,
     Partition::Partition,
     PartitionType::PartitionType,
     VirtualMachineAppConfig::{DebugLevel::DebugLevel, Payload::Payload, VirtualMachineAppConfig},
     VirtualMachineConfig::VirtualMachineConfig,
     VirtualMachineDebugInfo::VirtualMachineDebugInfo,
+    VirtualMachineInstanceDebugInfo::VirtualMachineInstanceDebugInfo,
+    VirtualMachineMetrics::{
+        Disk::Disk as DiskMetrics, Network::Network as NetworkMetrics, VirtualMachineMetrics,
+    },
     VirtualMachinePayloadConfig::VirtualMachinePayloadConfig,
     VirtualMachineRawConfig::VirtualMachineRawConfig,
     VirtualMachineState::VirtualMachineState,
@@ -58,7 +68,7 @@ use android_hardware_security_authgraph::aidl::android::hardware::security::auth
     Key::Key, PubKey::PubKey, SessionIdSignature::SessionIdSignature, SessionInfo::SessionInfo,
     SessionInitiationInfo::SessionInitiationInfo,
 };
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use apkverify::{HashAlgorithm, V4Signature};
 use avflog::LogResult;
 use binder::{
@@ -84,12 +94,15 @@ use std::io::{BufRead, BufReader, Error, ErrorKind, Seek, SeekFrom, Write};
 use std::iter;
 use std::num::{NonZeroU16, NonZeroU32};
 use std::ops::Range;
-use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
 use std::os::unix::raw::pid_t;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex, Weak, LazyLock};
+use std::thread;
+use std::time::Duration;
 use vbmeta::VbMetaImage;
-use vmconfig::{VmConfig, get_debug_level};
+use vmconfig::{parse_mac_address, VmConfig, get_debug_level};
 use vsock::VsockStream;
 use zip::ZipArchive;
 
@@ -98,6 +111,12 @@ pub type Cid = u32;
 
 pub const BINDER_SERVICE_IDENTIFIER: &str = "android.system.virtualizationservice";
 
+/// The shortest interval at which samples of the resource usage of a VM can be streamed.
+const MIN_METRICS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The most callbacks to which samples of the resource usage of a VM can be streamed at once.
+const MAX_METRICS_CALLBACKS: usize = 8;
+
 /// The size of zero.img.
 /// Gaps in composite disk images are filled with a shared zero.img.
 const ZERO_FILLER_SIZE: u64 = 4096;
@@ -176,7 +195,13 @@ fn get_current_sdk() -> Result<u32> {
 
 pub fn remove_temporary_files(path: &PathBuf) -> Result<()> {
     for dir_entry in read_dir(path)? {
-        remove_file(dir_entry?.path())?;
+        let dir_entry = dir_entry?;
+        if dir_entry.file_type()?.is_dir() {
+            // e.g. a snapshot extracted to restore the VM.
+            remove_dir_all(dir_entry.path())?;
+        } else {
+            remove_file(dir_entry.path())?;
+        }
     }
     Ok(())
 }
@@ -301,6 +326,50 @@ impl IVirtualizationService for VirtualizationService {
         GLOBAL_SERVICE.debugListVms()
     }
 
+    /// Get a list of the VM instances with secrets in Secretkeeper. This method is only intended
+    /// for debug purposes, and as such is only permitted from the shell user.
+    fn debugListVmInstances(&self) -> binder::Result<Vec<VirtualMachineInstanceDebugInfo>> {
+        // Delegate to the global service, including checking the debug permission.
+        GLOBAL_SERVICE.debugListVmInstances()
+    }
+
+    /// Stop the running VM with the given CID. This method is only intended for debug purposes,
+    /// and as such is only permitted from the shell user.
+    fn debugStopVm(&self, cid: i32) -> binder::Result<()> {
+        // Delegate to the global service, including checking the debug permission and that the
+        // VM is owned by the caller.
+        GLOBAL_SERVICE.debugGetVm(cid)?.stop()
+    }
+
+    /// Suspend the running VM with the given CID.
+    fn debugSuspendVm(&self, cid: i32) -> binder::Result<()> {
+        GLOBAL_SERVICE.debugGetVm(cid)?.suspend()
+    }
+
+    /// Resume the suspended VM with the given CID.
+    fn debugResumeVm(&self, cid: i32) -> binder::Result<()> {
+        GLOBAL_SERVICE.debugGetVm(cid)?.resume()
+    }
+
+    /// Get the size of the memory balloon of the VM with the given CID.
+    fn debugGetMemoryBalloon(&self, cid: i32) -> binder::Result<i64> {
+        GLOBAL_SERVICE.debugGetVm(cid)?.getMemoryBalloon()
+    }
+
+    /// Set the size of the memory balloon of the VM with the given CID.
+    fn debugSetMemoryBalloon(&self, cid: i32, num_bytes: i64) -> binder::Result<()> {
+        if num_bytes < 0 {
+            return Err(anyhow!("Invalid balloon size: {num_bytes}"))
+                .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+        }
+        GLOBAL_SERVICE.debugGetVm(cid)?.setMemoryBalloon(num_bytes)
+    }
+
+    /// Get a sample of the resource usage of the VM with the given CID.
+    fn debugGetMetrics(&self, cid: i32) -> binder::Result<VirtualMachineMetrics> {
+        GLOBAL_SERVICE.debugGetVm(cid)?.getMetrics()
+    }
+
     /// Get a list of assignable device types.
     fn getAssignableDevices(&self) -> binder::Result<Vec<AssignableDevice>> {
         // Delegate to the global service, including checking the permission.
@@ -391,6 +460,15 @@ impl IGlobalVmContext for EarlyVmContext {
             Some("Early VM doesn't support setting host console name"),
         ))
     }
+
+    // Early VMs are not known to the global service, so there is nothing to register.
+    fn setVmInfo(&self, _name: &str, _memory_mib: i32, _protected_vm: bool) -> binder::Result<()> {
+        Ok(())
+    }
+
+    fn setVirtualMachine(&self, _vm: &Strong<dyn IVirtualMachine>) -> binder::Result<()> {
+        Ok(())
+    }
 }
 
 fn find_partition(path: &Path) -> binder::Result<String> {
@@ -515,6 +593,10 @@ impl VirtualizationService {
 
         let device_tree_overlay = maybe_create_device_tree_overlay(config, &temporary_directory)?;
 
+        // Whose boot is recorded against the VM's secrets, see `write_vm_instance_booted`.
+        let instance_id = cfg!(llpvm_changes).then(|| extract_instance_id(config));
+        let updatable = extract_want_updatable(config) && is_secretkeeper_supported();
+
         let debug_config = DebugConfig::new(config);
         let ramdump = if !uses_gki_kernel(config) && debug_config.is_ramdump_needed() {
             Some(prepare_ramdump_file(&temporary_directory)?)
@@ -672,24 +754,8 @@ impl VirtualizationService {
             vec![]
         };
 
-        // Create TAP network interface if the VM supports network.
-        let tap = if cfg!(network) && config.networkSupported {
-            if *is_protected {
-                return Err(anyhow!("Network feature is not supported for pVM yet"))
-                    .with_log()
-                    .or_binder_exception(ExceptionCode::UNSUPPORTED_OPERATION)?;
-            }
-            Some(File::from(
-                GLOBAL_SERVICE
-                    .createTapInterface(&get_this_pid().to_string())?
-                    .as_ref()
-                    .try_clone()
-                    .context("Failed to get TAP interface from ParcelFileDescriptor")
-                    .or_binder_exception(ExceptionCode::BAD_PARCELABLE)?,
-            ))
-        } else {
-            None
-        };
+        // Create TAP network interfaces if the VM supports network.
+        let taps = create_tap_devices(config, cid, *is_protected)?;
 
         let audio_config = if cfg!(paravirtualized_devices) {
             config.audioConfig.as_ref().map(AudioConfig::new)
@@ -704,10 +770,38 @@ impl VirtualizationService {
             .unwrap_or(Ok(UsbConfig { controller: false }))
             .or_binder_exception(ExceptionCode::BAD_PARCELABLE)?;
 
+        let balloon_policy = config
+            .balloonPolicy
+            .as_ref()
+            .map(BalloonPolicy::new)
+            .transpose()
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+
+        let restart_policy = config
+            .restartPolicy
+            .as_ref()
+            .map(RestartPolicy::new)
+            .transpose()
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?
+            .unwrap_or_default();
+
+        let hangup_timeout = match config.payloadStartTimeoutMillis {
+            0 if is_app_config => Some(*BOOT_HANGUP_TIMEOUT),
+            0 => None,
+            millis => Some(Duration::from_millis(
+                millis
+                    .try_into()
+                    .context("Invalid payload start timeout")
+                    .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?,
+            )),
+        };
+
         // Actually start the VM.
         let crosvm_config = CrosvmConfig {
             cid,
             name: config.name.clone(),
+            instance_id,
+            updatable,
             bootloader: maybe_clone_file(&config.bootloader)?,
             kernel,
             initrd,
@@ -729,7 +823,7 @@ impl VirtualizationService {
             ramdump,
             indirect_files,
             platform_version: parse_platform_version_req(&config.platformVersion)?,
-            detect_hangup: is_app_config,
+            hangup_timeout,
             gdb_port,
             vfio_devices,
             dtbo,
@@ -737,14 +831,17 @@ impl VirtualizationService {
             display_config,
             input_device_options,
             hugepages: config.hugePages,
-            tap,
+            taps,
             console_input_device: config.consoleInputDevice.clone(),
             boost_uclamp: config.boostUclamp,
             gpu_config,
             audio_config,
             no_balloon: config.noBalloon,
             usb_config,
+            balloon_policy,
+            restart_policy,
         };
+        let memory_mib = crosvm_config.memory_mib.get() as i32;
         let instance = Arc::new(
             VmInstance::new(
                 crosvm_config,
@@ -758,7 +855,11 @@ impl VirtualizationService {
             .or_service_specific_exception(-1)?,
         );
         state.add_vm(Arc::downgrade(&instance));
-        Ok(VirtualMachine::create(instance))
+        let global_context = &instance.vm_context.global_context;
+        global_context.setVmInfo(&instance.name, memory_mib, instance.protected)?;
+        let vm = VirtualMachine::create(instance.clone());
+        global_context.setVirtualMachine(&vm)?;
+        Ok(vm)
     }
 }
 
@@ -948,6 +1049,69 @@ fn to_input_device_option_from(input_device: &InputDevice) -> Result<InputDevice
         },
     })
 }
+
+/// Creates a TAP network interface for each network interface of the VM with CID `cid`. Without
+/// `networkInterfaces`, the VM has a single NAT network interface if `networkSupported` is set.
+fn create_tap_devices(
+    config: &VirtualMachineRawConfig,
+    cid: Cid,
+    is_protected: bool,
+) -> binder::Result<Vec<TapDevice>> {
+    let default_interfaces = [NetworkInterface::default()];
+    let interfaces: &[NetworkInterface] = match &config.networkInterfaces[..] {
+        [] if cfg!(network) && config.networkSupported => &default_interfaces,
+        [] => return Ok(vec![]),
+        _ if !cfg!(network) => {
+            return Err(anyhow!("Network feature is not supported"))
+                .with_log()
+                .or_binder_exception(ExceptionCode::UNSUPPORTED_OPERATION);
+        }
+        interfaces => interfaces,
+    };
+    if is_protected {
+        return Err(anyhow!("Network feature is not supported for pVM yet"))
+            .with_log()
+            .or_binder_exception(ExceptionCode::UNSUPPORTED_OPERATION);
+    }
+    if interfaces.len() > MAX_NETWORK_INTERFACES {
+        return Err(anyhow!("Can't have more than {MAX_NETWORK_INTERFACES} network interfaces"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+    }
+    let mac_addresses = interfaces
+        .iter()
+        .map(|interface| interface.macAddress.as_deref().map(parse_mac_address).transpose())
+        .collect::<Result<Vec<_>>>()
+        .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+
+    let mut taps = Vec::with_capacity(interfaces.len());
+    for (index, (interface, mac_address)) in interfaces.iter().zip(mac_addresses).enumerate() {
+        // The CID is unique, and short enough for the name of the interface to fit in IFNAMSIZ.
+        let suffix = format!("{cid}_{index}");
+        let tap = GLOBAL_SERVICE.createTapInterface(&suffix, interface).and_then(|tap_fd| {
+            tap_fd
+                .as_ref()
+                .try_clone()
+                .context("Failed to get TAP interface from ParcelFileDescriptor")
+                .or_binder_exception(ExceptionCode::BAD_PARCELABLE)
+        });
+        match tap {
+            Ok(tap) => taps.push(TapDevice { tap: File::from(tap), mac_address }),
+            Err(e) => {
+                // Don't leave the interfaces which were already created attached to bridges.
+                for tap_device in taps {
+                    GLOBAL_SERVICE
+                        .deleteTapInterface(&ParcelFileDescriptor::new(OwnedFd::from(
+                            tap_device.tap,
+                        )))
+                        .unwrap_or_else(|e| error!("Error deleting TAP interface: {e:?}"));
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(taps)
+}
+
 /// Given the configuration for a disk image, assembles the `DiskFile` to pass to crosvm.
 ///
 /// This may involve assembling a composite disk from a set of partition images.
@@ -991,7 +1155,7 @@ fn assemble_disk_image(
             .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
     };
 
-    Ok(DiskFile { image, writable: disk.writable })
+    Ok(DiskFile { image, writable: disk.writable, label: disk.label.clone() })
 }
 
 fn append_kernel_param(param: &str, vm_config: &mut VirtualMachineRawConfig) {
@@ -1196,7 +1360,11 @@ fn create_vm_payload_config(
         bail!("Payload binary name must not specify a path: {payload_binary_name}");
     }
 
-    let task = Task { type_: TaskType::MicrodroidLauncher, command: payload_binary_name.clone() };
+    let task = Task {
+        type_: TaskType::MicrodroidLauncher,
+        command: payload_binary_name.clone(),
+        ..Default::default()
+    };
 
     // The VM only cares about how many there are, these names are actually ignored.
     let extra_apk_count = payload_config.extraApks.len();
@@ -1348,6 +1516,10 @@ impl IVirtualMachine for VirtualMachine {
         Ok(get_state(&self.instance))
     }
 
+    fn getPayloadTaskStatuses(&self) -> binder::Result<Vec<PayloadTaskStatus>> {
+        Ok(self.instance.payload_task_statuses())
+    }
+
     fn registerCallback(
         &self,
         callback: &Strong<dyn IVirtualMachineCallback>,
@@ -1394,6 +1566,48 @@ impl IVirtualMachine for VirtualMachine {
             .or_service_specific_exception(-1)
     }
 
+    fn getMetrics(&self) -> binder::Result<VirtualMachineMetrics> {
+        let metrics = self
+            .instance
+            .get_metrics()
+            .with_context(|| format!("Error getting metrics for VM with CID {}", self.instance.cid))
+            .with_log()
+            .or_service_specific_exception(-1)?;
+        Ok(metrics_to_parcelable(metrics))
+    }
+
+    fn registerMetricsCallback(
+        &self,
+        callback: &Strong<dyn IVirtualMachineMetricsCallback>,
+        interval_millis: i32,
+    ) -> binder::Result<()> {
+        // Don't check permission. The owner of the VM might have passed this binder object to
+        // others.
+        let interval = Duration::from_millis(interval_millis.try_into().unwrap_or(0));
+        if interval < MIN_METRICS_INTERVAL {
+            return Err(anyhow!("Invalid metrics interval: {interval_millis} ms"))
+                .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+        }
+        self.instance
+            .metrics_callbacks
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < MAX_METRICS_CALLBACKS).then_some(count + 1)
+            })
+            .map_err(|_| anyhow!("Too many metrics callbacks"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_STATE)?;
+        // The thread doesn't keep the VM alive, so that it stops once the VM is dropped even if
+        // the VM never started.
+        let instance = Arc::downgrade(&self.instance);
+        let callback = callback.clone();
+        thread::spawn(move || {
+            stream_metrics(&instance, &callback, interval);
+            if let Some(instance) = instance.upgrade() {
+                instance.metrics_callbacks.fetch_sub(1, Ordering::SeqCst);
+            }
+        });
+        Ok(())
+    }
+
     fn connectVsock(&self, port: i32) -> binder::Result<ParcelFileDescriptor> {
         if !matches!(&*self.instance.vm_state.lock().unwrap(), VmState::Running { .. }) {
             return Err(anyhow!("VM is not running")).or_service_specific_exception(-1);
@@ -1409,6 +1623,11 @@ impl IVirtualMachine for VirtualMachine {
         Ok(vsock_stream_to_pfd(stream))
     }
 
+    fn getPayloadOutput(&self) -> binder::Result<ParcelFileDescriptor> {
+        let output = self.instance.take_payload_output().or_service_specific_exception(-1)?;
+        Ok(ParcelFileDescriptor::new(output))
+    }
+
     fn setHostConsoleName(&self, ptsname: &str) -> binder::Result<()> {
         self.instance.vm_context.global_context.setHostConsoleName(ptsname)
     }
@@ -1428,6 +1647,36 @@ impl IVirtualMachine for VirtualMachine {
             .with_log()
             .or_service_specific_exception(-1)
     }
+
+    fn snapshot(&self, output: &ParcelFileDescriptor) -> binder::Result<()> {
+        let output = clone_file(output)?;
+        self.instance
+            .snapshot(output)
+            .with_context(|| format!("Error snapshotting VM with CID {}", self.instance.cid))
+            .with_log()
+            .or_service_specific_exception(-1)
+    }
+
+    fn restore(&self, snapshot: &ParcelFileDescriptor) -> binder::Result<()> {
+        let snapshot = clone_file(snapshot)?;
+        self.instance
+            .restore(snapshot)
+            .with_context(|| format!("Error restoring VM with CID {}", self.instance.cid))
+            .with_log()
+            .or_service_specific_exception(-1)
+    }
+
+    fn resizeDisk(&self, label: &str, size_bytes: i64) -> binder::Result<()> {
+        let size = size_bytes
+            .try_into()
+            .context("Invalid disk size")
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        self.instance
+            .resize_disk(label, size)
+            .with_context(|| format!("Error resizing disk of VM with CID {}", self.instance.cid))
+            .with_log()
+            .or_service_specific_exception(-1)
+    }
 }
 
 impl Drop for VirtualMachine {
@@ -1534,6 +1783,63 @@ impl State {
     }
 }
 
+/// Calls `callback` with a sample of the resource usage of the VM every `interval`, until the VM
+/// dies or is dropped, or the callback fails.
+fn stream_metrics(
+    instance: &Weak<VmInstance>,
+    callback: &Strong<dyn IVirtualMachineMetricsCallback>,
+    interval: Duration,
+) {
+    loop {
+        thread::sleep(interval);
+        let Some(instance) = instance.upgrade() else {
+            break;
+        };
+        match &*instance.vm_state.lock().unwrap() {
+            VmState::NotStarted { .. } | VmState::Restarting { .. } => continue,
+            VmState::Running { .. } => {}
+            VmState::Dead | VmState::Failed => break,
+        }
+        let metrics = match instance.get_metrics() {
+            Ok(metrics) => metrics,
+            Err(e) => {
+                // The VM may have died meanwhile.
+                debug!("Failed to get metrics for VM with CID {}: {:?}", instance.cid, e);
+                continue;
+            }
+        };
+        if let Err(e) = callback.onMetrics(instance.cid as i32, &metrics_to_parcelable(metrics)) {
+            info!("Stopped sending metrics for VM with CID {}: {:?}", instance.cid, e);
+            break;
+        }
+    }
+}
+
+fn metrics_to_parcelable(metrics: VmMetrics) -> VirtualMachineMetrics {
+    let clamp = |value: u64| -> i64 { value.try_into().unwrap_or(i64::MAX) };
+    VirtualMachineMetrics {
+        uptimeMillis: clamp(metrics.uptime.as_millis() as u64),
+        vcpuTimeMillis: metrics.vcpu_time_millis,
+        guestRssKib: metrics.guest_rss_kib,
+        crosvmRssKib: metrics.crosvm_rss_kib,
+        balloonBytes: metrics.balloon.map_or(-1, clamp),
+        disks: metrics
+            .disks
+            .into_iter()
+            .map(|disk| DiskMetrics {
+                readBytes: clamp(disk.read_bytes),
+                writtenBytes: clamp(disk.written_bytes),
+            })
+            .collect(),
+        network: metrics.network.map(|network| NetworkMetrics {
+            rxBytes: clamp(network.rx_bytes),
+            rxPackets: clamp(network.rx_packets),
+            txBytes: clamp(network.tx_bytes),
+            txPackets: clamp(network.tx_packets),
+        }),
+    }
+}
+
 /// Gets the `VirtualMachineState` of the given `VmInstance`.
 fn get_state(instance: &VmInstance) -> VirtualMachineState {
     match &*instance.vm_state.lock().unwrap() {
@@ -1545,6 +1851,7 @@ fn get_state(instance: &VmInstance) -> VirtualMachineState {
             PayloadState::Finished => VirtualMachineState::FINISHED,
             PayloadState::Hangup => VirtualMachineState::DEAD,
         },
+        VmState::Restarting { .. } => VirtualMachineState::STARTING,
         VmState::Dead => VirtualMachineState::DEAD,
         VmState::Failed => VirtualMachineState::DEAD,
     }
@@ -1788,6 +2095,9 @@ impl IVirtualMachineService for VirtualMachineService {
 
             let vm_start_timestamp = vm.vm_metric.lock().unwrap().start_timestamp;
             write_vm_booted_stats(vm.requester_uid as i32, &vm.name, vm_start_timestamp);
+            if let Some(instance_id) = vm.instance_id {
+                write_vm_instance_booted(instance_id, &vm.name, vm.updatable);
+            }
             Ok(())
         } else {
             error!("notifyPayloadStarted is called from an unknown CID {}", cid);
@@ -1837,6 +2147,30 @@ impl IVirtualMachineService for VirtualMachineService {
         }
     }
 
+    fn notifyPayloadTaskStatus(&self, status: &PayloadTaskStatus) -> binder::Result<()> {
+        let cid = self.cid;
+        if let Some(vm) = self.state.lock().unwrap().get_vm(cid) {
+            info!(
+                "VM with CID {} reported task {} is {:?} (exit code {}, {} restarts)",
+                cid, status.name, status.state, status.exitCode, status.restarts
+            );
+            vm.update_payload_task_status(status).or_service_specific_exception(-1)
+        } else {
+            error!("notifyPayloadTaskStatus is called from an unknown CID {}", cid);
+            Err(anyhow!("cannot find a VM with CID {}", cid)).or_service_specific_exception(-1)
+        }
+    }
+
+    fn writePayloadOutput(&self, data: &[u8]) -> binder::Result<()> {
+        let cid = self.cid;
+        if let Some(vm) = self.state.lock().unwrap().get_vm(cid) {
+            vm.write_payload_output(data).or_service_specific_exception(-1)
+        } else {
+            error!("writePayloadOutput is called from an unknown CID {}", cid);
+            Err(anyhow!("cannot find a VM with CID {}", cid)).or_service_specific_exception(-1)
+        }
+    }
+
     fn getSecretkeeper(&self) -> binder::Result<Strong<dyn ISecretkeeper>> {
         if !is_secretkeeper_supported() {
             return Err(StatusCode::NAME_NOT_FOUND)?;
@@ -1850,6 +2184,21 @@ impl IVirtualMachineService for VirtualMachineService {
     }
 }
 
+/// Records the boot of a VM against its instance ID, so that the instances of an app can be told
+/// apart. The function creates a separate thread, as the boot doesn't wait for it.
+fn write_vm_instance_booted(instance_id: [u8; 64], name: &str, updatable: bool) {
+    if cfg!(early) {
+        return;
+    }
+
+    let name = name.to_owned();
+    thread::spawn(move || {
+        GLOBAL_SERVICE.vmInstanceBooted(&instance_id, &name, updatable).unwrap_or_else(|e| {
+            warn!("Failed to record the boot of VM instance: {e}");
+        });
+    });
+}
+
 fn is_secretkeeper_supported() -> bool {
     binder::is_declared(SECRETKEEPER_IDENTIFIER)
         .expect("Could not check for declared Secretkeeper interface")
@@ -0,0 +1,216 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Policy for inflating and deflating the memory balloon of a VM automatically.
+//!
+//! While a VM with a balloon policy runs, its balloon is periodically resized from the memory
+//! pressure of the host, read from `/proc/pressure/memory`, and from the memory statistics which
+//! the guest reports through the balloon. When the host is under pressure, the balloon is
+//! inflated to reclaim the memory which the guest doesn't use. When the host is not, or when the
+//! guest runs low on memory, it is deflated. The memory left to the guest always stays between
+//! the floor and the ceiling of the policy.
+
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::BalloonPolicy::BalloonPolicy as BalloonPolicyParcelable;
+use anyhow::{bail, ensure, Context, Result};
+use std::fs::read_to_string;
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+const MIB: u64 = 1 << 20;
+
+/// How often the balloon of a VM is resized.
+pub const POLICY_INTERVAL: Duration = Duration::from_secs(5);
+
+const PSI_MEMORY_PATH: &str = "/proc/pressure/memory";
+
+/// Percentage of the last 10 seconds during which some tasks of the host were stalled on memory,
+/// at and above which guest memory is reclaimed.
+const HOST_PRESSURE_HIGH: f64 = 10.0;
+/// Percentage below which memory is given back to the guest.
+const HOST_PRESSURE_LOW: f64 = 1.0;
+
+/// Memory which the guest keeps available when its memory is reclaimed. Below it, the balloon is
+/// deflated whatever the host pressure.
+const GUEST_RESERVE: u64 = 64 * MIB;
+/// Most memory given back to the guest at once, so that memory is returned gradually when the
+/// host pressure goes away.
+const DEFLATE_STEP: u64 = 64 * MIB;
+
+/// The limits within which the balloon of a VM is resized.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BalloonPolicy {
+    /// The least memory left to the guest, in bytes.
+    floor: u64,
+    /// The most memory left to the guest, in bytes.
+    ceiling: u64,
+}
+
+impl BalloonPolicy {
+    pub fn new(raw_policy: &BalloonPolicyParcelable) -> Result<BalloonPolicy> {
+        let floor: u64 = raw_policy.floorMib.try_into().context("Invalid balloon floor")?;
+        let ceiling: u64 = raw_policy.ceilingMib.try_into().context("Invalid balloon ceiling")?;
+        ensure!(floor > 0, "Balloon floor must not be 0");
+        ensure!(floor <= ceiling, "Balloon floor {floor} MiB is above the ceiling {ceiling} MiB");
+        Ok(BalloonPolicy { floor: floor * MIB, ceiling: ceiling * MIB })
+    }
+
+    /// Checks that the policy can be applied to a VM with `memory_mib` of memory.
+    pub fn validate(&self, memory_mib: NonZeroU32) -> Result<()> {
+        ensure!(
+            self.ceiling <= memory_mib.get() as u64 * MIB,
+            "Balloon ceiling {} MiB is above the memory of the VM {} MiB",
+            self.ceiling / MIB,
+            memory_mib
+        );
+        Ok(())
+    }
+
+    /// Returns the size which the balloon of a VM with `memory` bytes should have, given the
+    /// current state of its memory and the memory pressure of the host.
+    pub fn target_balloon_size(&self, memory: u64, guest: &GuestMemory, host_pressure: f64) -> u64 {
+        let current = guest.balloon;
+        let target = match guest.available {
+            Some(available) if available < GUEST_RESERVE => {
+                current.saturating_sub(GUEST_RESERVE - available)
+            }
+            // Only take half of what the guest can spare, as its usage may grow back meanwhile.
+            Some(available) if host_pressure >= HOST_PRESSURE_HIGH => {
+                current + (available - GUEST_RESERVE) / 2
+            }
+            _ if host_pressure < HOST_PRESSURE_LOW => current.saturating_sub(DEFLATE_STEP),
+            _ => current,
+        };
+        let min_balloon = memory.saturating_sub(self.ceiling);
+        let max_balloon = memory.saturating_sub(self.floor);
+        target.clamp(min_balloon, max_balloon)
+    }
+}
+
+/// The state of the memory of a guest, as reported by its balloon.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GuestMemory {
+    /// The current size of the balloon, in bytes.
+    pub balloon: u64,
+    /// Memory available to the guest for starting new applications, in bytes, if reported.
+    pub available: Option<u64>,
+}
+
+/// Returns the percentage of the last 10 seconds during which some tasks of the host were stalled
+/// on memory.
+pub fn host_memory_pressure() -> Result<f64> {
+    let psi = read_to_string(PSI_MEMORY_PATH)
+        .with_context(|| format!("Failed to read {PSI_MEMORY_PATH}"))?;
+    parse_psi_some_avg10(&psi)
+}
+
+/// Parses the `some avg10` value of a PSI file, whose lines look like:
+/// `some avg10=0.00 avg60=0.00 avg300=0.00 total=0`
+fn parse_psi_some_avg10(psi: &str) -> Result<f64> {
+    for line in psi.lines() {
+        let mut fields = line.split_whitespace();
+        if fields.next() != Some("some") {
+            continue;
+        }
+        for field in fields {
+            if let Some(value) = field.strip_prefix("avg10=") {
+                return value.parse().with_context(|| format!("Invalid PSI value {value:?}"));
+            }
+        }
+    }
+    bail!("No \"some avg10\" in PSI {psi:?}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEMORY: u64 = 1024 * MIB;
+
+    fn policy(floor_mib: i32, ceiling_mib: i32) -> BalloonPolicy {
+        BalloonPolicy::new(&BalloonPolicyParcelable {
+            floorMib: floor_mib,
+            ceilingMib: ceiling_mib,
+        })
+        .unwrap()
+    }
+
+    fn guest(balloon_mib: u64, available_mib: Option<u64>) -> GuestMemory {
+        GuestMemory { balloon: balloon_mib * MIB, available: available_mib.map(|a| a * MIB) }
+    }
+
+    #[test]
+    fn invalid_policies_are_rejected() {
+        let new = |floor_mib, ceiling_mib| {
+            BalloonPolicy::new(&BalloonPolicyParcelable {
+                floorMib: floor_mib,
+                ceilingMib: ceiling_mib,
+            })
+        };
+        assert!(new(0, 512).is_err());
+        assert!(new(-1, 512).is_err());
+        assert!(new(512, 256).is_err());
+        assert!(new(256, 512).is_ok());
+
+        let memory_mib = NonZeroU32::new(1024).unwrap();
+        assert!(policy(256, 1024).validate(memory_mib).is_ok());
+        assert!(policy(256, 2048).validate(memory_mib).is_err());
+    }
+
+    #[test]
+    fn parse_psi() {
+        let psi = "some avg10=12.34 avg60=1.00 avg300=0.10 total=12345\n\
+                   full avg10=5.00 avg60=0.50 avg300=0.05 total=6789\n";
+        assert_eq!(parse_psi_some_avg10(psi).unwrap(), 12.34);
+        assert!(
+            parse_psi_some_avg10("full avg10=5.00 avg60=0.50 avg300=0.05 total=6789\n").is_err()
+        );
+        assert!(parse_psi_some_avg10("some avg10=abc avg60=0.00\n").is_err());
+    }
+
+    #[test]
+    fn inflates_under_host_pressure() {
+        let policy = policy(256, 1024);
+        // Half of what is available above the reserve is reclaimed.
+        assert_eq!(
+            policy.target_balloon_size(MEMORY, &guest(0, Some(576)), HOST_PRESSURE_HIGH),
+            256 * MIB
+        );
+        // But never more than what leaves the floor to the guest.
+        assert_eq!(policy.target_balloon_size(MEMORY, &guest(700, Some(300)), 50.0), 768 * MIB);
+        // Nothing is reclaimed if the guest doesn't report its available memory.
+        assert_eq!(policy.target_balloon_size(MEMORY, &guest(100, None), 50.0), 100 * MIB);
+    }
+
+    #[test]
+    fn deflates_without_host_pressure() {
+        let policy = policy(256, 896);
+        assert_eq!(
+            policy.target_balloon_size(MEMORY, &guest(512, Some(500)), 0.0),
+            512 * MIB - DEFLATE_STEP
+        );
+        // But never less than what leaves the ceiling to the guest.
+        assert_eq!(policy.target_balloon_size(MEMORY, &guest(150, None), 0.0), 128 * MIB);
+        // Neither inflated nor deflated in between.
+        assert_eq!(policy.target_balloon_size(MEMORY, &guest(512, Some(500)), 5.0), 512 * MIB);
+    }
+
+    #[test]
+    fn deflates_when_guest_is_low_on_memory() {
+        let policy = policy(256, 1024);
+        assert_eq!(
+            policy.target_balloon_size(MEMORY, &guest(512, Some(16)), 50.0),
+            512 * MIB - (GUEST_RESERVE - 16 * MIB)
+        );
+    }
+}
@@ -16,32 +16,41 @@
 
 use crate::aidl::{remove_temporary_files, Cid, GLOBAL_SERVICE, VirtualMachineCallbacks};
 use crate::atom::{get_num_cpus, write_vm_exited_stats_sync};
+use crate::balloon::{host_memory_pressure, BalloonPolicy, GuestMemory, POLICY_INTERVAL};
 use crate::debug_config::DebugConfig;
-use anyhow::{anyhow, bail, Context, Error, Result};
+use crate::metrics::{
+    get_disk_io, get_network_counters, get_tap_interface_name, NetworkCounters, VmMetrics,
+};
+use crate::restart::{restarts_to_count, RestartPolicy};
+use crate::snapshot::{extract_archive, write_archive, SnapshotConfig};
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
 use binder::ParcelFileDescriptor;
 use command_fds::CommandFdExt;
 use libc::{sysconf, _SC_CLK_TCK};
 use log::{debug, error, info};
 use semver::{Version, VersionReq};
-use nix::{fcntl::OFlag, unistd::pipe2, unistd::Uid, unistd::User};
+use nix::{fcntl::fcntl, fcntl::FcntlArg, fcntl::OFlag, unistd::pipe2, unistd::Uid, unistd::User};
 use regex::{Captures, Regex};
 use rustutils::system_properties;
 use shared_child::SharedChild;
 use std::borrow::Cow;
 use std::cmp::max;
+use std::collections::HashSet;
 use std::fmt;
-use std::fs::{read_to_string, File};
-use std::io::{self, Read};
+use std::fs::{create_dir, read_to_string, remove_dir_all, remove_file, File};
+use std::io::{self, BufReader, Read, Seek, Write};
 use std::mem;
 use std::num::{NonZeroU16, NonZeroU32};
 use std::os::unix::io::{AsRawFd, OwnedFd};
 use std::os::unix::process::ExitStatusExt;
 use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use std::sync::{Arc, Condvar, Mutex, LazyLock};
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use std::thread::{self, JoinHandle};
 use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::DeathReason::DeathReason;
+use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::PayloadTaskStatus::PayloadTaskStatus;
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
     VirtualMachineAppConfig::DebugLevel::DebugLevel,
     AudioConfig::AudioConfig as AudioConfigParcelable,
@@ -57,15 +66,15 @@ use tombstoned_client::{TombstonedConnection, DebuggerdDumpType};
 use rpcbinder::RpcServer;
 
 /// external/crosvm
-use vm_control::{BalloonControlCommand, VmRequest, VmResponse};
+use vm_control::{
+    BalloonControlCommand, DiskControlCommand, SnapshotCommand, VmRequest, VmResponse,
+};
 
 const CROSVM_PATH: &str = "/apex/com.android.virt/bin/crosvm";
 
-/// Version of the platform that crosvm currently implements. The format follows SemVer. This
-/// should be updated when there is a platform change in the crosvm side. Having this value here is
-/// fine because virtualizationservice and crosvm are supposed to be updated together in the virt
-/// APEX.
-const CROSVM_PLATFORM_VERSION: &str = "1.0.0";
+/// Version of the platform that crosvm currently implements. It is shared with vmconfig so that
+/// configs can be validated without starting a VM.
+const CROSVM_PLATFORM_VERSION: &str = vmconfig::PLATFORM_VERSION;
 
 /// The exit status which crosvm returns when it has an error starting a VM.
 const CROSVM_START_ERROR_STATUS: i32 = 1;
@@ -77,6 +86,19 @@ const CROSVM_CRASH_STATUS: i32 = 33;
 const CROSVM_WATCHDOG_REBOOT_STATUS: i32 = 36;
 /// The size of memory (in MiB) reserved for ramdump
 const RAMDUMP_RESERVED_MIB: u32 = 17;
+/// The most network interfaces of a VM.
+pub const MAX_NETWORK_INTERFACES: usize = 8;
+/// The most tasks of a payload whose status is kept, as reported by the guest.
+const MAX_PAYLOAD_TASKS: usize = microdroid_payload_config::MAX_TASKS;
+/// The longest name of a task of a payload, in bytes.
+const MAX_PAYLOAD_TASK_NAME_LEN: usize = microdroid_payload_config::MAX_TASK_NAME_LEN;
+
+/// Directories in the temporary directory of a VM, where crosvm writes a snapshot and reads the
+/// snapshot to restore.
+const SNAPSHOT_DIR: &str = "snapshot";
+const RESTORE_DIR: &str = "restore";
+/// Name of the snapshot which crosvm writes in the snapshot directory.
+const CROSVM_SNAPSHOT_NAME: &str = "vm";
 
 const MILLIS_PER_SEC: i64 = 1000;
 
@@ -89,8 +111,8 @@ const CONSOLE_HVC0: &str = "hvc0";
 const CONSOLE_TTYS0: &str = "ttyS0";
 
 /// If the VM doesn't move to the Started state within this amount time, a hang-up error is
-/// triggered.
-static BOOT_HANGUP_TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
+/// triggered. This is the default for VMs running an app config.
+pub(crate) static BOOT_HANGUP_TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
     if nested_virt::is_nested_virtualization().unwrap() {
         // Nested virtualization is slow, so we need a longer timeout.
         Duration::from_secs(300)
@@ -104,6 +126,10 @@ static BOOT_HANGUP_TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
 pub struct CrosvmConfig {
     pub cid: Cid,
     pub name: String,
+    /// The instance ID of the VM, if it has one.
+    pub instance_id: Option<[u8; 64]>,
+    /// Whether the VM is an updatable VM.
+    pub updatable: bool,
     pub bootloader: Option<File>,
     pub kernel: Option<File>,
     pub initrd: Option<File>,
@@ -120,7 +146,8 @@ pub struct CrosvmConfig {
     pub ramdump: Option<File>,
     pub indirect_files: Vec<File>,
     pub platform_version: VersionReq,
-    pub detect_hangup: bool,
+    /// If set, the VM is killed if its payload doesn't start within this time.
+    pub hangup_timeout: Option<Duration>,
     pub gdb_port: Option<NonZeroU16>,
     pub vfio_devices: Vec<VfioDevice>,
     pub dtbo: Option<File>,
@@ -128,16 +155,74 @@ pub struct CrosvmConfig {
     pub display_config: Option<DisplayConfig>,
     pub input_device_options: Vec<InputDeviceOption>,
     pub hugepages: bool,
-    pub tap: Option<File>,
+    pub taps: Vec<TapDevice>,
     pub console_input_device: Option<String>,
     pub boost_uclamp: bool,
     pub gpu_config: Option<GpuConfig>,
     pub audio_config: Option<AudioConfig>,
     pub no_balloon: bool,
     pub usb_config: UsbConfig,
+    pub balloon_policy: Option<BalloonPolicy>,
+    pub restart_policy: RestartPolicy,
 }
 
-#[derive(Debug)]
+impl CrosvmConfig {
+    /// Duplicates the configuration, including its files, so that the VM can be started again.
+    fn try_clone(&self) -> io::Result<CrosvmConfig> {
+        let clone_file = |file: &Option<File>| -> io::Result<Option<File>> {
+            file.as_ref().map(File::try_clone).transpose()
+        };
+        Ok(CrosvmConfig {
+            cid: self.cid,
+            name: self.name.clone(),
+            instance_id: self.instance_id,
+            updatable: self.updatable,
+            bootloader: clone_file(&self.bootloader)?,
+            kernel: clone_file(&self.kernel)?,
+            initrd: clone_file(&self.initrd)?,
+            disks: self.disks.iter().map(DiskFile::try_clone).collect::<io::Result<_>>()?,
+            params: self.params.clone(),
+            protected: self.protected,
+            debug_config: self.debug_config.clone(),
+            memory_mib: self.memory_mib,
+            cpus: self.cpus,
+            host_cpu_topology: self.host_cpu_topology,
+            console_out_fd: clone_file(&self.console_out_fd)?,
+            console_in_fd: clone_file(&self.console_in_fd)?,
+            log_fd: clone_file(&self.log_fd)?,
+            ramdump: clone_file(&self.ramdump)?,
+            indirect_files: self
+                .indirect_files
+                .iter()
+                .map(File::try_clone)
+                .collect::<io::Result<_>>()?,
+            platform_version: self.platform_version.clone(),
+            hangup_timeout: self.hangup_timeout,
+            gdb_port: self.gdb_port,
+            vfio_devices: self.vfio_devices.clone(),
+            dtbo: clone_file(&self.dtbo)?,
+            device_tree_overlay: clone_file(&self.device_tree_overlay)?,
+            display_config: self.display_config.clone(),
+            input_device_options: self
+                .input_device_options
+                .iter()
+                .map(InputDeviceOption::try_clone)
+                .collect::<io::Result<_>>()?,
+            hugepages: self.hugepages,
+            taps: self.taps.iter().map(TapDevice::try_clone).collect::<io::Result<_>>()?,
+            console_input_device: self.console_input_device.clone(),
+            boost_uclamp: self.boost_uclamp,
+            gpu_config: self.gpu_config.clone(),
+            audio_config: self.audio_config.clone(),
+            no_balloon: self.no_balloon,
+            usb_config: self.usb_config.clone(),
+            balloon_policy: self.balloon_policy.clone(),
+            restart_policy: self.restart_policy.clone(),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct AudioConfig {
     pub use_microphone: bool,
     pub use_speaker: bool,
@@ -149,7 +234,7 @@ impl AudioConfig {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct UsbConfig {
     pub controller: bool,
 }
@@ -160,7 +245,7 @@ impl UsbConfig {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct DisplayConfig {
     pub width: NonZeroU32,
     pub height: NonZeroU32,
@@ -180,7 +265,7 @@ impl DisplayConfig {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct GpuConfig {
     pub backend: Option<String>,
     pub context_types: Option<Vec<String>>,
@@ -221,6 +306,55 @@ fn try_into_non_zero_u32(value: i32) -> Result<NonZeroU32> {
 pub struct DiskFile {
     pub image: File,
     pub writable: bool,
+    /// A label identifying the disk, e.g. to resize it with [`VmInstance::resize_disk`].
+    pub label: Option<String>,
+}
+
+impl DiskFile {
+    fn try_clone(&self) -> io::Result<DiskFile> {
+        Ok(DiskFile {
+            image: self.image.try_clone()?,
+            writable: self.writable,
+            label: self.label.clone(),
+        })
+    }
+}
+
+/// A disk of a VM which has a label.
+#[derive(Debug)]
+struct LabeledDisk {
+    label: String,
+    writable: bool,
+    /// The index of the disk among the block devices of crosvm, which are numbered in the order
+    /// of `CrosvmConfig::disks`.
+    index: usize,
+}
+
+impl LabeledDisk {
+    fn from_disks(disks: &[DiskFile]) -> Vec<LabeledDisk> {
+        disks
+            .iter()
+            .enumerate()
+            .filter_map(|(index, disk)| {
+                let label = disk.label.clone()?;
+                Some(LabeledDisk { label, writable: disk.writable, index })
+            })
+            .collect()
+    }
+}
+
+/// A TAP network interface to pass to crosvm for a virtio-net device of a VM.
+#[derive(Debug)]
+pub struct TapDevice {
+    pub tap: File,
+    /// The MAC address of the device in the guest, or `None` to let crosvm pick one.
+    pub mac_address: Option<[u8; 6]>,
+}
+
+impl TapDevice {
+    fn try_clone(&self) -> io::Result<TapDevice> {
+        Ok(TapDevice { tap: self.tap.try_clone()?, mac_address: self.mac_address })
+    }
 }
 
 /// virtio-input device configuration from `external/crosvm/src/crosvm/config.rs`
@@ -236,6 +370,41 @@ pub enum InputDeviceOption {
     MultiTouch { file: File, width: u32, height: u32, name: Option<String> },
 }
 
+impl InputDeviceOption {
+    fn try_clone(&self) -> io::Result<InputDeviceOption> {
+        Ok(match self {
+            InputDeviceOption::EvDev(file) => InputDeviceOption::EvDev(file.try_clone()?),
+            InputDeviceOption::SingleTouch { file, width, height, name } => {
+                InputDeviceOption::SingleTouch {
+                    file: file.try_clone()?,
+                    width: *width,
+                    height: *height,
+                    name: name.clone(),
+                }
+            }
+            InputDeviceOption::Keyboard(file) => InputDeviceOption::Keyboard(file.try_clone()?),
+            InputDeviceOption::Mouse(file) => InputDeviceOption::Mouse(file.try_clone()?),
+            InputDeviceOption::Switches(file) => InputDeviceOption::Switches(file.try_clone()?),
+            InputDeviceOption::MultiTouchTrackpad { file, width, height, name } => {
+                InputDeviceOption::MultiTouchTrackpad {
+                    file: file.try_clone()?,
+                    width: *width,
+                    height: *height,
+                    name: name.clone(),
+                }
+            }
+            InputDeviceOption::MultiTouch { file, width, height, name } => {
+                InputDeviceOption::MultiTouch {
+                    file: file.try_clone()?,
+                    width: *width,
+                    height: *height,
+                    name: name.clone(),
+                }
+            }
+        })
+    }
+}
+
 type VfioDevice = Strong<dyn IBoundDevice>;
 
 /// The lifecycle state which the payload in the VM has reported itself to be in.
@@ -265,6 +434,15 @@ pub enum VmState {
         child: Arc<SharedChild>,
         /// The thread waiting for crosvm to finish.
         monitor_vm_exit_thread: Option<JoinHandle<()>>,
+        /// A copy of the configuration to restart the VM with, if its restart policy allows it.
+        restart_config: Option<Box<CrosvmConfig>>,
+    },
+    /// crosvm exited, and the VM is waiting to be restarted according to its restart policy.
+    Restarting {
+        /// The configuration to restart the VM with.
+        config: Box<CrosvmConfig>,
+        /// The thread which restarts the VM.
+        monitor_vm_exit_thread: Option<JoinHandle<()>>,
     },
     /// The VM died or was killed.
     Dead,
@@ -292,22 +470,44 @@ pub struct VmMetric {
 }
 
 impl VmState {
-    /// Tries to start the VM, if it is in the `NotStarted` state.
+    /// Tries to start the VM, if it is in the `NotStarted` state. If `restore` is set, the VM is
+    /// restored from the crosvm snapshot there instead of booting.
     ///
     /// Returns an error if the VM is in the wrong state, or fails to start.
-    fn start(&mut self, instance: Arc<VmInstance>) -> Result<(), Error> {
+    fn start(&mut self, instance: Arc<VmInstance>, restore: Option<&Path>) -> Result<(), Error> {
         let state = mem::replace(self, VmState::Failed);
         if let VmState::NotStarted { config } = state {
             let config = *config;
-            let detect_hangup = config.detect_hangup;
+            // The payload of a restored VM has started before the snapshot was taken.
+            let hangup_timeout = config.hangup_timeout.filter(|_| restore.is_none());
+            let restart_config = if config.restart_policy.may_restart() {
+                Some(Box::new(config.try_clone().context("Failed to keep the restart config")?))
+            } else {
+                None
+            };
             let (failure_pipe_read, failure_pipe_write) = create_pipe()?;
             let vfio_devices = config.vfio_devices.clone();
-            let tap =
-                if let Some(tap_file) = &config.tap { Some(tap_file.try_clone()?) } else { None };
+            let taps = config
+                .taps
+                .iter()
+                .map(|tap_device| tap_device.tap.try_clone())
+                .collect::<io::Result<Vec<_>>>()?;
+            let balloon_policy = match config.balloon_policy.clone() {
+                Some(_) if !balloon_enabled(&config)? => {
+                    info!("Memory balloon is not supported, ignoring the balloon policy");
+                    None
+                }
+                policy => policy,
+            };
+            let memory = config.memory_mib.get() as u64 * (1 << 20);
 
             // If this fails and returns an error, `self` will be left in the `Failed` state.
-            let child =
-                Arc::new(run_vm(config, &instance.crosvm_control_socket_path, failure_pipe_write)?);
+            let child = Arc::new(run_vm(
+                config,
+                &instance.crosvm_control_socket_path,
+                failure_pipe_write,
+                restore,
+            )?);
 
             let instance_monitor_status = instance.clone();
             let child_monitor_status = child.clone();
@@ -315,21 +515,29 @@ impl VmState {
                 instance_monitor_status.clone().monitor_vm_status(child_monitor_status);
             });
 
+            if let Some(policy) = balloon_policy {
+                let child_clone = child.clone();
+                let instance_clone = instance.clone();
+                thread::spawn(move || {
+                    instance_clone.monitor_balloon(child_clone, policy, memory);
+                });
+            }
+
             let child_clone = child.clone();
             let instance_clone = instance.clone();
             let monitor_vm_exit_thread = Some(thread::spawn(move || {
-                instance_clone.monitor_vm_exit(child_clone, failure_pipe_read, vfio_devices, tap);
+                instance_clone.monitor_vm_exit(child_clone, failure_pipe_read, vfio_devices, taps);
             }));
 
-            if detect_hangup {
+            if let Some(timeout) = hangup_timeout {
                 let child_clone = child.clone();
                 thread::spawn(move || {
-                    instance.monitor_payload_hangup(child_clone);
+                    instance.monitor_payload_hangup(child_clone, timeout);
                 });
             }
 
             // If it started correctly, update the state.
-            *self = VmState::Running { child, monitor_vm_exit_thread };
+            *self = VmState::Running { child, monitor_vm_exit_thread, restart_config };
             Ok(())
         } else {
             *self = state;
@@ -368,8 +576,20 @@ pub struct VmInstance {
     crosvm_control_socket_path: PathBuf,
     /// The name of the VM.
     pub name: String,
+    /// The instance ID of the VM, if it has one.
+    pub instance_id: Option<[u8; 64]>,
+    /// Whether the VM is an updatable VM.
+    pub updatable: bool,
     /// Whether the VM is a protected VM.
     pub protected: bool,
+    /// The configuration of the VM, as recorded in its snapshots.
+    snapshot_config: SnapshotConfig,
+    /// The number of disks of the VM.
+    num_disks: usize,
+    /// The disks of the VM which have a label.
+    labeled_disks: Vec<LabeledDisk>,
+    /// The names of the network interfaces of the TAP devices of the VM.
+    tap_interfaces: Vec<String>,
     /// Directory of temporary files used by the VM while it is running.
     pub temporary_directory: PathBuf,
     /// The UID of the process which requested the VM.
@@ -384,12 +604,32 @@ pub struct VmInstance {
     pub vm_service: Mutex<Option<Strong<dyn IVirtualMachineService>>>,
     /// Recorded metrics of VM such as timestamp or cpu / memory usage.
     pub vm_metric: Mutex<VmMetric>,
+    /// The number of callbacks to which samples of the resource usage of the VM are streamed.
+    pub metrics_callbacks: AtomicUsize,
     /// The latest lifecycle state which the payload reported itself to be in.
     payload_state: Mutex<PayloadState>,
     /// Represents the condition that payload_state was updated
     payload_state_updated: Condvar,
+    /// When the payload became ready, if it is.
+    payload_ready_since: Mutex<Option<Instant>>,
+    /// The latest status which each task of the payload reported itself to be in.
+    payload_task_statuses: Mutex<Vec<PayloadTaskStatus>>,
+    /// Represents the condition that vm_state was updated while the VM waits to be restarted.
+    vm_state_changed: Condvar,
+    /// When the VM is restarted after crosvm exits.
+    restart_policy: RestartPolicy,
+    /// The number of times the VM has been restarted.
+    restarts: AtomicU32,
+    /// Whether the VM was killed on request, in which case it is not restarted.
+    stop_requested: AtomicBool,
     /// The human readable name of requester_uid
     requester_uid_name: String,
+    /// The read end of the stream of payload output, until a client takes it.
+    payload_output_reader: Mutex<Option<File>>,
+    /// The non-blocking write end of the stream of payload output. It is kept while the VM is
+    /// `Restarting`, so that the payload of the new crosvm writes to the same stream, and only
+    /// closed once the VM dies for good.
+    payload_output_writer: Mutex<Option<File>>,
 }
 
 impl fmt::Display for VmInstance {
@@ -415,27 +655,60 @@ impl VmInstance {
         validate_config(&config)?;
         let cid = config.cid;
         let name = config.name.clone();
+        let instance_id = config.instance_id;
+        let updatable = config.updatable;
         let protected = config.protected;
+        let snapshot_config = SnapshotConfig::new(&config);
+        let num_disks = config.disks.len();
+        let labeled_disks = LabeledDisk::from_disks(&config.disks);
+        let restart_policy = config.restart_policy.clone();
+        let tap_interfaces = config
+            .taps
+            .iter()
+            .filter_map(|tap_device| {
+                get_tap_interface_name(&tap_device.tap)
+                    .map_err(|e| error!("Failed to get the TAP interface of {name}: {e:?}"))
+                    .ok()
+            })
+            .collect();
         let requester_uid_name = User::from_uid(Uid::from_raw(requester_uid))
             .ok()
             .flatten()
             .map_or_else(|| format!("{}", requester_uid), |u| u.name);
+        let (payload_output_reader, payload_output_writer) = create_pipe()?;
+        // Output is dropped rather than blocking the guest when nobody reads it.
+        fcntl(payload_output_writer.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
         let instance = VmInstance {
             vm_state: Mutex::new(VmState::NotStarted { config: Box::new(config) }),
             vm_context,
             cid,
             crosvm_control_socket_path: temporary_directory.join("crosvm.sock"),
             name,
+            instance_id,
+            updatable,
             protected,
+            snapshot_config,
+            num_disks,
+            labeled_disks,
+            tap_interfaces,
             temporary_directory,
             requester_uid,
             requester_debug_pid,
             callbacks: Default::default(),
             vm_service: Mutex::new(None),
             vm_metric: Mutex::new(Default::default()),
+            metrics_callbacks: AtomicUsize::new(0),
             payload_state: Mutex::new(PayloadState::Starting),
             payload_state_updated: Condvar::new(),
+            payload_ready_since: Mutex::new(None),
+            payload_task_statuses: Mutex::new(Vec::new()),
+            vm_state_changed: Condvar::new(),
+            restart_policy,
+            restarts: AtomicU32::new(0),
+            stop_requested: AtomicBool::new(false),
             requester_uid_name,
+            payload_output_reader: Mutex::new(Some(payload_output_reader)),
+            payload_output_writer: Mutex::new(Some(payload_output_writer)),
         };
         info!("{} created", &instance);
         Ok(instance)
@@ -446,22 +719,78 @@ impl VmInstance {
     pub fn start(self: &Arc<Self>) -> Result<(), Error> {
         let mut vm_metric = self.vm_metric.lock().unwrap();
         vm_metric.start_timestamp = Some(SystemTime::now());
-        let ret = self.vm_state.lock().unwrap().start(self.clone());
+        let ret = self.vm_state.lock().unwrap().start(self.clone(), None);
         if ret.is_ok() {
             info!("{} started", &self);
         }
         ret.with_context(|| format!("{} failed to start", &self))
     }
 
+    /// Starts an instance of `crosvm` which restores the VM from the snapshot archive `snapshot`,
+    /// instead of booting it. The VM must have been created with the same configuration as the
+    /// snapshotted VM.
+    pub fn restore(self: &Arc<Self>, snapshot: File) -> Result<(), Error> {
+        ensure!(!self.protected, "Protected VMs can't be restored from a snapshot");
+        ensure!(
+            matches!(&*self.vm_state.lock().unwrap(), VmState::NotStarted { .. }),
+            "VM already started or failed"
+        );
+        let restore_dir = self.temporary_directory.join(RESTORE_DIR);
+        let ret = extract_archive(snapshot, &restore_dir)
+            .and_then(|config| self.snapshot_config.check_restorable_from(&config))
+            .and_then(|()| {
+                let mut vm_metric = self.vm_metric.lock().unwrap();
+                vm_metric.start_timestamp = Some(SystemTime::now());
+                self.vm_state.lock().unwrap().start(self.clone(), Some(&restore_dir))
+            });
+        if ret.is_ok() {
+            info!("{} restored", &self);
+        }
+        ret.with_context(|| format!("{} failed to restore", &self))
+    }
+
+    /// Writes a snapshot archive of the running VM to `output`. The VM is paused while crosvm
+    /// takes the snapshot, and keeps running afterwards.
+    pub fn snapshot(&self, output: File) -> Result<(), Error> {
+        ensure!(!self.protected, "Protected VMs can't be snapshotted");
+        ensure!(
+            matches!(&*self.vm_state.lock().unwrap(), VmState::Running { .. }),
+            "VM is not running"
+        );
+        let snapshot_dir = self.temporary_directory.join(SNAPSHOT_DIR);
+        create_dir(&snapshot_dir)
+            .with_context(|| format!("Failed to create {snapshot_dir:?}. Is a snapshot ongoing?"))?;
+        let ret = self.take_snapshot(&snapshot_dir.join(CROSVM_SNAPSHOT_NAME)).and_then(|()| {
+            write_archive(output, &self.snapshot_config, &snapshot_dir)
+                .context("Failed to write snapshot archive")
+        });
+        remove_dir_all(&snapshot_dir)
+            .unwrap_or_else(|e| error!("Error removing {snapshot_dir:?}: {e}"));
+        ret
+    }
+
+    fn take_snapshot(&self, snapshot_path: &Path) -> Result<(), Error> {
+        let request = VmRequest::Snapshot(SnapshotCommand::Take {
+            snapshot_path: snapshot_path.to_path_buf(),
+            compress_memory: false,
+            encrypt: false,
+        });
+        match vm_control::client::handle_request(&request, &self.crosvm_control_socket_path) {
+            Ok(VmResponse::Ok) => Ok(()),
+            e => bail!("Failed to snapshot VM: {e:?}"),
+        }
+    }
+
     /// Monitors the exit of the VM (i.e. termination of the `child` process). When that happens,
-    /// handles the event by updating the state, noityfing the event to clients by calling
-    /// callbacks, and removing temporary files for the VM.
+    /// handles the event by restarting the VM if its restart policy says so, or otherwise by
+    /// updating the state, noityfing the event to clients by calling callbacks, and removing
+    /// temporary files for the VM.
     fn monitor_vm_exit(
-        &self,
+        self: &Arc<Self>,
         child: Arc<SharedChild>,
         mut failure_pipe_read: File,
         vfio_devices: Vec<VfioDevice>,
-        tap: Option<File>,
+        taps: Vec<File>,
     ) {
         let result = child.wait();
         match &result {
@@ -476,12 +805,6 @@ impl VmInstance {
             }
         }
 
-        let mut vm_state = self.vm_state.lock().unwrap();
-        *vm_state = VmState::Dead;
-        // Ensure that the mutex is released before calling the callbacks.
-        drop(vm_state);
-        info!("{} exited", &self);
-
         // Read the pipe to see if any failure reason is written
         let mut failure_reason = String::new();
         match failure_pipe_read.read_to_string(&mut failure_reason) {
@@ -505,23 +828,56 @@ impl VmInstance {
         let death_reason = death_reason(&result, &failure_reason);
         let exit_signal = exit_signal(&result);
 
-        self.callbacks.callback_on_died(self.cid, death_reason);
+        let ready_for = self.payload_ready_since.lock().unwrap().take().map(|t| t.elapsed());
+        let mut vm_state = self.vm_state.lock().unwrap();
+        let restart_delay = match mem::replace(&mut *vm_state, VmState::Dead) {
+            VmState::Running { restart_config: Some(config), monitor_vm_exit_thread, .. }
+                if !self.stop_requested.load(Ordering::SeqCst) =>
+            {
+                let restarts = restarts_to_count(self.restarts.load(Ordering::SeqCst), ready_for);
+                self.restarts.store(restarts, Ordering::SeqCst);
+                let delay = self.restart_policy.restart_delay(death_reason, restarts);
+                if delay.is_some() {
+                    *vm_state = VmState::Restarting { config, monitor_vm_exit_thread };
+                }
+                delay
+            }
+            _ => None,
+        };
+        // Ensure that the mutex is released before calling the callbacks.
+        drop(vm_state);
+        info!("{} exited", &self);
 
-        let vm_metric = self.vm_metric.lock().unwrap();
-        write_vm_exited_stats_sync(
-            self.requester_uid as i32,
-            &self.name,
-            death_reason,
-            exit_signal,
-            &vm_metric,
-        );
+        {
+            let vm_metric = self.vm_metric.lock().unwrap();
+            write_vm_exited_stats_sync(
+                self.requester_uid as i32,
+                &self.name,
+                death_reason,
+                exit_signal,
+                &vm_metric,
+            );
+        }
+
+        if let Some(delay) = restart_delay {
+            if self.restart_after(delay) {
+                // The payload output stream, the TAP interfaces and the devices are still used by
+                // the new crosvm.
+                return;
+            }
+        }
+
+        // The VM is dead for good. End the payload output stream before notifying clients, so
+        // that they have all of it.
+        self.close_payload_output();
+        self.callbacks.callback_on_died(self.cid, death_reason);
 
         // Delete temporary files. The folder itself is removed by VirtualizationServiceInternal.
         remove_temporary_files(&self.temporary_directory).unwrap_or_else(|e| {
             error!("Error removing temporary files from {:?}: {}", self.temporary_directory, e);
         });
 
-        if let Some(tap_file) = tap {
+        for tap_file in taps {
             GLOBAL_SERVICE
                 .deleteTapInterface(&ParcelFileDescriptor::new(OwnedFd::from(tap_file)))
                 .unwrap_or_else(|e| {
@@ -532,13 +888,65 @@ impl VmInstance {
         drop(vfio_devices); // Cleanup devices.
     }
 
-    /// Waits until payload is started, or timeout expires. When timeout occurs, kill
-    /// the VM to prevent indefinite hangup and update the payload_state accordingly.
-    fn monitor_payload_hangup(&self, child: Arc<SharedChild>) {
-        debug!("Starting to monitor hangup for Microdroid({})", child.id());
+    /// Waits for `delay`, then starts a new crosvm for the VM with the config kept in the
+    /// `Restarting` state. Returns whether the VM was restarted, which it isn't if it is killed
+    /// while waiting or if crosvm fails to start.
+    fn restart_after(self: &Arc<Self>, delay: Duration) -> bool {
+        info!("Restarting {} in {:?}", &self, delay);
+        let (mut vm_state, _) = self
+            .vm_state_changed
+            .wait_timeout_while(self.vm_state.lock().unwrap(), delay, |state| {
+                matches!(state, VmState::Restarting { .. })
+            })
+            .unwrap();
+        let VmState::Restarting { config, .. } = mem::replace(&mut *vm_state, VmState::Dead) else {
+            info!("{} was killed before restarting", &self);
+            return false;
+        };
+        if let Err(e) = self.prepare_restart(&config) {
+            error!("Failed to prepare restarting {}: {:?}", &self, e);
+            return false;
+        }
+        *self.payload_state.lock().unwrap() = PayloadState::Starting;
+        self.payload_task_statuses.lock().unwrap().clear();
+        let restarts = self.restarts.fetch_add(1, Ordering::SeqCst) + 1;
+        *vm_state = VmState::NotStarted { config };
+        if let Err(e) = vm_state.start(self.clone(), None) {
+            error!("Failed to restart {}: {:?}", &self, e);
+            *vm_state = VmState::Dead;
+            return false;
+        }
+        // The lock on vm_metric is taken before the one on vm_state elsewhere.
+        drop(vm_state);
+        *self.vm_metric.lock().unwrap() =
+            VmMetric { start_timestamp: Some(SystemTime::now()), ..Default::default() };
+        info!("{} restarted ({} restarts)", &self, restarts);
+        true
+    }
+
+    /// Cleans up what the previous crosvm left behind, so that a new one can start.
+    fn prepare_restart(&self, config: &CrosvmConfig) -> Result<(), Error> {
+        // The control socket is bound again by `run_vm`.
+        if self.crosvm_control_socket_path.try_exists()? {
+            remove_file(&self.crosvm_control_socket_path)
+                .context("Failed to remove the crosvm control socket")?;
+        }
+        // The ramdump, if any, was already sent to tombstoned.
+        if let Some(mut ramdump) = config.ramdump.as_ref() {
+            ramdump.set_len(0).context("Failed to truncate the ramdump")?;
+            ramdump.rewind().context("Failed to rewind the ramdump")?;
+        }
+        Ok(())
+    }
+
+    /// Waits until payload is started, or `timeout` expires. When timeout occurs, kill the VM to
+    /// prevent indefinite hangup and update the payload_state accordingly. If the restart policy
+    /// of the VM allows it, only crosvm is killed so that the VM is restarted.
+    fn monitor_payload_hangup(&self, child: Arc<SharedChild>, timeout: Duration) {
+        debug!("Starting to monitor hangup for crosvm({})", child.id());
         let (state, result) = self
             .payload_state_updated
-            .wait_timeout_while(self.payload_state.lock().unwrap(), *BOOT_HANGUP_TIMEOUT, |s| {
+            .wait_timeout_while(self.payload_state.lock().unwrap(), timeout, |s| {
                 *s < PayloadState::Started
             })
             .unwrap();
@@ -546,25 +954,38 @@ impl VmInstance {
         let child_still_running = child.try_wait().ok() == Some(None);
         if result.timed_out() && child_still_running {
             error!(
-                "Microdroid({}) failed to start payload within {} secs timeout. Shutting down.",
+                "crosvm({}) failed to start payload within {} secs timeout. Shutting down.",
                 child.id(),
-                BOOT_HANGUP_TIMEOUT.as_secs()
+                timeout.as_secs()
             );
             self.update_payload_state(PayloadState::Hangup).unwrap();
-            if let Err(e) = self.kill() {
+            let result = if self.restart_policy.may_restart() {
+                child.kill().context("Error killing crosvm")
+            } else {
+                self.kill()
+            };
+            if let Err(e) = result {
                 error!("Error stopping timed-out VM with CID {}: {:?}", child.id(), e);
             }
         }
     }
 
+    /// Returns whether the VM is running in the crosvm process `child`, rather than being dead or
+    /// restarted in another process.
+    fn is_running_in(&self, child: &Arc<SharedChild>) -> bool {
+        match &*self.vm_state.lock().unwrap() {
+            VmState::Running { child: running, .. } => Arc::ptr_eq(running, child),
+            _ => false,
+        }
+    }
+
     fn monitor_vm_status(&self, child: Arc<SharedChild>) {
         let pid = child.id();
 
         loop {
             {
                 // Check VM state
-                let vm_state = &*self.vm_state.lock().unwrap();
-                if let VmState::Dead = vm_state {
+                if !self.is_running_in(&child) {
                     break;
                 }
 
@@ -592,6 +1013,33 @@ impl VmInstance {
         }
     }
 
+    /// Periodically resizes the memory balloon of the VM, which has `memory` bytes of memory,
+    /// according to `policy` until the VM stops running in `child`.
+    fn monitor_balloon(&self, child: Arc<SharedChild>, policy: BalloonPolicy, memory: u64) {
+        loop {
+            thread::sleep(POLICY_INTERVAL);
+            if !self.is_running_in(&child) {
+                break;
+            }
+            if let Err(e) = self.apply_balloon_policy(&policy, memory) {
+                error!("Failed to apply the balloon policy: {e:?}");
+            }
+        }
+    }
+
+    fn apply_balloon_policy(&self, policy: &BalloonPolicy, memory: u64) -> Result<(), Error> {
+        let Some(guest) = self.get_guest_memory()? else {
+            // The balloon is not ready yet.
+            return Ok(());
+        };
+        let target = policy.target_balloon_size(memory, &guest, host_memory_pressure()?);
+        if target != guest.balloon {
+            debug!("Resizing memory balloon of {self} from {} to {target} bytes", guest.balloon);
+            self.set_memory_balloon(target)?;
+        }
+        Ok(())
+    }
+
     /// Returns the last reported state of the VM payload.
     pub fn payload_state(&self) -> PayloadState {
         *self.payload_state.lock().unwrap()
@@ -603,6 +1051,9 @@ impl VmInstance {
         // Only allow forward transitions, e.g. from starting to started or finished, not back in
         // the other direction.
         if new_state > *state_locked {
+            if new_state == PayloadState::Ready {
+                *self.payload_ready_since.lock().unwrap() = Some(Instant::now());
+            }
             *state_locked = new_state;
             self.payload_state_updated.notify_all();
             Ok(())
@@ -611,18 +1062,88 @@ impl VmInstance {
         }
     }
 
-    /// Kills the crosvm instance, if it is running.
+    /// Returns the read end of the stream of payload output. It can only be taken once.
+    pub fn take_payload_output(&self) -> Result<File, Error> {
+        self.payload_output_reader
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| anyhow!("Payload output was already taken"))
+    }
+
+    /// Closes the write end of the payload output stream, which ends the stream for its reader.
+    /// Only called once the VM dies without being restarted.
+    fn close_payload_output(&self) {
+        self.payload_output_writer.lock().unwrap().take();
+    }
+
+    /// Writes output of the payload to the payload output stream. What doesn't fit in the stream
+    /// is dropped.
+    pub fn write_payload_output(&self, data: &[u8]) -> Result<(), Error> {
+        let mut writer = self.payload_output_writer.lock().unwrap();
+        let writer = writer.as_mut().context("Payload output stream is closed")?;
+        match writer.write(data) {
+            Ok(len) if len < data.len() => {
+                debug!("Dropped {} bytes of payload output of {}", data.len() - len, self)
+            }
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                debug!("Dropped {} bytes of payload output of {}", data.len(), self)
+            }
+            Err(e) => return Err(e).context("Failed to write payload output"),
+        }
+        Ok(())
+    }
+
+    /// Returns the last reported status of each task of the payload, in the order they were first
+    /// reported in.
+    pub fn payload_task_statuses(&self) -> Vec<PayloadTaskStatus> {
+        self.payload_task_statuses.lock().unwrap().clone()
+    }
+
+    /// Records the status reported by a task of the payload. As the guest can report any name,
+    /// names longer than `MAX_PAYLOAD_TASK_NAME_LEN` and tasks beyond the first
+    /// `MAX_PAYLOAD_TASKS` are rejected.
+    pub fn update_payload_task_status(&self, status: &PayloadTaskStatus) -> Result<(), Error> {
+        ensure!(
+            status.name.len() <= MAX_PAYLOAD_TASK_NAME_LEN,
+            "Task name is longer than {MAX_PAYLOAD_TASK_NAME_LEN} bytes"
+        );
+        let mut statuses = self.payload_task_statuses.lock().unwrap();
+        if let Some(existing) = statuses.iter_mut().find(|existing| existing.name == status.name) {
+            *existing = status.clone();
+        } else {
+            ensure!(
+                statuses.len() < MAX_PAYLOAD_TASKS,
+                "Payload has more than {MAX_PAYLOAD_TASKS} tasks"
+            );
+            statuses.push(status.clone());
+        }
+        Ok(())
+    }
+
+    /// Kills the crosvm instance, if it is running, or cancels the restart of the VM if it is
+    /// waiting to be restarted.
     pub fn kill(&self) -> Result<(), Error> {
         let monitor_vm_exit_thread = {
             let vm_state = &mut *self.vm_state.lock().unwrap();
-            if let VmState::Running { child, monitor_vm_exit_thread } = vm_state {
-                let id = child.id();
-                debug!("Killing crosvm({})", id);
-                // TODO: Talk to crosvm to shutdown cleanly.
-                child.kill().with_context(|| format!("Error killing crosvm({id}) instance"))?;
-                monitor_vm_exit_thread.take()
-            } else {
-                bail!("VM is not running")
+            match vm_state {
+                VmState::Running { child, monitor_vm_exit_thread, .. } => {
+                    let id = child.id();
+                    debug!("Killing crosvm({})", id);
+                    self.stop_requested.store(true, Ordering::SeqCst);
+                    // TODO: Talk to crosvm to shutdown cleanly.
+                    child.kill().with_context(|| format!("Error killing crosvm({id}) instance"))?;
+                    monitor_vm_exit_thread.take()
+                }
+                VmState::Restarting { monitor_vm_exit_thread, .. } => {
+                    debug!("Cancelling restart of {}", &self);
+                    let monitor_vm_exit_thread = monitor_vm_exit_thread.take();
+                    *vm_state = VmState::Dead;
+                    self.vm_state_changed.notify_all();
+                    monitor_vm_exit_thread
+                }
+                _ => bail!("VM is not running"),
             }
         };
 
@@ -640,19 +1161,29 @@ impl VmInstance {
     /// Responds to memory-trimming notifications by inflating the virtio
     /// balloon to reclaim guest memory.
     pub fn get_memory_balloon(&self) -> Result<u64, Error> {
+        // Trim is just a hint, so it is ignored if the balloon is not ready.
+        Ok(self.get_guest_memory()?.map_or(0, |guest| guest.balloon))
+    }
+
+    /// Returns the state of the guest memory reported by the balloon, or `None` if the balloon is
+    /// not ready.
+    fn get_guest_memory(&self) -> Result<Option<GuestMemory>, Error> {
         let request = VmRequest::BalloonCommand(BalloonControlCommand::Stats {});
         let result =
             match vm_control::client::handle_request(&request, &self.crosvm_control_socket_path) {
-                Ok(VmResponse::BalloonStats { stats: _, balloon_actual }) => balloon_actual,
+                Ok(VmResponse::BalloonStats { stats, balloon_actual }) => Some(GuestMemory {
+                    balloon: balloon_actual,
+                    available: stats.available_memory,
+                }),
                 Ok(VmResponse::Err(e)) => {
                     // ENOTSUP is returned when the balloon protocol is not initialized. This
                     // can occur for numerous reasons: Guest is still booting, guest doesn't
                     // support ballooning, host doesn't support ballooning. We don't log or
-                    // raise an error in this case: trim is just a hint and we can ignore it.
+                    // raise an error in this case.
                     if e.errno() != libc::ENOTSUP {
                         bail!("Errno return when requesting balloon stats: {}", e.errno())
                     }
-                    0
+                    None
                 }
                 e => bail!("Error requesting balloon stats: {:?}", e),
             };
@@ -672,6 +1203,42 @@ impl VmInstance {
         Ok(())
     }
 
+    /// Returns a sample of the resource usage of the VM, which must be running.
+    pub fn get_metrics(&self) -> Result<VmMetrics, Error> {
+        let pid = match &*self.vm_state.lock().unwrap() {
+            VmState::Running { child, .. } => child.id(),
+            _ => bail!("VM is not running"),
+        };
+        let uptime = self
+            .vm_metric
+            .lock()
+            .unwrap()
+            .start_timestamp
+            .and_then(|start| start.elapsed().ok())
+            .unwrap_or_default();
+        let rss = get_rss(pid)?;
+        // The counters of all the network interfaces are added up.
+        let mut network = None;
+        for interface in &self.tap_interfaces {
+            let counters = get_network_counters(interface)?;
+            let total: &mut NetworkCounters = network.get_or_insert_with(Default::default);
+            total.rx_bytes += counters.rx_bytes;
+            total.rx_packets += counters.rx_packets;
+            total.tx_bytes += counters.tx_bytes;
+            total.tx_packets += counters.tx_packets;
+        }
+        Ok(VmMetrics {
+            uptime,
+            vcpu_time_millis: get_guest_time(pid)?,
+            guest_rss_kib: rss.vm,
+            crosvm_rss_kib: rss.crosvm - rss.vm,
+            // Without a balloon, crosvm returns an error.
+            balloon: self.get_guest_memory().ok().flatten().map(|guest| guest.balloon),
+            disks: get_disk_io(pid, self.num_disks)?,
+            network,
+        })
+    }
+
     /// Checks if ramdump has been created. If so, send it to tombstoned.
     fn handle_ramdump(&self) -> Result<(), Error> {
         let ramdump_path = self.temporary_directory.join("ramdump");
@@ -685,7 +1252,7 @@ impl VmInstance {
     }
 
     fn send_ramdump_to_tombstoned(ramdump_path: &Path) -> Result<(), Error> {
-        let mut input = File::open(ramdump_path)
+        let input = File::open(ramdump_path)
             .context(format!("Failed to open ramdump {:?} for reading", ramdump_path))?;
 
         let pid = std::process::id() as i32;
@@ -696,7 +1263,9 @@ impl VmInstance {
             .as_ref()
             .ok_or_else(|| anyhow!("Could not get file to write the tombstones on"))?;
 
-        std::io::copy(&mut input, &mut output).context("Failed to send ramdump to tombstoned")?;
+        // The summary comes first, followed by the raw ramdump from which it can be recomputed.
+        ramdump::write_tombstone(BufReader::new(input), &mut output)
+            .context("Failed to send ramdump to tombstoned")?;
         info!("Ramdump {:?} sent to tombstoned", ramdump_path);
 
         conn.notify_completion()?;
@@ -724,6 +1293,35 @@ impl VmInstance {
             e => bail!("Failed to resume: {e:?}"),
         }
     }
+
+    /// Resizes the writable disk labeled `label` to `size` bytes.
+    pub fn resize_disk(&self, label: &str, size: u64) -> Result<(), Error> {
+        ensure!(
+            matches!(&*self.vm_state.lock().unwrap(), VmState::Running { .. }),
+            "VM is not running"
+        );
+        let request = resize_disk_request(&self.labeled_disks, label, size)?;
+        match vm_control::client::handle_request(&request, &self.crosvm_control_socket_path) {
+            Ok(VmResponse::Ok) => {}
+            e => bail!("Failed to resize disk: {e:?}"),
+        }
+        info!("Resized disk {label} of {self} to {size} bytes");
+        Ok(())
+    }
+}
+
+/// Returns the request for crosvm to resize the disk labeled `label`, among `disks`, to `size`
+/// bytes.
+fn resize_disk_request(disks: &[LabeledDisk], label: &str, size: u64) -> Result<VmRequest, Error> {
+    let disk = disks
+        .iter()
+        .find(|disk| disk.label == label)
+        .with_context(|| format!("No disk is labeled {label}"))?;
+    ensure!(disk.writable, "Disk {label} is read-only");
+    Ok(VmRequest::DiskCommand {
+        disk_index: disk.index,
+        command: DiskControlCommand::Resize { new_size: size },
+    })
 }
 
 impl Rss {
@@ -883,11 +1481,18 @@ fn vfio_argument_for_platform_device(device: &VfioDevice) -> Result<String, Erro
     }
 }
 
+/// Returns whether the VM has a memory balloon.
+fn balloon_enabled(config: &CrosvmConfig) -> Result<bool, Error> {
+    Ok(system_properties::read_bool("hypervisor.memory_reclaim.supported", false)?
+        && !config.no_balloon)
+}
+
 /// Starts an instance of `crosvm` to manage a new VM.
 fn run_vm(
     config: CrosvmConfig,
     crosvm_control_socket_path: &Path,
     failure_pipe_write: File,
+    restore: Option<&Path>,
 ) -> Result<SharedChild, Error> {
     validate_config(&config)?;
 
@@ -904,9 +1509,7 @@ fn run_vm(
         .arg("--cid")
         .arg(config.cid.to_string());
 
-    if system_properties::read_bool("hypervisor.memory_reclaim.supported", false)?
-        && !config.no_balloon
-    {
+    if balloon_enabled(&config)? {
         command.arg("--balloon-page-reporting");
     } else {
         command.arg("--no-balloon");
@@ -982,6 +1585,10 @@ fn run_vm(
         command.arg("--gdb").arg(gdb_port.to_string());
     }
 
+    if let Some(restore) = restore {
+        command.arg("--restore").arg(restore.join(CROSVM_SNAPSHOT_NAME));
+    }
+
     // Keep track of what file descriptors should be mapped to the crosvm process.
     let mut preserved_fds = config.indirect_files.into_iter().map(|f| f.into()).collect();
 
@@ -1111,10 +1718,15 @@ fn run_vm(
     }
 
     if cfg!(network) {
-        if let Some(tap) = config.tap {
-            add_preserved_fd(&mut preserved_fds, tap);
+        for tap_device in config.taps {
+            add_preserved_fd(&mut preserved_fds, tap_device.tap);
             let tap_fd = preserved_fds.last().unwrap().as_raw_fd();
-            command.arg("--net").arg(format!("tap-fd={tap_fd}"));
+            let mut net_arg = format!("tap-fd={tap_fd}");
+            if let Some(mac) = tap_device.mac_address {
+                let mac: Vec<_> = mac.iter().map(|octet| format!("{octet:02x}")).collect();
+                net_arg.push_str(&format!(",mac={}", mac.join(":")));
+            }
+            command.arg("--net").arg(net_arg);
         }
     }
 
@@ -1218,6 +1830,24 @@ fn validate_config(config: &CrosvmConfig) -> Result<(), Error> {
             version
         );
     }
+    if let Some(balloon_policy) = &config.balloon_policy {
+        if config.no_balloon {
+            bail!("Can't have a balloon policy without a balloon.");
+        }
+        balloon_policy.validate(config.memory_mib)?;
+    }
+    if config.taps.len() > MAX_NETWORK_INTERFACES {
+        bail!("Can't have more than {MAX_NETWORK_INTERFACES} network interfaces.");
+    }
+    let mut labels = HashSet::new();
+    for label in config.disks.iter().filter_map(|disk| disk.label.as_deref()) {
+        if label.is_empty() {
+            bail!("Disk labels must not be empty.");
+        }
+        if !labels.insert(label) {
+            bail!("More than one disk is labeled {label}.");
+        }
+    }
 
     Ok(())
 }
@@ -1290,3 +1920,33 @@ fn create_crosvm_control_listener(crosvm_control_socket_path: &Path) -> Result<O
     socket::listen(&fd, socket::Backlog::new(127).unwrap()).context("listen failed")?;
     Ok(fd)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disk(label: Option<&str>, writable: bool) -> DiskFile {
+        DiskFile { image: tempfile::tempfile().unwrap(), writable, label: label.map(str::to_owned) }
+    }
+
+    #[test]
+    fn resize_labeled_disk() -> Result<()> {
+        let disks = LabeledDisk::from_disks(&[
+            disk(None, false),
+            disk(Some("data"), true),
+            disk(Some("ro"), false),
+        ]);
+
+        let request = resize_disk_request(&disks, "data", 4096)?;
+        assert!(matches!(
+            request,
+            VmRequest::DiskCommand {
+                disk_index: 1,
+                command: DiskControlCommand::Resize { new_size: 4096 }
+            }
+        ));
+        assert!(resize_disk_request(&disks, "ro", 4096).is_err());
+        assert!(resize_disk_request(&disks, "missing", 4096).is_err());
+        Ok(())
+    }
+}
@@ -149,7 +149,7 @@ impl OwnedFdt {
 }
 
 /// Debug configurations for debug policy.
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct DebugPolicy {
     log: bool,
     ramdump: bool,
@@ -180,7 +180,7 @@ impl DebugPolicy {
 }
 
 /// Debug configurations for both debug level and debug policy
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct DebugConfig {
     pub debug_level: DebugLevel,
     debug_policy: DebugPolicy,
@@ -16,12 +16,16 @@
 
 mod aidl;
 mod atom;
+mod balloon;
 mod composite;
 mod crosvm;
 mod debug_config;
 mod dt_overlay;
+mod metrics;
 mod payload;
+mod restart;
 mod selinux;
+mod snapshot;
 
 use crate::aidl::{GLOBAL_SERVICE, VirtualizationService};
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::IVirtualizationService::BnVirtualizationService;
@@ -42,11 +46,6 @@ static PID_CURRENT: LazyLock<Pid> = LazyLock::new(Pid::this);
 static PID_PARENT: LazyLock<Pid> = LazyLock::new(Pid::parent);
 static UID_CURRENT: LazyLock<Uid> = LazyLock::new(Uid::current);
 
-fn get_this_pid() -> pid_t {
-    // Return the process ID of this process.
-    PID_CURRENT.as_raw()
-}
-
 fn get_calling_pid() -> pid_t {
     // The caller is the parent of this process.
     PID_PARENT.as_raw()
@@ -0,0 +1,165 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sampling of the resource usage of a running VM from procfs and sysfs.
+
+use anyhow::{bail, Context, Result};
+use std::fs::{read_dir, read_to_string, File};
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+/// Name of the threads in which crosvm runs its virtio-block devices, one per disk.
+const BLOCK_THREAD_NAME: &str = "v_block";
+
+/// A sample of the resource usage of a running VM.
+#[derive(Clone, Debug, Default)]
+pub struct VmMetrics {
+    pub uptime: Duration,
+    pub vcpu_time_millis: i64,
+    pub guest_rss_kib: i64,
+    pub crosvm_rss_kib: i64,
+    /// Size of the memory balloon in bytes, if the balloon is ready.
+    pub balloon: Option<u64>,
+    /// I/O on each disk, or empty if the I/O of the disks can't be told apart.
+    pub disks: Vec<DiskIo>,
+    pub network: Option<NetworkCounters>,
+}
+
+/// I/O on a disk, in bytes.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DiskIo {
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+}
+
+/// Counters of a network device, from the point of view of the guest.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct NetworkCounters {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+}
+
+/// Returns the I/O on each of the `num_disks` disks of the VM run by the crosvm process `pid`.
+///
+/// crosvm serves each virtio-block device from its own worker thread, started when the guest
+/// activates the device, and doesn't report which thread serves which disk. The threads are
+/// assumed to be started in the order of the disks, and matched to the disks in the order of
+/// their TIDs. This is fragile: it relies on the guest activating the disks in order, and on
+/// TIDs growing, which stops being true when the PID space wraps around. The I/O of a disk is the
+/// I/O of its thread. If there are not as many threads as disks, e.g. while the guest is booting,
+/// the I/O can't be attributed and an empty list is returned.
+pub fn get_disk_io(pid: u32, num_disks: usize) -> Result<Vec<DiskIo>> {
+    let mut tids = Vec::new();
+    for entry in read_dir(format!("/proc/{pid}/task"))? {
+        let entry = entry?;
+        let Some(tid) = entry.file_name().to_str().and_then(|tid| tid.parse::<u32>().ok()) else {
+            continue;
+        };
+        // The thread may have exited meanwhile.
+        let Ok(comm) = read_to_string(entry.path().join("comm")) else {
+            continue;
+        };
+        if comm.trim_end() == BLOCK_THREAD_NAME {
+            tids.push(tid);
+        }
+    }
+    if tids.len() != num_disks {
+        return Ok(vec![]);
+    }
+    tids.sort_unstable();
+    tids.iter()
+        .map(|tid| {
+            let io = read_to_string(format!("/proc/{pid}/task/{tid}/io"))?;
+            parse_io(&io)
+        })
+        .collect()
+}
+
+/// Parses a `/proc/<pid>/io` file. The bytes read and written through syscalls are used rather
+/// than the bytes which hit the storage, so that reads served from the page cache are counted.
+fn parse_io(io: &str) -> Result<DiskIo> {
+    let mut read_bytes = None;
+    let mut written_bytes = None;
+    for line in io.lines() {
+        match line.split_once(':') {
+            Some(("rchar", value)) => read_bytes = Some(value.trim().parse()?),
+            Some(("wchar", value)) => written_bytes = Some(value.trim().parse()?),
+            _ => {}
+        }
+    }
+    let (Some(read_bytes), Some(written_bytes)) = (read_bytes, written_bytes) else {
+        bail!("Failed to parse I/O counters:\n{}", io);
+    };
+    Ok(DiskIo { read_bytes, written_bytes })
+}
+
+/// Returns the name of the network interface of the TAP device `tap`.
+pub fn get_tap_interface_name(tap: &File) -> Result<String> {
+    let fdinfo = read_to_string(format!("/proc/self/fdinfo/{}", tap.as_raw_fd()))?;
+    parse_tap_interface_name(&fdinfo)
+}
+
+/// Parses the fdinfo of a TUN/TAP file, in which the driver adds the name of the interface.
+fn parse_tap_interface_name(fdinfo: &str) -> Result<String> {
+    fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("iff:"))
+        .map(|name| name.trim().to_owned())
+        .context("No interface in fdinfo of TAP device")
+}
+
+/// Returns the counters of the network interface `interface` backing the virtio-net device of a
+/// VM. What the TAP device transmits is received by the guest, and vice versa.
+pub fn get_network_counters(interface: &str) -> Result<NetworkCounters> {
+    let read_counter = |name: &str| -> Result<u64> {
+        let path = format!("/sys/class/net/{interface}/statistics/{name}");
+        let value = read_to_string(&path).with_context(|| format!("Failed to read {path}"))?;
+        value.trim().parse().with_context(|| format!("Invalid counter in {path}"))
+    };
+    Ok(NetworkCounters {
+        rx_bytes: read_counter("tx_bytes")?,
+        rx_packets: read_counter("tx_packets")?,
+        tx_bytes: read_counter("rx_bytes")?,
+        tx_packets: read_counter("rx_packets")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_io_counters() {
+        let io = "rchar: 12345\nwchar: 678\nsyscr: 10\nsyscw: 2\nread_bytes: 4096\n\
+                  write_bytes: 0\ncancelled_write_bytes: 0\n";
+        assert_eq!(parse_io(io).unwrap(), DiskIo { read_bytes: 12345, written_bytes: 678 });
+        assert!(parse_io("rchar: 12345\n").is_err());
+    }
+
+    #[test]
+    fn parse_tap_fdinfo() {
+        let fdinfo = "pos:\t0\nflags:\t0104002\nmnt_id:\t25\nino:\t1045\niff:\tcrosvm_tap3\n";
+        assert_eq!(parse_tap_interface_name(fdinfo).unwrap(), "crosvm_tap3");
+        assert!(parse_tap_interface_name("pos:\t0\nflags:\t0100002\n").is_err());
+    }
+
+    #[test]
+    fn disk_io_of_own_process() {
+        // This process has no virtio-block thread, so the I/O of one disk can't be attributed.
+        assert_eq!(get_disk_io(std::process::id(), 0).unwrap(), vec![]);
+        assert_eq!(get_disk_io(std::process::id(), 1).unwrap(), vec![]);
+    }
+}
@@ -22,10 +22,12 @@ use android_system_virtualizationservice::aidl::android::system::virtualizations
     VirtualMachineAppConfig::{Payload::Payload, VirtualMachineAppConfig},
     VirtualMachineRawConfig::VirtualMachineRawConfig,
 };
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use binder::{wait_for_interface, ParcelFileDescriptor};
 use log::{info, warn};
-use microdroid_metadata::{ApexPayload, ApkPayload, Metadata, PayloadConfig, PayloadMetadata};
+use microdroid_metadata::{
+    ApexPayload, ApkPayload, Metadata, PayloadConfig, PayloadMetadata, PayloadTaskOverrides,
+};
 use microdroid_payload_config::{ApexConfig, VmPayloadConfig};
 use once_cell::sync::OnceCell;
 use packagemanager_aidl::aidl::android::content::pm::{
@@ -34,7 +36,7 @@ use packagemanager_aidl::aidl::android::content::pm::{
 use regex::Regex;
 use serde::Deserialize;
 use serde_xml_rs::from_reader;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs::{metadata, File, OpenOptions};
 use std::path::{Path, PathBuf};
@@ -192,6 +194,45 @@ impl PackageManager {
     }
 }
 
+/// Returns the overrides of how the main task of the payload is run requested in `app_config`, if
+/// any.
+fn make_task_overrides(
+    app_config: &VirtualMachineAppConfig,
+) -> Result<Option<PayloadTaskOverrides>> {
+    let payload_env = app_config.payloadEnv.as_deref().unwrap_or_default();
+    if app_config.payloadArgs.is_none() && payload_env.is_empty() {
+        return Ok(None);
+    }
+    let args: Vec<String> = app_config.payloadArgs.iter().flatten().flatten().cloned().collect();
+    for arg in &args {
+        ensure!(!arg.contains('\0'), "Payload argument {arg:?} contains a NUL character");
+    }
+    let mut env = HashMap::new();
+    for variable in payload_env.iter().flatten() {
+        ensure!(
+            !variable.name.is_empty() && !variable.name.contains(['=', '\0']),
+            "Invalid payload environment variable name {:?}",
+            variable.name
+        );
+        ensure!(
+            !variable.value.contains('\0'),
+            "Payload environment variable {} contains a NUL character",
+            variable.name
+        );
+        ensure!(
+            env.insert(variable.name.clone(), variable.value.clone()).is_none(),
+            "Duplicate payload environment variable {}",
+            variable.name
+        );
+    }
+    Ok(Some(PayloadTaskOverrides {
+        override_args: app_config.payloadArgs.is_some(),
+        args,
+        env,
+        ..Default::default()
+    }))
+}
+
 fn make_metadata_file(
     app_config: &VirtualMachineAppConfig,
     apex_infos: &[&ApexInfo],
@@ -231,6 +272,7 @@ fn make_metadata_file(
         })
         .into(),
         payload: Some(payload_metadata),
+        task_overrides: make_task_overrides(app_config)?.into(),
         ..Default::default()
     };
 
@@ -354,7 +396,7 @@ fn make_payload_disk(
         });
     }
 
-    Ok(DiskImage { image: None, partitions, writable: false })
+    Ok(DiskImage { image: None, partitions, writable: false, label: None })
 }
 
 fn run_derive_classpath() -> Result<String> {
@@ -433,6 +475,7 @@ pub fn add_microdroid_vendor_image(vendor_image: File, vm_config: &mut VirtualMa
     vm_config.disks.push(DiskImage {
         image: None,
         writable: false,
+        label: None,
         partitions: vec![Partition {
             label: "microdroid-vendor".to_owned(),
             image: Some(ParcelFileDescriptor::new(vendor_image)),
@@ -477,6 +520,7 @@ pub fn add_microdroid_system_images(
         image: None,
         partitions: writable_partitions,
         writable: true,
+        label: None,
     });
 
     Ok(())
@@ -509,7 +553,7 @@ pub fn add_microdroid_payload_images(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashMap;
+    use android_system_virtualizationservice::aidl::android::system::virtualizationservice::VirtualMachineAppConfig::EnvironmentVariable::EnvironmentVariable;
     use tempfile::NamedTempFile;
 
     #[test]
@@ -807,4 +851,57 @@ export OTHER /foo/bar:/baz:/apex/second.valid.apex/:gibberish:"#;
             }
         );
     }
+
+    fn env_var(name: &str, value: &str) -> Option<EnvironmentVariable> {
+        Some(EnvironmentVariable { name: name.to_owned(), value: value.to_owned() })
+    }
+
+    #[test]
+    fn test_make_task_overrides_none() -> Result<()> {
+        let app_config = VirtualMachineAppConfig::default();
+        assert_eq!(make_task_overrides(&app_config)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_make_task_overrides() -> Result<()> {
+        let app_config = VirtualMachineAppConfig {
+            payloadArgs: Some(vec![Some("--port".to_owned()), Some("5678".to_owned())]),
+            payloadEnv: Some(vec![env_var("FOO", "1"), env_var("BAR", "a=b")]),
+            ..Default::default()
+        };
+        let overrides = make_task_overrides(&app_config)?.unwrap();
+        assert!(overrides.override_args);
+        assert_eq!(overrides.args, vec!["--port", "5678"]);
+        assert_eq!(
+            overrides.env,
+            HashMap::from([
+                ("FOO".to_owned(), "1".to_owned()),
+                ("BAR".to_owned(), "a=b".to_owned())
+            ])
+        );
+
+        let app_config = VirtualMachineAppConfig {
+            payloadEnv: Some(vec![env_var("FOO", "1")]),
+            ..Default::default()
+        };
+        let overrides = make_task_overrides(&app_config)?.unwrap();
+        assert!(!overrides.override_args);
+        assert!(overrides.args.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_make_task_overrides_invalid_env() {
+        for env in [
+            vec![env_var("", "1")],
+            vec![env_var("FOO=BAR", "1")],
+            vec![env_var("FOO", "1\0")],
+            vec![env_var("FOO", "1"), env_var("FOO", "2")],
+        ] {
+            let app_config =
+                VirtualMachineAppConfig { payloadEnv: Some(env), ..Default::default() };
+            assert!(make_task_overrides(&app_config).is_err());
+        }
+    }
 }
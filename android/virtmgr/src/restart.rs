@@ -0,0 +1,152 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Policy for restarting a VM when it exits.
+
+use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::DeathReason::DeathReason;
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::RestartPolicy::{
+    Mode::Mode, RestartPolicy as RestartPolicyParcelable,
+};
+use anyhow::{bail, ensure, Context, Result};
+use std::time::Duration;
+
+/// The longest delay before restarting a VM.
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(300);
+
+/// How long the payload of a VM must have been ready for its previous restarts to be forgotten.
+const RESTARTS_RESET_AFTER: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+enum RestartMode {
+    #[default]
+    Never,
+    OnFailure {
+        max_retries: u32,
+    },
+    Always,
+}
+
+/// When and how fast a VM is restarted after it exits.
+#[derive(Clone, Debug, Default)]
+pub struct RestartPolicy {
+    mode: RestartMode,
+    /// Delay before the first restart, doubled with each restart.
+    backoff: Duration,
+}
+
+impl RestartPolicy {
+    pub fn new(raw_policy: &RestartPolicyParcelable) -> Result<RestartPolicy> {
+        let mode = match raw_policy.mode {
+            Mode::NEVER => RestartMode::Never,
+            Mode::ON_FAILURE => {
+                let max_retries =
+                    raw_policy.maxRetries.try_into().context("Invalid restart max retries")?;
+                ensure!(max_retries > 0, "Restart max retries must be positive");
+                RestartMode::OnFailure { max_retries }
+            }
+            Mode::ALWAYS => RestartMode::Always,
+            mode => bail!("Invalid restart mode {mode:?}"),
+        };
+        let backoff_millis =
+            raw_policy.backoffMillis.try_into().context("Invalid restart backoff")?;
+        Ok(RestartPolicy { mode, backoff: Duration::from_millis(backoff_millis) })
+    }
+
+    /// Returns whether the VM may be restarted at all.
+    pub fn may_restart(&self) -> bool {
+        self.mode != RestartMode::Never
+    }
+
+    /// Returns how long to wait before restarting a VM which has already been restarted
+    /// `restarts` times and exited for `death_reason`, or `None` if it must not be restarted.
+    pub fn restart_delay(&self, death_reason: DeathReason, restarts: u32) -> Option<Duration> {
+        match self.mode {
+            RestartMode::Never => return None,
+            RestartMode::OnFailure { max_retries } => {
+                if death_reason == DeathReason::SHUTDOWN || restarts >= max_retries {
+                    return None;
+                }
+            }
+            RestartMode::Always => {}
+        }
+        let factor = 2u32.saturating_pow(restarts);
+        Some(self.backoff.saturating_mul(factor).min(MAX_RESTART_BACKOFF))
+    }
+}
+
+/// Returns the number of times a VM which exits is considered to have been restarted, given that
+/// it was restarted `restarts` times and that its payload had been ready for `ready_for`, if it
+/// was ready. The count starts over once the payload has been ready for a while, so that crashes
+/// long apart don't use up the retries or grow the backoff.
+pub fn restarts_to_count(restarts: u32, ready_for: Option<Duration>) -> u32 {
+    match ready_for {
+        Some(ready_for) if ready_for >= RESTARTS_RESET_AFTER => 0,
+        _ => restarts,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(mode: Mode, max_retries: i32, backoff_millis: i32) -> Result<RestartPolicy> {
+        RestartPolicy::new(&RestartPolicyParcelable {
+            mode,
+            maxRetries: max_retries,
+            backoffMillis: backoff_millis,
+        })
+    }
+
+    #[test]
+    fn invalid_policies_are_rejected() {
+        assert!(policy(Mode::ON_FAILURE, 0, 1000).is_err());
+        assert!(policy(Mode::ON_FAILURE, 3, -1).is_err());
+        assert!(policy(Mode(42), 3, 1000).is_err());
+        // The max retries are only used on failure.
+        assert!(policy(Mode::ALWAYS, 0, 1000).is_ok());
+    }
+
+    #[test]
+    fn never_restarts() {
+        let policy = policy(Mode::NEVER, 0, 1000).unwrap();
+        assert!(!policy.may_restart());
+        assert_eq!(policy.restart_delay(DeathReason::CRASH, 0), None);
+        assert!(!RestartPolicy::default().may_restart());
+    }
+
+    #[test]
+    fn restarts_on_failure_up_to_max_retries() {
+        let policy = policy(Mode::ON_FAILURE, 2, 1000).unwrap();
+        assert!(policy.may_restart());
+        assert_eq!(policy.restart_delay(DeathReason::SHUTDOWN, 0), None);
+        assert_eq!(policy.restart_delay(DeathReason::CRASH, 0), Some(Duration::from_secs(1)));
+        assert_eq!(policy.restart_delay(DeathReason::HANGUP, 1), Some(Duration::from_secs(2)));
+        assert_eq!(policy.restart_delay(DeathReason::CRASH, 2), None);
+    }
+
+    #[test]
+    fn always_restarts_with_bounded_backoff() {
+        let policy = policy(Mode::ALWAYS, 0, 1000).unwrap();
+        assert_eq!(policy.restart_delay(DeathReason::SHUTDOWN, 0), Some(Duration::from_secs(1)));
+        assert_eq!(policy.restart_delay(DeathReason::SHUTDOWN, 3), Some(Duration::from_secs(8)));
+        assert_eq!(policy.restart_delay(DeathReason::CRASH, 100), Some(MAX_RESTART_BACKOFF));
+    }
+
+    #[test]
+    fn restarts_are_forgotten_after_being_ready_for_a_while() {
+        assert_eq!(restarts_to_count(3, None), 3);
+        assert_eq!(restarts_to_count(3, Some(Duration::from_secs(1))), 3);
+        assert_eq!(restarts_to_count(3, Some(RESTARTS_RESET_AFTER)), 0);
+    }
+}
@@ -0,0 +1,261 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Archives of VM snapshots.
+//!
+//! A snapshot archive is a zip file holding the snapshot written by crosvm under `crosvm/`, i.e.
+//! the guest memory and the state of the devices, and the description of the configuration of
+//! the VM in `config.json`. The files of the VM, e.g. its disk images, are not part of the
+//! snapshot: the VM is restored by creating it again with the same configuration.
+
+use crate::crosvm::CrosvmConfig;
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, read_dir, File};
+use std::io::{self, Seek, Write};
+use std::path::Path;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+const CONFIG_ENTRY: &str = "config.json";
+const CROSVM_DIR: &str = "crosvm/";
+
+/// The parts of a `CrosvmConfig` which crosvm needs to be the same when restoring a snapshot, as
+/// they determine the memory layout and the devices of the VM.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SnapshotConfig {
+    platform_version: String,
+    memory_mib: u32,
+    cpus: Option<u32>,
+    host_cpu_topology: bool,
+    bootloader: bool,
+    kernel: bool,
+    initrd: bool,
+    params: Option<String>,
+    /// Whether each disk is writable.
+    disks: Vec<bool>,
+    ramdump: bool,
+    hugepages: bool,
+    no_balloon: bool,
+    usb_controller: bool,
+    network_interfaces: usize,
+    console_input_device: Option<String>,
+    gpu: bool,
+    display: bool,
+    audio: bool,
+    input_devices: usize,
+    vfio_devices: usize,
+}
+
+impl SnapshotConfig {
+    pub fn new(config: &CrosvmConfig) -> SnapshotConfig {
+        SnapshotConfig {
+            platform_version: vmconfig::PLATFORM_VERSION.to_owned(),
+            memory_mib: config.memory_mib.get(),
+            cpus: config.cpus.map(|cpus| cpus.get()),
+            host_cpu_topology: config.host_cpu_topology,
+            bootloader: config.bootloader.is_some(),
+            kernel: config.kernel.is_some(),
+            initrd: config.initrd.is_some(),
+            params: config.params.clone(),
+            disks: config.disks.iter().map(|disk| disk.writable).collect(),
+            ramdump: config.ramdump.is_some(),
+            hugepages: config.hugepages,
+            no_balloon: config.no_balloon,
+            usb_controller: config.usb_config.controller,
+            network_interfaces: config.taps.len(),
+            console_input_device: config.console_input_device.clone(),
+            gpu: config.gpu_config.is_some(),
+            display: config.display_config.is_some(),
+            audio: config.audio_config.is_some(),
+            input_devices: config.input_device_options.len(),
+            vfio_devices: config.vfio_devices.len(),
+        }
+    }
+
+    /// Checks that a VM with this configuration can be restored from a snapshot of a VM with the
+    /// `snapshot` configuration.
+    pub fn check_restorable_from(&self, snapshot: &SnapshotConfig) -> Result<()> {
+        let serde_json::Value::Object(current) = serde_json::to_value(self)? else {
+            bail!("Config is not serialized to an object");
+        };
+        let serde_json::Value::Object(snapshot) = serde_json::to_value(snapshot)? else {
+            bail!("Config is not serialized to an object");
+        };
+        let mismatches: Vec<_> = current
+            .iter()
+            .filter(|(key, value)| snapshot.get(*key) != Some(value))
+            .map(|(key, _)| key.as_str())
+            .collect();
+        ensure!(
+            mismatches.is_empty(),
+            "The configuration of the VM doesn't match the snapshot: {} differ",
+            mismatches.join(", ")
+        );
+        Ok(())
+    }
+}
+
+/// Writes a snapshot archive to `output`, with the VM configuration `config` and the files which
+/// crosvm wrote in `crosvm_snapshot_dir`.
+pub fn write_archive(
+    output: File,
+    config: &SnapshotConfig,
+    crosvm_snapshot_dir: &Path,
+) -> Result<()> {
+    let mut writer = ZipWriter::new(output);
+    // Guest memory is mostly incompressible or zero pages which crosvm already skips, so the
+    // files are stored as they are.
+    let options =
+        FileOptions::default().compression_method(CompressionMethod::Stored).large_file(true);
+    writer.start_file(CONFIG_ENTRY, options)?;
+    serde_json::to_writer(&mut writer, config)?;
+    add_dir(&mut writer, options, crosvm_snapshot_dir, CROSVM_DIR)?;
+    writer.finish()?.flush()?;
+    Ok(())
+}
+
+fn add_dir<W: Write + Seek>(
+    writer: &mut ZipWriter<W>,
+    options: FileOptions,
+    dir: &Path,
+    prefix: &str,
+) -> Result<()> {
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| anyhow::anyhow!("Invalid file name {:?}", name))?;
+        let name = format!("{prefix}{name}");
+        if entry.file_type()?.is_dir() {
+            writer.add_directory(&name, options)?;
+            add_dir(writer, options, &entry.path(), &format!("{name}/"))?;
+        } else {
+            writer.start_file(&name, options)?;
+            io::copy(&mut File::open(entry.path())?, writer)
+                .with_context(|| format!("Failed to archive {:?}", entry.path()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Extracts the crosvm snapshot of the archive `input` to `crosvm_snapshot_dir`, and returns the
+/// configuration of the snapshotted VM.
+pub fn extract_archive(input: File, crosvm_snapshot_dir: &Path) -> Result<SnapshotConfig> {
+    let mut archive = ZipArchive::new(input).context("Snapshot is not a valid archive")?;
+    let config = serde_json::from_reader(
+        archive.by_name(CONFIG_ENTRY).context("Snapshot has no configuration")?,
+    )
+    .context("Invalid snapshot configuration")?;
+
+    create_dir_all(crosvm_snapshot_dir)?;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        // Entries with absolute paths or ".." components would be extracted outside of the
+        // directory.
+        let Some(path) = entry.enclosed_name().map(Path::to_path_buf) else {
+            bail!("Invalid path in snapshot: {}", entry.name());
+        };
+        let Ok(path) = path.strip_prefix(CROSVM_DIR) else {
+            continue;
+        };
+        let path = crosvm_snapshot_dir.join(path);
+        if entry.is_dir() {
+            create_dir_all(&path)?;
+        } else {
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent)?;
+            }
+            io::copy(&mut entry, &mut File::create(&path)?)
+                .with_context(|| format!("Failed to extract {:?}", path))?;
+        }
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn config() -> SnapshotConfig {
+        SnapshotConfig {
+            platform_version: "1.0.0".to_owned(),
+            memory_mib: 1024,
+            cpus: Some(2),
+            host_cpu_topology: false,
+            bootloader: false,
+            kernel: true,
+            initrd: true,
+            params: Some("console=hvc0".to_owned()),
+            disks: vec![false, true],
+            ramdump: false,
+            hugepages: false,
+            no_balloon: false,
+            usb_controller: false,
+            network_interfaces: 0,
+            console_input_device: None,
+            gpu: false,
+            display: false,
+            audio: false,
+            input_devices: 0,
+            vfio_devices: 0,
+        }
+    }
+
+    #[test]
+    fn archive_round_trip() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let snapshot_dir = dir.path().join("snapshot");
+        fs::create_dir_all(snapshot_dir.join("devices"))?;
+        fs::write(snapshot_dir.join("mem"), vec![7; 100_000])?;
+        fs::write(snapshot_dir.join("devices/block"), b"block state")?;
+
+        let archive_path = dir.path().join("snapshot.zip");
+        write_archive(File::create(&archive_path)?, &config(), &snapshot_dir)?;
+
+        let restore_dir = dir.path().join("restore");
+        let restored_config = extract_archive(File::open(&archive_path)?, &restore_dir)?;
+        assert_eq!(restored_config, config());
+        assert_eq!(fs::read(restore_dir.join("mem"))?, vec![7; 100_000]);
+        assert_eq!(fs::read(restore_dir.join("devices/block"))?, b"block state");
+        Ok(())
+    }
+
+    #[test]
+    fn archive_without_config_is_rejected() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let archive_path = dir.path().join("snapshot.zip");
+        let mut writer = ZipWriter::new(File::create(&archive_path)?);
+        writer.start_file("crosvm/mem", FileOptions::default())?;
+        writer.finish()?;
+
+        assert!(extract_archive(File::open(&archive_path)?, &dir.path().join("restore")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn mismatching_config_is_not_restorable() {
+        let mut other = config();
+        other.memory_mib = 2048;
+        other.disks.push(false);
+
+        assert!(config().check_restorable_from(&config()).is_ok());
+        let error = config().check_restorable_from(&other).unwrap_err().to_string();
+        assert!(error.contains("memory_mib"), "{error}");
+        assert!(error.contains("disks"), "{error}");
+        assert!(!error.contains("cpus"), "{error}");
+    }
+}
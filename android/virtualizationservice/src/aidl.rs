@@ -37,6 +37,7 @@ use libc::{VMADDR_CID_HOST, VMADDR_CID_HYPERVISOR, VMADDR_CID_LOCAL};
 use log::{error, info, warn};
 use nix::unistd::{chown, Uid};
 use openssl::x509::X509;
+use packagemanager_aidl::aidl::android::content::pm::IPackageManagerNative::IPackageManagerNative;
 use rand::Fill;
 use rkpd_client::get_rkpd_attestation_key;
 use rustutils::{
@@ -59,7 +60,12 @@ use virtualizationmaintenance::{
     IVirtualizationReconciliationCallback::IVirtualizationReconciliationCallback,
 };
 use virtualizationservice::{
-    AssignableDevice::AssignableDevice, VirtualMachineDebugInfo::VirtualMachineDebugInfo,
+    AssignableDevice::AssignableDevice,
+    IVirtualMachine::IVirtualMachine,
+    NetworkInterface::{Mode::Mode as NetworkMode, NetworkInterface},
+    VirtualMachineDebugInfo::VirtualMachineDebugInfo,
+    VirtualMachineInstanceDebugInfo::VirtualMachineInstanceDebugInfo,
+    VirtualMachineState::VirtualMachineState,
 };
 use virtualizationservice_internal::{
     AtomVmBooted::AtomVmBooted,
@@ -91,6 +97,8 @@ const SYSPROP_LAST_CID: &str = "virtualizationservice.state.last_cid";
 
 const CHUNK_RECV_MAX_LEN: usize = 1024;
 
+const PACKAGE_MANAGER_NATIVE_SERVICE: &str = "package_native";
+
 /// The fake certificate is used for testing only when a client VM requests attestation in test
 /// mode, it is a single certificate extracted on an unregistered device for testing.
 /// Here is the snapshot of the certificate:
@@ -179,6 +187,10 @@ fn is_valid_guest_cid(cid: Cid) -> bool {
 pub struct VirtualizationServiceInternal {
     state: Arc<Mutex<GlobalState>>,
     display_service_set: Arc<Condvar>,
+    /// The number of TAP interfaces in NAT mode. VM tethering is enabled while there is any. This
+    /// isn't part of `state`, as it is locked while tethering is enabled or disabled, so that it's
+    /// done in the same order as the NAT interfaces are created and deleted.
+    nat_interfaces: Arc<Mutex<usize>>,
 }
 
 impl VirtualizationServiceInternal {
@@ -186,6 +198,7 @@ impl VirtualizationServiceInternal {
         let service = VirtualizationServiceInternal {
             state: Arc::new(Mutex::new(GlobalState::new())),
             display_service_set: Arc::new(Condvar::new()),
+            nat_interfaces: Arc::new(Mutex::new(0)),
         };
 
         std::thread::spawn(|| {
@@ -281,23 +294,96 @@ impl IVirtualizationServiceInternal for VirtualizationServiceInternal {
     fn debugListVms(&self) -> binder::Result<Vec<VirtualMachineDebugInfo>> {
         check_debug_access()?;
 
-        let state = &mut *self.state.lock().unwrap();
-        let cids = state
-            .held_contexts
-            .iter()
-            .filter_map(|(_, inst)| Weak::upgrade(inst))
-            .map(|vm| {
-                let vm = vm.lock().unwrap();
-                VirtualMachineDebugInfo {
-                    cid: vm.cid as i32,
-                    temporaryDirectory: vm.get_temp_dir().to_string_lossy().to_string(),
-                    requesterUid: vm.requester_uid as i32,
-                    requesterPid: vm.requester_debug_pid,
-                    hostConsoleName: vm.host_console_name.clone(),
-                }
+        let vms: Vec<_> = {
+            let state = &mut *self.state.lock().unwrap();
+            state
+                .held_contexts
+                .iter()
+                .filter_map(|(_, inst)| Weak::upgrade(inst))
+                .map(|vm| {
+                    let vm = vm.lock().unwrap();
+                    let info = VirtualMachineDebugInfo {
+                        cid: vm.cid as i32,
+                        temporaryDirectory: vm.get_temp_dir().to_string_lossy().to_string(),
+                        requesterUid: vm.requester_uid as i32,
+                        requesterPid: vm.requester_debug_pid,
+                        hostConsoleName: vm.host_console_name.clone(),
+                        name: vm.name.clone(),
+                        memoryMib: vm.memory_mib,
+                        protectedVm: vm.protected,
+                        ..Default::default()
+                    };
+                    (info, vm.vm.clone())
+                })
+                .collect()
+        };
+        // Query the state from the virtmgr owning each VM without holding the lock, as it is a
+        // call into a different process.
+        let infos = vms
+            .into_iter()
+            .map(|(info, vm)| {
+                let state = match vm {
+                    // The VM is still being created.
+                    None => VirtualMachineState::NOT_STARTED,
+                    Some(vm) => vm
+                        .upgrade()
+                        .and_then(|vm| vm.getState())
+                        .unwrap_or(VirtualMachineState::DEAD),
+                };
+                VirtualMachineDebugInfo { state, ..info }
             })
             .collect();
-        Ok(cids)
+        Ok(infos)
+    }
+
+    fn debugListVmInstances(&self) -> binder::Result<Vec<VirtualMachineInstanceDebugInfo>> {
+        check_debug_access()?;
+
+        let state = &mut *self.state.lock().unwrap();
+        let Some(sk_state) = &mut state.sk_state else {
+            info!("no VM instances to list as no ISecretkeeper");
+            return Ok(vec![]);
+        };
+        let infos = sk_state.vm_id_infos().or_service_specific_exception(-1)?;
+        Ok(infos
+            .into_iter()
+            .map(|info| VirtualMachineInstanceDebugInfo {
+                instanceId: info.vm_id,
+                userId: info.user_id,
+                appId: info.app_id,
+                createdMillis: info.created.map_or(-1, |t| t as i64),
+                lastUsedMillis: info.last_used.map_or(-1, |t| t as i64),
+                name: info.vm_name,
+                packageName: info.package_name,
+                updatable: info.updatable.unwrap_or(false),
+            })
+            .collect())
+    }
+
+    fn debugGetVm(&self, cid: i32) -> binder::Result<Strong<dyn IVirtualMachine>> {
+        check_debug_access()?;
+
+        let state = &mut *self.state.lock().unwrap();
+        let instance = Cid::try_from(cid)
+            .ok()
+            .and_then(|cid| state.held_contexts.get(&cid))
+            .and_then(Weak::upgrade)
+            .ok_or_else(|| anyhow!("No running VM with CID {cid}"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        let instance = instance.lock().unwrap();
+
+        let calling_uid = get_calling_uid();
+        // Root can control any VM.
+        if calling_uid != 0 && calling_uid != instance.requester_uid {
+            return Err(anyhow!("VM with CID {cid} is not owned by uid {calling_uid}"))
+                .or_binder_exception(ExceptionCode::SECURITY);
+        }
+        instance
+            .vm
+            .as_ref()
+            .and_then(|vm| vm.upgrade().ok())
+            .ok_or_else(|| anyhow!("VM with CID {cid} is not running"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)
     }
 
     fn enableTestAttestation(&self) -> binder::Result<()> {
@@ -508,7 +594,7 @@ impl IVirtualizationServiceInternal for VirtualizationServiceInternal {
             let user_id = multiuser_get_user_id(uid);
             let app_id = multiuser_get_app_id(uid);
             info!("Recording possible new owner of state for (user_id={user_id}, app_id={app_id})");
-            if let Err(e) = sk_state.add_id(instance_id, user_id, app_id) {
+            if let Err(e) = sk_state.claim_id(instance_id, user_id, app_id) {
                 error!("Failed to update the instance_id owner: {e:?}");
             }
         } else {
@@ -517,7 +603,43 @@ impl IVirtualizationServiceInternal for VirtualizationServiceInternal {
         Ok(())
     }
 
-    fn createTapInterface(&self, _iface_name_suffix: &str) -> binder::Result<ParcelFileDescriptor> {
+    fn vmInstanceBooted(
+        &self,
+        instance_id: &[u8; 64],
+        name: &str,
+        updatable: bool,
+    ) -> binder::Result<()> {
+        let uid = get_calling_uid();
+        if self.state.lock().unwrap().sk_state.is_none() {
+            info!("ignoring vmInstanceBooted() as no ISecretkeeper");
+            return Ok(());
+        }
+        // Look up the package without holding the lock, as it is a call into a different process.
+        let package_name = get_package_name(uid);
+
+        let state = &mut *self.state.lock().unwrap();
+        if let Some(sk_state) = &mut state.sk_state {
+            let user_id = multiuser_get_user_id(uid);
+            let app_id = multiuser_get_app_id(uid);
+            if let Err(e) = sk_state.record_boot(
+                instance_id,
+                user_id,
+                app_id,
+                name,
+                package_name.as_deref(),
+                updatable,
+            ) {
+                error!("Failed to record the boot of the instance_id: {e:?}");
+            }
+        }
+        Ok(())
+    }
+
+    fn createTapInterface(
+        &self,
+        iface_name_suffix: &str,
+        config: &NetworkInterface,
+    ) -> binder::Result<ParcelFileDescriptor> {
         check_internet_permission()?;
         check_use_custom_virtual_machine()?;
         if !cfg!(network) {
@@ -527,15 +649,18 @@ impl IVirtualizationServiceInternal for VirtualizationServiceInternal {
             ))
             .with_log();
         }
-        // TODO(340377643): Use iface_name_suffix after introducing bridge interface, not fixed
-        // value.
-        let tap_fd = NETWORK_SERVICE.createTapInterface("fixed")?;
-
-        // TODO(340377643): Due to lack of implementation of creating bridge interface, tethering is
-        // enabled for TAP interface instead of bridge interface. After introducing creation of
-        // bridge interface in AVF, we should modify it.
-        TETHERING_SERVICE.enableVmTethering()?;
-
+        let tap_fd = NETWORK_SERVICE.createTapInterface(iface_name_suffix, config)?;
+        if config.mode == NetworkMode::NAT {
+            let mut nat_interfaces = self.nat_interfaces.lock().unwrap();
+            if *nat_interfaces == 0 {
+                if let Err(e) = TETHERING_SERVICE.enableVmTethering() {
+                    drop(nat_interfaces);
+                    NETWORK_SERVICE.deleteTapInterface(&tap_fd)?;
+                    return Err(e);
+                }
+            }
+            *nat_interfaces += 1;
+        }
         Ok(tap_fd)
     }
 
@@ -550,10 +675,14 @@ impl IVirtualizationServiceInternal for VirtualizationServiceInternal {
             .with_log();
         }
 
-        // TODO(340377643): Disabling tethering should be for bridge interface, not TAP interface.
-        TETHERING_SERVICE.disableVmTethering()?;
-
-        NETWORK_SERVICE.deleteTapInterface(tap_fd)
+        if NETWORK_SERVICE.deleteTapInterface(tap_fd)? == NetworkMode::NAT {
+            let mut nat_interfaces = self.nat_interfaces.lock().unwrap();
+            *nat_interfaces = nat_interfaces.saturating_sub(1);
+            if *nat_interfaces == 0 {
+                TETHERING_SERVICE.disableVmTethering()?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -595,6 +724,26 @@ impl IVirtualizationMaintenance for VirtualizationServiceInternal {
     }
 }
 
+/// Return the name of the package with the given UID, if the package manager knows of one.
+fn get_package_name(uid: uid_t) -> Option<String> {
+    let pm = match binder::check_interface::<dyn IPackageManagerNative>(
+        PACKAGE_MANAGER_NATIVE_SERVICE,
+    ) {
+        Ok(pm) => pm,
+        Err(e) => {
+            warn!("Failed to connect to {PACKAGE_MANAGER_NATIVE_SERVICE}: {e:?}");
+            return None;
+        }
+    };
+    match pm.getNamesForUids(&[uid as i32]) {
+        Ok(names) => names.into_iter().next().filter(|name| !name.is_empty()),
+        Err(e) => {
+            warn!("Failed to get the package name of uid {uid}: {e:?}");
+            None
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct Device {
     dtbo_label: String,
@@ -666,6 +815,14 @@ struct GlobalVmInstance {
     requester_debug_pid: pid_t,
     /// Name of the host console.
     host_console_name: Option<String>,
+    /// Weak reference to the handle of the VM, set by its virtmgr once the VM is created.
+    vm: Option<binder::Weak<dyn IVirtualMachine>>,
+    /// Name of the VM, as reported by its virtmgr.
+    name: String,
+    /// Memory size of the VM in MiB, as reported by its virtmgr.
+    memory_mib: i32,
+    /// Whether the VM is a protected VM, as reported by its virtmgr.
+    protected: bool,
 }
 
 impl GlobalVmInstance {
@@ -867,6 +1024,21 @@ impl IGlobalVmContext for GlobalVmContext {
         self.instance.lock().unwrap().host_console_name = Some(pathname.to_string());
         Ok(())
     }
+
+    fn setVmInfo(&self, name: &str, memory_mib: i32, protected_vm: bool) -> binder::Result<()> {
+        let instance = &mut *self.instance.lock().unwrap();
+        instance.name = name.to_string();
+        instance.memory_mib = memory_mib;
+        instance.protected = protected_vm;
+        Ok(())
+    }
+
+    fn setVirtualMachine(&self, vm: &Strong<dyn IVirtualMachine>) -> binder::Result<()> {
+        // Only keep a weak reference, otherwise the VM would be kept alive by this service after
+        // its owner has dropped it.
+        self.instance.lock().unwrap().vm = Some(Strong::downgrade(vm));
+        Ok(())
+    }
 }
 
 fn handle_stream_connection_tombstoned() -> Result<()> {
@@ -22,6 +22,7 @@ use log::{error, info, warn};
 use virtualizationmaintenance::IVirtualizationReconciliationCallback::IVirtualizationReconciliationCallback;
 
 mod vmdb;
+pub use vmdb::VmIdInfo;
 use vmdb::{VmId, VmIdDb};
 
 /// Interface name for the Secretkeeper HAL.
@@ -80,6 +81,30 @@ impl State {
         self.get_inner()?.add_id(vm_id, user_id, app_id)
     }
 
+    /// Record a VM ID as claimed by `(user_id, app_id)`, replacing any existing owner, and as
+    /// used now.
+    pub fn claim_id(&mut self, vm_id: &VmId, user_id: u32, app_id: u32) -> Result<()> {
+        self.get_inner()?.claim_id(vm_id, user_id, app_id)
+    }
+
+    /// Record that the VM with the given VM ID, owned by `(user_id, app_id)`, has booted.
+    pub fn record_boot(
+        &mut self,
+        vm_id: &VmId,
+        user_id: u32,
+        app_id: u32,
+        vm_name: &str,
+        package_name: Option<&str>,
+        updatable: bool,
+    ) -> Result<()> {
+        self.get_inner()?.record_boot(vm_id, user_id, app_id, vm_name, package_name, updatable)
+    }
+
+    /// Return what is recorded about every VM ID, grouped by owner and most recently used first.
+    pub fn vm_id_infos(&mut self) -> Result<Vec<VmIdInfo>> {
+        self.get_inner()?.vm_id_db.all_vm_id_infos()
+    }
+
     /// Delete the VM IDs associated with Android user ID `user_id`.
     pub fn delete_ids_for_user(&mut self, user_id: i32) -> Result<()> {
         self.get_inner()?.delete_ids_for_user(user_id)
@@ -158,6 +183,38 @@ impl InnerState {
         self.vm_id_db.add_vm_id(vm_id, user_id, app_id)
     }
 
+    fn claim_id(&mut self, vm_id: &VmId, user_id: u32, app_id: u32) -> Result<()> {
+        self.add_id(vm_id, user_id, app_id)?;
+        // The IDs were already range checked by `add_id`.
+        self.vm_id_db.mark_vm_id_used(vm_id, user_id as i32, app_id as i32)?;
+        Ok(())
+    }
+
+    fn record_boot(
+        &mut self,
+        vm_id: &VmId,
+        user_id: u32,
+        app_id: u32,
+        vm_name: &str,
+        package_name: Option<&str>,
+        updatable: bool,
+    ) -> Result<()> {
+        let user_id: i32 = user_id.try_into().context(format!("user_id {user_id} out of range"))?;
+        let app_id: i32 = app_id.try_into().context(format!("app_id {app_id} out of range"))?;
+        let recorded = self.vm_id_db.record_vm_boot(
+            vm_id,
+            user_id,
+            app_id,
+            vm_name,
+            package_name,
+            updatable,
+        )?;
+        if !recorded {
+            info!("record_boot - VM id not associated with user_id={user_id}, app_id={app_id}");
+        }
+        Ok(())
+    }
+
     fn delete_id_for_app(&mut self, vm_id: &VmId, user_id: u32, app_id: u32) {
         if !self.vm_id_db.is_vm_id_for_app(vm_id, user_id, app_id).unwrap_or(false) {
             info!(
@@ -535,6 +592,29 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_sk_state_claim_and_boot() {
+        let history = Arc::new(Mutex::new(Vec::new()));
+        let mut sk_state = new_test_state(history.clone(), 20);
+
+        get_db(&mut sk_state).add_vm_id(&VM_ID1, USER1, APP_A).unwrap();
+        get_db(&mut sk_state).add_vm_id(&VM_ID2, USER1, APP_A).unwrap();
+
+        // Booting a VM owned by another app records nothing.
+        sk_state.record_boot(&VM_ID1, USER1 as u32, APP_B as u32, "vm", None, true).unwrap();
+        sk_state.record_boot(&VM_ID2, USER1 as u32, APP_A as u32, "vm2", None, true).unwrap();
+        let infos = sk_state.vm_id_infos().unwrap();
+        assert_eq!(vec![VM_ID2, VM_ID1], infos.iter().map(|i| i.vm_id).collect::<Vec<_>>());
+        assert_eq!(None, infos[1].vm_name);
+        assert_eq!(Some("vm2".to_string()), infos[0].vm_name);
+
+        sk_state.claim_id(&VM_ID1, USER2 as u32, APP_B as u32).unwrap();
+        let infos = sk_state.vm_id_infos().unwrap();
+        assert_eq!((USER2, APP_B), (infos[1].user_id, infos[1].app_id));
+        assert!(infos[1].last_used.is_some());
+        assert_eq!((*history.lock().unwrap()).clone(), vec![]);
+    }
+
     struct Irreconcilable;
 
     impl IVirtualizationReconciliationCallback for Irreconcilable {
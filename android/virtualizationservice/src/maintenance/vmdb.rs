@@ -16,7 +16,7 @@
 
 use anyhow::{anyhow, Context, Result};
 use log::{debug, error, info, warn};
-use rusqlite::{params, params_from_iter, Connection, OpenFlags, Rows};
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, Row, Rows};
 use std::path::PathBuf;
 
 /// Subdirectory to hold the database.
@@ -41,6 +41,25 @@ fn db_now() -> u64 {
 /// Identifier for a VM and its corresponding secret.
 pub type VmId = [u8; 64];
 
+/// What is recorded about a VM ID. Fields are `None` if they were never recorded, e.g. for rows left
+/// over from an older database, or for VMs which haven't booted since being claimed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VmIdInfo {
+    pub vm_id: VmId,
+    pub user_id: i32,
+    pub app_id: i32,
+    /// When the VM ID was added, in milliseconds since epoch.
+    pub created: Option<u64>,
+    /// When the VM last booted or was claimed, in milliseconds since epoch.
+    pub last_used: Option<u64>,
+    /// The name of the VM when it last booted.
+    pub vm_name: Option<String>,
+    /// The package of the owner of the VM when it last booted.
+    pub package_name: Option<String>,
+    /// Whether the VM was an updatable VM when it last booted.
+    pub updatable: Option<bool>,
+}
+
 /// Representation of an on-disk database of VM IDs.
 pub struct VmIdDb {
     conn: Connection,
@@ -106,8 +125,12 @@ impl VmIdDb {
                         return Err(anyhow!("failed to reset database file {db_path:?}"));
                     }
                 }
-                Ok(0) => db.upgrade_tables_v0_v1().context("failed to upgrade schema v0 -> v1")?,
-                Ok(1) => {
+                Ok(0) => {
+                    db.upgrade_tables_v0_v1().context("failed to upgrade schema v0 -> v1")?;
+                    db.upgrade_tables_v1_v2().context("failed to upgrade schema v1 -> v2")?;
+                }
+                Ok(1) => db.upgrade_tables_v1_v2().context("failed to upgrade schema v1 -> v2")?,
+                Ok(2) => {
                     // Current version, no action needed.
                 }
                 Ok(version) => {
@@ -145,10 +168,44 @@ impl VmIdDb {
 
     /// Create the database table and indices using the current schema.
     fn init_tables(&mut self) -> Result<()> {
-        self.init_tables_v1()
+        self.init_tables_v2()
+    }
+
+    /// Create the database table and indices using the v2 schema.
+    fn init_tables_v2(&mut self) -> Result<()> {
+        info!("creating v2 database schema");
+        self.conn
+            .execute(
+                "CREATE TABLE IF NOT EXISTS main.vmids (
+                     vm_id BLOB PRIMARY KEY,
+                     user_id INTEGER,
+                     app_id INTEGER,
+                     created INTEGER,
+                     last_used INTEGER,
+                     vm_name TEXT,
+                     package_name TEXT,
+                     updatable INTEGER
+                 ) WITHOUT ROWID;",
+                (),
+            )
+            .context("failed to create table")?;
+        self.conn
+            .execute("CREATE INDEX IF NOT EXISTS main.vmids_user_index ON vmids(user_id);", [])
+            .context("Failed to create user index")?;
+        self.conn
+            .execute(
+                "CREATE INDEX IF NOT EXISTS main.vmids_app_index ON vmids(user_id, app_id);",
+                [],
+            )
+            .context("Failed to create app index")?;
+        self.conn
+            .execute("PRAGMA main.user_version = 2;", ())
+            .context("failed to declare version")?;
+        Ok(())
     }
 
     /// Create the database table and indices using the v1 schema.
+    #[cfg(test)]
     fn init_tables_v1(&mut self) -> Result<()> {
         info!("creating v1 database schema");
         self.conn
@@ -188,6 +245,21 @@ impl VmIdDb {
         Ok(())
     }
 
+    fn upgrade_tables_v1_v2(&mut self) -> Result<()> {
+        // If any step fails, the database is left at v1 rather than with only some of the columns.
+        let tx = self.conn.transaction().context("failed to start transaction")?;
+        for column in
+            ["last_used INTEGER", "vm_name TEXT", "package_name TEXT", "updatable INTEGER"]
+        {
+            let _rows = tx
+                .execute(&format!("ALTER TABLE main.vmids ADD COLUMN {column};"), ())
+                .context("failed to alter table v1->v2")?;
+        }
+        tx.execute("PRAGMA main.user_version = 2;", ()).context("failed to set schema version")?;
+        tx.commit().context("failed to commit upgrade v1->v2")?;
+        Ok(())
+    }
+
     /// Create the database table and indices using the v0 schema.
     #[cfg(test)]
     fn init_tables_v0(&mut self) -> Result<()> {
@@ -227,6 +299,46 @@ impl VmIdDb {
         Ok(())
     }
 
+    /// Record that the given VM ID, if associated with `(user_id, app_id)`, has been used now.
+    /// Returns false if there is no such VM ID, or it exists but is not associated.
+    pub fn mark_vm_id_used(&mut self, vm_id: &VmId, user_id: i32, app_id: i32) -> Result<bool> {
+        let now = db_now();
+        let rows = self
+            .conn
+            .execute(
+                "UPDATE main.vmids SET last_used = ?1 \
+                        WHERE vm_id = ?2 AND user_id = ?3 AND app_id = ?4;",
+                params![&now, vm_id, &user_id, &app_id],
+            )
+            .context("failed to mark VM ID as used")?;
+        Ok(rows != 0)
+    }
+
+    /// Record that the VM with the given VM ID, if associated with `(user_id, app_id)`, has booted
+    /// now, and what it was. Returns false if there is no such VM ID, or it exists but is not
+    /// associated.
+    pub fn record_vm_boot(
+        &mut self,
+        vm_id: &VmId,
+        user_id: i32,
+        app_id: i32,
+        vm_name: &str,
+        package_name: Option<&str>,
+        updatable: bool,
+    ) -> Result<bool> {
+        let now = db_now();
+        let rows = self
+            .conn
+            .execute(
+                "UPDATE main.vmids \
+                        SET last_used = ?1, vm_name = ?2, package_name = ?3, updatable = ?4 \
+                        WHERE vm_id = ?5 AND user_id = ?6 AND app_id = ?7;",
+                params![&now, vm_name, package_name, updatable, vm_id, &user_id, &app_id],
+            )
+            .context("failed to record VM boot")?;
+        Ok(rows != 0)
+    }
+
     /// Remove the given VM IDs from the database.  The collection of IDs is assumed to be smaller
     /// than the maximum number of SQLite parameters.
     pub fn delete_vm_ids(&mut self, vm_ids: &[VmId]) -> Result<()> {
@@ -315,6 +427,42 @@ impl VmIdDb {
         Self::vm_ids_from_rows(rows)
     }
 
+    /// Return everything recorded about all of the VM IDs, ordered by `(user_id, app_id)` and then
+    /// with the most recently used first. VM IDs which were never used come last.
+    pub fn all_vm_id_infos(&mut self) -> Result<Vec<VmIdInfo>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT vm_id, user_id, app_id, created, last_used, vm_name, package_name, \
+                        updatable FROM main.vmids \
+                        ORDER BY user_id, app_id, last_used IS NULL, last_used DESC;",
+            )
+            .context("failed to prepare SELECT stmt")?;
+        let mut rows = stmt.query(()).context("query failed")?;
+        let mut infos = Vec::new();
+        while let Some(row) = rows.next().context("failed row unpack")? {
+            match Self::vm_id_info_from_row(row) {
+                Ok(info) => infos.push(info),
+                Err(e) => error!("failed to parse row: {e:?}"),
+            }
+        }
+
+        Ok(infos)
+    }
+
+    fn vm_id_info_from_row(row: &Row) -> rusqlite::Result<VmIdInfo> {
+        Ok(VmIdInfo {
+            vm_id: row.get(0)?,
+            user_id: row.get(1)?,
+            app_id: row.get(2)?,
+            created: row.get(3)?,
+            last_used: row.get(4)?,
+            vm_name: row.get(5)?,
+            package_name: row.get(6)?,
+            updatable: row.get(7)?,
+        })
+    }
+
     /// Return all of the `(user_id, app_id)` pairs present in the database.
     pub fn get_all_owners(&mut self) -> Result<Vec<(i32, i32)>> {
         let mut stmt = self
@@ -347,7 +495,7 @@ impl VmIdDb {
 
 /// Current schema version.
 #[cfg(test)]
-const SCHEMA_VERSION: usize = 1;
+const SCHEMA_VERSION: usize = 2;
 
 /// Create a new in-memory database for testing.
 #[cfg(test)]
@@ -380,6 +528,7 @@ mod tests {
         match version {
             0 => db.init_tables_v0().unwrap(),
             1 => db.init_tables_v1().unwrap(),
+            2 => db.init_tables_v2().unwrap(),
             _ => panic!("unexpected version {version}"),
         }
         db
@@ -422,6 +571,14 @@ mod tests {
         assert_eq!(1, version);
     }
 
+    #[test]
+    fn test_schema_version2() {
+        let mut db2 = VmIdDb { conn: Connection::open_in_memory().unwrap() };
+        db2.init_tables_v2().unwrap();
+        let version = db2.schema_version().unwrap();
+        assert_eq!(2, version);
+    }
+
     #[test]
     fn test_schema_upgrade_v0_v1() {
         let mut db = new_test_db_version(0);
@@ -444,6 +601,63 @@ mod tests {
         show_contents(&db);
     }
 
+    #[test]
+    fn test_schema_upgrade_v1_v2() {
+        let mut db = new_test_db_version(1);
+        let version = db.schema_version().unwrap();
+        assert_eq!(1, version);
+
+        db.add_vm_id(&VM_ID1, USER1, APP_A).unwrap();
+
+        db.upgrade_tables_v1_v2().unwrap();
+        let version = db.schema_version().unwrap();
+        assert_eq!(2, version);
+
+        // The existing row is kept, with nothing recorded about its use.
+        let infos = db.all_vm_id_infos().unwrap();
+        assert_eq!(1, infos.len());
+        assert_eq!(VM_ID1, infos[0].vm_id);
+        assert!(infos[0].created.is_some());
+        assert_eq!(None, infos[0].last_used);
+        assert_eq!(None, infos[0].vm_name);
+
+        assert!(db.record_vm_boot(&VM_ID1, USER1, APP_A, "vm", None, true).unwrap());
+        show_contents(&db);
+    }
+
+    #[test]
+    fn test_failed_schema_upgrade_v1_v2_is_rolled_back() {
+        let mut db = new_test_db_version(1);
+        // A column added by the upgrade already exists, so adding it fails halfway through.
+        db.conn.execute("ALTER TABLE main.vmids ADD COLUMN package_name TEXT;", ()).unwrap();
+
+        assert!(db.upgrade_tables_v1_v2().is_err());
+        assert_eq!(1, db.schema_version().unwrap());
+        // The columns added before the failure are gone.
+        db.conn
+            .execute("ALTER TABLE main.vmids ADD COLUMN last_used INTEGER;", ())
+            .expect("last_used column left over from failed upgrade");
+    }
+
+    #[test]
+    fn test_upgrade_database_file_v1() {
+        let db_dir = tempfile::Builder::new().prefix("vmdb-test-").tempdir().unwrap();
+        let mut db_path = db_dir.path().to_owned();
+        db_path.push(DB_DIR);
+        std::fs::create_dir(&db_path).unwrap();
+        db_path.push(DB_FILENAME);
+        {
+            let mut db = VmIdDb { conn: Connection::open(&db_path).unwrap() };
+            db.init_tables_v1().unwrap();
+            db.add_vm_id(&VM_ID1, USER1, APP_A).unwrap();
+        }
+
+        let (mut db, created) = VmIdDb::new(&db_dir.path().to_string_lossy()).unwrap();
+        assert!(!created);
+        assert_eq!(2, db.schema_version().unwrap());
+        assert_eq!(vec![VM_ID1], db.vm_ids_for_user(USER1).unwrap());
+    }
+
     #[test]
     fn test_corrupt_database_file() {
         let db_dir = tempfile::Builder::new().prefix("vmdb-test-").tempdir().unwrap();
@@ -563,6 +777,57 @@ mod tests {
         show_contents(&db);
     }
 
+    #[test]
+    fn test_record_use() {
+        let mut db = new_test_db();
+        db.add_vm_id(&VM_ID1, USER1, APP_A).unwrap();
+        db.add_vm_id(&VM_ID2, USER1, APP_A).unwrap();
+        db.add_vm_id(&VM_ID3, USER1, APP_A).unwrap();
+        db.add_vm_id(&VM_ID4, USER2, APP_B).unwrap();
+
+        // Only the owner of a VM ID can record its use.
+        assert!(!db.mark_vm_id_used(&VM_ID1, USER2, APP_A).unwrap());
+        assert!(!db.mark_vm_id_used(&VM_ID_UNKNOWN, USER1, APP_A).unwrap());
+        assert!(!db.record_vm_boot(&VM_ID4, USER1, APP_A, "vm", None, false).unwrap());
+
+        assert!(db.mark_vm_id_used(&VM_ID3, USER1, APP_A).unwrap());
+        assert!(db
+            .record_vm_boot(&VM_ID1, USER1, APP_A, "vm1", Some("com.example.a"), true)
+            .unwrap());
+        // Make VM_ID1 the most recently used, whatever the resolution of the clock.
+        db.conn
+            .execute(
+                "UPDATE main.vmids SET last_used = last_used + 1000 WHERE vm_id = ?1;",
+                params![&VM_ID1],
+            )
+            .unwrap();
+        show_contents(&db);
+
+        let infos = db.all_vm_id_infos().unwrap();
+        let ids: Vec<VmId> = infos.iter().map(|info| info.vm_id).collect();
+        assert_eq!(vec![VM_ID1, VM_ID3, VM_ID2, VM_ID4], ids);
+
+        assert_eq!((USER1, APP_A), (infos[0].user_id, infos[0].app_id));
+        assert_eq!(Some("vm1".to_string()), infos[0].vm_name);
+        assert_eq!(Some("com.example.a".to_string()), infos[0].package_name);
+        assert_eq!(Some(true), infos[0].updatable);
+        assert!(infos[0].last_used.is_some());
+
+        assert!(infos[1].last_used.is_some());
+        assert_eq!(None, infos[1].vm_name);
+        assert_eq!(None, infos[1].updatable);
+
+        assert_eq!(None, infos[2].last_used);
+        assert_eq!((USER2, APP_B), (infos[3].user_id, infos[3].app_id));
+
+        // Re-adding a VM ID, e.g. when claimed by a new owner, forgets about its previous use.
+        db.add_vm_id(&VM_ID1, USER2, APP_B).unwrap();
+        let infos = db.all_vm_id_infos().unwrap();
+        let info = infos.iter().find(|info| info.vm_id == VM_ID1).unwrap();
+        assert_eq!(None, info.last_used);
+        assert_eq!(None, info.vm_name);
+    }
+
     #[test]
     fn test_invalid_vm_id() {
         let mut db = new_test_db();
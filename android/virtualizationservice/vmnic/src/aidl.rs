@@ -14,18 +14,28 @@
 
 //! Implementation of the AIDL interface of Vmnic.
 
-use anyhow::{anyhow, Context, Result};
+use crate::bridge::{
+    attach_interface, create_bridge, delete_bridge, detach_interface, set_mtu, Ipv4Network,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::NetworkInterface::{
+    Mode::Mode, NetworkInterface,
+};
 use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IVmnic::IVmnic;
-use binder::{self, Interface, IntoBinderResult, ParcelFileDescriptor};
+use binder::{self, ExceptionCode, Interface, IntoBinderResult, ParcelFileDescriptor};
+use cstr::cstr;
 use libc::{c_char, c_int, c_short, ifreq, IFF_NO_PI, IFF_TAP, IFF_UP, IFF_VNET_HDR, IFNAMSIZ};
-use log::info;
+use log::{error, info};
 use nix::ioctl_write_ptr_bad;
 use nix::sys::ioctl::ioctl_num_type;
 use nix::sys::socket::{socket, AddressFamily, SockFlag, SockType};
+use std::collections::HashMap;
 use std::ffi::{CStr, CString};
 use std::fs::OpenOptions;
+use std::net::Ipv4Addr;
 use std::os::fd::{AsRawFd, RawFd};
 use std::slice::from_raw_parts;
+use std::sync::Mutex;
 
 const TUNGETIFF: ioctl_num_type = 0x800454d2u32 as ioctl_num_type;
 const TUNSETIFF: ioctl_num_type = 0x400454ca;
@@ -35,8 +45,27 @@ ioctl_write_ptr_bad!(ioctl_tungetiff, TUNGETIFF, ifreq);
 ioctl_write_ptr_bad!(ioctl_tunsetiff, TUNSETIFF, ifreq);
 ioctl_write_ptr_bad!(ioctl_siocsifflags, SIOCSIFFLAGS, ifreq);
 
+/// Bridge of the TAP interfaces in NAT mode, for which VM tethering is configured.
+const NAT_BRIDGE: &CStr = cstr!("avf_v_bridge");
+/// Bridge of the TAP interfaces in host-only mode.
+const HOST_ONLY_BRIDGE: &CStr = cstr!("avf_h_bridge");
+/// Address and netmask of the host on the host-only bridge.
+const HOST_ONLY_ADDRESS: Ipv4Network =
+    (Ipv4Addr::new(192, 168, 251, 1), Ipv4Addr::new(255, 255, 255, 0));
+
+/// Returns the bridge to which the TAP interfaces in `mode` are attached, with the address of the
+/// host on it, if any.
+fn bridge_of(mode: Mode) -> Result<Option<(&'static CStr, Option<Ipv4Network>)>> {
+    match mode {
+        Mode::ISOLATED => Ok(None),
+        Mode::HOST_ONLY => Ok(Some((HOST_ONLY_BRIDGE, Some(HOST_ONLY_ADDRESS)))),
+        Mode::NAT => Ok(Some((NAT_BRIDGE, None))),
+        mode => bail!("Invalid network interface mode {mode:?}"),
+    }
+}
+
 fn validate_ifname(ifname: &[c_char]) -> Result<()> {
-    if ifname.len() >= IFNAMSIZ {
+    if ifname.len() > IFNAMSIZ {
         return Err(anyhow!(format!("Interface name is too long")));
     }
     Ok(())
@@ -74,18 +103,76 @@ fn delete_tap_interface(sockfd: c_int, ifr: &mut ifreq) -> Result<()> {
 }
 
 #[derive(Debug, Default)]
-pub struct Vmnic {}
+pub struct Vmnic {
+    /// The mode of each TAP interface created, by name. The bridge of a mode exists while there is
+    /// an interface in that mode.
+    interfaces: Mutex<HashMap<CString, Mode>>,
+}
 
 impl Vmnic {
     pub fn init() -> Vmnic {
         Vmnic::default()
     }
+
+    /// Attaches the TAP interface `ifname` to the bridge of `mode`, creating the bridge if it is
+    /// the first interface in that mode.
+    fn attach_to_bridge(&self, sockfd: RawFd, ifname: &CStr, mode: Mode) -> Result<()> {
+        let mut interfaces = self.interfaces.lock().unwrap();
+        if let Some((bridge, address)) = bridge_of(mode)? {
+            let first = !interfaces.values().any(|m| *m == mode);
+            if first {
+                create_bridge(sockfd, bridge, address)
+                    .with_context(|| format!("Failed to create bridge {bridge:?}"))?;
+                info!("Created bridge {bridge:?}");
+            }
+            if let Err(e) = attach_interface(sockfd, bridge, ifname) {
+                if first {
+                    delete_bridge(sockfd, bridge)
+                        .unwrap_or_else(|e| error!("Failed to delete bridge {bridge:?}: {e:?}"));
+                }
+                return Err(e).with_context(|| format!("Failed to attach to bridge {bridge:?}"));
+            }
+        }
+        interfaces.insert(ifname.to_owned(), mode);
+        Ok(())
+    }
+
+    /// Detaches the TAP interface `ifname` from the bridge of its mode, deleting the bridge if it
+    /// was the last interface in that mode. Returns the mode.
+    fn detach_from_bridge(&self, sockfd: RawFd, ifname: &CStr) -> Result<Mode> {
+        let mut interfaces = self.interfaces.lock().unwrap();
+        let mode = interfaces.remove(ifname).context("TAP interface wasn't created by Vmnic")?;
+        if let Some((bridge, _)) = bridge_of(mode)? {
+            detach_interface(sockfd, bridge, ifname)
+                .with_context(|| format!("Failed to detach from bridge {bridge:?}"))?;
+            if !interfaces.values().any(|m| *m == mode) {
+                delete_bridge(sockfd, bridge)
+                    .with_context(|| format!("Failed to delete bridge {bridge:?}"))?;
+                info!("Deleted bridge {bridge:?}");
+            }
+        }
+        Ok(mode)
+    }
 }
 
 impl Interface for Vmnic {}
 
 impl IVmnic for Vmnic {
-    fn createTapInterface(&self, iface_name_suffix: &str) -> binder::Result<ParcelFileDescriptor> {
+    fn createTapInterface(
+        &self,
+        iface_name_suffix: &str,
+        config: &NetworkInterface,
+    ) -> binder::Result<ParcelFileDescriptor> {
+        if !iface_name_suffix.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(anyhow!("Invalid interface name suffix: {iface_name_suffix:?}"))
+                .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+        }
+        if config.mtu < 0 {
+            return Err(anyhow!("Invalid MTU: {}", config.mtu))
+                .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+        }
+        bridge_of(config.mode).or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+
         let ifname = CString::new(format!("avf_tap_{iface_name_suffix}"))
             .context(format!(
                 "Failed to construct TAP interface name as CString: avf_tap_{iface_name_suffix}"
@@ -111,12 +198,20 @@ impl IVmnic for Vmnic {
         create_tap_interface(tunfd.as_raw_fd(), sock.as_raw_fd(), ifname_bytes)
             .context(format!("Failed to create TAP interface: {ifname:#?}"))
             .or_service_specific_exception(-1)?;
+        if config.mtu > 0 {
+            set_mtu(sock.as_raw_fd(), &ifname, config.mtu)
+                .context(format!("Failed to set MTU of TAP interface: {ifname:#?}"))
+                .or_service_specific_exception(-1)?;
+        }
+        self.attach_to_bridge(sock.as_raw_fd(), &ifname, config.mode)
+            .context(format!("Failed to attach TAP interface: {ifname:#?}"))
+            .or_service_specific_exception(-1)?;
 
-        info!("Created TAP network interface: {ifname:#?}");
+        info!("Created TAP network interface: {ifname:#?} in mode {:?}", config.mode);
         Ok(ParcelFileDescriptor::new(tunfd))
     }
 
-    fn deleteTapInterface(&self, tapfd: &ParcelFileDescriptor) -> binder::Result<()> {
+    fn deleteTapInterface(&self, tapfd: &ParcelFileDescriptor) -> binder::Result<Mode> {
         let mut tap_ifreq = get_tap_ifreq(tapfd.as_raw_fd())
             .context("Failed to get ifreq of TAP interface")
             .or_service_specific_exception(-1)?;
@@ -130,8 +225,94 @@ impl IVmnic for Vmnic {
         delete_tap_interface(sock.as_raw_fd(), &mut tap_ifreq)
             .context(format!("Failed to create TAP interface: {ifname:#?}"))
             .or_service_specific_exception(-1)?;
+        let mode = self
+            .detach_from_bridge(sock.as_raw_fd(), ifname)
+            .context(format!("Failed to detach TAP interface: {ifname:#?}"))
+            .or_service_specific_exception(-1)?;
 
         info!("Deleted TAP network interface: {ifname:#?}");
-        Ok(())
+        Ok(mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::net::if_::if_nametoindex;
+    use nix::sched::{unshare, CloneFlags};
+    use std::fs::File;
+
+    /// Moves the test thread to a new network namespace, so that interfaces are created without
+    /// affecting the host. This needs CAP_SYS_ADMIN, which the test has as it runs as root.
+    fn enter_new_network_namespace() {
+        unshare(CloneFlags::CLONE_NEWNET).expect("Failed to create a network namespace");
+    }
+
+    fn create_tap(sockfd: RawFd, ifname: &CStr) -> File {
+        let tunfd = ["/dev/tun", "/dev/net/tun"]
+            .iter()
+            .find_map(|path| OpenOptions::new().read(true).write(true).open(path).ok())
+            .expect("No TUN device");
+        let ifname = ifname.to_bytes_with_nul();
+        // SAFETY: Converting from &[u8] into &[c_char].
+        let ifname = unsafe { from_raw_parts(ifname.as_ptr().cast::<c_char>(), ifname.len()) };
+        create_tap_interface(tunfd.as_raw_fd(), sockfd, ifname).unwrap();
+        tunfd
+    }
+
+    fn exists(ifname: &CStr) -> bool {
+        if_nametoindex(ifname).is_ok()
+    }
+
+    #[test]
+    fn bridges_exist_while_interfaces_are_attached() {
+        enter_new_network_namespace();
+        let sock = socket(AddressFamily::Inet, SockType::Datagram, SockFlag::empty(), None)
+            .expect("Failed to create socket");
+        let sockfd = sock.as_raw_fd();
+        let vmnic = Vmnic::init();
+        let interfaces = [
+            (cstr!("avf_tap_test_0"), Mode::NAT),
+            (cstr!("avf_tap_test_1"), Mode::NAT),
+            (cstr!("avf_tap_test_2"), Mode::HOST_ONLY),
+            (cstr!("avf_tap_test_3"), Mode::ISOLATED),
+        ];
+        let _taps: Vec<_> = interfaces
+            .iter()
+            .map(|(ifname, mode)| {
+                let tap = create_tap(sockfd, ifname);
+                vmnic.attach_to_bridge(sockfd, ifname, *mode).unwrap();
+                tap
+            })
+            .collect();
+        assert!(exists(NAT_BRIDGE));
+        assert!(exists(HOST_ONLY_BRIDGE));
+
+        assert_eq!(vmnic.detach_from_bridge(sockfd, interfaces[0].0).unwrap(), Mode::NAT);
+        assert!(exists(NAT_BRIDGE));
+        assert_eq!(vmnic.detach_from_bridge(sockfd, interfaces[1].0).unwrap(), Mode::NAT);
+        assert!(!exists(NAT_BRIDGE));
+        assert_eq!(vmnic.detach_from_bridge(sockfd, interfaces[2].0).unwrap(), Mode::HOST_ONLY);
+        assert!(!exists(HOST_ONLY_BRIDGE));
+        assert_eq!(vmnic.detach_from_bridge(sockfd, interfaces[3].0).unwrap(), Mode::ISOLATED);
+        assert!(vmnic.detach_from_bridge(sockfd, interfaces[3].0).is_err());
+    }
+
+    #[test]
+    fn interface_is_attached_to_one_bridge() {
+        enter_new_network_namespace();
+        let sock = socket(AddressFamily::Inet, SockType::Datagram, SockFlag::empty(), None)
+            .expect("Failed to create socket");
+        let sockfd = sock.as_raw_fd();
+        let vmnic = Vmnic::init();
+        let ifname = cstr!("avf_tap_test_0");
+        let _tap = create_tap(sockfd, ifname);
+        vmnic.attach_to_bridge(sockfd, ifname, Mode::NAT).unwrap();
+
+        // The interface is already attached to the NAT bridge, and the host-only bridge isn't
+        // left behind.
+        assert!(vmnic.attach_to_bridge(sockfd, ifname, Mode::HOST_ONLY).is_err());
+        assert!(!exists(HOST_ONLY_BRIDGE));
+        assert!(vmnic.attach_to_bridge(sockfd, ifname, Mode(42)).is_err());
     }
 }
@@ -0,0 +1,153 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Setup and teardown of the network bridges to which TAP interfaces of VMs are attached.
+
+use anyhow::{ensure, Context, Result};
+use libc::{c_char, c_short, ifreq, sockaddr, sockaddr_in, AF_INET, IFF_UP, IFNAMSIZ};
+use nix::errno::Errno;
+use nix::sys::ioctl::ioctl_num_type;
+use nix::{ioctl_read_bad, ioctl_write_ptr_bad};
+use std::ffi::CStr;
+use std::net::Ipv4Addr;
+use std::os::fd::RawFd;
+
+const SIOCGIFFLAGS: ioctl_num_type = 0x00008913;
+const SIOCSIFFLAGS: ioctl_num_type = 0x00008914;
+const SIOCSIFADDR: ioctl_num_type = 0x00008916;
+const SIOCSIFNETMASK: ioctl_num_type = 0x0000891c;
+const SIOCSIFMTU: ioctl_num_type = 0x00008922;
+const SIOCGIFINDEX: ioctl_num_type = 0x00008933;
+const SIOCBRADDBR: ioctl_num_type = 0x000089a0;
+const SIOCBRDELBR: ioctl_num_type = 0x000089a1;
+const SIOCBRADDIF: ioctl_num_type = 0x000089a2;
+const SIOCBRDELIF: ioctl_num_type = 0x000089a3;
+
+ioctl_read_bad!(ioctl_siocgifflags, SIOCGIFFLAGS, ifreq);
+ioctl_write_ptr_bad!(ioctl_siocsifflags, SIOCSIFFLAGS, ifreq);
+ioctl_write_ptr_bad!(ioctl_siocsifaddr, SIOCSIFADDR, ifreq);
+ioctl_write_ptr_bad!(ioctl_siocsifnetmask, SIOCSIFNETMASK, ifreq);
+ioctl_write_ptr_bad!(ioctl_siocsifmtu, SIOCSIFMTU, ifreq);
+ioctl_read_bad!(ioctl_siocgifindex, SIOCGIFINDEX, ifreq);
+ioctl_write_ptr_bad!(ioctl_siocbraddbr, SIOCBRADDBR, c_char);
+ioctl_write_ptr_bad!(ioctl_siocbrdelbr, SIOCBRDELBR, c_char);
+ioctl_write_ptr_bad!(ioctl_siocbraddif, SIOCBRADDIF, ifreq);
+ioctl_write_ptr_bad!(ioctl_siocbrdelif, SIOCBRDELIF, ifreq);
+
+/// Address and netmask of an interface.
+pub type Ipv4Network = (Ipv4Addr, Ipv4Addr);
+
+/// Returns an `ifreq` for the interface `ifname`, with an all-zero request.
+fn new_ifreq(ifname: &CStr) -> Result<ifreq> {
+    let ifname = ifname.to_bytes_with_nul();
+    ensure!(ifname.len() <= IFNAMSIZ, "Interface name is too long: {ifname:?}");
+    // SAFETY: All-zero is a valid value for the ifreq type.
+    let mut ifr: ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(ifname) {
+        *dst = *src as c_char;
+    }
+    Ok(ifr)
+}
+
+/// Brings the interface `ifname` up or down.
+fn set_up(sockfd: RawFd, ifname: &CStr, up: bool) -> Result<()> {
+    let mut ifr = new_ifreq(ifname)?;
+    // SAFETY: The kernel only writes the flags of the interface to `ifr`.
+    unsafe { ioctl_siocgifflags(sockfd, &mut ifr) }.context("Failed to ioctl SIOCGIFFLAGS")?;
+    // SAFETY: After calling SIOCGIFFLAGS, ifr_ifru holds ifru_flags in its union field.
+    unsafe {
+        if up {
+            ifr.ifr_ifru.ifru_flags |= IFF_UP as c_short;
+        } else {
+            ifr.ifr_ifru.ifru_flags &= !IFF_UP as c_short;
+        }
+    }
+    // SAFETY: It modifies the state in the kernel, not the state of this process in any way.
+    unsafe { ioctl_siocsifflags(sockfd, &ifr) }.context("Failed to ioctl SIOCSIFFLAGS")?;
+    Ok(())
+}
+
+/// Sets the MTU of the interface `ifname`.
+pub fn set_mtu(sockfd: RawFd, ifname: &CStr, mtu: i32) -> Result<()> {
+    let mut ifr = new_ifreq(ifname)?;
+    ifr.ifr_ifru.ifru_mtu = mtu;
+    // SAFETY: It modifies the state in the kernel, not the state of this process in any way.
+    unsafe { ioctl_siocsifmtu(sockfd, &ifr) }.context("Failed to ioctl SIOCSIFMTU")?;
+    Ok(())
+}
+
+fn to_sockaddr(address: Ipv4Addr) -> sockaddr {
+    // SAFETY: All-zero is a valid value for the sockaddr_in type.
+    let mut addr: sockaddr_in = unsafe { std::mem::zeroed() };
+    addr.sin_family = AF_INET as _;
+    addr.sin_addr.s_addr = u32::from(address).to_be();
+    // SAFETY: sockaddr_in and sockaddr have the same size, and sockaddr is the generic type which
+    // the kernel casts back according to its family.
+    unsafe { std::mem::transmute::<sockaddr_in, sockaddr>(addr) }
+}
+
+/// Creates the bridge `name` and brings it up, with the host address `address` if given. A bridge
+/// which already exists, e.g. if Vmnic restarted while VMs were running, is reused.
+pub fn create_bridge(sockfd: RawFd, name: &CStr, address: Option<Ipv4Network>) -> Result<()> {
+    // SAFETY: The kernel only reads the NUL-terminated name of the bridge.
+    match unsafe { ioctl_siocbraddbr(sockfd, name.as_ptr()) } {
+        Ok(_) | Err(Errno::EEXIST) => {}
+        Err(e) => return Err(e).context("Failed to ioctl SIOCBRADDBR"),
+    }
+    if let Some((address, netmask)) = address {
+        let mut ifr = new_ifreq(name)?;
+        ifr.ifr_ifru.ifru_addr = to_sockaddr(address);
+        // SAFETY: It modifies the state in the kernel, not the state of this process in any way.
+        unsafe { ioctl_siocsifaddr(sockfd, &ifr) }.context("Failed to ioctl SIOCSIFADDR")?;
+        ifr.ifr_ifru.ifru_netmask = to_sockaddr(netmask);
+        // SAFETY: It modifies the state in the kernel, not the state of this process in any way.
+        unsafe { ioctl_siocsifnetmask(sockfd, &ifr) }.context("Failed to ioctl SIOCSIFNETMASK")?;
+    }
+    set_up(sockfd, name, true)
+}
+
+/// Brings the bridge `name` down and deletes it. Interfaces still attached to it are detached.
+pub fn delete_bridge(sockfd: RawFd, name: &CStr) -> Result<()> {
+    set_up(sockfd, name, false)?;
+    // SAFETY: The kernel only reads the NUL-terminated name of the bridge.
+    unsafe { ioctl_siocbrdelbr(sockfd, name.as_ptr()) }.context("Failed to ioctl SIOCBRDELBR")?;
+    Ok(())
+}
+
+fn bridge_ifreq(sockfd: RawFd, bridge: &CStr, ifname: &CStr) -> Result<ifreq> {
+    let mut ifr = new_ifreq(ifname)?;
+    // SAFETY: The kernel only writes the index of the interface to `ifr`.
+    unsafe { ioctl_siocgifindex(sockfd, &mut ifr) }.context("Failed to ioctl SIOCGIFINDEX")?;
+    // SAFETY: After calling SIOCGIFINDEX, ifr_ifru holds ifru_ifindex in its union field.
+    let ifindex = unsafe { ifr.ifr_ifru.ifru_ifindex };
+    let mut ifr = new_ifreq(bridge)?;
+    ifr.ifr_ifru.ifru_ifindex = ifindex;
+    Ok(ifr)
+}
+
+/// Attaches the interface `ifname` to the bridge `bridge`.
+pub fn attach_interface(sockfd: RawFd, bridge: &CStr, ifname: &CStr) -> Result<()> {
+    let ifr = bridge_ifreq(sockfd, bridge, ifname)?;
+    // SAFETY: It modifies the state in the kernel, not the state of this process in any way.
+    unsafe { ioctl_siocbraddif(sockfd, &ifr) }.context("Failed to ioctl SIOCBRADDIF")?;
+    Ok(())
+}
+
+/// Detaches the interface `ifname` from the bridge `bridge`.
+pub fn detach_interface(sockfd: RawFd, bridge: &CStr, ifname: &CStr) -> Result<()> {
+    let ifr = bridge_ifreq(sockfd, bridge, ifname)?;
+    // SAFETY: It modifies the state in the kernel, not the state of this process in any way.
+    unsafe { ioctl_siocbrdelif(sockfd, &ifr) }.context("Failed to ioctl SIOCBRDELIF")?;
+    Ok(())
+}
@@ -0,0 +1,28 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Commands to inspect VM config files.
+
+use anyhow::{Context, Error};
+use std::path::Path;
+use vmconfig::VmConfig;
+
+/// Print the given VM config with all the config files it extends merged in, after checking that
+/// the result is valid.
+pub fn command_config_render(path: &Path) -> Result<(), Error> {
+    let config = VmConfig::load_from_path(path)
+        .with_context(|| format!("Failed to load config file {:?}", path))?;
+    println!("{}", serde_json::to_string_pretty(&config)?);
+    Ok(())
+}
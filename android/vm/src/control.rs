@@ -0,0 +1,61 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Commands to control a VM which is already running.
+
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::IVirtualizationService::IVirtualizationService;
+use anyhow::{Context, Error};
+
+/// Stop the VM with the given CID.
+pub fn command_stop(service: &dyn IVirtualizationService, cid: i32) -> Result<(), Error> {
+    service.debugStopVm(cid).with_context(|| format!("Failed to stop VM with CID {cid}"))?;
+    println!("Stopped VM with CID {cid}.");
+    Ok(())
+}
+
+/// Suspend the VM with the given CID.
+pub fn command_suspend(service: &dyn IVirtualizationService, cid: i32) -> Result<(), Error> {
+    service.debugSuspendVm(cid).with_context(|| format!("Failed to suspend VM with CID {cid}"))?;
+    println!("Suspended VM with CID {cid}.");
+    Ok(())
+}
+
+/// Resume the suspended VM with the given CID.
+pub fn command_resume(service: &dyn IVirtualizationService, cid: i32) -> Result<(), Error> {
+    service.debugResumeVm(cid).with_context(|| format!("Failed to resume VM with CID {cid}"))?;
+    println!("Resumed VM with CID {cid}.");
+    Ok(())
+}
+
+/// Print the size of the memory balloon of the VM with the given CID, or set it if `size` is
+/// given.
+pub fn command_balloon(
+    service: &dyn IVirtualizationService,
+    cid: i32,
+    size: Option<u64>,
+) -> Result<(), Error> {
+    if let Some(size) = size {
+        let num_bytes = size.try_into().with_context(|| format!("Invalid balloon size {size}"))?;
+        service
+            .debugSetMemoryBalloon(cid, num_bytes)
+            .with_context(|| format!("Failed to set balloon of VM with CID {cid}"))?;
+        println!("Requested balloon size of {size} bytes for VM with CID {cid}.");
+    } else {
+        let num_bytes = service
+            .debugGetMemoryBalloon(cid)
+            .with_context(|| format!("Failed to get balloon of VM with CID {cid}"))?;
+        println!("Balloon size of VM with CID {cid}: {num_bytes} bytes");
+    }
+    Ok(())
+}
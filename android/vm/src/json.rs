@@ -0,0 +1,142 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Machine-readable output of the `list` and `info` commands.
+//!
+//! The schema is documented in docs/vm_json_output.md. Fields may be added without changing
+//! `SCHEMA_VERSION`, but renaming or removing a field, or changing its meaning, requires bumping
+//! it.
+
+use crate::run::state_to_str;
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
+    AssignableDevice::AssignableDevice as AidlAssignableDevice,
+    VirtualMachineDebugInfo::VirtualMachineDebugInfo,
+};
+use serde::Serialize;
+
+/// Version of the JSON schema emitted by `vm list --json` and `vm info --json`.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Output of `vm list --json`.
+#[derive(Debug, Serialize)]
+pub struct VmList {
+    pub version: u32,
+    pub vms: Vec<VmEntry>,
+}
+
+/// A running VM.
+#[derive(Debug, Serialize)]
+pub struct VmEntry {
+    pub cid: i32,
+    pub name: String,
+    pub state: &'static str,
+    pub requester_uid: i32,
+    pub requester_pid: i32,
+    pub host_console_name: Option<String>,
+    pub memory_mib: i32,
+    pub protected: bool,
+}
+
+impl From<&VirtualMachineDebugInfo> for VmEntry {
+    fn from(info: &VirtualMachineDebugInfo) -> Self {
+        VmEntry {
+            cid: info.cid,
+            name: info.name.clone(),
+            state: state_to_str(info.state),
+            requester_uid: info.requesterUid,
+            requester_pid: info.requesterPid,
+            host_console_name: info.hostConsoleName.clone(),
+            memory_mib: info.memoryMib,
+            protected: info.protectedVm,
+        }
+    }
+}
+
+impl VmList {
+    pub fn new(vms: &[VirtualMachineDebugInfo]) -> Self {
+        VmList { version: SCHEMA_VERSION, vms: vms.iter().map(VmEntry::from).collect() }
+    }
+}
+
+/// Output of `vm info --json`.
+#[derive(Debug, Serialize)]
+pub struct Info {
+    pub version: u32,
+    pub hypervisor: HypervisorInfo,
+    pub assignable_devices: Vec<AssignableDevice>,
+    pub os_list: Vec<String>,
+}
+
+/// Capabilities of the hypervisor and of the host kernel.
+#[derive(Debug, Serialize)]
+pub struct HypervisorInfo {
+    pub non_protected_vm_supported: bool,
+    pub protected_vm_supported: bool,
+    pub version: Option<String>,
+    pub kvm: bool,
+    pub vfio: bool,
+    pub vfio_platform: bool,
+}
+
+/// A device which can be assigned to a VM.
+#[derive(Debug, Serialize)]
+pub struct AssignableDevice {
+    pub node: String,
+    pub dtbo_label: String,
+}
+
+impl From<AidlAssignableDevice> for AssignableDevice {
+    fn from(device: AidlAssignableDevice) -> Self {
+        AssignableDevice { node: device.node, dtbo_label: device.dtbo_label }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_system_virtualizationservice::aidl::android::system::virtualizationservice::VirtualMachineState::VirtualMachineState;
+    use serde_json::json;
+
+    #[test]
+    fn vm_list_schema() {
+        let info = VirtualMachineDebugInfo {
+            cid: 2049,
+            temporaryDirectory: "/data/misc/virtualizationservice/2049".to_owned(),
+            requesterUid: 2000,
+            requesterPid: 1234,
+            hostConsoleName: None,
+            name: "VmRun".to_owned(),
+            state: VirtualMachineState::READY,
+            memoryMib: 256,
+            protectedVm: true,
+        };
+        let list = serde_json::to_value(VmList::new(&[info])).unwrap();
+        assert_eq!(
+            list,
+            json!({
+                "version": 1,
+                "vms": [{
+                    "cid": 2049,
+                    "name": "VmRun",
+                    "state": "READY",
+                    "requester_uid": 2000,
+                    "requester_pid": 1234,
+                    "host_console_name": null,
+                    "memory_mib": 256,
+                    "protected": true,
+                }],
+            })
+        );
+    }
+}
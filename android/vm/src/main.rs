@@ -14,9 +14,13 @@
 
 //! Android VM control tool.
 
+mod config;
+mod control;
 mod create_idsig;
 mod create_partition;
+mod json;
 mod run;
+mod top;
 
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
     CpuTopology::CpuTopology, IVirtualizationService::IVirtualizationService,
@@ -26,16 +30,19 @@ use android_system_virtualizationservice::aidl::android::system::virtualizations
 use anyhow::anyhow;
 use anyhow::{bail, Context, Error};
 use binder::{ProcessState, Strong};
-use clap::{Args, Parser};
+use clap::{Args, Parser, Subcommand};
+use config::command_config_render;
+use control::{command_balloon, command_resume, command_stop, command_suspend};
 use create_idsig::command_create_idsig;
 use create_partition::command_create_partition;
 use run::{command_run, command_run_app, command_run_microdroid};
-use serde::Serialize;
 use std::io::{self, IsTerminal};
 use std::num::NonZeroU16;
 use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use top::command_top;
 
 #[derive(Args, Default)]
 /// Collection of flags that are at VM level and therefore applicable to all subcommands
@@ -136,7 +143,9 @@ pub struct MicrodroidConfig {
     #[arg(long)]
     storage: Option<PathBuf>,
 
-    /// Size of the storage. Used only if --storage is supplied but path does not exist
+    /// Size of the storage. Used only if --storage is supplied. The storage is created with this
+    /// size if the path does not exist, or grown to it otherwise. It can't be shrunk. The file
+    /// backing it is about 1 MiB larger, for the metadata of the storage.
     /// Default size is 10*1024*1024
     #[arg(long)]
     storage_size: Option<u64>,
@@ -233,6 +242,19 @@ pub struct RunAppConfig {
     /// Paths to extra idsig files.
     #[arg(long = "extra-idsig")]
     extra_idsigs: Vec<PathBuf>,
+
+    /// Path to file for the stdout and stderr of the payload, if its config forwards them. If
+    /// unspecified, they are printed to stdout.
+    #[arg(long)]
+    payload_output: Option<PathBuf>,
+
+    /// Arguments to run the main task of the payload with, instead of the ones in its config.
+    #[arg(long = "payload-arg", allow_hyphen_values = true)]
+    payload_args: Vec<String>,
+
+    /// Environment variables to set for the main task of the payload, as NAME=VALUE.
+    #[arg(long = "payload-env", value_parser = parse_payload_env)]
+    payload_env: Vec<(String, String)>,
 }
 
 impl RunAppConfig {
@@ -299,6 +321,10 @@ pub struct RunCustomVmConfig {
 
     /// Path to VM config JSON
     config: PathBuf,
+
+    /// Only validate the config and report every problem found, without starting the VM.
+    #[arg(long)]
+    dry_run: bool,
 }
 
 #[derive(Parser)]
@@ -321,9 +347,19 @@ enum Opt {
         config: RunCustomVmConfig,
     },
     /// List running virtual machines
-    List,
+    List {
+        /// Print the list as JSON. See docs/vm_json_output.md for the schema.
+        #[arg(long)]
+        json: bool,
+    },
+    /// List the VM instances with secrets in Secretkeeper, per app and most recently used first
+    ListInstances,
     /// Print information about virtual machine support
-    Info,
+    Info {
+        /// Print the information as JSON. See docs/vm_json_output.md for the schema.
+        #[arg(long)]
+        json: bool,
+    },
     /// Create a new empty partition to be used as a writable partition for a VM
     CreatePartition {
         /// Path at which to create the image file
@@ -350,6 +386,57 @@ enum Opt {
         /// CID of the VM
         cid: Option<i32>,
     },
+    /// Stop a running VM
+    Stop {
+        /// CID of the VM
+        cid: i32,
+    },
+    /// Suspend a running VM
+    Suspend {
+        /// CID of the VM
+        cid: i32,
+    },
+    /// Resume a suspended VM
+    Resume {
+        /// CID of the VM
+        cid: i32,
+    },
+    /// Print the memory balloon size of a running VM, or set it if a size is given
+    Balloon {
+        /// CID of the VM
+        cid: i32,
+
+        /// The desired size of the balloon, in bytes.
+        size: Option<u64>,
+    },
+    /// Show the resource usage of the running VMs, refreshed periodically
+    Top {
+        /// CID of the VM. If unspecified, all the VMs requested by the caller are shown.
+        cid: Option<i32>,
+
+        /// Seconds between two refreshes
+        #[arg(short = 'd', long, default_value_t = 2,
+              value_parser = clap::value_parser!(u64).range(1..))]
+        delay: u64,
+
+        /// Exit after this number of refreshes
+        #[arg(short = 'n', long)]
+        iterations: Option<u32>,
+    },
+    /// Inspect VM config files
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print a VM config with the config files it extends merged in, and check that it is valid
+    Render {
+        /// Path to VM config JSON
+        config: PathBuf,
+    },
 }
 
 fn parse_debug_level(s: &str) -> Result<DebugLevel, String> {
@@ -376,6 +463,13 @@ fn parse_cpu_topology(s: &str) -> Result<CpuTopology, String> {
     }
 }
 
+fn parse_payload_env(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_owned(), value.to_owned())),
+        _ => Err(format!("Invalid environment variable {}, expected NAME=VALUE", s)),
+    }
+}
+
 fn get_service() -> Result<Strong<dyn IVirtualizationService>, Error> {
     let virtmgr =
         vmclient::VirtualizationService::new().context("Failed to spawn VirtualizationService")?;
@@ -404,8 +498,9 @@ fn main() -> Result<(), Error> {
         Opt::RunApp { config } => command_run_app(config),
         Opt::RunMicrodroid { config } => command_run_microdroid(config),
         Opt::Run { config } => command_run(config),
-        Opt::List => command_list(get_service()?.as_ref()),
-        Opt::Info => command_info(),
+        Opt::List { json } => command_list(get_service()?.as_ref(), json),
+        Opt::ListInstances => command_list_instances(get_service()?.as_ref()),
+        Opt::Info { json } => command_info(json),
         Opt::CreatePartition { path, size, partition_type } => {
             command_create_partition(get_service()?.as_ref(), &path, size, partition_type)
         }
@@ -413,65 +508,117 @@ fn main() -> Result<(), Error> {
             command_create_idsig(get_service()?.as_ref(), &apk, &path)
         }
         Opt::Console { cid } => command_console(cid),
+        Opt::Stop { cid } => command_stop(get_service()?.as_ref(), cid),
+        Opt::Suspend { cid } => command_suspend(get_service()?.as_ref(), cid),
+        Opt::Resume { cid } => command_resume(get_service()?.as_ref(), cid),
+        Opt::Balloon { cid, size } => command_balloon(get_service()?.as_ref(), cid, size),
+        Opt::Top { cid, delay, iterations } => {
+            command_top(get_service()?.as_ref(), cid, Duration::from_secs(delay), iterations)
+        }
+        Opt::Config { command: ConfigCommand::Render { config } } => command_config_render(&config),
     }
 }
 
 /// List the VMs currently running.
-fn command_list(service: &dyn IVirtualizationService) -> Result<(), Error> {
+fn command_list(service: &dyn IVirtualizationService, json: bool) -> Result<(), Error> {
     let vms = service.debugListVms().context("Failed to get list of VMs")?;
-    println!("Running VMs: {:#?}", vms);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&json::VmList::new(&vms))?);
+    } else {
+        println!("Running VMs: {:#?}", vms);
+    }
+    Ok(())
+}
+
+/// List the VM instances with secrets in Secretkeeper.
+fn command_list_instances(service: &dyn IVirtualizationService) -> Result<(), Error> {
+    let instances = service.debugListVmInstances().context("Failed to get list of VM instances")?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+    let mut owner = None;
+    for instance in &instances {
+        if owner != Some((instance.userId, instance.appId)) {
+            owner = Some((instance.userId, instance.appId));
+            let package = instance.packageName.as_deref().unwrap_or("unknown package");
+            println!("user {} app {} ({package}):", instance.userId, instance.appId);
+        }
+        let id: String = instance.instanceId[..8].iter().map(|b| format!("{b:02x}")).collect();
+        let last_used = match instance.lastUsedMillis {
+            -1 => "never used".to_owned(),
+            millis => format!("last used {}s ago", (now - millis).max(0) / 1000),
+        };
+        let created = match instance.createdMillis {
+            -1 => "created at unknown time".to_owned(),
+            millis => format!("created {}s ago", (now - millis).max(0) / 1000),
+        };
+        match &instance.name {
+            Some(name) => {
+                let kind = if instance.updatable { "updatable" } else { "not updatable" };
+                println!("  {id}... \"{name}\", {kind}, {last_used}, {created}");
+            }
+            None => println!("  {id}... {last_used}, {created}"),
+        }
+    }
+    println!("{} VM instances", instances.len());
     Ok(())
 }
 
 /// Print information about supported VM types.
-fn command_info() -> Result<(), Error> {
-    let non_protected_vm_supported = hypervisor_props::is_vm_supported()?;
-    let protected_vm_supported = hypervisor_props::is_protected_vm_supported()?;
-    match (non_protected_vm_supported, protected_vm_supported) {
+fn command_info(json: bool) -> Result<(), Error> {
+    let hypervisor = json::HypervisorInfo {
+        non_protected_vm_supported: hypervisor_props::is_vm_supported()?,
+        protected_vm_supported: hypervisor_props::is_protected_vm_supported()?,
+        version: hypervisor_props::version()?,
+        kvm: Path::new("/dev/kvm").exists(),
+        vfio: Path::new("/dev/vfio/vfio").exists(),
+        vfio_platform: Path::new("/sys/bus/platform/drivers/vfio-platform").exists(),
+    };
+    let devices: Vec<json::AssignableDevice> =
+        get_service()?.getAssignableDevices()?.into_iter().map(Into::into).collect();
+    let os_list = get_service()?.getSupportedOSList()?;
+
+    if json {
+        let info = json::Info {
+            version: json::SCHEMA_VERSION,
+            hypervisor,
+            assignable_devices: devices,
+            os_list,
+        };
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
+
+    match (hypervisor.non_protected_vm_supported, hypervisor.protected_vm_supported) {
         (false, false) => println!("VMs are not supported."),
         (false, true) => println!("Only protected VMs are supported."),
         (true, false) => println!("Only non-protected VMs are supported."),
         (true, true) => println!("Both protected and non-protected VMs are supported."),
     }
 
-    if let Some(version) = hypervisor_props::version()? {
+    if let Some(version) = hypervisor.version {
         println!("Hypervisor version: {}", version);
     } else {
         println!("Hypervisor version not set.");
     }
 
-    if Path::new("/dev/kvm").exists() {
+    if hypervisor.kvm {
         println!("/dev/kvm exists.");
     } else {
         println!("/dev/kvm does not exist.");
     }
 
-    if Path::new("/dev/vfio/vfio").exists() {
+    if hypervisor.vfio {
         println!("/dev/vfio/vfio exists.");
     } else {
         println!("/dev/vfio/vfio does not exist.");
     }
 
-    if Path::new("/sys/bus/platform/drivers/vfio-platform").exists() {
+    if hypervisor.vfio_platform {
         println!("VFIO-platform is supported.");
     } else {
         println!("VFIO-platform is not supported.");
     }
 
-    #[derive(Serialize)]
-    struct AssignableDevice {
-        node: String,
-        dtbo_label: String,
-    }
-
-    let devices = get_service()?.getAssignableDevices()?;
-    let devices: Vec<_> = devices
-        .into_iter()
-        .map(|device| AssignableDevice { node: device.node, dtbo_label: device.dtbo_label })
-        .collect();
     println!("Assignable devices: {}", serde_json::to_string(&devices)?);
-
-    let os_list = get_service()?.getSupportedOSList()?;
     println!("Available OS list: {}", serde_json::to_string(&os_list)?);
 
     Ok(())
@@ -502,4 +649,18 @@ mod tests {
         // Check that the command parsing has been configured in a valid way.
         Opt::command().debug_assert();
     }
+
+    #[test]
+    fn parse_control_commands() {
+        assert!(matches!(Opt::try_parse_from(["vm", "stop", "2049"]), Ok(Opt::Stop { cid: 2049 })));
+        assert!(matches!(
+            Opt::try_parse_from(["vm", "balloon", "2049"]),
+            Ok(Opt::Balloon { cid: 2049, size: None })
+        ));
+        assert!(matches!(
+            Opt::try_parse_from(["vm", "balloon", "2049", "4096"]),
+            Ok(Opt::Balloon { cid: 2049, size: Some(4096) })
+        ));
+        assert!(Opt::try_parse_from(["vm", "suspend"]).is_err());
+    }
 }
@@ -17,11 +17,11 @@
 use crate::create_partition::command_create_partition;
 use crate::{get_service, RunAppConfig, RunCustomVmConfig, RunMicrodroidConfig};
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
-    IVirtualizationService::IVirtualizationService,
+    IVirtualizationService::{IVirtualizationService, ENCRYPTEDSTORE_OVERHEAD_BYTES},
     PartitionType::PartitionType,
     VirtualMachineAppConfig::{
-        CustomConfig::CustomConfig, DebugLevel::DebugLevel, Payload::Payload,
-        VirtualMachineAppConfig,
+        CustomConfig::CustomConfig, DebugLevel::DebugLevel,
+        EnvironmentVariable::EnvironmentVariable, Payload::Payload, VirtualMachineAppConfig,
     },
     VirtualMachineConfig::VirtualMachineConfig,
     VirtualMachinePayloadConfig::VirtualMachinePayloadConfig,
@@ -38,6 +38,7 @@ use std::io;
 use std::io::{Read, Write};
 use std::os::fd::AsFd;
 use std::path::{Path, PathBuf};
+use std::thread;
 use vmclient::{ErrorCode, VmInstance};
 use vmconfig::{get_debug_level, open_parcel_file, VmConfig};
 use zip::ZipArchive;
@@ -108,9 +109,11 @@ pub fn command_run_app(config: RunAppConfig) -> Result<(), Error> {
             command_create_partition(
                 service.as_ref(),
                 path,
-                config.microdroid.storage_size.unwrap_or(10 * 1024 * 1024),
+                storage_image_size(config.microdroid.storage_size.unwrap_or(10 * 1024 * 1024)),
                 PartitionType::ENCRYPTEDSTORE,
             )?;
+        } else if let Some(storage_size) = config.microdroid.storage_size {
+            grow_storage(path, storage_image_size(storage_size))?;
         }
         Some(open_parcel_file(path, true)?)
     } else {
@@ -148,6 +151,14 @@ pub fn command_run_app(config: RunAppConfig) -> Result<(), Error> {
 
     let payload_config_str = format!("{:?}!{:?}", config.apk, payload);
 
+    let payload_output = if let Some(payload_output_path) = &config.payload_output {
+        File::create(payload_output_path).with_context(|| {
+            format!("Failed to open payload output file {:?}", payload_output_path)
+        })?
+    } else {
+        duplicate_fd(io::stdout())?
+    };
+
     let mut custom_config = CustomConfig {
         gdbPort: config.debug.gdb.map(u16::from).unwrap_or(0) as i32, // 0 means no gdb
         vendorImage: vendor,
@@ -187,6 +198,15 @@ pub fn command_run_app(config: RunAppConfig) -> Result<(), Error> {
         instanceId: instance_id,
         encryptedStorageImage: storage,
         payload,
+        payloadArgs: (!config.payload_args.is_empty())
+            .then(|| config.payload_args.into_iter().map(Some).collect()),
+        payloadEnv: Some(
+            config
+                .payload_env
+                .into_iter()
+                .map(|(name, value)| Some(EnvironmentVariable { name, value }))
+                .collect(),
+        ),
         debugLevel: config.debug.debug,
         protectedVm: config.common.protected,
         memoryMib: config.common.mem.unwrap_or(0) as i32, // 0 means use the VM default
@@ -203,6 +223,7 @@ pub fn command_run_app(config: RunAppConfig) -> Result<(), Error> {
         config.debug.console.as_ref().map(|p| p.as_ref()),
         config.debug.console_in.as_ref().map(|p| p.as_ref()),
         config.debug.log.as_ref().map(|p| p.as_ref()),
+        Some(payload_output),
     )
 }
 
@@ -228,6 +249,30 @@ fn create_work_dir() -> Result<PathBuf, Error> {
     Ok(work_dir)
 }
 
+/// Grows the storage image at `path` to `size` bytes, rounded up to a whole number of 4 KiB blocks.
+/// The guest grows the filesystem on it when it boots.
+/// Returns the size of the image of a storage of `storage_size` bytes.
+fn storage_image_size(storage_size: u64) -> u64 {
+    storage_size + ENCRYPTEDSTORE_OVERHEAD_BYTES as u64
+}
+
+fn grow_storage(path: &Path, size: u64) -> Result<(), Error> {
+    let size = size.next_multiple_of(4096);
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let current_size = file.metadata()?.len();
+    if size < current_size {
+        bail!("The storage can't shrink from {} to {} bytes", current_size, size);
+    }
+    if size > current_size {
+        println!("Growing the storage from {} to {} bytes", current_size, size);
+        file.set_len(size).context("Failed to grow the storage")?;
+    }
+    Ok(())
+}
+
 /// Run a VM with Microdroid
 pub fn command_run_microdroid(config: RunMicrodroidConfig) -> Result<(), Error> {
     let apk = find_empty_payload_apk_path()?;
@@ -260,9 +305,15 @@ pub fn command_run_microdroid(config: RunMicrodroidConfig) -> Result<(), Error>
 
 /// Run a VM from the given configuration file.
 pub fn command_run(config: RunCustomVmConfig) -> Result<(), Error> {
-    let config_file = File::open(&config.config).context("Failed to open config file")?;
-    let mut vm_config =
-        VmConfig::load(&config_file).context("Failed to parse config file")?.to_parcelable()?;
+    if config.dry_run {
+        VmConfig::load_from_path(&config.config)?.validate_with_files()?;
+        println!("{:?} is a valid VM config.", &config.config);
+        return Ok(());
+    }
+    let vm_config =
+        VmConfig::load_from_path(&config.config).context("Failed to parse config file")?;
+    vm_config.validate_with_files()?;
+    let mut vm_config = vm_config.to_parcelable()?;
     if let Some(mem) = config.common.mem {
         vm_config.memoryMib = mem as i32;
     }
@@ -284,10 +335,11 @@ pub fn command_run(config: RunCustomVmConfig) -> Result<(), Error> {
         config.debug.console.as_ref().map(|p| p.as_ref()),
         config.debug.console_in.as_ref().map(|p| p.as_ref()),
         config.debug.log.as_ref().map(|p| p.as_ref()),
+        None,
     )
 }
 
-fn state_to_str(vm_state: VirtualMachineState) -> &'static str {
+pub(crate) fn state_to_str(vm_state: VirtualMachineState) -> &'static str {
     match vm_state {
         VirtualMachineState::NOT_STARTED => "NOT_STARTED",
         VirtualMachineState::STARTING => "STARTING",
@@ -306,6 +358,7 @@ fn run(
     console_out_path: Option<&Path>,
     console_in_path: Option<&Path>,
     log_path: Option<&Path>,
+    payload_output: Option<File>,
 ) -> Result<(), Error> {
     let console_out = if let Some(console_out_path) = console_out_path {
         Some(File::create(console_out_path).with_context(|| {
@@ -333,6 +386,18 @@ fn run(
     let callback = Box::new(Callback {});
     let vm = VmInstance::create(service, config, console_out, console_in, log, Some(callback))
         .context("Failed to create VM")?;
+    // Take the payload output before starting the VM, not to miss any of it.
+    let payload_output_thread = if let Some(mut payload_output) = payload_output {
+        let stream = vm.vm.getPayloadOutput().context("Failed to get payload output")?;
+        let mut stream = stream.as_ref().try_clone()?;
+        Some(thread::spawn(move || {
+            if let Err(e) = io::copy(&mut stream, &mut payload_output) {
+                eprintln!("Failed to copy payload output: {e:?}");
+            }
+        }))
+    } else {
+        None
+    };
     vm.start().context("Failed to start VM")?;
 
     let debug_level = get_debug_level(config).unwrap_or(DebugLevel::NONE);
@@ -348,6 +413,10 @@ fn run(
     // Wait until the VM or VirtualizationService dies. If we just returned immediately then the
     // IVirtualMachine Binder object would be dropped and the VM would be killed.
     let death_reason = vm.wait_for_death();
+    if let Some(payload_output_thread) = payload_output_thread {
+        // The stream ends when the VM dies, so this doesn't block.
+        let _ = payload_output_thread.join();
+    }
     println!("VM ended: {:?}", death_reason);
     Ok(())
 }
@@ -0,0 +1,204 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Command to show the resource usage of the running VMs, like `top`.
+
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
+    IVirtualizationService::IVirtualizationService,
+    VirtualMachineDebugInfo::VirtualMachineDebugInfo, VirtualMachineMetrics::VirtualMachineMetrics,
+};
+use anyhow::{Context, Error};
+use std::collections::HashMap;
+use std::io::{self, IsTerminal};
+use std::thread;
+use std::time::Duration;
+
+const HEADER: [&str; 11] = [
+    "CID",
+    "NAME",
+    "UPTIME",
+    "CPU%",
+    "GUEST RSS",
+    "CROSVM RSS",
+    "BALLOON",
+    "DISK R/s",
+    "DISK W/s",
+    "NET RX/s",
+    "NET TX/s",
+];
+
+/// Print the resource usage of the VMs requested by the caller, or only of the VM with CID `cid`,
+/// every `interval`. Rates are computed between two samples, so they are only shown from the
+/// second one. Stops after `iterations` samples if set.
+pub fn command_top(
+    service: &dyn IVirtualizationService,
+    cid: Option<i32>,
+    interval: Duration,
+    iterations: Option<u32>,
+) -> Result<(), Error> {
+    let clear_screen = io::stdout().is_terminal();
+    let mut previous: HashMap<i32, VirtualMachineMetrics> = HashMap::new();
+    for iteration in 1.. {
+        let mut vms = service.debugListVms().context("Failed to get list of VMs")?;
+        if let Some(cid) = cid {
+            vms.retain(|vm| vm.cid == cid);
+        }
+        // VMs which aren't running, or which were requested by someone else, are skipped.
+        let samples: HashMap<_, _> = vms
+            .iter()
+            .filter_map(|vm| Some((vm.cid, service.debugGetMetrics(vm.cid).ok()?)))
+            .collect();
+
+        let mut rows = vec![HEADER.map(String::from).to_vec()];
+        for vm in &vms {
+            if let Some(metrics) = samples.get(&vm.cid) {
+                rows.push(format_row(vm, metrics, previous.get(&vm.cid)));
+            }
+        }
+        if clear_screen {
+            print!("\x1b[2J\x1b[H");
+        }
+        print_table(&rows);
+
+        if iterations == Some(iteration) {
+            break;
+        }
+        previous = samples;
+        thread::sleep(interval);
+    }
+    Ok(())
+}
+
+fn format_row(
+    vm: &VirtualMachineDebugInfo,
+    metrics: &VirtualMachineMetrics,
+    previous: Option<&VirtualMachineMetrics>,
+) -> Vec<String> {
+    let elapsed_millis =
+        previous.map(|previous| metrics.uptimeMillis - previous.uptimeMillis).filter(|e| *e > 0);
+    // Rate per second of a counter, from its value in the previous and the current sample.
+    let rate = |get: &dyn Fn(&VirtualMachineMetrics) -> Option<i64>| -> Option<i64> {
+        let delta = get(metrics)? - get(previous?)?;
+        Some(delta * 1000 / elapsed_millis?)
+    };
+    let disk_total = |metrics: &VirtualMachineMetrics, written: bool| -> Option<i64> {
+        if metrics.disks.is_empty() {
+            return None;
+        }
+        Some(
+            metrics
+                .disks
+                .iter()
+                .map(|disk| if written { disk.writtenBytes } else { disk.readBytes })
+                .sum(),
+        )
+    };
+    let cpu =
+        rate(&|m| Some(m.vcpuTimeMillis)).map(|permille| format!("{:.1}", permille as f64 / 10.0));
+    let balloon = (metrics.balloonBytes >= 0).then_some(metrics.balloonBytes);
+
+    vec![
+        vm.cid.to_string(),
+        vm.name.clone(),
+        format_duration(metrics.uptimeMillis),
+        cpu.unwrap_or_else(|| "-".to_owned()),
+        format_bytes(Some(metrics.guestRssKib * 1024)),
+        format_bytes(Some(metrics.crosvmRssKib * 1024)),
+        format_bytes(balloon),
+        format_bytes(rate(&|m| disk_total(m, false))),
+        format_bytes(rate(&|m| disk_total(m, true))),
+        format_bytes(rate(&|m| m.network.as_ref().map(|n| n.rxBytes))),
+        format_bytes(rate(&|m| m.network.as_ref().map(|n| n.txBytes))),
+    ]
+}
+
+fn print_table(rows: &[Vec<String>]) {
+    let mut widths = vec![0; HEADER.len()];
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in rows {
+        let cells: Vec<_> =
+            row.iter().zip(&widths).map(|(cell, width)| format!("{cell:>width$}")).collect();
+        println!("{}", cells.join("  "));
+    }
+}
+
+/// Formats a size in bytes with a binary unit, or `-` if it is unknown.
+fn format_bytes(bytes: Option<i64>) -> String {
+    let Some(bytes) = bytes else {
+        return "-".to_owned();
+    };
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes}B")
+    } else {
+        format!("{value:.1}{}", UNITS[unit])
+    }
+}
+
+fn format_duration(millis: i64) -> String {
+    let secs = millis / 1000;
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_system_virtualizationservice::aidl::android::system::virtualizationservice::VirtualMachineMetrics::Disk::Disk;
+
+    #[test]
+    fn format_units() {
+        assert_eq!(format_bytes(None), "-");
+        assert_eq!(format_bytes(Some(1000)), "1000B");
+        assert_eq!(format_bytes(Some(1536)), "1.5K");
+        assert_eq!(format_bytes(Some(3 << 30)), "3.0G");
+        assert_eq!(format_duration(3_723_456), "1:02:03");
+    }
+
+    #[test]
+    fn rates_need_two_samples() {
+        let vm = VirtualMachineDebugInfo { cid: 10, name: "vm".to_owned(), ..Default::default() };
+        let first = VirtualMachineMetrics {
+            uptimeMillis: 1000,
+            vcpuTimeMillis: 500,
+            guestRssKib: 1024,
+            disks: vec![Disk { readBytes: 0, writtenBytes: 0 }],
+            ..Default::default()
+        };
+        let second = VirtualMachineMetrics {
+            uptimeMillis: 3000,
+            vcpuTimeMillis: 1500,
+            guestRssKib: 2048,
+            disks: vec![Disk { readBytes: 4096, writtenBytes: 0 }],
+            ..Default::default()
+        };
+
+        let row = format_row(&vm, &first, None);
+        assert_eq!(row[3], "-");
+        assert_eq!(row[7], "-");
+
+        let row = format_row(&vm, &second, Some(&first));
+        assert_eq!(row[..7], ["10", "vm", "0:00:03", "50.0", "2.0M", "0B", "-"]);
+        assert_eq!(row[7..], ["2.0K", "0B", "-", "-"]);
+    }
+}
@@ -23,7 +23,7 @@
 
 #![cfg_attr(test, allow(unused))]
 
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use apkverify::{HashAlgorithm, V4Signature};
 use clap::{arg, Arg, ArgAction, Command};
 use dm::loopdevice;
@@ -142,6 +142,7 @@ fn enable_verity<P: AsRef<Path> + Debug>(
         })
         .hash_algorithm(match sig.hashing_info.hash_algorithm {
             HashAlgorithm::SHA256 => DmVerityHashAlgorithm::SHA256,
+            HashAlgorithm::SHA512 => DmVerityHashAlgorithm::SHA512,
         })
         .salt(&sig.hashing_info.salt)
         .build()
@@ -149,6 +150,11 @@ fn enable_verity<P: AsRef<Path> + Debug>(
 
     // Actually create a dm-verity block device using the spec.
     let dm = dm::DeviceMapper::new()?;
+    // A device with the same name is left over if a previous run crashed after creating it.
+    if let Some(info) = dm.info(name)? {
+        ensure!(info.open_count == 0, "dm-verity device {} already exists and is in use", name);
+        dm.delete_device_deferred(name).context("Failed to remove stale dm-verity device")?;
+    }
     let mapper_device =
         dm.create_verity_device(name, &target).context("Failed to create dm-verity device")?;
 
@@ -242,6 +248,9 @@ mod tests {
             let original = fs::read(&ctx.result.data_device).unwrap();
             assert_eq!(verity.len(), original.len()); // fail fast
             assert_eq!(verity.as_slice(), original.as_slice());
+
+            let dm = dm::DeviceMapper::new().unwrap();
+            assert!(!dm.status("correct").unwrap().iter().any(dm::verity::is_corrupted));
         });
     }
 
@@ -306,6 +315,10 @@ mod tests {
             let f = File::open(&ctx.result.mapper_device).unwrap();
             let mut buf = vec![0; 10]; // just read 10 bytes
             f.read_at(&mut buf, MODIFIED_OFFSET).expect_err("Should fail");
+
+            // The status of the device reports the corruption.
+            let dm = dm::DeviceMapper::new().unwrap();
+            assert!(dm.status("tampered_apk").unwrap().iter().any(dm::verity::is_corrupted));
         });
     }
 
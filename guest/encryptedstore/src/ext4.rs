@@ -0,0 +1,112 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Reads the size of an ext4 filesystem, and grows it while it is mounted.
+
+use anyhow::{ensure, Context, Result};
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const EXT4_SUPER_MAGIC: u16 = 0xEF53;
+const EXT4_FEATURE_INCOMPAT_64BIT: u32 = 0x80;
+
+// EXT4_IOC_RESIZE_FS in fs/ext4/ext4.h.
+nix::ioctl_write_ptr!(ext4_ioc_resize_fs, b'f', 16, u64);
+
+/// The size of an ext4 filesystem.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Ext4Size {
+    /// Size of a block, in bytes.
+    pub block_size: u64,
+    /// Number of blocks of the filesystem.
+    pub blocks_count: u64,
+}
+
+impl Ext4Size {
+    /// Reads the size of the ext4 filesystem on `device`.
+    pub fn read(device: &Path) -> Result<Self> {
+        let file = File::open(device).with_context(|| format!("Failed to open {:?}", device))?;
+        let mut superblock = [0; SUPERBLOCK_SIZE];
+        file.read_exact_at(&mut superblock, SUPERBLOCK_OFFSET)
+            .context("Failed to read the superblock")?;
+        Self::from_superblock(&superblock)
+    }
+
+    fn from_superblock(sb: &[u8; SUPERBLOCK_SIZE]) -> Result<Self> {
+        // struct ext4_super_block in fs/ext4/ext4.h.
+        let u16_at = |offset: usize| u16::from_le_bytes(sb[offset..offset + 2].try_into().unwrap());
+        let u32_at = |offset: usize| u32::from_le_bytes(sb[offset..offset + 4].try_into().unwrap());
+        let magic = u16_at(0x38);
+        ensure!(magic == EXT4_SUPER_MAGIC, "Not an ext4 filesystem, magic is {:#x}", magic);
+        let log_block_size = u32_at(0x18);
+        ensure!(log_block_size <= 6, "Invalid block size 2^{} KiB", log_block_size);
+        let mut blocks_count = u32_at(0x4) as u64;
+        if u32_at(0x60) & EXT4_FEATURE_INCOMPAT_64BIT != 0 {
+            blocks_count |= (u32_at(0x150) as u64) << 32;
+        }
+        Ok(Self { block_size: 1024 << log_block_size, blocks_count })
+    }
+}
+
+/// Grows the ext4 filesystem mounted at `mountpoint` to `blocks_count` blocks.
+pub fn resize(mountpoint: &Path, blocks_count: u64) -> Result<()> {
+    let dir = File::open(mountpoint).with_context(|| format!("Failed to open {:?}", mountpoint))?;
+    // SAFETY: The ioctl only reads the u64 it is given, and `dir` is a valid file descriptor.
+    unsafe { ext4_ioc_resize_fs(dir.as_raw_fd(), &blocks_count) }
+        .context("EXT4_IOC_RESIZE_FS failed")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdroidtest::rdroidtest;
+
+    fn superblock(log_block_size: u32, blocks_count: u64, is_64bit: bool) -> [u8; SUPERBLOCK_SIZE] {
+        let mut sb = [0; SUPERBLOCK_SIZE];
+        sb[0x38..0x3a].copy_from_slice(&EXT4_SUPER_MAGIC.to_le_bytes());
+        sb[0x18..0x1c].copy_from_slice(&log_block_size.to_le_bytes());
+        sb[0x4..0x8].copy_from_slice(&(blocks_count as u32).to_le_bytes());
+        if is_64bit {
+            sb[0x60..0x64].copy_from_slice(&EXT4_FEATURE_INCOMPAT_64BIT.to_le_bytes());
+            sb[0x150..0x154].copy_from_slice(&((blocks_count >> 32) as u32).to_le_bytes());
+        }
+        sb
+    }
+
+    #[rdroidtest]
+    fn read_size() {
+        let size = Ext4Size::from_superblock(&superblock(2, 2560, false)).unwrap();
+
+        assert_eq!(size, Ext4Size { block_size: 4096, blocks_count: 2560 });
+    }
+
+    #[rdroidtest]
+    fn read_size_64bit() {
+        let size = Ext4Size::from_superblock(&superblock(2, (1 << 32) + 5, true)).unwrap();
+
+        assert_eq!(size.blocks_count, (1 << 32) + 5);
+    }
+
+    #[rdroidtest]
+    fn read_size_not_ext4() {
+        assert!(Ext4Size::from_superblock(&[0; SUPERBLOCK_SIZE]).is_err());
+    }
+}
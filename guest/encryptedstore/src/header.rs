@@ -0,0 +1,182 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The header at the start of the block device, which records the version of the key the storage
+//! is encrypted with, and the progress of its re-encryption with a new key.
+//!
+//! The block device is laid out as follows:
+//!
+//! | Offset         | Size           | Content                                               |
+//! |----------------|----------------|-------------------------------------------------------|
+//! | 0              | 4 KiB          | Header, in plain text                                 |
+//! | 4 KiB          | `CHUNK_SIZE`   | Journal: the chunk being re-encrypted, as on the disk |
+//! | `DATA_OFFSET`  | rest           | Data, encrypted by dm-crypt                           |
+//!
+//! Authenticated storage is never re-encrypted, so it has no journal: its data, along with the
+//! authentication tags kept by dm-integrity, starts at `AUTHENTICATED_DATA_OFFSET` instead.
+//!
+//! Storage formatted before the header was introduced has no header, and its data starts at the
+//! beginning of the block device until it is migrated, see `migrate`.
+
+use anyhow::{ensure, Context, Result};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+const HEADER_MAGIC: &[u8; 16] = b"ENCRYPTEDSTORE\0\0";
+const HEADER_FORMAT_VERSION: u32 = 1;
+
+/// Flag of the header telling that the storage uses authenticated encryption.
+const FLAG_AUTHENTICATED: u32 = 1 << 0;
+
+/// Size of the part of the header which is written at once. A single sector is written
+/// atomically by the block device, so the header is never left half updated.
+const HEADER_WRITE_SIZE: usize = 512;
+
+/// Size of the chunks in which the data is re-encrypted, and therefore of the journal.
+pub const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Offset of the journal on the block device.
+pub const JOURNAL_OFFSET: u64 = 4096;
+
+/// Offset of the data on the block device.
+pub const DATA_OFFSET: u64 = JOURNAL_OFFSET + CHUNK_SIZE;
+
+/// Offset of the data on the block device, if the storage uses authenticated encryption.
+pub const AUTHENTICATED_DATA_OFFSET: u64 = JOURNAL_OFFSET;
+
+/// The header of the block device.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Header {
+    /// Version of the key the data is encrypted with. While the data is being re-encrypted, this
+    /// is the version of the new key.
+    pub key_version: u32,
+    /// While the data is being re-encrypted, version of the key the data which isn't re-encrypted
+    /// yet is encrypted with. 0 otherwise.
+    pub old_key_version: u32,
+    /// Number of bytes at the start of the data which are re-encrypted with the new key.
+    pub progress: u64,
+    /// Number of bytes of the data at `progress` which are saved in the journal, as they were
+    /// before being re-encrypted. 0 if the journal is empty.
+    pub journal_size: u64,
+    /// Whether the data is encrypted with an AEAD cipher, over dm-integrity which stores the
+    /// authentication tags. This is chosen when the storage is formatted, and never changes.
+    pub authenticated: bool,
+}
+
+impl Header {
+    /// Reads the header of `device`, or returns `None` if it has none.
+    pub fn read(device: &File) -> Result<Option<Self>> {
+        let mut buf = [0; HEADER_WRITE_SIZE];
+        device.read_exact_at(&mut buf, 0).context("Failed to read the header")?;
+        Self::decode(&buf)
+    }
+
+    /// Writes the header to `device`, and waits until it is on the disk.
+    pub fn write(&self, device: &File) -> Result<()> {
+        device.write_all_at(&self.encode(), 0).context("Failed to write the header")?;
+        device.sync_data().context("Failed to sync the header")
+    }
+
+    /// Returns whether the data is being re-encrypted.
+    pub fn is_reencrypting(&self) -> bool {
+        self.old_key_version != 0
+    }
+
+    fn decode(buf: &[u8; HEADER_WRITE_SIZE]) -> Result<Option<Self>> {
+        if &buf[0..16] != HEADER_MAGIC {
+            return Ok(None);
+        }
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
+        let format_version = u32_at(16);
+        ensure!(
+            format_version == HEADER_FORMAT_VERSION,
+            "Unsupported header format version {}",
+            format_version
+        );
+        let flags = u32_at(28);
+        ensure!(flags & !FLAG_AUTHENTICATED == 0, "Unsupported header flags {:#x}", flags);
+        let header = Self {
+            key_version: u32_at(20),
+            old_key_version: u32_at(24),
+            progress: u64_at(32),
+            journal_size: u64_at(40),
+            authenticated: flags & FLAG_AUTHENTICATED != 0,
+        };
+        ensure!(header.journal_size <= CHUNK_SIZE, "Invalid journal size {}", header.journal_size);
+        Ok(Some(header))
+    }
+
+    fn encode(&self) -> [u8; HEADER_WRITE_SIZE] {
+        let mut buf = [0; HEADER_WRITE_SIZE];
+        buf[0..16].copy_from_slice(HEADER_MAGIC);
+        buf[16..20].copy_from_slice(&HEADER_FORMAT_VERSION.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.key_version.to_le_bytes());
+        buf[24..28].copy_from_slice(&self.old_key_version.to_le_bytes());
+        let flags = if self.authenticated { FLAG_AUTHENTICATED } else { 0 };
+        buf[28..32].copy_from_slice(&flags.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.progress.to_le_bytes());
+        buf[40..48].copy_from_slice(&self.journal_size.to_le_bytes());
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdroidtest::rdroidtest;
+
+    #[rdroidtest]
+    fn header_round_trip() {
+        let header = Header {
+            key_version: 3,
+            old_key_version: 2,
+            progress: 5 * CHUNK_SIZE,
+            journal_size: 42,
+            authenticated: false,
+        };
+        assert_eq!(Header::decode(&header.encode()).unwrap(), Some(header));
+
+        let header = Header { key_version: 2, authenticated: true, ..Default::default() };
+        assert_eq!(Header::decode(&header.encode()).unwrap(), Some(header));
+    }
+
+    #[rdroidtest]
+    fn no_header() {
+        let mut buf = [0; HEADER_WRITE_SIZE];
+        assert_eq!(Header::decode(&buf).unwrap(), None);
+
+        buf[..19].copy_from_slice(b"UNFORMATTED-STORAGE");
+        assert_eq!(Header::decode(&buf).unwrap(), None);
+    }
+
+    #[rdroidtest]
+    fn invalid_header() {
+        let mut buf = Header { key_version: 1, ..Default::default() }.encode();
+        buf[16] = 2;
+        assert!(Header::decode(&buf).is_err());
+
+        let buf =
+            Header { key_version: 1, journal_size: CHUNK_SIZE + 1, ..Default::default() }.encode();
+        assert!(Header::decode(&buf).is_err());
+
+        let mut buf = Header { key_version: 1, ..Default::default() }.encode();
+        buf[28] = 2;
+        assert!(Header::decode(&buf).is_err());
+    }
+}
@@ -15,24 +15,44 @@
  */
 
 //! `encryptedstore` is a program that (as the name indicates) provides encrypted storage
-//! solution in a VM. This is based on dm-crypt & requires the (64 bytes') key & the backing device.
-//! It uses dm_rust lib.
+//! solution in a VM. This is based on dm-crypt & requires the (32 bytes') keys & the backing
+//! device. It uses dm_rust lib.
 
-use anyhow::{ensure, Context, Result};
-use clap::arg;
+mod ext4;
+mod header;
+mod migrate;
+mod reencrypt;
+
+use crate::ext4::Ext4Size;
+use crate::header::{Header, AUTHENTICATED_DATA_OFFSET, DATA_OFFSET};
+use anyhow::{bail, ensure, Context, Result};
+use clap::{arg, value_parser};
 use dm::{crypt::CipherType, util};
-use log::{error, info};
+use log::{info, warn};
+use std::collections::BTreeMap;
 use std::ffi::CString;
-use std::fs::{create_dir_all, OpenOptions};
+use std::fs::{create_dir_all, File, OpenOptions};
 use std::io::{Error, Read, Write};
+use std::os::fd::{FromRawFd, RawFd};
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::{FileTypeExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use zeroize::Zeroizing;
 
 const MK2FS_BIN: &str = "/system/bin/mke2fs";
 const UNFORMATTED_STORAGE_MAGIC: &str = "UNFORMATTED-STORAGE";
+const KEY_SIZE: usize = 32;
+const BLOCK_SIZE: u64 = 4096;
+
+/// Version of the key that storage without a header is encrypted with.
+///
+/// Storage without a header can't be re-encrypted until it is migrated to the layout with a
+/// header, which needs the host to grow its image by `migrate::MIGRATION_SPACE` bytes. Until then,
+/// it stays encrypted with this version of the key, which must always be passed.
+const LEGACY_KEY_VERSION: u32 = 1;
 
+#[cfg(not(test))]
 fn main() {
     android_logger::init_once(
         android_logger::Config::default()
@@ -41,21 +61,26 @@ fn main() {
     );
 
     if let Err(e) = try_main() {
-        error!("{:?}", e);
+        log::error!("{:?}", e);
         std::process::exit(1)
     }
 }
 
+#[cfg_attr(test, allow(dead_code))]
 fn try_main() -> Result<()> {
     info!("Starting encryptedstore binary");
 
     let matches = clap_command().get_matches();
 
     let blkdevice = Path::new(matches.get_one::<String>("blkdevice").unwrap());
-    let key = matches.get_one::<String>("key").unwrap();
+    let key_fd = *matches.get_one::<RawFd>("key-fd").unwrap();
+    // SAFETY: The caller passes the file descriptor to read the keys from, which isn't used
+    // anywhere else in this process.
+    let keys = read_keys(unsafe { File::from_raw_fd(key_fd) })?;
     let mountpoint = Path::new(matches.get_one::<String>("mountpoint").unwrap());
+    let authenticated = matches.get_flag("authenticated");
     // Note this error context is used in MicrodroidTests.
-    encryptedstore_init(blkdevice, key, mountpoint).with_context(|| {
+    encryptedstore_init(blkdevice, &keys, mountpoint, authenticated).with_context(|| {
         format!(
             "Unable to initialize encryptedstore on {:?} & mount at {:?}",
             blkdevice, mountpoint
@@ -67,12 +92,20 @@ fn try_main() -> Result<()> {
 fn clap_command() -> clap::Command {
     clap::Command::new("encryptedstore").args(&[
         arg!(--blkdevice <FILE> "the block device backing the encrypted storage").required(true),
-        arg!(--key <KEY> "key (in hex) equivalent to 32 bytes)").required(true),
+        arg!(--"key-fd" <FD> "file descriptor to read the versioned keys from")
+            .required(true)
+            .value_parser(value_parser!(RawFd)),
         arg!(--mountpoint <MOUNTPOINT> "mount point for the storage").required(true),
+        arg!(--authenticated "use authenticated encryption if the storage is formatted"),
     ])
 }
 
-fn encryptedstore_init(blkdevice: &Path, key: &str, mountpoint: &Path) -> Result<()> {
+fn encryptedstore_init(
+    blkdevice: &Path,
+    keys: &Keys,
+    mountpoint: &Path,
+    authenticated: bool,
+) -> Result<()> {
     ensure!(
         std::fs::metadata(blkdevice)
             .with_context(|| format!("Failed to get metadata of {:?}", blkdevice))?
@@ -84,16 +117,29 @@ fn encryptedstore_init(blkdevice: &Path, key: &str, mountpoint: &Path) -> Result
 
     let needs_formatting =
         needs_formatting(blkdevice).context("Unable to check if formatting is required")?;
-    let crypt_device =
-        enable_crypt(blkdevice, key, "cryptdev").context("Unable to map crypt device")?;
+    // The devices are left over if a previous run failed after mapping them.
+    let dm = dm::DeviceMapper::new()?;
+    // The crypt device is removed before the integrity device below it, which it keeps open.
+    let stale_devices = ["cryptdev", "cryptdev-integrity", migrate::DEVICE_NAME];
+    for name in stale_devices.into_iter().chain(reencrypt::DEVICE_NAMES) {
+        remove_stale_device(&dm, name)?;
+    }
+    let crypt_device = map_storage(blkdevice, keys, needs_formatting, authenticated)
+        .context("Unable to map crypt device")?;
 
     // We might need to format it with filesystem if this is a "seen-for-the-first-time" device.
-    if needs_formatting {
+    let fs_size = if needs_formatting {
         info!("Freshly formatting the crypt device");
         format_ext4(&crypt_device)?;
-    }
+        None
+    } else {
+        Some(Ext4Size::read(&crypt_device).context("Unable to read the filesystem size")?)
+    };
     mount(&crypt_device, mountpoint)
         .with_context(|| format!("Unable to mount {:?}", crypt_device))?;
+    if let Some(fs_size) = fs_size {
+        grow_ext4(&crypt_device, mountpoint, fs_size)?;
+    }
     if cfg!(multi_tenant) && needs_formatting {
         set_root_dir_permissions(mountpoint)?;
     }
@@ -109,15 +155,165 @@ fn set_root_dir_permissions(mountpoint: &Path) -> Result<()> {
     std::fs::set_permissions(mountpoint, permissions).context("Failed to chmod root directory")
 }
 
-fn enable_crypt(data_device: &Path, key: &str, name: &str) -> Result<PathBuf> {
-    let dev_size = util::blkgetsize64(data_device)?;
-    let key = hex::decode(key).context("Unable to decode hex key")?;
+/// The keys the storage can be encrypted with, by version.
+struct Keys(BTreeMap<u32, Zeroizing<[u8; KEY_SIZE]>>);
+
+impl Keys {
+    /// Parses the keys, which are a sequence of records made of a little-endian u32 version
+    /// followed by the key.
+    fn parse(buf: &[u8]) -> Result<Self> {
+        const RECORD_SIZE: usize = 4 + KEY_SIZE;
+        ensure!(!buf.is_empty(), "No key");
+        ensure!(buf.len() % RECORD_SIZE == 0, "Invalid size of the keys: {}", buf.len());
+        let mut keys = BTreeMap::new();
+        for record in buf.chunks_exact(RECORD_SIZE) {
+            let version = u32::from_le_bytes(record[..4].try_into().unwrap());
+            ensure!(version != 0, "Invalid key version 0");
+            let key = Zeroizing::new(record[4..].try_into().unwrap());
+            ensure!(keys.insert(version, key).is_none(), "Duplicate key version {}", version);
+        }
+        Ok(Self(keys))
+    }
+
+    /// Returns the key of the given version.
+    fn get(&self, version: u32) -> Result<&[u8]> {
+        match self.0.get(&version) {
+            Some(key) => Ok(key.as_slice()),
+            None => bail!("No key of version {}", version),
+        }
+    }
+
+    /// Returns the latest version of the keys, which new storage is encrypted with.
+    fn current_version(&self) -> u32 {
+        *self.0.keys().next_back().unwrap()
+    }
+}
+
+fn read_keys(mut file: File) -> Result<Keys> {
+    let mut buf = Zeroizing::new(Vec::new());
+    file.read_to_end(&mut buf).context("Unable to read the keys")?;
+    Keys::parse(&buf)
+}
+
+/// Maps the crypt device of the storage, first re-encrypting it with the current key if needed.
+/// If `needs_formatting`, the storage is formatted with authenticated encryption if
+/// `authenticated`. Otherwise it keeps the encryption it was formatted with.
+fn map_storage(
+    blkdevice: &Path,
+    keys: &Keys,
+    needs_formatting: bool,
+    authenticated: bool,
+) -> Result<PathBuf> {
+    let device = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(blkdevice)
+        .with_context(|| format!("Failed to open {:?}", blkdevice))?;
+    if needs_formatting {
+        Header { key_version: keys.current_version(), authenticated, ..Default::default() }
+            .write(&device)?;
+    }
+    let dev_size = util::blkgetsize64(blkdevice)?;
+    let header = match Header::read(&device)? {
+        Some(header) => Some(header),
+        None if keys.current_version() != LEGACY_KEY_VERSION => {
+            let legacy_key = keys.get(LEGACY_KEY_VERSION)?;
+            migrate::migrate(blkdevice, &device, legacy_key, LEGACY_KEY_VERSION, dev_size)?
+        }
+        None => None,
+    };
+    let Some(mut header) = header else {
+        info!("The storage has no header, it is encrypted with key version {}", LEGACY_KEY_VERSION);
+        if keys.current_version() != LEGACY_KEY_VERSION {
+            warn!(
+                "Storage without a header can't be re-encrypted with key version {} until its \
+                 image is grown by {} bytes",
+                keys.current_version(),
+                migrate::MIGRATION_SPACE
+            );
+        }
+        return enable_crypt(blkdevice, keys.get(LEGACY_KEY_VERSION)?, "cryptdev", 0, dev_size);
+    };
+    if header.authenticated {
+        return map_authenticated_storage(blkdevice, keys, &header, needs_formatting);
+    }
+    if authenticated {
+        warn!("The storage was formatted without authenticated encryption, which it keeps");
+    }
+    ensure!(dev_size > DATA_OFFSET, "The block device is too small: {} bytes", dev_size);
+    // The data is resized along with the block device, which the host may have grown.
+    let data_size = (dev_size - DATA_OFFSET) / BLOCK_SIZE * BLOCK_SIZE;
 
+    if header.is_reencrypting() {
+        let (old_key, new_key) = (keys.get(header.old_key_version)?, keys.get(header.key_version)?);
+        reencrypt::reencrypt(blkdevice, &device, &mut header, old_key, new_key, data_size)?;
+    }
+    let current_version = keys.current_version();
+    if header.key_version != current_version {
+        ensure!(
+            header.key_version < current_version,
+            "The storage is encrypted with key version {}, newer than {}",
+            header.key_version,
+            current_version
+        );
+        header.old_key_version = header.key_version;
+        header.key_version = current_version;
+        header.progress = 0;
+        header.journal_size = 0;
+        header.write(&device)?;
+        let (old_key, new_key) = (keys.get(header.old_key_version)?, keys.get(header.key_version)?);
+        reencrypt::reencrypt(blkdevice, &device, &mut header, old_key, new_key, data_size)?;
+    }
+    enable_crypt(blkdevice, keys.get(header.key_version)?, "cryptdev", DATA_OFFSET, data_size)
+}
+
+/// Maps the crypt device of authenticated storage, whose header is `header`, formatting it first if
+/// `format`.
+///
+/// Authenticated storage isn't re-encrypted: the tags dm-integrity stores for each sector aren't
+/// part of the data a chunk of the journal could save. It therefore stays encrypted with the key it
+/// was formatted with. It isn't grown either, as dm-integrity fixes the size of the data when it is
+/// formatted.
+fn map_authenticated_storage(
+    blkdevice: &Path,
+    keys: &Keys,
+    header: &Header,
+    format: bool,
+) -> Result<PathBuf> {
+    if header.key_version != keys.current_version() {
+        info!("Authenticated storage stays encrypted with key version {}", header.key_version);
+    }
+    let dm = dm::DeviceMapper::new()?;
+    ensure!(
+        dm.target_version("integrity")?.is_some(),
+        "Authenticated storage requires dm-integrity, which the kernel doesn't support"
+    );
+    dm.create_authenticated_crypt_device(
+        "cryptdev",
+        blkdevice,
+        AUTHENTICATED_DATA_OFFSET / 512,
+        CipherType::AES256GCM,
+        keys.get(header.key_version)?,
+        BLOCK_SIZE as u32,
+        format,
+    )
+    .context("Failed to create the authenticated crypt device")
+}
+
+/// Maps a crypt device named `name` over the `size` bytes at `offset` of `data_device`.
+fn enable_crypt(
+    data_device: &Path,
+    key: &[u8],
+    name: &str,
+    offset: u64,
+    size: u64,
+) -> Result<PathBuf> {
     // Create the dm-crypt spec
     let target = dm::crypt::DmCryptTargetBuilder::default()
-        .data_device(data_device, dev_size)
+        .data_device(data_device, size)
+        .offset(offset / 512)
         .cipher(CipherType::AES256HCTR2)
-        .key(&key)
+        .key(key)
         .opt_param("sector_size:4096")
         .opt_param("iv_large_sectors")
         .build()
@@ -126,6 +322,15 @@ fn enable_crypt(data_device: &Path, key: &str, name: &str) -> Result<PathBuf> {
     dm.create_crypt_device(name, &target).context("Failed to create dm-crypt device")
 }
 
+fn remove_stale_device(dm: &dm::DeviceMapper, name: &str) -> Result<()> {
+    if let Some(device) = dm.info(name)? {
+        ensure!(device.open_count == 0, "{} already exists and is in use", name);
+        info!("Removing stale device {}", name);
+        dm.delete_device_deferred(name)?;
+    }
+    Ok(())
+}
+
 // The disk contains UNFORMATTED_STORAGE_MAGIC to indicate we need to format the crypt device.
 // This function looks for it, zeroing it, if present.
 fn needs_formatting(data_device: &Path) -> Result<bool> {
@@ -173,6 +378,17 @@ fn format_ext4(device: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Grows the filesystem mounted at `mountpoint`, of size `fs_size`, to fill `device` if the host
+/// has enlarged the storage since it was last mounted.
+fn grow_ext4(device: &Path, mountpoint: &Path, fs_size: Ext4Size) -> Result<()> {
+    let blocks_count = util::blkgetsize64(device)? / fs_size.block_size;
+    if blocks_count > fs_size.blocks_count {
+        info!("Growing the filesystem from {} to {} blocks", fs_size.blocks_count, blocks_count);
+        ext4::resize(mountpoint, blocks_count).context("Unable to grow the filesystem")?;
+    }
+    Ok(())
+}
+
 fn mount(source: &Path, mountpoint: &Path) -> Result<()> {
     create_dir_all(mountpoint).with_context(|| format!("Failed to create {:?}", &mountpoint))?;
     let mount_options = CString::new(
@@ -202,13 +418,39 @@ fn mount(source: &Path, mountpoint: &Path) -> Result<()> {
     }
 }
 
+#[cfg(test)]
+rdroidtest::test_main!();
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rdroidtest::rdroidtest;
 
-    #[test]
+    #[rdroidtest]
     fn verify_command() {
         // Check that the command parsing has been configured in a valid way.
         clap_command().debug_assert();
     }
+
+    fn record(version: u32, key: u8) -> Vec<u8> {
+        [&version.to_le_bytes()[..], &[key; KEY_SIZE]].concat()
+    }
+
+    #[rdroidtest]
+    fn parse_keys() {
+        let keys = Keys::parse(&[record(2, 0xbb), record(1, 0xaa)].concat()).unwrap();
+
+        assert_eq!(keys.current_version(), 2);
+        assert_eq!(keys.get(1).unwrap(), &[0xaa; KEY_SIZE]);
+        assert_eq!(keys.get(2).unwrap(), &[0xbb; KEY_SIZE]);
+        assert!(keys.get(3).is_err());
+    }
+
+    #[rdroidtest]
+    fn parse_invalid_keys() {
+        assert!(Keys::parse(&[]).is_err());
+        assert!(Keys::parse(&record(1, 0xaa)[1..]).is_err());
+        assert!(Keys::parse(&record(0, 0xaa)).is_err());
+        assert!(Keys::parse(&[record(1, 0xaa), record(1, 0xbb)].concat()).is_err());
+    }
 }
@@ -0,0 +1,213 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Migration of storage formatted before the header was introduced, whose data starts at the
+//! beginning of the block device, to the layout with a header.
+//!
+//! The data is moved forward by `DATA_OFFSET`, as it is on the disk: dm-crypt derives the IV of a
+//! sector from its position in the crypt device, not on the block device, so the moved data is
+//! still encrypted with the legacy key. This needs room after the data, which the storage has once
+//! the host grows its image by `MIGRATION_SPACE` bytes.
+//!
+//! The data is moved in chunks, starting from the end. As chunks are smaller than `DATA_OFFSET`,
+//! moving a chunk never overwrites the data of the chunks which aren't moved yet, so a chunk can
+//! be moved again if the VM is killed half way. The number of bytes moved so far is recorded in a
+//! trailer, right after where the data is moved to. The header is written once all the data is
+//! moved, which ends the migration.
+
+use crate::ext4::Ext4Size;
+use crate::header::{Header, CHUNK_SIZE, DATA_OFFSET};
+use anyhow::{ensure, Context, Result};
+use log::info;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// Name of the device mapped to read the size of the data.
+pub const DEVICE_NAME: &str = "cryptdev-legacy";
+
+const TRAILER_MAGIC: &[u8; 16] = b"ENCRYPTEDSTORE-M";
+const TRAILER_WRITE_SIZE: usize = 512;
+const TRAILER_SIZE: u64 = 4096;
+
+/// How many bytes the block device must have beyond the data for it to be migrated.
+pub const MIGRATION_SPACE: u64 = DATA_OFFSET + TRAILER_SIZE;
+
+/// The progress of the migration.
+#[derive(Debug, Eq, PartialEq)]
+struct Trailer {
+    /// Number of bytes of data to move.
+    data_size: u64,
+    /// Number of bytes at the end of the data which are moved.
+    moved: u64,
+}
+
+impl Trailer {
+    fn offset(data_size: u64) -> u64 {
+        DATA_OFFSET + data_size
+    }
+
+    /// Reads the trailer of the migration of `data_size` bytes, or returns `None` if it hasn't
+    /// started.
+    fn read(device: &File, data_size: u64) -> Result<Option<Self>> {
+        let mut buf = [0; TRAILER_WRITE_SIZE];
+        device
+            .read_exact_at(&mut buf, Self::offset(data_size))
+            .context("Failed to read the migration trailer")?;
+        if &buf[0..16] != TRAILER_MAGIC {
+            return Ok(None);
+        }
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
+        let trailer = Self { data_size: u64_at(16), moved: u64_at(24) };
+        if trailer.data_size != data_size {
+            return Ok(None);
+        }
+        ensure!(trailer.moved <= data_size, "Invalid migration progress {}", trailer.moved);
+        Ok(Some(trailer))
+    }
+
+    /// Writes the trailer to `device`, and waits until it is on the disk.
+    fn write(&self, device: &File) -> Result<()> {
+        let mut buf = [0; TRAILER_WRITE_SIZE];
+        buf[0..16].copy_from_slice(TRAILER_MAGIC);
+        buf[16..24].copy_from_slice(&self.data_size.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.moved.to_le_bytes());
+        device
+            .write_all_at(&buf, Self::offset(self.data_size))
+            .context("Failed to write the migration trailer")?;
+        device.sync_data().context("Failed to sync the migration trailer")
+    }
+}
+
+/// Migrates the storage without a header on `blkdevice`, of `dev_size` bytes, whose data is
+/// encrypted with `legacy_key`, resuming from where a previous attempt stopped. Returns the header
+/// it is migrated with, or `None` if the block device has no room to migrate it.
+pub fn migrate(
+    blkdevice: &Path,
+    device: &File,
+    legacy_key: &[u8],
+    key_version: u32,
+    dev_size: u64,
+) -> Result<Option<Header>> {
+    let data_size = data_size(blkdevice, legacy_key, dev_size)?;
+    if dev_size < data_size + MIGRATION_SPACE {
+        info!(
+            "The storage has no room to be migrated: {} of {} bytes are used",
+            data_size, dev_size
+        );
+        return Ok(None);
+    }
+
+    let mut trailer = match Trailer::read(device, data_size)? {
+        Some(trailer) => trailer,
+        None => {
+            let trailer = Trailer { data_size, moved: 0 };
+            trailer.write(device)?;
+            trailer
+        }
+    };
+    info!("Migrating the storage, {} of {} bytes done", trailer.moved, data_size);
+    move_data(device, &mut trailer)?;
+
+    let header = Header { key_version, ..Default::default() };
+    header.write(device)?;
+    info!("Migrated the storage");
+    Ok(Some(header))
+}
+
+/// Returns the size of the data, which is the size of the filesystem on it.
+fn data_size(blkdevice: &Path, legacy_key: &[u8], dev_size: u64) -> Result<u64> {
+    let legacy_device = crate::enable_crypt(blkdevice, legacy_key, DEVICE_NAME, 0, dev_size)?;
+    let fs_size = Ext4Size::read(&legacy_device);
+    dm::DeviceMapper::new()?.delete_device_deferred(DEVICE_NAME)?;
+    let fs_size = fs_size.context("Unable to read the filesystem size")?;
+    let data_size = fs_size.block_size * fs_size.blocks_count;
+    ensure!(data_size <= dev_size, "The filesystem is larger than the block device");
+    Ok(data_size)
+}
+
+fn move_data(device: &File, trailer: &mut Trailer) -> Result<()> {
+    let mut buf = vec![0; CHUNK_SIZE as usize];
+    while trailer.moved < trailer.data_size {
+        let chunk = &mut buf[..(trailer.data_size - trailer.moved).min(CHUNK_SIZE) as usize];
+        let offset = trailer.data_size - trailer.moved - chunk.len() as u64;
+
+        device.read_exact_at(chunk, offset)?;
+        device.write_all_at(chunk, DATA_OFFSET + offset)?;
+        device.sync_data()?;
+        trailer.moved += chunk.len() as u64;
+        trailer.write(device)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdroidtest::rdroidtest;
+
+    /// The first chunk of the data is only partially filled.
+    const DATA_SIZE: u64 = 2 * CHUNK_SIZE + CHUNK_SIZE / 2;
+
+    /// Returns a storage without a header, with room to be migrated, and its data.
+    fn legacy_storage() -> (File, Vec<u8>) {
+        let device = tempfile::tempfile().unwrap();
+        device.set_len(DATA_SIZE + MIGRATION_SPACE).unwrap();
+        let data: Vec<u8> = (0..DATA_SIZE).map(|i| (i % 251) as u8).collect();
+        device.write_all_at(&data, 0).unwrap();
+        (device, data)
+    }
+
+    fn read_moved_data(device: &File) -> Vec<u8> {
+        let mut data = vec![0; DATA_SIZE as usize];
+        device.read_exact_at(&mut data, DATA_OFFSET).unwrap();
+        data
+    }
+
+    #[rdroidtest]
+    fn move_all_data() {
+        let (device, data) = legacy_storage();
+        assert_eq!(Trailer::read(&device, DATA_SIZE).unwrap(), None);
+        let mut trailer = Trailer { data_size: DATA_SIZE, moved: 0 };
+
+        move_data(&device, &mut trailer).unwrap();
+
+        assert_eq!(read_moved_data(&device), data);
+        assert_eq!(
+            Trailer::read(&device, DATA_SIZE).unwrap(),
+            Some(Trailer { data_size: DATA_SIZE, moved: DATA_SIZE })
+        );
+    }
+
+    #[rdroidtest]
+    fn resume_interrupted_move() {
+        let (device, data) = legacy_storage();
+
+        // Interrupt the move of the second chunk from the end after half of it is written.
+        let last_chunk = &data[(DATA_SIZE - CHUNK_SIZE) as usize..];
+        device.write_all_at(last_chunk, DATA_OFFSET + DATA_SIZE - CHUNK_SIZE).unwrap();
+        Trailer { data_size: DATA_SIZE, moved: CHUNK_SIZE }.write(&device).unwrap();
+        let offset = DATA_SIZE - 2 * CHUNK_SIZE;
+        let half_chunk = &data[offset as usize..(offset + CHUNK_SIZE / 2) as usize];
+        device.write_all_at(half_chunk, DATA_OFFSET + offset).unwrap();
+
+        let mut trailer = Trailer::read(&device, DATA_SIZE).unwrap().unwrap();
+        move_data(&device, &mut trailer).unwrap();
+
+        assert_eq!(read_moved_data(&device), data);
+    }
+}
@@ -0,0 +1,279 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Re-encryption of the data with a new key, in place and in chunks, so that it can be resumed
+//! after being interrupted, e.g. by the VM being killed.
+//!
+//! The data before `Header::progress` is encrypted with the new key, and the rest with the old
+//! key. Before a chunk is rewritten, it is copied as is to the journal, so that it can be restored
+//! if the rewrite is interrupted half way.
+
+use crate::header::{Header, CHUNK_SIZE, DATA_OFFSET, JOURNAL_OFFSET};
+use anyhow::{Context, Result};
+use log::info;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+const OLD_DEVICE_NAME: &str = "cryptdev-old";
+const NEW_DEVICE_NAME: &str = "cryptdev-new";
+
+/// Names of the devices mapped while re-encrypting.
+pub const DEVICE_NAMES: [&str; 2] = [OLD_DEVICE_NAME, NEW_DEVICE_NAME];
+
+/// Re-encrypts the `data_size` bytes of data on `blkdevice`, whose header is `header`, from
+/// `old_key` to `new_key`, resuming from where a previous attempt stopped.
+pub fn reencrypt(
+    blkdevice: &Path,
+    device: &File,
+    header: &mut Header,
+    old_key: &[u8],
+    new_key: &[u8],
+    data_size: u64,
+) -> Result<()> {
+    info!(
+        "Re-encrypting the storage from key version {} to {}, {} of {} bytes done",
+        header.old_key_version, header.key_version, header.progress, data_size
+    );
+    // The chunk might be partially rewritten, so restore it before the old key device is mapped
+    // over it.
+    restore_journal(device, header)?;
+
+    let dm = dm::DeviceMapper::new()?;
+    let result = (|| {
+        let old_device =
+            crate::enable_crypt(blkdevice, old_key, OLD_DEVICE_NAME, DATA_OFFSET, data_size)?;
+        let new_device =
+            crate::enable_crypt(blkdevice, new_key, NEW_DEVICE_NAME, DATA_OFFSET, data_size)?;
+        let old_device =
+            File::open(&old_device).with_context(|| format!("Failed to open {:?}", old_device))?;
+        let new_device = OpenOptions::new()
+            .write(true)
+            .open(&new_device)
+            .with_context(|| format!("Failed to open {:?}", new_device))?;
+        reencrypt_chunks(device, &old_device, &new_device, header, data_size)
+    })();
+    for name in DEVICE_NAMES {
+        if dm.info(name)?.is_some() {
+            dm.delete_device_deferred(name)?;
+        }
+    }
+    result?;
+
+    info!("Re-encrypted the storage with key version {}", header.key_version);
+    header.old_key_version = 0;
+    header.progress = 0;
+    header.write(device)
+}
+
+/// Copies the chunk saved in the journal, if any, back to where it was in the data.
+fn restore_journal(device: &File, header: &Header) -> Result<()> {
+    if header.journal_size == 0 {
+        return Ok(());
+    }
+    let mut chunk = vec![0; header.journal_size as usize];
+    device.read_exact_at(&mut chunk, JOURNAL_OFFSET).context("Failed to read the journal")?;
+    device
+        .write_all_at(&chunk, DATA_OFFSET + header.progress)
+        .context("Failed to restore the journal")?;
+    device.sync_data().context("Failed to sync the restored journal")
+}
+
+fn reencrypt_chunks(
+    device: &File,
+    old_device: &File,
+    new_device: &File,
+    header: &mut Header,
+    data_size: u64,
+) -> Result<()> {
+    let mut buf = vec![0; CHUNK_SIZE as usize];
+    while header.progress < data_size {
+        let chunk = &mut buf[..(data_size - header.progress).min(CHUNK_SIZE) as usize];
+
+        device.read_exact_at(chunk, DATA_OFFSET + header.progress)?;
+        device.write_all_at(chunk, JOURNAL_OFFSET).context("Failed to write the journal")?;
+        device.sync_data()?;
+        header.journal_size = chunk.len() as u64;
+        header.write(device)?;
+
+        old_device.read_exact_at(chunk, header.progress)?;
+        new_device.write_all_at(chunk, header.progress)?;
+        new_device.sync_data()?;
+        header.progress += chunk.len() as u64;
+        header.journal_size = 0;
+        header.write(device)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dm::{loopdevice, util};
+    use rdroidtest::{ignore_if, rdroidtest};
+    use rustutils::system_properties;
+    use std::path::PathBuf;
+
+    const OLD_KEY: &[u8] = b"thirtytwobyteslongreallylongword";
+    const NEW_KEY: &[u8] = b"drowgnolyllaergnolsetybowtytriht";
+    /// The last chunk of the data is only partially filled.
+    const DATA_SIZE: u64 = 2 * CHUNK_SIZE + CHUNK_SIZE / 2;
+
+    fn is_hctr2_supported() -> bool {
+        // hctr2 is NOT enabled in kernel 5.10 or lower. We run Microdroid tests on kernel versions
+        // 5.10 or above & therefore,  we don't really care to skip test on other versions.
+        if let Some(version) = system_properties::read("ro.kernel.version")
+            .expect("Unable to read system property ro.kernel.version")
+        {
+            version != "5.10"
+        } else {
+            panic!("Could not read property: kernel.version!!");
+        }
+    }
+
+    /// Creates the backing file of a storage in `test_dir`, and attaches it to a loop device.
+    fn attach_storage(test_dir: &Path) -> PathBuf {
+        let backing_file = test_dir.join("storage");
+        let size = DATA_OFFSET + DATA_SIZE;
+        File::create(&backing_file).unwrap().set_len(size).unwrap();
+        loopdevice::attach(
+            backing_file,
+            0,
+            size,
+            /* direct_io */ true,
+            /* writable */ true,
+        )
+        .unwrap()
+    }
+
+    /// Maps a crypt device named `name` over the data of the storage on `blkdevice`.
+    fn map_data(blkdevice: &Path, key: &[u8], name: &str) -> PathBuf {
+        crate::enable_crypt(blkdevice, key, name, DATA_OFFSET, DATA_SIZE).unwrap()
+    }
+
+    fn open(path: &Path) -> File {
+        OpenOptions::new().read(true).write(true).open(path).unwrap()
+    }
+
+    /// Deletes the device `name`, and waits until its `path` is gone.
+    fn delete_device(dm: &dm::DeviceMapper, name: &str, path: &Path) -> Result<()> {
+        dm.delete_device_deferred(name)?;
+        util::wait_for_path_disappears(path)
+    }
+
+    fn read_data(device: &File) -> Vec<u8> {
+        let mut data = vec![0; DATA_SIZE as usize];
+        device.read_exact_at(&mut data, 0).unwrap();
+        data
+    }
+
+    #[rdroidtest]
+    #[ignore_if(!is_hctr2_supported())]
+    fn reencrypt_all_chunks() {
+        let dm = dm::DeviceMapper::new().unwrap();
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let blkdevice = attach_storage(test_dir.path());
+        scopeguard::defer! {
+            loopdevice::detach(&blkdevice).unwrap();
+        }
+        let device = open(&blkdevice);
+        let old_path = map_data(&blkdevice, OLD_KEY, "reencrypt-all-0");
+        scopeguard::defer! {
+            let _ignored = delete_device(&dm, "reencrypt-all-0", &old_path);
+        }
+        let new_path = map_data(&blkdevice, NEW_KEY, "reencrypt-all-1");
+        scopeguard::defer! {
+            let _ignored = delete_device(&dm, "reencrypt-all-1", &new_path);
+        }
+        let (old_device, new_device) = (open(&old_path), open(&new_path));
+        let plaintext: Vec<u8> = (0..DATA_SIZE).map(|i| (i % 251) as u8).collect();
+        old_device.write_all_at(&plaintext, 0).unwrap();
+        old_device.sync_data().unwrap();
+        let mut header = Header { key_version: 2, old_key_version: 1, ..Default::default() };
+
+        reencrypt_chunks(&device, &old_device, &new_device, &mut header, DATA_SIZE).unwrap();
+
+        assert_eq!(header.progress, DATA_SIZE);
+        assert_eq!(header.journal_size, 0);
+        assert_eq!(Header::read(&device).unwrap(), Some(header));
+        let check_path = map_data(&blkdevice, NEW_KEY, "reencrypt-all-2");
+        scopeguard::defer! {
+            let _ignored = delete_device(&dm, "reencrypt-all-2", &check_path);
+        }
+        assert_eq!(read_data(&open(&check_path)), plaintext);
+    }
+
+    #[rdroidtest]
+    #[ignore_if(!is_hctr2_supported())]
+    fn resume_interrupted_reencryption() {
+        let dm = dm::DeviceMapper::new().unwrap();
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let blkdevice = attach_storage(test_dir.path());
+        scopeguard::defer! {
+            loopdevice::detach(&blkdevice).unwrap();
+        }
+        let device = open(&blkdevice);
+        let old_path = map_data(&blkdevice, OLD_KEY, "reencrypt-resume-0");
+        scopeguard::defer! {
+            let _ignored = delete_device(&dm, "reencrypt-resume-0", &old_path);
+        }
+        let new_path = map_data(&blkdevice, NEW_KEY, "reencrypt-resume-1");
+        scopeguard::defer! {
+            let _ignored = delete_device(&dm, "reencrypt-resume-1", &new_path);
+        }
+        let plaintext: Vec<u8> = (0..DATA_SIZE).map(|i| (i % 251) as u8).collect();
+        let mut header = Header { key_version: 2, old_key_version: 1, ..Default::default() };
+        {
+            let (old_device, new_device) = (open(&old_path), open(&new_path));
+            old_device.write_all_at(&plaintext, 0).unwrap();
+            old_device.sync_data().unwrap();
+            reencrypt_chunks(&device, &old_device, &new_device, &mut header, CHUNK_SIZE).unwrap();
+        }
+
+        // Interrupt the re-encryption of the second chunk after it is saved in the journal and
+        // half of it is overwritten.
+        let mut chunk = vec![0; CHUNK_SIZE as usize];
+        device.read_exact_at(&mut chunk, DATA_OFFSET + CHUNK_SIZE).unwrap();
+        device.write_all_at(&chunk, JOURNAL_OFFSET).unwrap();
+        header.journal_size = CHUNK_SIZE;
+        header.write(&device).unwrap();
+        let half_chunk = vec![0xff; CHUNK_SIZE as usize / 2];
+        device.write_all_at(&half_chunk, DATA_OFFSET + CHUNK_SIZE).unwrap();
+        device.sync_data().unwrap();
+
+        // The devices are mapped again, as they would be after the VM is restarted, so that
+        // nothing is read from the cache of the previous ones.
+        let mut header = Header::read(&device).unwrap().unwrap();
+        restore_journal(&device, &header).unwrap();
+        let old_path = map_data(&blkdevice, OLD_KEY, "reencrypt-resume-2");
+        scopeguard::defer! {
+            let _ignored = delete_device(&dm, "reencrypt-resume-2", &old_path);
+        }
+        let new_path = map_data(&blkdevice, NEW_KEY, "reencrypt-resume-3");
+        scopeguard::defer! {
+            let _ignored = delete_device(&dm, "reencrypt-resume-3", &new_path);
+        }
+        let (old_device, new_device) = (open(&old_path), open(&new_path));
+        reencrypt_chunks(&device, &old_device, &new_device, &mut header, DATA_SIZE).unwrap();
+
+        assert_eq!(header.progress, DATA_SIZE);
+        let check_path = map_data(&blkdevice, NEW_KEY, "reencrypt-resume-4");
+        scopeguard::defer! {
+            let _ignored = delete_device(&dm, "reencrypt-resume-4", &check_path);
+        }
+        assert_eq!(read_data(&open(&check_path)), plaintext);
+    }
+}
@@ -55,9 +55,10 @@ static constexpr const char* kAllowedLibs[] = {
 };
 
 int main(int argc, char* argv[]) {
-    if (argc != 2) {
+    // Arguments after LIBNAME are for the payload, which can find them in its command line.
+    if (argc < 2) {
         std::cout << "Usage:\n";
-        std::cout << "    " << argv[0] << " LIBNAME\n";
+        std::cout << "    " << argv[0] << " LIBNAME [ARG...]\n";
         return EXIT_FAILURE;
     }
 
@@ -19,8 +19,9 @@ use ciborium::{cbor, Value};
 use coset::CborSerializable;
 use dice_driver::DiceDriver;
 use diced_open_dice::{Hidden, OwnedDiceArtifacts, HIDDEN_SIZE};
-use microdroid_metadata::PayloadMetadata;
+use microdroid_metadata::{PayloadMetadata, PayloadTaskOverrides};
 use openssl::sha::{sha512, Sha512};
+use std::collections::BTreeMap;
 use std::iter::once;
 
 /// Perform an open DICE derivation for the payload.
@@ -28,10 +29,12 @@ pub fn dice_derivation(
     dice: DiceDriver,
     instance_data: &MicrodroidData,
     payload_metadata: &PayloadMetadata,
+    task_overrides: Option<&PayloadTaskOverrides>,
 ) -> Result<OwnedDiceArtifacts> {
     let subcomponents = build_subcomponent_list(instance_data);
-    let config_descriptor = format_payload_config_descriptor(payload_metadata, subcomponents)
-        .context("Building config descriptor")?;
+    let config_descriptor =
+        format_payload_config_descriptor(payload_metadata, task_overrides, subcomponents)
+            .context("Building config descriptor")?;
 
     // Calculate compound digests of code and authorities
     let mut code_hash_ctx = Sha512::new();
@@ -138,6 +141,7 @@ fn build_subcomponent_list(instance_data: &MicrodroidData) -> Vec<Subcomponent>
 // definition of the format.
 fn format_payload_config_descriptor(
     payload: &PayloadMetadata,
+    task_overrides: Option<&PayloadTaskOverrides>,
     subcomponents: Vec<Subcomponent>,
 ) -> Result<Vec<u8>> {
     let mut map = Vec::new();
@@ -152,6 +156,10 @@ fn format_payload_config_descriptor(
         _ => bail!("Failed to match the payload against a config type: {:?}", payload),
     });
 
+    if let Some(task_overrides) = task_overrides {
+        map.push((cbor!(-71004)?, format_task_overrides(task_overrides)?));
+    }
+
     if !subcomponents.is_empty() {
         let values =
             subcomponents.into_iter().map(Subcomponent::into_value).collect::<Result<Vec<_>>>()?;
@@ -161,6 +169,20 @@ fn format_payload_config_descriptor(
     Ok(Value::Map(map).to_vec()?)
 }
 
+// Returns the descriptor of how the host overrides the way the main task of the payload is run.
+// The environment variables are sorted by name, so that the descriptor is stable.
+fn format_task_overrides(task_overrides: &PayloadTaskOverrides) -> Result<Value> {
+    let mut map = Vec::new();
+    if task_overrides.override_args {
+        map.push((cbor!(1)?, cbor!(task_overrides.args)?));
+    }
+    if !task_overrides.env.is_empty() {
+        let env: BTreeMap<_, _> = task_overrides.env.iter().collect();
+        map.push((cbor!(2)?, cbor!(env)?));
+    }
+    Ok(Value::Map(map))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -182,7 +204,7 @@ mod tests {
     fn payload_metadata_with_path_formats_correctly() -> Result<()> {
         let payload_metadata = PayloadMetadata::ConfigPath("/config_path".to_string());
         let config_descriptor =
-            format_payload_config_descriptor(&payload_metadata, NO_SUBCOMPONENTS)?;
+            format_payload_config_descriptor(&payload_metadata, None, NO_SUBCOMPONENTS)?;
         static EXPECTED_CONFIG_DESCRIPTOR: &[u8] = &[
             0xa2, 0x3a, 0x00, 0x01, 0x11, 0x71, 0x72, 0x4d, 0x69, 0x63, 0x72, 0x6f, 0x64, 0x72,
             0x6f, 0x69, 0x64, 0x20, 0x70, 0x61, 0x79, 0x6c, 0x6f, 0x61, 0x64, 0x3a, 0x00, 0x01,
@@ -201,7 +223,7 @@ mod tests {
         };
         let payload_metadata = PayloadMetadata::Config(payload_config);
         let config_descriptor =
-            format_payload_config_descriptor(&payload_metadata, NO_SUBCOMPONENTS)?;
+            format_payload_config_descriptor(&payload_metadata, None, NO_SUBCOMPONENTS)?;
         static EXPECTED_CONFIG_DESCRIPTOR: &[u8] = &[
             0xa2, 0x3a, 0x00, 0x01, 0x11, 0x71, 0x72, 0x4d, 0x69, 0x63, 0x72, 0x6f, 0x64, 0x72,
             0x6f, 0x69, 0x64, 0x20, 0x70, 0x61, 0x79, 0x6c, 0x6f, 0x61, 0x64, 0x3a, 0x00, 0x01,
@@ -212,6 +234,35 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn payload_metadata_with_task_overrides_formats_correctly() -> Result<()> {
+        let payload_metadata = PayloadMetadata::ConfigPath("/config_path".to_string());
+        let task_overrides = PayloadTaskOverrides {
+            override_args: true,
+            args: vec!["--port".to_string(), "5678".to_string()],
+            env: [("FOO", "1"), ("BAR", "2")]
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            ..Default::default()
+        };
+        let config_descriptor = format_payload_config_descriptor(
+            &payload_metadata,
+            Some(&task_overrides),
+            NO_SUBCOMPONENTS,
+        )?;
+        static EXPECTED_CONFIG_DESCRIPTOR: &[u8] = &[
+            0xa3, 0x3a, 0x00, 0x01, 0x11, 0x71, 0x72, 0x4d, 0x69, 0x63, 0x72, 0x6f, 0x64, 0x72,
+            0x6f, 0x69, 0x64, 0x20, 0x70, 0x61, 0x79, 0x6c, 0x6f, 0x61, 0x64, 0x3a, 0x00, 0x01,
+            0x15, 0x57, 0x6c, 0x2f, 0x63, 0x6f, 0x6e, 0x66, 0x69, 0x67, 0x5f, 0x70, 0x61, 0x74,
+            0x68, 0x3a, 0x00, 0x01, 0x15, 0x5b, 0xa2, 0x01, 0x82, 0x66, 0x2d, 0x2d, 0x70, 0x6f,
+            0x72, 0x74, 0x64, 0x35, 0x36, 0x37, 0x38, 0x02, 0xa2, 0x63, 0x42, 0x41, 0x52, 0x61,
+            0x32, 0x63, 0x46, 0x4f, 0x4f, 0x61, 0x31,
+        ];
+        assert_eq_bytes(EXPECTED_CONFIG_DESCRIPTOR, &config_descriptor);
+        Ok(())
+    }
+
     #[test]
     fn payload_metadata_with_subcomponents_formats_correctly() -> Result<()> {
         let payload_metadata = PayloadMetadata::ConfigPath("/config_path".to_string());
@@ -229,7 +280,8 @@ mod tests {
                 authority_hash: vec![19, 20],
             },
         ];
-        let config_descriptor = format_payload_config_descriptor(&payload_metadata, subcomponents)?;
+        let config_descriptor =
+            format_payload_config_descriptor(&payload_metadata, None, subcomponents)?;
         // Verified using cbor.me.
         static EXPECTED_CONFIG_DESCRIPTOR: &[u8] = &[
             0xa3, 0x3a, 0x00, 0x01, 0x11, 0x71, 0x72, 0x4d, 0x69, 0x63, 0x72, 0x6f, 0x64, 0x72,
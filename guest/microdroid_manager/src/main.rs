@@ -18,6 +18,7 @@ mod dice;
 mod instance;
 mod ioutil;
 mod payload;
+mod supervisor;
 mod swap;
 mod verify;
 mod vm_payload_service;
@@ -33,6 +34,7 @@ use android_system_virtualization_payload::aidl::android::system::virtualization
 
 use crate::dice::dice_derivation;
 use crate::instance::{InstanceDisk, MicrodroidData};
+use crate::supervisor::{payload_tasks, run_tasks};
 use crate::verify::verify_payload;
 use crate::vm_payload_service::register_vm_payload_service;
 use anyhow::{anyhow, bail, ensure, Context, Error, Result};
@@ -40,11 +42,10 @@ use binder::Strong;
 use dice_driver::DiceDriver;
 use keystore2_crypto::ZVec;
 use libc::VMADDR_CID_HOST;
-use log::{error, info};
+use log::{error, info, warn};
 use microdroid_metadata::{Metadata, PayloadMetadata};
 use microdroid_payload_config::{ApkConfig, OsConfig, Task, TaskType, VmPayloadConfig};
 use nix::mount::{umount2, MntFlags};
-use nix::sys::signal::Signal;
 use payload::load_metadata;
 use rpcbinder::RpcSession;
 use rustutils::sockets::android_get_control_socket;
@@ -57,13 +58,12 @@ use std::ffi::CString;
 use std::fs::{self, create_dir, File, OpenOptions};
 use std::io::{Read, Write};
 use std::os::unix::io::OwnedFd;
-use std::os::unix::process::CommandExt;
-use std::os::unix::process::ExitStatusExt;
 use std::path::Path;
 use std::process::{Child, Command, Stdio};
 use std::str;
 use std::time::Duration;
 use vm_secret::VmSecret;
+use zeroize::Zeroizing;
 
 const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
 const AVF_STRICT_BOOT: &str = "/proc/device-tree/chosen/avf,strict-boot";
@@ -86,6 +86,12 @@ const FAILURE_SERIAL_DEVICE: &str = "/dev/ttyS1";
 
 const ENCRYPTEDSTORE_BACKING_DEVICE: &str = "/dev/block/by-name/encryptedstore";
 const ENCRYPTEDSTORE_KEYSIZE: usize = 32;
+/// Version of the key the encrypted storage is encrypted with, unless the payload config asks for a
+/// later one. Storage encrypted with an older version is re-encrypted by encryptedstore.
+const ENCRYPTEDSTORE_KEY_VERSION: u32 = 2;
+/// The latest version of the key which the payload config can ask for. The keys of all the versions
+/// up to the requested one are passed to encryptedstore.
+const MAX_ENCRYPTEDSTORE_KEY_VERSION: u32 = 64;
 
 const DICE_CHAIN_FILE: &str = "/microdroid_resources/dice_chain.raw";
 
@@ -121,6 +127,21 @@ fn translate_error(err: &Error) -> (ErrorCode, String) {
     }
 }
 
+/// Reports `err` as a payload verification failure if dm-verity found a corrupted block in an APK,
+/// which is then the likely cause of `err`, e.g. an I/O error reading the APK.
+fn blame_corrupted_apks(err: Error) -> Error {
+    match verify::find_corrupted_apks() {
+        Ok(apks) if !apks.is_empty() => err.context(MicrodroidError::PayloadVerificationFailed(
+            format!("dm-verity found corrupted blocks in {}", apks.join(", ")),
+        )),
+        Ok(_) => err,
+        Err(e) => {
+            warn!("Failed to check the APKs for corruption: {:?}", e);
+            err
+        }
+    }
+}
+
 fn write_death_reason_to_serial(err: &Error) -> Result<()> {
     let death_reason = if let Some(e) = err.downcast_ref::<MicrodroidError>() {
         Borrowed(match e {
@@ -234,6 +255,7 @@ fn try_main() -> Result<()> {
             Ok(())
         }
         Err(err) => {
+            let err = blame_corrupted_apks(err);
             let (error_code, message) = translate_error(&err);
             service.notifyError(error_code, &message)?;
             Err(err)
@@ -325,7 +347,9 @@ fn try_run_payload(
 
     // To minimize the exposure to untrusted data, derive dice profile as soon as possible.
     info!("DICE derivation for payload");
-    let dice_artifacts = dice_derivation(dice, &instance_data, &payload_metadata)?;
+    let task_overrides = metadata.task_overrides.into_option();
+    let dice_artifacts =
+        dice_derivation(dice, &instance_data, &payload_metadata, task_overrides.as_ref())?;
     let vm_secret =
         VmSecret::new(dice_artifacts, service).context("Failed to create VM secrets")?;
 
@@ -340,14 +364,6 @@ fn try_run_payload(
         umount2("/microdroid_resources", MntFlags::MNT_DETACH)?;
     }
 
-    // Run encryptedstore binary to prepare the storage
-    let encryptedstore_child = if Path::new(ENCRYPTEDSTORE_BACKING_DEVICE).exists() {
-        info!("Preparing encryptedstore ...");
-        Some(prepare_encryptedstore(&vm_secret).context("encryptedstore run")?)
-    } else {
-        None
-    };
-
     let mut zipfuse = Zipfuse::default();
 
     // Before reading a file from the APK, start zipfuse
@@ -369,10 +385,23 @@ fn try_run_payload(
 
     let config = load_config(payload_metadata).context("Failed to load payload metadata")?;
 
-    let task = config
-        .task
-        .as_ref()
-        .ok_or_else(|| MicrodroidError::PayloadInvalidConfig("No task in VM config".to_string()))?;
+    // Run encryptedstore binary to prepare the storage. This waits for the config, which tells
+    // whether new storage uses authenticated encryption.
+    let encryptedstore_child = if Path::new(ENCRYPTEDSTORE_BACKING_DEVICE).exists() {
+        info!("Preparing encryptedstore ...");
+        Some(
+            prepare_encryptedstore(
+                &vm_secret,
+                encryptedstore_key_version(&config)?,
+                config.authenticated_encrypted_storage,
+            )
+            .context("encryptedstore run")?,
+        )
+    } else {
+        None
+    };
+
+    let tasks = payload_tasks(&config, task_overrides.as_ref())?;
 
     ensure!(
         config.extra_apks.len() == instance_data.extra_apks_data.len(),
@@ -426,7 +455,7 @@ fn try_run_payload(
         .context("set microdroid_manager.init_done")?;
 
     info!("boot completed, time to run payload");
-    exec_task(task, service).context("Failed to run payload")
+    run_tasks(tasks, service, config.forward_output).context("Failed to run payload")
 }
 
 fn post_payload_work() -> Result<()> {
@@ -595,6 +624,7 @@ fn load_config(payload_metadata: PayloadMetadata) -> Result<VmPayloadConfig> {
             let task = Task {
                 type_: TaskType::MicrodroidLauncher,
                 command: payload_config.payload_binary_name,
+                ..Default::default()
             };
             // We don't care about the paths, only the number of extra APKs really matters.
             let extra_apks = (0..payload_config.extra_apk_count)
@@ -603,12 +633,16 @@ fn load_config(payload_metadata: PayloadMetadata) -> Result<VmPayloadConfig> {
             Ok(VmPayloadConfig {
                 os: OsConfig { name: "microdroid".to_owned() },
                 task: Some(task),
+                tasks: vec![],
                 apexes: vec![],
                 extra_apks,
                 prefer_staged: false,
                 export_tombstones: None,
                 enable_authfs: false,
                 hugepages: false,
+                forward_output: false,
+                authenticated_encrypted_storage: false,
+                encrypted_storage_key_version: None,
             })
         }
         _ => bail!("Failed to match config against a config type."),
@@ -640,55 +674,6 @@ fn load_crashkernel_if_supported() -> Result<()> {
     Ok(())
 }
 
-/// Executes the given task.
-fn exec_task(task: &Task, service: &Strong<dyn IVirtualMachineService>) -> Result<i32> {
-    info!("executing main task {:?}...", task);
-    let mut command = match task.type_ {
-        TaskType::Executable => {
-            // TODO(b/297501338): Figure out how to handle non-root for system payloads.
-            Command::new(&task.command)
-        }
-        TaskType::MicrodroidLauncher => {
-            let mut command = Command::new("/system/bin/microdroid_launcher");
-            command.arg(find_library_path(&task.command)?);
-            command.uid(microdroid_uids::MICRODROID_PAYLOAD_UID);
-            command.gid(microdroid_uids::MICRODROID_PAYLOAD_GID);
-            command
-        }
-    };
-
-    // SAFETY: We are not accessing any resource of the parent process. This means we can't make any
-    // log calls inside the closure.
-    unsafe {
-        command.pre_exec(|| {
-            // It is OK to continue with payload execution even if the calls below fail, since
-            // whether process can use a capability is controlled by the SELinux. Dropping the
-            // capabilities here is just another defense-in-depth layer.
-            let _ = cap::drop_inheritable_caps();
-            let _ = cap::drop_bounding_set();
-            Ok(())
-        });
-    }
-
-    command.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null());
-
-    info!("notifying payload started");
-    service.notifyPayloadStarted()?;
-
-    let exit_status = command.spawn()?.wait()?;
-    match exit_status.code() {
-        Some(exit_code) => Ok(exit_code),
-        None => Err(match exit_status.signal() {
-            Some(signal) => anyhow!(
-                "Payload exited due to signal: {} ({})",
-                signal,
-                Signal::try_from(signal).map_or("unknown", |s| s.as_str())
-            ),
-            None => anyhow!("Payload has neither exit code nor signal"),
-        }),
-    }
-}
-
 fn find_library_path(name: &str) -> Result<String> {
     let mut watcher = PropertyWatcher::new("ro.product.cpu.abilist")?;
     let value = watcher.read(|_name, value| Ok(value.trim().to_string()))?;
@@ -703,15 +688,44 @@ fn find_library_path(name: &str) -> Result<String> {
     Ok(path)
 }
 
-fn prepare_encryptedstore(vm_secret: &VmSecret) -> Result<Child> {
-    let mut key = ZVec::new(ENCRYPTEDSTORE_KEYSIZE)?;
-    vm_secret.derive_encryptedstore_key(&mut key)?;
-    let mut cmd = Command::new(ENCRYPTEDSTORE_BIN);
-    cmd.arg("--blkdevice")
+/// Returns the version of the key the encrypted storage must be encrypted with.
+fn encryptedstore_key_version(config: &VmPayloadConfig) -> Result<u32, MicrodroidError> {
+    let version = config.encrypted_storage_key_version.unwrap_or(ENCRYPTEDSTORE_KEY_VERSION);
+    if !(ENCRYPTEDSTORE_KEY_VERSION..=MAX_ENCRYPTEDSTORE_KEY_VERSION).contains(&version) {
+        return Err(MicrodroidError::PayloadInvalidConfig(format!(
+            "Encrypted storage key version {version} not in range \
+             {ENCRYPTEDSTORE_KEY_VERSION}..={MAX_ENCRYPTEDSTORE_KEY_VERSION}"
+        )));
+    }
+    Ok(version)
+}
+
+fn prepare_encryptedstore(
+    vm_secret: &VmSecret,
+    key_version: u32,
+    authenticated: bool,
+) -> Result<Child> {
+    // The keys of all the versions up to `key_version` are passed on stdin, so that they never
+    // appear on a command line, and so that storage encrypted with an older key can be
+    // re-encrypted.
+    let mut keys = Zeroizing::new(Vec::new());
+    for version in 1..=key_version {
+        let mut key = ZVec::new(ENCRYPTEDSTORE_KEYSIZE)?;
+        vm_secret.derive_encryptedstore_key(version, &mut key)?;
+        keys.extend_from_slice(&version.to_le_bytes());
+        keys.extend_from_slice(&key);
+    }
+    let mut command = Command::new(ENCRYPTEDSTORE_BIN);
+    command
+        .arg("--blkdevice")
         .arg(ENCRYPTEDSTORE_BACKING_DEVICE)
-        .arg("--key")
-        .arg(hex::encode(&*key))
-        .args(["--mountpoint", ENCRYPTEDSTORE_MOUNTPOINT])
-        .spawn()
-        .context("encryptedstore failed")
+        .args(["--key-fd", "0"])
+        .args(["--mountpoint", ENCRYPTEDSTORE_MOUNTPOINT]);
+    if authenticated {
+        command.arg("--authenticated");
+    }
+    let mut child = command.stdin(Stdio::piped()).spawn().context("encryptedstore failed")?;
+    // Dropping stdin closes it, so that encryptedstore reads all the keys.
+    child.stdin.take().unwrap().write_all(&keys).context("Failed to pass the keys")?;
+    Ok(child)
 }
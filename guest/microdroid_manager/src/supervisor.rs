@@ -0,0 +1,877 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Supervision of the tasks of the payload. Each task is started once the tasks it depends on are
+//! ready, and restarted according to its restart policy. The status of the tasks is reported to
+//! the host, and the payload finishes when its main task exits.
+
+use crate::{find_library_path, MicrodroidError};
+use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::{
+    PayloadTaskState::PayloadTaskState, PayloadTaskStatus::PayloadTaskStatus,
+};
+use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::IVirtualMachineService::IVirtualMachineService;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use binder::Strong;
+use log::{error, info, warn};
+use microdroid_metadata::PayloadTaskOverrides;
+use microdroid_payload_config::{
+    Task, TaskReadiness, TaskRestartPolicy, TaskType, VmPayloadConfig, MAX_TASKS, MAX_TASK_NAME_LEN,
+};
+use nix::fcntl::OFlag;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::{pipe2, Pid};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::OwnedFd;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Name of the single `task` of a payload config, if it doesn't have one.
+const DEFAULT_TASK_NAME: &str = "main";
+/// How long to wait before restarting a task which exited, the first time.
+const TASK_RESTART_DELAY: Duration = Duration::from_secs(1);
+/// The longest to wait before restarting a task which keeps exiting.
+const MAX_TASK_RESTART_DELAY: Duration = Duration::from_secs(60);
+/// How long a task must run before exiting for it to be restarted after `TASK_RESTART_DELAY` again.
+const TASK_STABLE_RUN_TIME: Duration = Duration::from_secs(60);
+/// How often to check whether the file which a task is ready with exists.
+const READY_FILE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Size of the chunks of payload output sent to the host at once.
+const PAYLOAD_OUTPUT_CHUNK_SIZE: usize = 4096;
+/// How long to wait for the last output of the payload to be forwarded once it has exited.
+const PAYLOAD_OUTPUT_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Returns the tasks of the payload, which are either its single `task`, as the main task, or its
+/// `tasks`, after checking that they can be supervised. The arguments and environment of the main
+/// task are overridden by `task_overrides`, if any.
+pub fn payload_tasks(
+    config: &VmPayloadConfig,
+    task_overrides: Option<&PayloadTaskOverrides>,
+) -> Result<Vec<Task>, MicrodroidError> {
+    let mut tasks = match (&config.task, config.tasks.is_empty()) {
+        (Some(task), true) => {
+            let name =
+                if task.name.is_empty() { DEFAULT_TASK_NAME.to_owned() } else { task.name.clone() };
+            vec![Task { name, main: true, ..task.clone() }]
+        }
+        (None, false) => config.tasks.clone(),
+        (Some(_), false) => {
+            return Err(MicrodroidError::PayloadInvalidConfig(
+                "Both task and tasks in VM config".to_string(),
+            ))
+        }
+        (None, true) => {
+            return Err(MicrodroidError::PayloadInvalidConfig("No task in VM config".to_string()))
+        }
+    };
+    check_tasks(&tasks).map_err(|e| MicrodroidError::PayloadInvalidConfig(e.to_string()))?;
+    if let Some(task_overrides) = task_overrides {
+        // There is exactly one main task, as checked above.
+        let main_task = tasks.iter_mut().find(|task| task.main).unwrap();
+        if task_overrides.override_args {
+            main_task.args = task_overrides.args.clone();
+        }
+        main_task.env.extend(task_overrides.env.clone());
+    }
+    Ok(tasks)
+}
+
+/// Checks that there aren't more tasks than the host keeps the status of, that they have unique
+/// names, that exactly one of them is the main task, and that the tasks they depend on exist and
+/// don't depend on them in turn.
+fn check_tasks(tasks: &[Task]) -> Result<()> {
+    ensure!(tasks.len() <= MAX_TASKS, "More than {MAX_TASKS} tasks");
+    let mut names = HashSet::new();
+    for task in tasks {
+        ensure!(!task.name.is_empty(), "Task {:?} has no name", task.command);
+        ensure!(
+            task.name.len() <= MAX_TASK_NAME_LEN,
+            "Task name {:?} is longer than {MAX_TASK_NAME_LEN} bytes",
+            task.name
+        );
+        ensure!(names.insert(task.name.as_str()), "Duplicate task name {:?}", task.name);
+    }
+    let main_tasks = tasks.iter().filter(|task| task.main).count();
+    ensure!(main_tasks == 1, "Expected exactly one main task, found {main_tasks}");
+    for task in tasks {
+        for dependency in &task.depends_on {
+            ensure!(
+                names.contains(dependency.as_str()),
+                "Task {:?} depends on unknown task {:?}",
+                task.name,
+                dependency
+            );
+        }
+    }
+
+    // Repeatedly remove the tasks whose dependencies have all been removed. What remains depends
+    // on itself.
+    let mut remaining: Vec<&Task> = tasks.iter().collect();
+    while !remaining.is_empty() {
+        let (unblocked, blocked): (Vec<_>, Vec<_>) = remaining.iter().copied().partition(|task| {
+            !task.depends_on.iter().any(|dependency| {
+                remaining.iter().any(|remaining_task| &remaining_task.name == dependency)
+            })
+        });
+        if unblocked.is_empty() {
+            let names: Vec<_> = blocked.iter().map(|task| task.name.as_str()).collect();
+            bail!("Dependency cycle between tasks {names:?}");
+        }
+        remaining = blocked;
+    }
+    Ok(())
+}
+
+/// Runs the tasks until the main task exits, and returns its exit code. The stdout and stderr of
+/// the tasks are forwarded to the host if `forward_output` is set, or discarded otherwise.
+pub fn run_tasks(
+    tasks: Vec<Task>,
+    service: &Strong<dyn IVirtualMachineService>,
+    forward_output: bool,
+) -> Result<i32> {
+    let (output, output_forwarded) = if forward_output {
+        let (read_fd, write_fd) =
+            pipe2(OFlag::O_CLOEXEC).context("Failed to create payload output pipe")?;
+        (Some(write_fd), Some(forward_payload_output(read_fd.into(), service.clone())))
+    } else {
+        (None, None)
+    };
+    let report_status = |status: &PayloadTaskStatus| service.notifyPayloadTaskStatus(status);
+    let mut supervisor = Supervisor::new(tasks, &report_status, output);
+
+    info!("notifying payload started");
+    service.notifyPayloadStarted()?;
+
+    let result = supervisor.run();
+    supervisor.stop();
+    if let Some(output_forwarded) = output_forwarded {
+        // Processes spawned by the tasks may keep the pipe open, so don't wait for them.
+        if output_forwarded.recv_timeout(PAYLOAD_OUTPUT_FLUSH_TIMEOUT).is_err() {
+            warn!("Payload output is still open after the main task exited");
+        }
+    }
+    result
+}
+
+/// Something which happened to a task, which the supervisor handles in order.
+enum Event {
+    /// The process of the task exited.
+    Exited { index: usize, status: io::Result<ExitStatus> },
+    /// The file which the given run of the task is ready with was created.
+    ReadyFileCreated { index: usize, run: u32 },
+    /// The task is due to be restarted.
+    Restart { index: usize },
+}
+
+/// A task run by `Supervisor`.
+struct SupervisedTask {
+    task: Task,
+    /// Indices of the tasks which this task depends on.
+    dependencies: Vec<usize>,
+    /// The status last reported to the host.
+    status: PayloadTaskStatus,
+    /// Whether the task has been ready, so the tasks depending on it may have been started.
+    ready: bool,
+    /// The process of the task, while it runs.
+    pid: Option<Pid>,
+    /// How many times the task has been started, to ignore events of previous runs.
+    run: u32,
+    /// Whether the current run of the task is still going, to stop waiting for its ready file.
+    running: Arc<AtomicBool>,
+    /// When the current or last run of the task was started.
+    started: Instant,
+    /// How long the supervisor waited before the last restart of the task, if it was restarted.
+    restart_delay: Option<Duration>,
+}
+
+struct Supervisor<'a> {
+    tasks: Vec<SupervisedTask>,
+    /// Reports the status of a task to the host.
+    report_status: &'a dyn Fn(&PayloadTaskStatus) -> binder::Result<()>,
+    /// How long to wait before restarting a task which exited, the first time.
+    min_restart_delay: Duration,
+    event_sender: Sender<Event>,
+    event_receiver: Receiver<Event>,
+    /// The write end of the pipe which the output of the tasks is forwarded from, if it is.
+    output: Option<OwnedFd>,
+}
+
+impl<'a> Supervisor<'a> {
+    fn new(
+        tasks: Vec<Task>,
+        report_status: &'a dyn Fn(&PayloadTaskStatus) -> binder::Result<()>,
+        output: Option<OwnedFd>,
+    ) -> Self {
+        let indices: HashMap<String, usize> =
+            tasks.iter().enumerate().map(|(index, task)| (task.name.clone(), index)).collect();
+        let tasks = tasks
+            .into_iter()
+            .map(|task| SupervisedTask {
+                dependencies: task.depends_on.iter().map(|name| indices[name]).collect(),
+                status: PayloadTaskStatus {
+                    name: task.name.clone(),
+                    main: task.main,
+                    state: PayloadTaskState::WAITING,
+                    exitCode: 0,
+                    restarts: 0,
+                },
+                task,
+                ready: false,
+                pid: None,
+                run: 0,
+                running: Default::default(),
+                started: Instant::now(),
+                restart_delay: None,
+            })
+            .collect();
+        let (event_sender, event_receiver) = mpsc::channel();
+        Self {
+            tasks,
+            report_status,
+            min_restart_delay: TASK_RESTART_DELAY,
+            event_sender,
+            event_receiver,
+            output,
+        }
+    }
+
+    /// Starts the tasks, and handles their events until the main task exits. Returns its exit
+    /// code.
+    fn run(&mut self) -> Result<i32> {
+        for index in 0..self.tasks.len() {
+            self.report(index);
+        }
+        self.start_unblocked_tasks()?;
+        loop {
+            // The supervisor holds a sender, so this can't fail.
+            match self.event_receiver.recv().unwrap() {
+                Event::Exited { index, status } => {
+                    if let Some(exit_code) = self.on_exited(index, status)? {
+                        return Ok(exit_code);
+                    }
+                }
+                Event::ReadyFileCreated { index, run } if run == self.tasks[index].run => {
+                    self.on_ready(index)?
+                }
+                Event::ReadyFileCreated { .. } => (),
+                Event::Restart { index } => {
+                    self.tasks[index].status.restarts += 1;
+                    self.start(index)?;
+                }
+            }
+        }
+    }
+
+    /// Asks the tasks which still run to terminate, and closes the output pipe.
+    fn stop(&mut self) {
+        for task in &self.tasks {
+            if let Some(pid) = task.pid {
+                info!("Terminating task {}", task.task.name);
+                if let Err(e) = kill(pid, Signal::SIGTERM) {
+                    warn!("Failed to terminate task {}: {e:?}", task.task.name);
+                }
+            }
+        }
+        self.output = None;
+    }
+
+    /// Reports the status of the task at `index` to the host.
+    fn report(&self, index: usize) {
+        let status = &self.tasks[index].status;
+        if let Err(e) = (self.report_status)(status) {
+            error!("Failed to report the status of task {}: {e:?}", status.name);
+        }
+    }
+
+    fn set_state(&mut self, index: usize, state: PayloadTaskState) {
+        self.tasks[index].status.state = state;
+        self.report(index);
+    }
+
+    /// Starts the tasks which wait for tasks which are all ready.
+    fn start_unblocked_tasks(&mut self) -> Result<()> {
+        for index in 0..self.tasks.len() {
+            let task = &self.tasks[index];
+            if task.status.state == PayloadTaskState::WAITING
+                && task.dependencies.iter().all(|&dependency| self.tasks[dependency].ready)
+            {
+                self.start(index)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts the task at `index`. Fails only if it is the main task and it can't be started.
+    fn start(&mut self, index: usize) -> Result<()> {
+        let task = &self.tasks[index].task;
+        info!("starting task {:?}...", task);
+        let child = self.command(task).and_then(|mut command| Ok(command.spawn()?));
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) if task.main => return Err(e.context("Failed to start the main task")),
+            Err(e) => {
+                error!("Failed to start task {}: {e:?}", task.name);
+                self.tasks[index].status.exitCode = -1;
+                self.set_state(index, PayloadTaskState::FAILED);
+                return self.skip_dependents(index);
+            }
+        };
+
+        let supervised = &mut self.tasks[index];
+        supervised.pid = Some(Pid::from_raw(child.id() as i32));
+        supervised.run += 1;
+        supervised.running = Arc::new(AtomicBool::new(true));
+        supervised.started = Instant::now();
+        let event_sender = self.event_sender.clone();
+        thread::spawn(move || {
+            let status = child.wait();
+            let _ = event_sender.send(Event::Exited { index, status });
+        });
+        self.set_state(index, PayloadTaskState::RUNNING);
+
+        match &self.tasks[index].task.ready {
+            TaskReadiness::Started => self.on_ready(index),
+            TaskReadiness::Exited => Ok(()),
+            TaskReadiness::File(path) => {
+                let path = path.clone();
+                let run = self.tasks[index].run;
+                let running = self.tasks[index].running.clone();
+                let event_sender = self.event_sender.clone();
+                thread::spawn(move || {
+                    while running.load(Ordering::Relaxed) {
+                        if Path::new(&path).exists() {
+                            let _ = event_sender.send(Event::ReadyFileCreated { index, run });
+                            return;
+                        }
+                        thread::sleep(READY_FILE_POLL_INTERVAL);
+                    }
+                });
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the command which runs `task`.
+    fn command(&self, task: &Task) -> Result<Command> {
+        let mut command = match task.type_ {
+            TaskType::Executable => {
+                // TODO(b/297501338): Figure out how to handle non-root for system payloads.
+                Command::new(&task.command)
+            }
+            TaskType::MicrodroidLauncher => {
+                let mut command = Command::new("/system/bin/microdroid_launcher");
+                command.arg(find_library_path(&task.command)?);
+                command.uid(microdroid_uids::MICRODROID_PAYLOAD_UID);
+                command.gid(microdroid_uids::MICRODROID_PAYLOAD_GID);
+                command
+            }
+        };
+        command.args(&task.args).envs(&task.env);
+
+        // SAFETY: We are not accessing any resource of the parent process. This means we can't
+        // make any log calls inside the closure.
+        unsafe {
+            command.pre_exec(|| {
+                // It is OK to continue with payload execution even if the calls below fail, since
+                // whether process can use a capability is controlled by the SELinux. Dropping the
+                // capabilities here is just another defense-in-depth layer.
+                let _ = cap::drop_inheritable_caps();
+                let _ = cap::drop_bounding_set();
+                Ok(())
+            });
+        }
+
+        command.stdin(Stdio::null());
+        if let Some(output) = &self.output {
+            command.stdout(output.try_clone()?).stderr(output.try_clone()?);
+        } else {
+            command.stdout(Stdio::null()).stderr(Stdio::null());
+        }
+        Ok(command)
+    }
+
+    /// Marks the task at `index` as ready, and starts the tasks which were waiting for it.
+    fn on_ready(&mut self, index: usize) -> Result<()> {
+        self.tasks[index].ready = true;
+        if self.tasks[index].status.state == PayloadTaskState::RUNNING {
+            self.set_state(index, PayloadTaskState::READY);
+        }
+        self.start_unblocked_tasks()
+    }
+
+    /// Handles the exit of the task at `index`, by restarting it if its restart policy says so,
+    /// with a growing delay if it keeps exiting. Returns the exit code of the main task once it has
+    /// exited for good.
+    fn on_exited(&mut self, index: usize, status: io::Result<ExitStatus>) -> Result<Option<i32>> {
+        let task = &mut self.tasks[index];
+        task.pid = None;
+        task.running.store(false, Ordering::Relaxed);
+        match &status {
+            Ok(status) => info!("Task {} exited: {status}", task.task.name),
+            Err(e) => error!("Failed to wait for task {}: {e:?}", task.task.name),
+        }
+        let exit_code = status.as_ref().ok().and_then(ExitStatus::code);
+        let success = exit_code == Some(0);
+        task.status.exitCode = exit_code.unwrap_or(-1);
+
+        let restart = match task.task.restart {
+            TaskRestartPolicy::Never => false,
+            TaskRestartPolicy::OnFailure => !success,
+            TaskRestartPolicy::Always => true,
+        };
+        if restart {
+            let delay =
+                restart_delay(task.restart_delay, task.started.elapsed(), self.min_restart_delay);
+            task.restart_delay = Some(delay);
+            info!("Restarting task {} in {delay:?}", task.task.name);
+            self.set_state(index, PayloadTaskState::RESTARTING);
+            let event_sender = self.event_sender.clone();
+            thread::spawn(move || {
+                thread::sleep(delay);
+                let _ = event_sender.send(Event::Restart { index });
+            });
+            return Ok(None);
+        }
+
+        let state = if success { PayloadTaskState::EXITED } else { PayloadTaskState::FAILED };
+        self.set_state(index, state);
+        let task = &self.tasks[index];
+        if task.task.main {
+            return main_task_exit_code(status).map(Some);
+        }
+        if success && task.task.ready == TaskReadiness::Exited {
+            self.on_ready(index)?;
+        } else if !task.ready {
+            self.skip_dependents(index)?;
+        }
+        Ok(None)
+    }
+
+    /// Marks the tasks waiting for the task at `index`, directly or not, as skipped, since it
+    /// failed before being ready. Fails if the main task is one of them.
+    fn skip_dependents(&mut self, index: usize) -> Result<()> {
+        for dependent in 0..self.tasks.len() {
+            let task = &self.tasks[dependent];
+            if task.status.state == PayloadTaskState::WAITING && task.dependencies.contains(&index)
+            {
+                self.set_state(dependent, PayloadTaskState::SKIPPED);
+                if self.tasks[dependent].task.main {
+                    bail!(
+                        "The main task can't be started, as task {} failed",
+                        self.tasks[index].task.name
+                    );
+                }
+                self.skip_dependents(dependent)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns how long to wait before restarting a task which ran for `ran_for`, given how long was
+/// waited before its previous restart, if any. The delay doubles each time the task exits soon after
+/// being started, up to `MAX_TASK_RESTART_DELAY`, so that a crash-looping task doesn't keep the VM
+/// busy, and is back to `min_delay` once the task ran for `TASK_STABLE_RUN_TIME`.
+fn restart_delay(previous: Option<Duration>, ran_for: Duration, min_delay: Duration) -> Duration {
+    match previous {
+        Some(previous) if ran_for < TASK_STABLE_RUN_TIME => {
+            (previous * 2).clamp(min_delay, MAX_TASK_RESTART_DELAY)
+        }
+        _ => min_delay,
+    }
+}
+
+/// Returns the exit code of the main task, or an error if it didn't exit normally.
+fn main_task_exit_code(status: io::Result<ExitStatus>) -> Result<i32> {
+    let exit_status = status.context("Failed to wait for the main task")?;
+    match exit_status.code() {
+        Some(exit_code) => Ok(exit_code),
+        None => Err(match exit_status.signal() {
+            Some(signal) => anyhow!(
+                "Payload exited due to signal: {} ({})",
+                signal,
+                Signal::try_from(signal).map_or("unknown", |s| s.as_str())
+            ),
+            None => anyhow!("Payload has neither exit code nor signal"),
+        }),
+    }
+}
+
+/// Sends what is read from `output` to the host on a new thread until EOF. Once forwarding to the
+/// host fails, the rest of the output is read and discarded so that the payload doesn't block on
+/// writing it. Returns a receiver which gets a message when all the output has been read.
+fn forward_payload_output(
+    mut output: File,
+    service: Strong<dyn IVirtualMachineService>,
+) -> Receiver<()> {
+    let (done_sender, done_receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0; PAYLOAD_OUTPUT_CHUNK_SIZE];
+        let mut forwarding = true;
+        loop {
+            let len = match output.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Failed to read payload output: {e:?}");
+                    break;
+                }
+            };
+            if forwarding {
+                if let Err(e) = service.writePayloadOutput(&buf[..len]) {
+                    error!("Failed to forward payload output, discarding the rest: {e:?}");
+                    forwarding = false;
+                }
+            }
+        }
+        let _ = done_sender.send(());
+    });
+    done_receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+
+    fn task(name: &str, depends_on: &[&str]) -> Task {
+        Task {
+            command: format!("/system/bin/{name}"),
+            name: name.to_owned(),
+            depends_on: depends_on.iter().map(|name| name.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn main_task(name: &str, depends_on: &[&str]) -> Task {
+        Task { main: true, ..task(name, depends_on) }
+    }
+
+    /// A task which runs `script` in a shell.
+    fn shell_task(name: &str, script: &str) -> Task {
+        Task {
+            command: "/system/bin/sh".to_owned(),
+            args: vec!["-c".to_owned(), script.to_owned()],
+            name: name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// Supervises `tasks` until the main task exits. Returns its exit code and the statuses
+    /// reported meanwhile.
+    fn supervise(tasks: Vec<Task>) -> (Result<i32>, Vec<PayloadTaskStatus>) {
+        check_tasks(&tasks).unwrap();
+        let statuses = RefCell::new(Vec::new());
+        let report_status = |status: &PayloadTaskStatus| {
+            statuses.borrow_mut().push(status.clone());
+            Ok(())
+        };
+        let mut supervisor = Supervisor::new(tasks, &report_status, None);
+        supervisor.min_restart_delay = Duration::from_millis(10);
+        let result = supervisor.run();
+        supervisor.stop();
+        drop(supervisor);
+        (result, statuses.into_inner())
+    }
+
+    /// Returns the states reported for the task named `name`, in order.
+    fn states(statuses: &[PayloadTaskStatus], name: &str) -> Vec<PayloadTaskState> {
+        statuses.iter().filter(|status| status.name == name).map(|status| status.state).collect()
+    }
+
+    /// Returns the position of the first status reported for the task named `name` with `state`.
+    fn position(statuses: &[PayloadTaskStatus], name: &str, state: PayloadTaskState) -> usize {
+        statuses.iter().position(|status| status.name == name && status.state == state).unwrap()
+    }
+
+    #[test]
+    fn single_task_is_main_task() {
+        let config = VmPayloadConfig {
+            task: Some(Task { command: "/system/bin/payload".to_owned(), ..Default::default() }),
+            ..Default::default()
+        };
+        let tasks = payload_tasks(&config, None).unwrap();
+        assert_eq!(1, tasks.len());
+        assert_eq!(DEFAULT_TASK_NAME, tasks[0].name);
+        assert!(tasks[0].main);
+    }
+
+    #[test]
+    fn task_or_tasks_required() {
+        let config = VmPayloadConfig::default();
+        assert!(payload_tasks(&config, None).is_err());
+
+        let config = VmPayloadConfig {
+            task: Some(task("main", &[])),
+            tasks: vec![main_task("main", &[])],
+            ..Default::default()
+        };
+        assert!(payload_tasks(&config, None).is_err());
+    }
+
+    #[test]
+    fn main_task_overridden() {
+        let config = VmPayloadConfig {
+            tasks: vec![
+                Task { args: vec!["--verbose".to_owned()], ..task("helper", &[]) },
+                Task {
+                    args: vec!["--port".to_owned(), "1234".to_owned()],
+                    env: [("FOO".to_owned(), "1".to_owned()), ("BAR".to_owned(), "2".to_owned())]
+                        .into(),
+                    ..main_task("main", &["helper"])
+                },
+            ],
+            ..Default::default()
+        };
+        let task_overrides = PayloadTaskOverrides {
+            override_args: true,
+            args: vec!["--port".to_owned(), "5678".to_owned()],
+            env: [("BAR".to_owned(), "3".to_owned())].into(),
+            ..Default::default()
+        };
+        let tasks = payload_tasks(&config, Some(&task_overrides)).unwrap();
+        assert_eq!(vec!["--verbose"], tasks[0].args);
+        assert_eq!(vec!["--port", "5678"], tasks[1].args);
+        assert_eq!(
+            BTreeMap::from([
+                ("FOO".to_owned(), "1".to_owned()),
+                ("BAR".to_owned(), "3".to_owned())
+            ]),
+            tasks[1].env
+        );
+
+        let task_overrides = PayloadTaskOverrides::default();
+        let tasks = payload_tasks(&config, Some(&task_overrides)).unwrap();
+        assert_eq!(vec!["--port", "1234"], tasks[1].args);
+    }
+
+    #[test]
+    fn parse_tasks() {
+        let config: VmPayloadConfig = serde_json::from_str(
+            r#"{
+                "tasks": [
+                    {
+                        "name": "setup",
+                        "command": "/system/bin/setup",
+                        "ready": "exited"
+                    },
+                    {
+                        "name": "daemon",
+                        "command": "/system/bin/daemon",
+                        "args": ["--verbose"],
+                        "env": {"DAEMON_PORT": "5678"},
+                        "depends_on": ["setup"],
+                        "ready": {"file": "/data/daemon.ready"},
+                        "restart": "on_failure"
+                    },
+                    {
+                        "name": "service",
+                        "type": "microdroid_launcher",
+                        "command": "service.so",
+                        "depends_on": ["daemon"],
+                        "main": true
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let tasks = payload_tasks(&config, None).unwrap();
+        assert_eq!(3, tasks.len());
+        assert_eq!(TaskReadiness::Exited, tasks[0].ready);
+        assert_eq!(vec!["--verbose"], tasks[1].args);
+        assert_eq!(Some("5678"), tasks[1].env.get("DAEMON_PORT").map(String::as_str));
+        assert_eq!(TaskReadiness::File("/data/daemon.ready".to_owned()), tasks[1].ready);
+        assert_eq!(TaskRestartPolicy::OnFailure, tasks[1].restart);
+        assert_eq!(TaskRestartPolicy::Never, tasks[2].restart);
+        assert!(tasks[2].main);
+    }
+
+    #[test]
+    fn check_valid_tasks() {
+        let tasks = [task("a", &[]), task("b", &["a"]), main_task("c", &["a", "b"])];
+        check_tasks(&tasks).unwrap();
+    }
+
+    #[test]
+    fn check_tasks_fails_without_name() {
+        let tasks = [main_task("", &[])];
+        assert!(check_tasks(&tasks).is_err());
+    }
+
+    #[test]
+    fn check_tasks_fails_with_duplicate_name() {
+        let tasks = [task("a", &[]), main_task("a", &[])];
+        assert!(check_tasks(&tasks).is_err());
+    }
+
+    #[test]
+    fn check_tasks_fails_without_single_main_task() {
+        let tasks = [task("a", &[]), task("b", &[])];
+        assert!(check_tasks(&tasks).is_err());
+
+        let tasks = [main_task("a", &[]), main_task("b", &[])];
+        assert!(check_tasks(&tasks).is_err());
+    }
+
+    #[test]
+    fn check_tasks_fails_with_unknown_dependency() {
+        let tasks = [task("a", &["c"]), main_task("b", &["a"])];
+        assert!(check_tasks(&tasks).is_err());
+    }
+
+    #[test]
+    fn check_tasks_fails_with_dependency_cycle() {
+        let tasks = [task("a", &["c"]), task("b", &["a"]), main_task("c", &["b"])];
+        assert!(check_tasks(&tasks).is_err());
+
+        let tasks = [main_task("a", &["a"])];
+        assert!(check_tasks(&tasks).is_err());
+    }
+
+    #[test]
+    fn check_tasks_fails_with_too_many_tasks() {
+        let mut tasks: Vec<_> = (1..MAX_TASKS).map(|i| task(&format!("task{i}"), &[])).collect();
+        tasks.push(main_task("main", &[]));
+        check_tasks(&tasks).unwrap();
+
+        tasks.push(task("extra", &[]));
+        assert!(check_tasks(&tasks).is_err());
+    }
+
+    #[test]
+    fn check_tasks_fails_with_long_name() {
+        let tasks = [main_task(&"a".repeat(MAX_TASK_NAME_LEN), &[])];
+        check_tasks(&tasks).unwrap();
+
+        let tasks = [main_task(&"a".repeat(MAX_TASK_NAME_LEN + 1), &[])];
+        assert!(check_tasks(&tasks).is_err());
+    }
+
+    #[test]
+    fn restart_delay_grows_while_task_keeps_exiting() {
+        let min = TASK_RESTART_DELAY;
+        let quick = Duration::from_millis(100);
+        assert_eq!(min, restart_delay(None, quick, min));
+        assert_eq!(2 * min, restart_delay(Some(min), quick, min));
+        assert_eq!(MAX_TASK_RESTART_DELAY, restart_delay(Some(MAX_TASK_RESTART_DELAY), quick, min));
+        assert_eq!(min, restart_delay(Some(MAX_TASK_RESTART_DELAY), TASK_STABLE_RUN_TIME, min));
+    }
+
+    #[test]
+    fn supervise_returns_main_task_exit_code() {
+        let (result, statuses) =
+            supervise(vec![Task { main: true, ..shell_task("main", "exit 3") }]);
+
+        assert_eq!(3, result.unwrap());
+        assert_eq!(
+            vec![
+                PayloadTaskState::WAITING,
+                PayloadTaskState::RUNNING,
+                PayloadTaskState::READY,
+                PayloadTaskState::FAILED
+            ],
+            states(&statuses, "main")
+        );
+        assert_eq!(3, statuses.last().unwrap().exitCode);
+    }
+
+    #[test]
+    fn supervise_starts_tasks_once_dependencies_are_ready() {
+        let (result, statuses) = supervise(vec![
+            Task { ready: TaskReadiness::Exited, ..shell_task("setup", "sleep 0.1") },
+            Task { depends_on: vec!["setup".to_owned()], main: true, ..shell_task("main", "true") },
+        ]);
+
+        assert_eq!(0, result.unwrap());
+        assert_eq!(
+            vec![PayloadTaskState::WAITING, PayloadTaskState::RUNNING, PayloadTaskState::EXITED],
+            states(&statuses, "setup")
+        );
+        assert!(
+            position(&statuses, "setup", PayloadTaskState::EXITED)
+                < position(&statuses, "main", PayloadTaskState::RUNNING)
+        );
+    }
+
+    #[test]
+    fn supervise_skips_dependents_of_failed_task() {
+        let (result, statuses) = supervise(vec![
+            Task { ready: TaskReadiness::Exited, ..shell_task("setup", "false") },
+            Task { depends_on: vec!["setup".to_owned()], ..shell_task("daemon", "sleep 10") },
+            Task {
+                depends_on: vec!["daemon".to_owned()],
+                main: true,
+                ..shell_task("main", "true")
+            },
+        ]);
+
+        assert!(result.is_err());
+        assert_eq!(
+            vec![PayloadTaskState::WAITING, PayloadTaskState::SKIPPED],
+            states(&statuses, "daemon")
+        );
+        assert_eq!(
+            vec![PayloadTaskState::WAITING, PayloadTaskState::SKIPPED],
+            states(&statuses, "main")
+        );
+    }
+
+    #[test]
+    fn supervise_restarts_failed_task() {
+        let (result, statuses) = supervise(vec![
+            Task { restart: TaskRestartPolicy::OnFailure, ..shell_task("daemon", "false") },
+            Task { main: true, ..shell_task("main", "sleep 0.5") },
+        ]);
+
+        assert_eq!(0, result.unwrap());
+        let restarts = statuses
+            .iter()
+            .filter(|status| status.name == "daemon")
+            .map(|status| status.restarts)
+            .max()
+            .unwrap();
+        // With a delay growing from 10ms, the daemon is restarted about 5 times in 0.5s, rather
+        // than 50 times.
+        assert!((2..=7).contains(&restarts), "{restarts} restarts");
+        assert!(states(&statuses, "daemon").contains(&PayloadTaskState::RESTARTING));
+    }
+
+    #[test]
+    fn supervise_does_not_restart_successful_task_on_failure_policy() {
+        let (result, statuses) = supervise(vec![
+            Task { restart: TaskRestartPolicy::OnFailure, ..shell_task("daemon", "true") },
+            Task { main: true, ..shell_task("main", "sleep 0.2") },
+        ]);
+
+        assert_eq!(0, result.unwrap());
+        assert_eq!(
+            vec![
+                PayloadTaskState::WAITING,
+                PayloadTaskState::RUNNING,
+                PayloadTaskState::READY,
+                PayloadTaskState::EXITED
+            ],
+            states(&statuses, "daemon")
+        );
+    }
+}
@@ -41,6 +41,22 @@ const EXTRA_IDSIG_PATH_PATTERN: &str = "/dev/block/by-name/extra-idsig-*";
 
 const APKDMVERITY_BIN: &str = "/system/bin/apkdmverity";
 
+/// Returns the names of the dm-verity devices of the APKs which found a corrupted block, i.e. a
+/// block which doesn't match the root hash of the APK and so failed to be read.
+pub fn find_corrupted_apks() -> Result<Vec<String>> {
+    let dm = dm::DeviceMapper::new()?;
+    let mut corrupted = Vec::new();
+    for device in dm.list_devices()? {
+        if device.name != MAIN_APK_DEVICE_NAME && !device.name.starts_with("extra-apk-") {
+            continue;
+        }
+        if dm.status(&device.name)?.iter().any(dm::verity::is_corrupted) {
+            corrupted.push(device.name);
+        }
+    }
+    Ok(corrupted)
+}
+
 /// Verify payload before executing it. For APK payload, Full verification (which is slow) is done
 /// when the root_hash values from the idsig file and the instance disk are different. This function
 /// returns the verified root hash (for APK payload) and pubkeys (for APEX payloads) that can be
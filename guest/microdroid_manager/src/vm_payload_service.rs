@@ -16,6 +16,7 @@
 
 use android_system_virtualization_payload::aidl::android::system::virtualization::payload::IVmPayloadService::{
     BnVmPayloadService, IVmPayloadService, VM_PAYLOAD_SERVICE_SOCKET_NAME, AttestationResult::AttestationResult,
+    PAYLOAD_SEALING_SECRET_SIZE,
     STATUS_FAILED_TO_PREPARE_CSR_AND_KEY
 };
 use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::IVirtualMachineService::IVirtualMachineService;
@@ -54,6 +55,16 @@ impl IVmPayloadService for VmPayloadService {
         Ok(instance_secret)
     }
 
+    fn getPayloadSealingSecret(&self) -> binder::Result<Vec<u8>> {
+        let mut secret = vec![0; PAYLOAD_SEALING_SECRET_SIZE as usize];
+        self.secret
+            .derive_payload_data_sealing_secret(&mut secret)
+            .context("Failed to derive payload sealing secret")
+            .with_log()
+            .or_service_specific_exception(-1)?;
+        Ok(secret)
+    }
+
     fn getDiceAttestationChain(&self) -> binder::Result<Vec<u8>> {
         self.check_restricted_apis_allowed()?;
         if let Some(bcc) = self.secret.dice_artifacts().bcc() {
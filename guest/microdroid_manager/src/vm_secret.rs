@@ -59,6 +59,11 @@ const SALT_PAYLOAD_SERVICE: &[u8] = &[
     0x8B, 0x0F, 0xF0, 0xD3, 0xB1, 0x69, 0x2B, 0x95, 0x84, 0x2C, 0x9E, 0x3C, 0x99, 0x56, 0x7A, 0x22,
     0x55, 0xF8, 0x08, 0x23, 0x81, 0x5F, 0xF5, 0x16, 0x20, 0x3E, 0xBE, 0xBA, 0xB7, 0xA8, 0x43, 0x92,
 ];
+const SALT_PAYLOAD_SEALING: &[u8] = &[
+    0xF6, 0xAA, 0x9A, 0x70, 0x71, 0xFE, 0x88, 0x49, 0xC4, 0x38, 0x2C, 0x38, 0x48, 0x03, 0xD7, 0x52,
+    0xD4, 0x10, 0x79, 0x22, 0x09, 0x6B, 0x08, 0x4D, 0xB1, 0x52, 0xC3, 0xD4, 0xD8, 0x5F, 0x8A, 0x5A,
+];
+const PAYLOAD_SEALING_SECRET_IDENTIFIER: &[u8] = b"payload_sealing_secret";
 
 pub enum VmSecret {
     // V2 secrets are derived from 2 independently secured secrets:
@@ -153,9 +158,21 @@ impl VmSecret {
         self.get_vm_secret(SALT_PAYLOAD_SERVICE, identifier, key)
     }
 
-    /// Derive encryptedstore key. This uses hardcoded random salt & fixed identifier.
-    pub fn derive_encryptedstore_key(&self, key: &mut [u8]) -> Result<()> {
-        self.get_vm_secret(SALT_ENCRYPTED_STORE, ENCRYPTEDSTORE_KEY_IDENTIFIER.as_bytes(), key)
+    /// Derive the secret which the payload seals data with. This uses its own salt, so that no
+    /// identifier of `derive_payload_sealing_key` yields the same secret.
+    pub fn derive_payload_data_sealing_secret(&self, secret: &mut [u8]) -> Result<()> {
+        self.get_vm_secret(SALT_PAYLOAD_SEALING, PAYLOAD_SEALING_SECRET_IDENTIFIER, secret)
+    }
+
+    /// Derive encryptedstore key of the given version. This uses hardcoded random salt & an
+    /// identifier which is fixed for each version.
+    pub fn derive_encryptedstore_key(&self, version: u32, key: &mut [u8]) -> Result<()> {
+        let identifier = match version {
+            // The first version predates key versioning.
+            1 => ENCRYPTEDSTORE_KEY_IDENTIFIER.to_owned(),
+            _ => format!("{ENCRYPTEDSTORE_KEY_IDENTIFIER}_v{version}"),
+        };
+        self.get_vm_secret(SALT_ENCRYPTED_STORE, identifier.as_bytes(), key)
     }
 }
 
@@ -0,0 +1,205 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A cache of decompressed chunks of compressed zip entries, bounded by a global memory budget.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::inode::ZipIndex;
+
+/// Index of a chunk within a zip entry.
+pub type ChunkIndex = u64;
+
+/// Identifies a chunk: the zip entry, and the index of the chunk in the entry.
+type ChunkKey = (ZipIndex, ChunkIndex);
+
+/// A compressed zip entry whose content can be decompressed one chunk at a time.
+pub trait ChunkedEntry: Send {
+    /// Returns the index of the chunk containing `offset`, and the range of the decompressed
+    /// content which the chunk covers. `offset` must be less than the size of the entry.
+    fn chunk_at(&self, offset: u64) -> (ChunkIndex, Range<u64>);
+
+    /// Decompresses the chunk `index` of the entry from `file`, the zip archive, and passes it to
+    /// `sink`. Neighbouring chunks which are decompressed along the way are passed to `sink` too.
+    fn decompress(
+        &mut self,
+        file: &File,
+        index: ChunkIndex,
+        sink: &mut dyn FnMut(ChunkIndex, Box<[u8]>),
+    ) -> io::Result<()>;
+}
+
+/// Decompressed chunks, evicted in least recently used order when their total size exceeds the
+/// budget.
+pub struct ChunkCache {
+    budget: usize,
+    used: usize,
+    /// Incremented on every access, to order the chunks by recency.
+    tick: u64,
+    chunks: HashMap<ChunkKey, (Arc<[u8]>, u64)>,
+    /// The key of each cached chunk, by the tick of its last access.
+    lru: BTreeMap<u64, ChunkKey>,
+}
+
+impl ChunkCache {
+    /// Creates an empty cache which holds at most `budget` bytes of decompressed data.
+    pub fn new(budget: usize) -> Self {
+        Self { budget, used: 0, tick: 0, chunks: HashMap::new(), lru: BTreeMap::new() }
+    }
+
+    /// Returns the chunk `index` of the zip entry `zip_index`, decompressing it from `file` with
+    /// `entry` if it isn't cached.
+    pub fn get_or_decompress(
+        &mut self,
+        zip_index: ZipIndex,
+        entry: &mut dyn ChunkedEntry,
+        file: &File,
+        index: ChunkIndex,
+    ) -> io::Result<Arc<[u8]>> {
+        if let Some(chunk) = self.get(&(zip_index, index)) {
+            return Ok(chunk);
+        }
+        let mut requested = None;
+        entry.decompress(file, index, &mut |i, data| {
+            let data: Arc<[u8]> = data.into();
+            if i == index {
+                requested = Some(data.clone());
+            }
+            self.insert((zip_index, i), data);
+        })?;
+        requested.ok_or_else(|| io::Error::from_raw_os_error(libc::EIO))
+    }
+
+    /// Returns the maximum number of bytes of decompressed data in the cache.
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Returns the number of bytes of decompressed data in the cache.
+    #[cfg(test)]
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    fn get(&mut self, key: &ChunkKey) -> Option<Arc<[u8]>> {
+        let (data, last_used) = self.chunks.get_mut(key)?;
+        self.lru.remove(last_used);
+        self.tick += 1;
+        *last_used = self.tick;
+        self.lru.insert(self.tick, *key);
+        Some(data.clone())
+    }
+
+    fn insert(&mut self, key: ChunkKey, data: Arc<[u8]>) {
+        if data.len() > self.budget {
+            return;
+        }
+        self.remove(&key);
+        while self.used + data.len() > self.budget {
+            let (_, oldest) = self.lru.pop_first().expect("cache is empty but over budget");
+            self.remove(&oldest);
+        }
+        self.tick += 1;
+        self.used += data.len();
+        self.lru.insert(self.tick, key);
+        self.chunks.insert(key, (data, self.tick));
+    }
+
+    fn remove(&mut self, key: &ChunkKey) {
+        if let Some((data, last_used)) = self.chunks.remove(key) {
+            self.lru.remove(&last_used);
+            self.used -= data.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An entry whose chunk `i` holds `CHUNK` bytes of value `i`. It counts the decompressions.
+    struct FakeEntry {
+        decompressed: Vec<ChunkIndex>,
+    }
+
+    const CHUNK: u64 = 10;
+
+    impl ChunkedEntry for FakeEntry {
+        fn chunk_at(&self, offset: u64) -> (ChunkIndex, Range<u64>) {
+            let index = offset / CHUNK;
+            (index, index * CHUNK..(index + 1) * CHUNK)
+        }
+
+        fn decompress(
+            &mut self,
+            _file: &File,
+            index: ChunkIndex,
+            sink: &mut dyn FnMut(ChunkIndex, Box<[u8]>),
+        ) -> io::Result<()> {
+            self.decompressed.push(index);
+            sink(index, vec![index as u8; CHUNK as usize].into());
+            Ok(())
+        }
+    }
+
+    fn get(cache: &mut ChunkCache, entry: &mut FakeEntry, index: ChunkIndex) -> u8 {
+        let file = tempfile::tempfile().unwrap();
+        let zip_index = ZipIndex { archive: 0, file: 0 };
+        cache.get_or_decompress(zip_index, entry, &file, index).unwrap()[0]
+    }
+
+    #[test]
+    fn cached_chunks_are_not_decompressed_again() {
+        let mut cache = ChunkCache::new(100);
+        let mut entry = FakeEntry { decompressed: vec![] };
+        assert_eq!(get(&mut cache, &mut entry, 1), 1);
+        assert_eq!(get(&mut cache, &mut entry, 2), 2);
+        assert_eq!(get(&mut cache, &mut entry, 1), 1);
+        assert_eq!(entry.decompressed, vec![1, 2]);
+        assert_eq!(cache.used(), 20);
+    }
+
+    #[test]
+    fn least_recently_used_chunk_is_evicted() {
+        let mut cache = ChunkCache::new(25);
+        let mut entry = FakeEntry { decompressed: vec![] };
+        get(&mut cache, &mut entry, 1);
+        get(&mut cache, &mut entry, 2);
+        // Touch 1 so that 2 is the least recently used chunk.
+        get(&mut cache, &mut entry, 1);
+        get(&mut cache, &mut entry, 3);
+        assert_eq!(cache.used(), 20);
+
+        get(&mut cache, &mut entry, 1);
+        get(&mut cache, &mut entry, 3);
+        get(&mut cache, &mut entry, 2);
+        assert_eq!(entry.decompressed, vec![1, 2, 3, 2]);
+    }
+
+    #[test]
+    fn chunk_larger_than_budget_is_not_cached() {
+        let mut cache = ChunkCache::new(5);
+        let mut entry = FakeEntry { decompressed: vec![] };
+        assert_eq!(get(&mut cache, &mut entry, 7), 7);
+        assert_eq!(get(&mut cache, &mut entry, 7), 7);
+        assert_eq!(entry.decompressed, vec![7, 7]);
+        assert_eq!(cache.used(), 0);
+    }
+}
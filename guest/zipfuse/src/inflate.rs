@@ -0,0 +1,456 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Random access to deflated zip entries.
+//!
+//! A deflate stream can only be decompressed from its beginning, because each block refers to the
+//! data decompressed before it. While an entry is decompressed, checkpoints are recorded at block
+//! boundaries roughly every [`CHECKPOINT_SPAN`] bytes of decompressed data. A checkpoint holds the
+//! position of the block in the compressed stream and the last 32 KiB of decompressed data, which
+//! is all that is needed to resume decompressing from there. This is the technique of zlib's
+//! `examples/zran.c`.
+
+use libz_sys::{
+    inflate, inflateEnd, inflateInit2_, inflatePrime, inflateSetDictionary, uInt, voidpf, z_stream,
+    zlibVersion, Z_BLOCK, Z_BUF_ERROR, Z_OK, Z_STREAM_END,
+};
+use std::fs::File;
+use std::io;
+use std::mem::size_of;
+use std::ops::Range;
+use std::os::raw::c_int;
+use std::os::unix::fs::FileExt;
+use std::ptr;
+
+use crate::cache::{ChunkIndex, ChunkedEntry};
+
+/// Size of the chunks of decompressed data.
+pub const CHUNK_SIZE: u64 = 64 << 10;
+
+/// Approximate distance between two checkpoints, in bytes of decompressed data.
+const CHECKPOINT_SPAN: u64 = 1 << 20;
+
+/// Maximum distance of a back reference in a deflate stream.
+const WINDOW_SIZE: usize = 32 << 10;
+
+/// Size of the buffer used to read the compressed data.
+const INPUT_SIZE: usize = 16 << 10;
+
+/// A point in the entry from which decompression can resume.
+struct Checkpoint {
+    /// Number of compressed bytes before the block, including the partially used byte if any.
+    in_offset: u64,
+    /// Number of bits of the byte at `in_offset - 1` which belong to the block.
+    bits: u8,
+    /// Number of decompressed bytes before the block.
+    out_offset: u64,
+    /// Up to [`WINDOW_SIZE`] bytes of decompressed data preceding the block.
+    window: Box<[u8]>,
+}
+
+/// A deflated zip entry.
+pub struct DeflateEntry {
+    /// Offset of the compressed data in the zip archive.
+    data_start: u64,
+    compressed_size: u64,
+    size: u64,
+    span: u64,
+    /// Recorded checkpoints, ordered by offset. The first one is the beginning of the entry.
+    checkpoints: Vec<Checkpoint>,
+    /// The most bytes of decompressed data held at once by `decompress`.
+    #[cfg(test)]
+    peak_history: usize,
+}
+
+impl DeflateEntry {
+    /// Creates an entry with `compressed_size` bytes of deflated data at `data_start` in the zip
+    /// archive, which decompress to `size` bytes.
+    pub fn new(data_start: u64, compressed_size: u64, size: u64) -> Self {
+        let start = Checkpoint { in_offset: 0, bits: 0, out_offset: 0, window: Box::new([]) };
+        Self {
+            data_start,
+            compressed_size,
+            size,
+            span: CHECKPOINT_SPAN,
+            checkpoints: vec![start],
+            #[cfg(test)]
+            peak_history: 0,
+        }
+    }
+
+    fn chunk_range(&self, index: ChunkIndex) -> Range<u64> {
+        let start = index * CHUNK_SIZE;
+        start..std::cmp::min(start + CHUNK_SIZE, self.size)
+    }
+}
+
+impl ChunkedEntry for DeflateEntry {
+    fn chunk_at(&self, offset: u64) -> (ChunkIndex, Range<u64>) {
+        let index = offset / CHUNK_SIZE;
+        (index, self.chunk_range(index))
+    }
+
+    /// Decompresses from the last checkpoint before the chunk, and passes the chunk and the one
+    /// after it, so that sequential reads only decompress every other chunk. Past the last
+    /// checkpoint, new ones are recorded along the way, up to one after the passed chunks. Only the
+    /// last [`WINDOW_SIZE`] bytes before the chunk being decompressed are kept, whatever the
+    /// distance from the checkpoint.
+    fn decompress(
+        &mut self,
+        file: &File,
+        index: ChunkIndex,
+        sink: &mut dyn FnMut(ChunkIndex, Box<[u8]>),
+    ) -> io::Result<()> {
+        let target = self.chunk_range(index);
+        let passed = index..=index + 1;
+        let passed_end = std::cmp::max(self.chunk_range(index + 1).end, target.end);
+        let k = self.checkpoints.partition_point(|c| c.out_offset <= target.start) - 1;
+        let start = &self.checkpoints[k];
+        let is_frontier = k + 1 == self.checkpoints.len();
+
+        let mut inflater = Inflater::new()?;
+        let mut in_offset = start.in_offset;
+        if start.bits > 0 {
+            let mut byte = [0];
+            file.read_exact_at(&mut byte, self.data_start + in_offset - 1)?;
+            inflater.prime(start.bits, byte[0] >> (8 - start.bits))?;
+        }
+        if !start.window.is_empty() {
+            inflater.set_dictionary(&start.window)?;
+        }
+        let mut out_offset = start.out_offset;
+        // Up to `WINDOW_SIZE` bytes decompressed before the current chunk, followed by what is
+        // decompressed of the current chunk, which starts at `chunk_start`.
+        let mut history = start.window.to_vec();
+        let mut chunk_start = history.len();
+
+        let mut input = vec![0; INPUT_SIZE];
+        let mut input_range = 0..0;
+        loop {
+            let recorded =
+                !is_frontier || self.checkpoints.last().unwrap().out_offset >= passed_end;
+            if out_offset == self.size || (out_offset >= passed_end && recorded) {
+                break;
+            }
+            if input_range.is_empty() {
+                let len = std::cmp::min(INPUT_SIZE as u64, self.compressed_size - in_offset);
+                if len == 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+                file.read_exact_at(&mut input[..len as usize], self.data_start + in_offset)?;
+                in_offset += len;
+                input_range = 0..len as usize;
+            }
+            // Stop at the next chunk boundary, so that a chunk is never split across two calls.
+            let room = CHUNK_SIZE - out_offset % CHUNK_SIZE;
+            let old_len = history.len();
+            history.resize(old_len + room as usize, 0);
+            let result = inflater.inflate(&input[input_range.clone()], &mut history[old_len..]);
+            let (consumed, produced, status) = result?;
+            history.truncate(old_len + produced);
+            input_range.start += consumed;
+            out_offset += produced as u64;
+            #[cfg(test)]
+            {
+                self.peak_history = std::cmp::max(self.peak_history, history.len());
+            }
+
+            if let InflateStatus::BlockBoundary { bits } = status {
+                let last = self.checkpoints.last().unwrap().out_offset;
+                if is_frontier && out_offset > last && out_offset - last >= self.span {
+                    let window_start = history.len().saturating_sub(WINDOW_SIZE);
+                    self.checkpoints.push(Checkpoint {
+                        in_offset: in_offset - input_range.len() as u64,
+                        bits,
+                        out_offset,
+                        window: history[window_start..].into(),
+                    });
+                }
+            }
+            let chunk_len = history.len() - chunk_start;
+            if chunk_len > 0 && (out_offset % CHUNK_SIZE == 0 || out_offset == self.size) {
+                // The chunk is complete, unless decompression started in its middle.
+                let chunk_offset = out_offset - chunk_len as u64;
+                let chunk_index = chunk_offset / CHUNK_SIZE;
+                if chunk_offset % CHUNK_SIZE == 0 && passed.contains(&chunk_index) {
+                    sink(chunk_index, history[chunk_start..].into());
+                }
+                history.drain(..history.len().saturating_sub(WINDOW_SIZE));
+                chunk_start = history.len();
+            }
+            if let InflateStatus::StreamEnd = status {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of a successful call to [`Inflater::inflate`].
+enum InflateStatus {
+    /// The end of the deflate stream was reached.
+    StreamEnd,
+    /// The decompression stopped at the beginning of a block, which starts `bits` bits before the
+    /// end of the consumed input.
+    BlockBoundary { bits: u8 },
+    /// The input was consumed, or the output is full.
+    Progress,
+}
+
+/// A zlib raw inflate stream.
+struct Inflater {
+    // Boxed because zlib keeps a pointer to the stream in its internal state.
+    stream: Box<z_stream>,
+}
+
+extern "C" fn zalloc(_opaque: voidpf, items: uInt, size: uInt) -> voidpf {
+    // SAFETY: `calloc` has no preconditions. zlib checks the returned pointer for null.
+    unsafe { libc::calloc(items as usize, size as usize) }
+}
+
+extern "C" fn zfree(_opaque: voidpf, address: voidpf) {
+    // SAFETY: zlib only frees what it allocated with `zalloc`.
+    unsafe { libc::free(address) }
+}
+
+fn zlib_error(function: &str, ret: c_int) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{function} failed: {ret}"))
+}
+
+impl Inflater {
+    fn new() -> io::Result<Self> {
+        let mut stream = Box::new(z_stream {
+            next_in: ptr::null_mut(),
+            avail_in: 0,
+            total_in: 0,
+            next_out: ptr::null_mut(),
+            avail_out: 0,
+            total_out: 0,
+            msg: ptr::null_mut(),
+            state: ptr::null_mut(),
+            zalloc,
+            zfree,
+            opaque: ptr::null_mut(),
+            data_type: 0,
+            adler: 0,
+            reserved: 0,
+        });
+        // SAFETY: The stream is initialized as zlib requires, and `zlibVersion` returns a static
+        // string. Negative window bits select a raw deflate stream, as used by zip.
+        let ret = unsafe {
+            inflateInit2_(&mut *stream, -15, zlibVersion(), size_of::<z_stream>() as c_int)
+        };
+        if ret != Z_OK {
+            return Err(zlib_error("inflateInit2", ret));
+        }
+        Ok(Self { stream })
+    }
+
+    /// Inserts the `bits` low bits of `value` in the input stream.
+    fn prime(&mut self, bits: u8, value: u8) -> io::Result<()> {
+        // SAFETY: The stream was initialized in `new`.
+        let ret = unsafe { inflatePrime(&mut *self.stream, bits.into(), value.into()) };
+        if ret != Z_OK {
+            return Err(zlib_error("inflatePrime", ret));
+        }
+        Ok(())
+    }
+
+    /// Sets the data decompressed before the current position.
+    fn set_dictionary(&mut self, dictionary: &[u8]) -> io::Result<()> {
+        // SAFETY: The stream was initialized in `new`, and zlib copies the dictionary.
+        let ret = unsafe {
+            inflateSetDictionary(&mut *self.stream, dictionary.as_ptr(), dictionary.len() as uInt)
+        };
+        if ret != Z_OK {
+            return Err(zlib_error("inflateSetDictionary", ret));
+        }
+        Ok(())
+    }
+
+    /// Decompresses `input` to `output`, stopping at the end of the current block. Returns the
+    /// number of bytes consumed and produced.
+    fn inflate(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+    ) -> io::Result<(usize, usize, InflateStatus)> {
+        let stream = &mut *self.stream;
+        // zlib doesn't write to the input despite the pointer being mutable.
+        stream.next_in = input.as_ptr() as *mut u8;
+        stream.avail_in = input.len() as uInt;
+        stream.next_out = output.as_mut_ptr();
+        stream.avail_out = output.len() as uInt;
+        // SAFETY: The stream was initialized in `new`, and its buffers point to `input` and
+        // `output`, which outlive the call. They are reset before the next call.
+        let ret = unsafe { inflate(stream, Z_BLOCK) };
+        let consumed = input.len() - stream.avail_in as usize;
+        let produced = output.len() - stream.avail_out as usize;
+        stream.next_in = ptr::null_mut();
+        stream.avail_in = 0;
+        stream.next_out = ptr::null_mut();
+        stream.avail_out = 0;
+        let status = match ret {
+            Z_STREAM_END => InflateStatus::StreamEnd,
+            // Bit 7 of `data_type` is set at the end of a block header, and bit 6 after the last
+            // block. The low 3 bits are the number of unused bits in the last consumed byte.
+            Z_OK if stream.data_type & 0xc0 == 0x80 => {
+                InflateStatus::BlockBoundary { bits: (stream.data_type & 7) as u8 }
+            }
+            Z_OK => InflateStatus::Progress,
+            // No progress was possible: the input is truncated.
+            Z_BUF_ERROR if !input.is_empty() && !output.is_empty() => {
+                return Err(zlib_error("inflate", ret));
+            }
+            Z_BUF_ERROR => InflateStatus::Progress,
+            _ => return Err(zlib_error("inflate", ret)),
+        };
+        Ok((consumed, produced, status))
+    }
+}
+
+impl Drop for Inflater {
+    fn drop(&mut self) {
+        // SAFETY: The stream was initialized in `new` and isn't used after this.
+        unsafe { inflateEnd(&mut *self.stream) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::ChunkCache;
+    use crate::inode::ZipIndex;
+    use std::io::Write;
+    use zip::write::FileOptions;
+
+    /// Returns data which compresses but isn't trivial, so that it spans several deflate blocks.
+    fn test_data(size: usize) -> Vec<u8> {
+        let mut state: u32 = 1;
+        (0..size)
+            .map(|i| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                if i % 7 == 0 {
+                    (state >> 16) as u8
+                } else {
+                    b"zipfuse"[i % 7]
+                }
+            })
+            .collect()
+    }
+
+    /// Writes `data` deflated to a zip archive, and returns the archive and the entry in it.
+    fn deflate_to_zip(data: &[u8]) -> (File, DeflateEntry) {
+        let mut zip = zip::ZipWriter::new(tempfile::tempfile().unwrap());
+        let options = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        zip.start_file("file", options).unwrap();
+        zip.write_all(data).unwrap();
+        let file = zip.finish().unwrap();
+        let mut archive = zip::ZipArchive::new(file.try_clone().unwrap()).unwrap();
+        let zip_file = archive.by_index_raw(0).unwrap();
+        let entry =
+            DeflateEntry::new(zip_file.data_start(), zip_file.compressed_size(), data.len() as u64);
+        (file, entry)
+    }
+
+    /// Decompresses chunk `index`, and returns it along with all the chunks that were passed.
+    fn decompress(
+        entry: &mut DeflateEntry,
+        file: &File,
+        index: ChunkIndex,
+    ) -> (Box<[u8]>, Vec<ChunkIndex>) {
+        let mut chunk = None;
+        let mut passed = vec![];
+        entry
+            .decompress(file, index, &mut |i, data| {
+                passed.push(i);
+                if i == index {
+                    chunk = Some(data);
+                }
+            })
+            .unwrap();
+        (chunk.unwrap(), passed)
+    }
+
+    #[test]
+    fn random_reads_match_data() {
+        let data = test_data(3 << 20);
+        let (file, mut entry) = deflate_to_zip(&data);
+        entry.span = 256 << 10;
+        let chunks = data.len() as u64 / CHUNK_SIZE;
+        for index in [20, 3, chunks - 1, 0, 21, 47, 5] {
+            let (chunk, _) = decompress(&mut entry, &file, index);
+            let range = entry.chunk_range(index);
+            assert_eq!(*chunk, data[range.start as usize..range.end as usize], "chunk {index}");
+        }
+        assert!(entry.checkpoints.len() > 3, "{} checkpoints", entry.checkpoints.len());
+    }
+
+    #[test]
+    fn decompression_resumes_from_closest_checkpoint() {
+        let data = test_data(2 << 20);
+        let (file, mut entry) = deflate_to_zip(&data);
+        entry.span = 256 << 10;
+        // Record the checkpoints.
+        decompress(&mut entry, &file, data.len() as u64 / CHUNK_SIZE - 1);
+        let checkpoint = &entry.checkpoints[2];
+        let index = checkpoint.out_offset.div_ceil(CHUNK_SIZE) + 1;
+        let checkpoints = entry.checkpoints.len();
+
+        let (chunk, passed) = decompress(&mut entry, &file, index);
+        let range = entry.chunk_range(index);
+        assert_eq!(*chunk, data[range.start as usize..range.end as usize]);
+        // Only the chunk and the next one were passed.
+        assert_eq!(passed, vec![index, index + 1]);
+        assert_eq!(entry.checkpoints.len(), checkpoints);
+    }
+
+    #[test]
+    fn last_chunk_is_partial() {
+        let data = test_data(CHUNK_SIZE as usize + 100);
+        let (file, mut entry) = deflate_to_zip(&data);
+        assert_eq!(entry.chunk_at(CHUNK_SIZE + 5), (1, CHUNK_SIZE..CHUNK_SIZE + 100));
+        let (chunk, passed) = decompress(&mut entry, &file, 1);
+        assert_eq!(*chunk, data[CHUNK_SIZE as usize..]);
+        assert_eq!(passed, vec![1]);
+    }
+
+    #[test]
+    fn reading_the_end_first_keeps_memory_bounded() {
+        let data = test_data(8 << 20);
+        let (file, mut entry) = deflate_to_zip(&data);
+        let mut cache = ChunkCache::new(data.len());
+        let zip_index = ZipIndex { archive: 0, file: 0 };
+        let index = data.len() as u64 / CHUNK_SIZE - 2;
+
+        let chunk = cache.get_or_decompress(zip_index, &mut entry, &file, index).unwrap();
+        let range = entry.chunk_range(index);
+        assert_eq!(*chunk, data[range.start as usize..range.end as usize]);
+        // Only the window and a chunk were held at once, and only the chunk and the next one were
+        // cached, despite the whole entry being decompressed.
+        assert!(entry.peak_history <= WINDOW_SIZE + CHUNK_SIZE as usize, "{}", entry.peak_history);
+        assert_eq!(cache.used(), 2 * CHUNK_SIZE as usize);
+        assert!(entry.checkpoints.len() >= 8, "{} checkpoints", entry.checkpoints.len());
+    }
+
+    #[test]
+    fn truncated_entry_is_an_error() {
+        let data = test_data(1 << 20);
+        let (file, mut entry) = deflate_to_zip(&data);
+        entry.compressed_size /= 2;
+        assert!(entry.decompress(&file, 15, &mut |_, _| {}).is_err());
+    }
+}
@@ -18,6 +18,7 @@ use std::collections::HashMap;
 use std::ffi::{CStr, CString};
 use std::io;
 use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path};
 
 /// `InodeTable` is a table of `InodeData` indexed by `Inode`.
 #[derive(Debug)]
@@ -60,12 +61,22 @@ pub struct InodeData {
     data: InodeDataData,
 }
 
-type ZipIndex = usize;
+/// Index of a zip archive among the archives mounted together.
+pub type ArchiveIndex = usize;
+
+/// `ZipIndex` identifies a file in the mounted zip archives: the archive, and the index of the file
+/// in the `ZipArchive`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ZipIndex {
+    pub archive: ArchiveIndex,
+    pub file: usize,
+}
 
 /// `InodeDataData` is the actual data (or a means to access the data) of the file or the directory
 /// that an inode is representing. In case of a directory, this data is the hash table of the
-/// directory entries. In case of a file, this data is the index of the file in `ZipArchive` which
-/// can be used to retrieve `ZipFile` that provides access to the content of the file.
+/// directory entries. In case of a file, this data is the index of the file in the mounted
+/// archives which can be used to retrieve `ZipFile` that provides access to the content of the
+/// file.
 #[derive(Debug)]
 enum InodeDataData {
     Directory(HashMap<CString, DirectoryEntry>),
@@ -173,20 +184,49 @@ impl InodeTable {
         inode
     }
 
-    /// Constructs `InodeTable` from a zip archive `archive`.
-    pub fn from_zip<R: io::Read + io::Seek>(
-        archive: &mut zip::ZipArchive<R>,
-    ) -> Result<InodeTable> {
+    /// Constructs an `InodeTable` with only the root directory.
+    pub fn new() -> InodeTable {
         let mut table = InodeTable { table: Vec::new() };
 
         // Add the inodes for the invalid and the root directory
         assert_eq!(INVALID, table.put(InodeData::new_dir(0)));
         assert_eq!(ROOT, table.put(InodeData::new_dir(DEFAULT_DIR_MODE)));
+        table
+    }
+
+    /// Constructs `InodeTable` from a zip archive `archive`.
+    #[cfg(test)]
+    pub fn from_zip<R: io::Read + io::Seek>(
+        archive: &mut zip::ZipArchive<R>,
+    ) -> Result<InodeTable> {
+        let mut table = InodeTable::new();
+        table.add_zip(0, archive, Path::new(""))?;
+        Ok(table)
+    }
+
+    /// Adds the files of the zip archive `archive`, whose index among the mounted archives is
+    /// `archive_index`, under the directory `subdir`. The files and directories already in the
+    /// table take precedence: a file of `archive` whose path is already taken is skipped, and so
+    /// is the mode of a directory which already exists.
+    pub fn add_zip<R: io::Read + io::Seek>(
+        &mut self,
+        archive_index: ArchiveIndex,
+        archive: &mut zip::ZipArchive<R>,
+        subdir: &Path,
+    ) -> Result<()> {
+        if !subdir.components().all(|c| matches!(c, Component::Normal(_))) {
+            bail!("{:?} is not a normalized relative path", subdir);
+        }
+        let table = self;
+        // Inodes at or after this one were created for `archive`.
+        let first_new_inode = table.table.len() as Inode;
 
         // For each zip file in the archive, create an inode and add it to the table. If the file's
         // parent directories don't have corresponding inodes in the table, handle them too.
         for i in 0..archive.len() {
-            let file = archive.by_index(i)?;
+            // The raw reader is enough to read the metadata, and also works for files compressed
+            // with methods that the zip crate can't decompress.
+            let file = archive.by_index_raw(i)?;
             let path = file
                 .enclosed_name()
                 .ok_or_else(|| anyhow!("{} is an invalid name", file.name()))?;
@@ -194,7 +234,8 @@ impl InodeTable {
             // fs::canonicalize as this is a non-existing path yet.
 
             let mut parent = ROOT;
-            let mut iter = path.iter().peekable();
+            let full_path = subdir.join(path);
+            let mut iter = full_path.iter().peekable();
 
             let mut file_mode = DEFAULT_FILE_MODE;
             if path.starts_with("bin/") {
@@ -218,9 +259,14 @@ impl InodeTable {
                 // to the next path element.
                 let name = CString::new(name.as_bytes()).unwrap();
                 if let Some(found) = table.find(parent, &name) {
+                    // A file takes the path, or this entry is a file whose path is taken by a
+                    // directory. The entry is shadowed.
+                    if is_file || !table.get(found).unwrap().is_dir() {
+                        break;
+                    }
                     parent = found;
-                    // Update the mode if this is a directory leaf.
-                    if !is_file && is_leaf {
+                    // Update the mode if this is a directory leaf of this archive.
+                    if is_leaf && found >= first_new_inode {
                         let inode = table.get_mut(parent).unwrap();
                         inode.mode = file.unix_mode().unwrap_or(DEFAULT_DIR_MODE);
                     }
@@ -232,7 +278,8 @@ impl InodeTable {
                 // permissions (apart from the ones on lib/), but it might change in the future.
                 // TODO(b/270955654): should we control the file permissions ourselves?
                 let inode = if is_file {
-                    InodeData::new_file(i, file.unix_mode().unwrap_or(file_mode), &file)
+                    let zip_index = ZipIndex { archive: archive_index, file: i };
+                    InodeData::new_file(zip_index, file.unix_mode().unwrap_or(file_mode), &file)
                 } else if is_leaf {
                     InodeData::new_dir(file.unix_mode().unwrap_or(DEFAULT_DIR_MODE))
                 } else {
@@ -242,7 +289,7 @@ impl InodeTable {
                 parent = new;
             }
         }
-        Ok(table)
+        Ok(())
     }
 }
 
@@ -267,6 +314,18 @@ mod tests {
         it.unwrap()
     }
 
+    // Creates an in-memory zip archive, with the files added by `add`.
+    fn zip_archive(
+        add: impl FnOnce(&mut zip::ZipWriter<&mut Cursor<Vec<u8>>>),
+    ) -> zip::ZipArchive<Cursor<Vec<u8>>> {
+        let mut buf: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        let mut writer = zip::ZipWriter::new(&mut buf);
+        add(&mut writer);
+        writer.finish().unwrap();
+        drop(writer);
+        zip::ZipArchive::new(buf).unwrap()
+    }
+
     fn check_dir(it: &InodeTable, parent: Inode, name: &str) -> Inode {
         let name = CString::new(name.as_bytes()).unwrap();
         let inode = it.find(parent, &name);
@@ -420,6 +479,73 @@ mod tests {
         assert_eq!(2 << 20, f.size);
     }
 
+    #[test]
+    fn merged_archives() {
+        let opt = FileOptions::default();
+        let mut first = zip_archive(|zip| {
+            zip.start_file("a/foo", opt).unwrap();
+            zip.write_all(b"first").unwrap();
+            zip.start_file("a/bar", opt).unwrap();
+            zip.start_file("x", opt).unwrap();
+        });
+        let mut second = zip_archive(|zip| {
+            zip.start_file("a/foo", opt).unwrap();
+            zip.write_all(b"second").unwrap();
+            zip.start_file("a/baz", opt).unwrap();
+            // Shadowed by the file "x" of the first archive.
+            zip.start_file("x/y", opt).unwrap();
+            // Shadowed by the directory "a" of the first archive.
+            zip.start_file("a", opt).unwrap();
+        });
+        let mut it = InodeTable::new();
+        it.add_zip(0, &mut first, Path::new("")).unwrap();
+        it.add_zip(1, &mut second, Path::new("")).unwrap();
+
+        let a = check_dir(&it, ROOT, "a");
+        assert_eq!(3, it.get(a).unwrap().get_directory().unwrap().len());
+        let foo = check_file(&it, a, "foo");
+        assert_eq!(Some(ZipIndex { archive: 0, file: 0 }), foo.get_zip_index());
+        assert_eq!(5, foo.size);
+        let baz = check_file(&it, a, "baz");
+        assert_eq!(Some(ZipIndex { archive: 1, file: 1 }), baz.get_zip_index());
+        check_file(&it, ROOT, "x");
+    }
+
+    #[test]
+    fn archive_in_subdir() {
+        let mut archive = zip_archive(|zip| {
+            zip.start_file("foo", FileOptions::default()).unwrap();
+            zip.add_directory("dir", FileOptions::default().unix_permissions(0o500)).unwrap();
+        });
+        let mut it = InodeTable::new();
+        it.add_zip(0, &mut archive, Path::new("extra/0")).unwrap();
+
+        let extra = check_dir(&it, ROOT, "extra");
+        let zero = check_dir(&it, extra, "0");
+        check_file(&it, zero, "foo");
+        let dir = check_dir(&it, zero, "dir");
+        assert_eq!(0o500, it.get(dir).unwrap().mode & 0o777);
+
+        for subdir in ["/abs", "a/../b", "./a"] {
+            assert!(it.add_zip(1, &mut archive, Path::new(subdir)).is_err(), "{subdir}");
+        }
+    }
+
+    #[test]
+    fn directory_mode_of_first_archive_is_kept() {
+        let mut first = zip_archive(|zip| {
+            zip.add_directory("dir", FileOptions::default().unix_permissions(0o500)).unwrap();
+        });
+        let mut second = zip_archive(|zip| {
+            zip.add_directory("dir", FileOptions::default().unix_permissions(0o700)).unwrap();
+        });
+        let mut it = InodeTable::new();
+        it.add_zip(0, &mut first, Path::new("")).unwrap();
+        it.add_zip(1, &mut second, Path::new("")).unwrap();
+        let dir = check_dir(&it, ROOT, "dir");
+        assert_eq!(0o500, it.get(dir).unwrap().mode & 0o777);
+    }
+
     #[test]
     fn rejects_invalid_paths() {
         let invalid_paths = [
@@ -18,7 +18,10 @@
 //! in a zip archive. This filesystem does not supporting writing files back to the zip archive.
 //! The filesystem has to be mounted read only.
 
+mod cache;
+mod inflate;
 mod inode;
+mod seekable_zstd;
 
 use anyhow::{Context as AnyhowContext, Result};
 use clap::{builder::ValueParser, Arg, ArgAction, Command};
@@ -30,26 +33,47 @@ use std::convert::TryFrom;
 use std::ffi::{CStr, CString};
 use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::Read;
 use std::mem::{size_of, MaybeUninit};
 use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Mutex;
 
-use crate::inode::{DirectoryEntry, Inode, InodeData, InodeKind, InodeTable};
+use crate::cache::{ChunkCache, ChunkedEntry};
+use crate::inflate::DeflateEntry;
+use crate::inode::{DirectoryEntry, Inode, InodeData, InodeKind, InodeTable, ZipIndex};
+use crate::seekable_zstd::ZstdEntry;
+
+/// Default memory budget of the cache of decompressed data, in MiB.
+const DEFAULT_CACHE_SIZE_MIB: usize = 16;
 
 fn main() -> Result<()> {
     let matches = clap_command().get_matches();
 
-    let zip_file = matches.get_one::<PathBuf>("ZIPFILE").unwrap();
+    let zip_sources: Vec<ZipSource> =
+        matches.get_many::<ZipSource>("ZIPFILE").unwrap().cloned().collect();
+    let precedence = match matches.get_one::<String>("precedence").unwrap().as_str() {
+        "last" => Precedence::Last,
+        _ => Precedence::First,
+    };
     let mount_point = matches.get_one::<PathBuf>("MOUNTPOINT").unwrap();
     let options = matches.get_one::<String>("options");
     let noexec = matches.get_flag("noexec");
     let ready_prop = matches.get_one::<String>("readyprop");
     let uid: u32 = matches.get_one::<String>("uid").map_or(0, |s| s.parse().unwrap());
     let gid: u32 = matches.get_one::<String>("gid").map_or(0, |s| s.parse().unwrap());
-    run_fuse(zip_file, mount_point, options, noexec, ready_prop, uid, gid)?;
+    let cache_size = *matches.get_one::<usize>("cachesize").unwrap() << 20;
+    run_fuse(
+        &zip_sources,
+        precedence,
+        mount_point,
+        options,
+        noexec,
+        ready_prop,
+        uid,
+        gid,
+        cache_size,
+    )?;
 
     Ok(())
 }
@@ -75,19 +99,72 @@ fn clap_command() -> Command {
         )
         .arg(Arg::new("uid").short('u').help("numeric UID who's the owner of the files"))
         .arg(Arg::new("gid").short('g').help("numeric GID who's the group of the files"))
-        .arg(Arg::new("ZIPFILE").value_parser(ValueParser::path_buf()).required(true))
+        .arg(
+            Arg::new("cachesize")
+                .long("cache-size")
+                .value_parser(clap::value_parser!(usize))
+                .default_value(DEFAULT_CACHE_SIZE_MIB.to_string())
+                .help("Memory budget in MiB for caching the content of compressed files"),
+        )
+        .arg(
+            Arg::new("precedence")
+                .long("precedence")
+                .value_parser(["first", "last"])
+                .default_value("first")
+                .help("Which of the zip files provides a path that several of them have"),
+        )
+        .arg(
+            Arg::new("ZIPFILE")
+                .value_parser(ZipSource::parse)
+                .num_args(1..)
+                .required(true)
+                .value_name("ZIPFILE[:SUBDIR]")
+                .help("Zip files to mount, each in SUBDIR of the mount point if specified"),
+        )
         .arg(Arg::new("MOUNTPOINT").value_parser(ValueParser::path_buf()).required(true))
 }
 
-/// Runs a fuse filesystem by mounting `zip_file` on `mount_point`.
+/// A zip file to mount, and the directory of the mount point where its files appear.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ZipSource {
+    pub zip_file: PathBuf,
+    /// Relative to the mount point. Empty for the mount point itself.
+    pub subdir: PathBuf,
+}
+
+impl ZipSource {
+    /// Parses `ZIPFILE[:SUBDIR]`. The path of the zip file can't contain ':' if `SUBDIR` is
+    /// omitted.
+    fn parse(arg: &str) -> Result<ZipSource> {
+        let (zip_file, subdir) = arg.rsplit_once(':').unwrap_or((arg, ""));
+        anyhow::ensure!(!zip_file.is_empty(), "Empty zip file path in {:?}", arg);
+        Ok(ZipSource { zip_file: zip_file.into(), subdir: subdir.into() })
+    }
+}
+
+/// Which zip file provides a path when several of the mounted ones have it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Precedence {
+    /// The zip file which comes first on the command line.
+    First,
+    /// The zip file which comes last on the command line.
+    Last,
+}
+
+/// Runs a fuse filesystem by mounting the files of `zip_sources` on `mount_point`, as a single
+/// tree. At most `cache_size` bytes of decompressed data are kept in memory. `ready_prop` is set
+/// once all of the zip files are mounted.
+#[allow(clippy::too_many_arguments)]
 pub fn run_fuse(
-    zip_file: &Path,
+    zip_sources: &[ZipSource],
+    precedence: Precedence,
     mount_point: &Path,
     extra_options: Option<&String>,
     noexec: bool,
     ready_prop: Option<&String>,
     uid: u32,
     gid: u32,
+    cache_size: usize,
 ) -> Result<()> {
     const MAX_READ: u32 = 1 << 20; // TODO(jiyong): tune this
     const MAX_WRITE: u32 = 1 << 13; // This is a read-only filesystem
@@ -120,30 +197,39 @@ pub fn run_fuse(
 
     let mut config = fuse::FuseConfig::new();
     config.dev_fuse(dev_fuse).max_write(MAX_WRITE).max_read(MAX_READ);
-    Ok(config.enter_message_loop(ZipFuse::new(zip_file, uid, gid)?)?)
+    let zipfuse = ZipFuse::new(zip_sources, precedence, uid, gid, cache_size)?;
+    Ok(config.enter_message_loop(zipfuse)?)
 }
 
 struct ZipFuse {
-    zip_archive: Mutex<zip::ZipArchive<File>>,
-    raw_file: Mutex<File>,
+    archives: Vec<Archive>,
     inode_table: InodeTable,
     open_files: Mutex<HashMap<Handle, OpenFile>>,
     open_dirs: Mutex<HashMap<Handle, OpenDirBuf>>,
+    chunk_cache: Mutex<ChunkCache>,
     uid: u32,
     gid: u32,
 }
 
+/// A mounted zip archive.
+struct Archive {
+    zip_archive: Mutex<zip::ZipArchive<File>>,
+    raw_file: Mutex<File>,
+}
+
 /// Represents a [`ZipFile`] that is opened.
 struct OpenFile {
-    open_count: u32, // multiple opens share the buf because this is a read-only filesystem
+    open_count: u32, // multiple opens share the content because this is a read-only filesystem
+    zip_index: ZipIndex,
     content: OpenFileContent,
 }
 
-/// Holds the content of a [`ZipFile`]. Depending on whether it is compressed or not, the
-/// entire content is stored, or only the zip index is stored.
+/// Describes how to read the content of a [`ZipFile`]. Uncompressed files are read directly from
+/// the zip archive. Compressed files are decompressed on demand, one chunk at a time, and the
+/// chunks are kept in [`ZipFuse::chunk_cache`].
 enum OpenFileContent {
-    Compressed(Box<[u8]>),
-    Uncompressed(usize), // zip index
+    Compressed(Box<dyn ChunkedEntry>),
+    Uncompressed,
 }
 
 /// Holds the directory entries in a directory opened by [`opendir`].
@@ -163,21 +249,46 @@ fn timeout_max() -> std::time::Duration {
 }
 
 impl ZipFuse {
-    fn new(zip_file: &Path, uid: u32, gid: u32) -> Result<ZipFuse> {
-        // TODO(jiyong): Use O_DIRECT to avoid double caching.
-        // `.custom_flags(nix::fcntl::OFlag::O_DIRECT.bits())` currently doesn't work.
-        let f = File::open(zip_file)?;
-        let mut z = zip::ZipArchive::new(f)?;
-        // Open the same file again so that we can directly access it when accessing
-        // uncompressed zip_file entries in it. `ZipFile` doesn't implement `Seek`.
-        let raw_file = File::open(zip_file)?;
-        let it = InodeTable::from_zip(&mut z)?;
+    fn new(
+        zip_sources: &[ZipSource],
+        precedence: Precedence,
+        uid: u32,
+        gid: u32,
+        cache_size: usize,
+    ) -> Result<ZipFuse> {
+        let mut archives = Vec::with_capacity(zip_sources.len());
+        for source in zip_sources {
+            let zip_file = &source.zip_file;
+            // TODO(jiyong): Use O_DIRECT to avoid double caching.
+            // `.custom_flags(nix::fcntl::OFlag::O_DIRECT.bits())` currently doesn't work.
+            let f =
+                File::open(zip_file).with_context(|| format!("Failed to open {:?}", zip_file))?;
+            let z = zip::ZipArchive::new(f)
+                .with_context(|| format!("Failed to read {:?}", zip_file))?;
+            // Open the same file again so that we can directly access it when accessing
+            // zip_file entries in it. `ZipFile` doesn't implement `Seek`.
+            let raw_file = File::open(zip_file)?;
+            archives.push(Archive { zip_archive: Mutex::new(z), raw_file: Mutex::new(raw_file) });
+        }
+
+        // The paths already in the table take precedence over those of the archives added later.
+        let mut order: Vec<_> = (0..archives.len()).collect();
+        if precedence == Precedence::Last {
+            order.reverse();
+        }
+        let mut it = InodeTable::new();
+        for i in order {
+            let zip_archive = archives[i].zip_archive.get_mut().unwrap();
+            let subdir = &zip_sources[i].subdir;
+            it.add_zip(i, zip_archive, subdir)
+                .with_context(|| format!("Failed to mount {:?}", zip_sources[i].zip_file))?;
+        }
         Ok(ZipFuse {
-            zip_archive: Mutex::new(z),
-            raw_file: Mutex::new(raw_file),
+            archives,
             inode_table: it,
             open_files: Mutex::new(HashMap::new()),
             open_dirs: Mutex::new(HashMap::new()),
+            chunk_cache: Mutex::new(ChunkCache::new(cache_size)),
             uid,
             gid,
         })
@@ -255,9 +366,8 @@ impl fuse::filesystem::FileSystem for ZipFuse {
         let mut open_files = self.open_files.lock().unwrap();
         let handle = inode as Handle;
 
-        // If the file is already opened, just increase the reference counter. If not, read the
-        // entire file content to the buffer. When `read` is called, a portion of the buffer is
-        // copied to the kernel.
+        // If the file is already opened, just increase the reference counter. If not, find out
+        // how to read its content. Nothing is decompressed until `read` is called.
         if let Some(file) = open_files.get_mut(&handle) {
             if file.open_count == 0 {
                 return Err(ebadf());
@@ -266,11 +376,13 @@ impl fuse::filesystem::FileSystem for ZipFuse {
         } else {
             let inode_data = self.find_inode(inode)?;
             let zip_index = inode_data.get_zip_index().ok_or_else(ebadf)?;
-            let mut zip_archive = self.zip_archive.lock().unwrap();
-            let mut zip_file = zip_archive.by_index(zip_index)?;
+            let archive = &self.archives[zip_index.archive];
+            let mut zip_archive = archive.zip_archive.lock().unwrap();
+            // The data is read from `raw_file`, so there is no need for a decompressing reader.
+            let zip_file = zip_archive.by_index_raw(zip_index.file)?;
             let content = match zip_file.compression() {
-                zip::CompressionMethod::Stored => OpenFileContent::Uncompressed(zip_index),
-                _ => {
+                zip::CompressionMethod::Stored => OpenFileContent::Uncompressed,
+                method => {
                     if let Some(mode) = zip_file.unix_mode() {
                         let is_reg_file = zip_file.is_file();
                         let is_executable =
@@ -283,12 +395,39 @@ impl fuse::filesystem::FileSystem for ZipFuse {
                             );
                         }
                     }
-                    let mut buf = Vec::with_capacity(inode_data.size as usize);
-                    zip_file.read_to_end(&mut buf)?;
-                    OpenFileContent::Compressed(buf.into_boxed_slice())
+                    let data_start = zip_file.data_start();
+                    let compressed_size = zip_file.compressed_size();
+                    let entry: Box<dyn ChunkedEntry> = match method {
+                        zip::CompressionMethod::Deflated => Box::new(DeflateEntry::new(
+                            data_start,
+                            compressed_size,
+                            inode_data.size,
+                        )),
+                        zip::CompressionMethod::ZSTD => {
+                            let raw_file = archive.raw_file.lock().unwrap();
+                            let size = inode_data.size;
+                            let max_chunk_size = self.chunk_cache.lock().unwrap().budget() as u64;
+                            Box::new(ZstdEntry::new(
+                                &raw_file,
+                                data_start,
+                                compressed_size,
+                                size,
+                                max_chunk_size,
+                            )?)
+                        }
+                        _ => {
+                            log::error!(
+                                "{:?} uses unsupported compression method {}",
+                                zip_file.mangled_name(),
+                                method
+                            );
+                            return Err(io::Error::from_raw_os_error(libc::ENOTSUP));
+                        }
+                    };
+                    OpenFileContent::Compressed(entry)
                 }
             };
-            open_files.insert(handle, OpenFile { open_count: 1, content });
+            open_files.insert(handle, OpenFile { open_count: 1, zip_index, content });
         }
         // Note: we don't return `DIRECT_IO` here, because then applications wouldn't be able to
         // mmap the files.
@@ -305,9 +444,9 @@ impl fuse::filesystem::FileSystem for ZipFuse {
         _flock_release: bool,
         _lock_owner: Option<u64>,
     ) -> io::Result<()> {
-        // Releases the buffer for the `handle` when it is opened for nobody. While this is good
-        // for saving memory, this has a performance implication because we need to decompress
-        // again when the same file is opened in the future.
+        // Releases the content for the `handle` when it is opened for nobody. The decompressed
+        // chunks stay in the cache until they are evicted, but the checkpoints recorded to resume
+        // decompression are lost.
         let mut open_files = self.open_files.lock().unwrap();
         let handle = inode as Handle;
         if let Some(file) = open_files.get_mut(&handle) {
@@ -320,11 +459,10 @@ impl fuse::filesystem::FileSystem for ZipFuse {
         }
     }
 
-    #[allow(clippy::unused_io_amount)]
     fn read<W: io::Write + ZeroCopyWriter>(
         &self,
         _ctx: Context,
-        _inode: Self::Inode,
+        inode: Self::Inode,
         handle: Self::Handle,
         mut w: W,
         size: u32,
@@ -332,27 +470,45 @@ impl fuse::filesystem::FileSystem for ZipFuse {
         _lock_owner: Option<u64>,
         _flags: u32,
     ) -> io::Result<usize> {
-        let open_files = self.open_files.lock().unwrap();
-        let file = open_files.get(&handle).ok_or_else(ebadf)?;
+        let mut open_files = self.open_files.lock().unwrap();
+        let file = open_files.get_mut(&handle).ok_or_else(ebadf)?;
         if file.open_count == 0 {
             return Err(ebadf());
         }
-        Ok(match &file.content {
-            OpenFileContent::Uncompressed(zip_index) => {
-                let mut zip_archive = self.zip_archive.lock().unwrap();
-                let zip_file = zip_archive.by_index(*zip_index)?;
+        Ok(match &mut file.content {
+            OpenFileContent::Uncompressed => {
+                let archive = &self.archives[file.zip_index.archive];
+                let mut zip_archive = archive.zip_archive.lock().unwrap();
+                let zip_file = zip_archive.by_index(file.zip_index.file)?;
                 let start = zip_file.data_start() + offset;
                 let remaining_size = zip_file.size() - offset;
                 let size = std::cmp::min(remaining_size, size.into());
 
-                let mut raw_file = self.raw_file.lock().unwrap();
+                let mut raw_file = archive.raw_file.lock().unwrap();
                 w.write_from(&mut raw_file, size as usize, start)?
             }
-            OpenFileContent::Compressed(buf) => {
-                let start = offset as usize;
-                let end = start + size as usize;
-                let end = std::cmp::min(end, buf.len());
-                w.write(&buf[start..end])?
+            OpenFileContent::Compressed(entry) => {
+                let file_size = self.find_inode(inode)?.size;
+                let end = std::cmp::min(offset.saturating_add(size.into()), file_size);
+                let archive = &self.archives[file.zip_index.archive];
+                let raw_file = archive.raw_file.lock().unwrap();
+                let mut chunk_cache = self.chunk_cache.lock().unwrap();
+                let mut pos = offset;
+                while pos < end {
+                    let (index, range) = entry.chunk_at(pos);
+                    let chunk = chunk_cache.get_or_decompress(
+                        file.zip_index,
+                        entry.as_mut(),
+                        &raw_file,
+                        index,
+                    )?;
+                    let chunk_end = std::cmp::min(end, range.end);
+                    w.write_all(
+                        &chunk[(pos - range.start) as usize..(chunk_end - range.start) as usize],
+                    )?;
+                    pos = chunk_end;
+                }
+                pos.saturating_sub(offset) as usize
             }
         })
     }
@@ -482,31 +638,51 @@ mod tests {
         noexec: bool,
         uid: u32,
         gid: u32,
+        precedence_last: bool,
     }
 
-    #[cfg(not(target_os = "android"))]
     fn start_fuse(zip_path: &Path, mnt_path: &Path, opt: Options) {
-        let zip_path = PathBuf::from(zip_path);
+        start_fuse_with_zips(&[zip_path.to_str().unwrap()], mnt_path, opt);
+    }
+
+    // Mounts the zip files specified as `ZIPFILE[:SUBDIR]` in `zip_args`.
+    #[cfg(not(target_os = "android"))]
+    fn start_fuse_with_zips(zip_args: &[&str], mnt_path: &Path, opt: Options) {
+        let zip_sources: Vec<_> = zip_args.iter().map(|a| ZipSource::parse(a).unwrap()).collect();
+        let precedence = if opt.precedence_last { Precedence::Last } else { Precedence::First };
         let mnt_path = PathBuf::from(mnt_path);
         std::thread::spawn(move || {
-            crate::run_fuse(&zip_path, &mnt_path, None, opt.noexec, opt.uid, opt.gid).unwrap();
+            crate::run_fuse(
+                &zip_sources,
+                precedence,
+                &mnt_path,
+                None,
+                opt.noexec,
+                None,
+                opt.uid,
+                opt.gid,
+                DEFAULT_CACHE_SIZE_MIB << 20,
+            )
+            .unwrap();
         });
     }
 
     #[cfg(target_os = "android")]
-    fn start_fuse(zip_path: &Path, mnt_path: &Path, opt: Options) {
+    fn start_fuse_with_zips(zip_args: &[&str], mnt_path: &Path, opt: Options) {
         // Note: for some unknown reason, running a thread to serve fuse doesn't work on Android.
         // Explicitly spawn a zipfuse process instead.
         // TODO(jiyong): fix this
         let noexec = if opt.noexec { "--noexec" } else { "" };
+        let precedence = if opt.precedence_last { "last" } else { "first" };
         assert!(std::process::Command::new("sh")
             .arg("-c")
             .arg(format!(
-                "/data/local/tmp/zipfuse {} -u {} -g {} {} {}",
+                "/data/local/tmp/zipfuse {} --precedence {} -u {} -g {} {} {}",
                 noexec,
+                precedence,
                 opt.uid,
                 opt.gid,
-                zip_path.display(),
+                zip_args.join(" "),
                 mnt_path.display()
             ))
             .spawn()
@@ -667,7 +843,7 @@ mod tests {
         const UID: u32 = 100;
         const GID: u32 = 200;
         run_test_with_options(
-            Options { noexec: true, uid: UID, gid: GID },
+            Options { noexec: true, uid: UID, gid: GID, ..Default::default() },
             |zip| {
                 zip.start_file("foo", FileOptions::default()).unwrap();
                 zip.write_all(b"0123456789").unwrap();
@@ -839,6 +1015,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn random_reads_of_compressed_file() {
+        fn data() -> Vec<u8> {
+            (0..(3 << 20) as u32).map(|i| (i.wrapping_mul(i) >> 11) as u8).collect()
+        }
+        run_test(
+            |zip| {
+                zip.start_file(
+                    "foo",
+                    FileOptions::default().compression_method(zip::CompressionMethod::Deflated),
+                )
+                .unwrap();
+                zip.write_all(&data()).unwrap();
+            },
+            |root| {
+                use std::io::{Read, Seek, SeekFrom};
+                let data = data();
+                let mut file = File::open(root.join("foo")).unwrap();
+                for offset in [2 << 20, 12345, (3 << 20) - 100, 0, (1 << 20) + 7] {
+                    let mut buf = vec![0; 200];
+                    file.seek(SeekFrom::Start(offset as u64)).unwrap();
+                    let len = file.read(&mut buf).unwrap();
+                    assert_eq!(buf[..len], data[offset..offset + len]);
+                }
+            },
+        );
+    }
+
     #[cfg(not(target_os = "android"))] // Android doesn't have the loopdev crate
     #[test]
     fn supports_zip_on_block_device() {
@@ -869,6 +1073,76 @@ mod tests {
         run_fuse_and_check_test_zip(&test_dir.path(), &ld.path().unwrap());
     }
 
+    fn run_multiple_zips_test(precedence_last: bool, check: fn(&Path)) {
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let mut zip_args = vec![];
+        for (name, subdir, content) in
+            [("a.zip", "", b"a".as_slice()), ("b.zip", "", b"b"), ("c.zip", ":extra/c", b"c")]
+        {
+            let zip_path = test_dir.path().join(name);
+            let mut zip = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+            zip.start_file("common", FileOptions::default()).unwrap();
+            zip.write_all(content).unwrap();
+            zip.start_file(format!("dir/{name}"), FileOptions::default()).unwrap();
+            zip.finish().unwrap();
+            zip_args.push(format!("{}{}", zip_path.display(), subdir));
+        }
+
+        let mnt_path = test_dir.path().join("mnt");
+        fs::create_dir(&mnt_path).unwrap();
+        let zip_args: Vec<_> = zip_args.iter().map(String::as_str).collect();
+        start_fuse_with_zips(
+            &zip_args,
+            &mnt_path,
+            Options { precedence_last, ..Default::default() },
+        );
+        assert!(wait_for_mount(&mnt_path).is_ok());
+        check(&mnt_path);
+        assert!(nix::mount::umount2(&mnt_path, nix::mount::MntFlags::empty()).is_ok());
+    }
+
+    #[test]
+    fn multiple_zips() {
+        run_multiple_zips_test(false, |root| {
+            check_dir(root, "", &["common"], &["dir", "extra"]);
+            check_dir(root, "dir", &["a.zip", "b.zip"], &[]);
+            check_file(root, "common", b"a");
+            check_dir(root, "extra/c", &["common"], &["dir"]);
+            check_file(root, "extra/c/common", b"c");
+            check_file(root, "extra/c/dir/c.zip", &[]);
+        });
+    }
+
+    #[test]
+    fn multiple_zips_last_takes_precedence() {
+        run_multiple_zips_test(true, |root| {
+            check_file(root, "common", b"b");
+            check_file(root, "extra/c/common", b"c");
+        });
+    }
+
+    #[test]
+    fn parse_zip_source() {
+        let parse = |arg| ZipSource::parse(arg).unwrap();
+        assert_eq!(
+            parse("/apk/base.apk"),
+            ZipSource { zip_file: "/apk/base.apk".into(), subdir: "".into() }
+        );
+        assert_eq!(
+            parse("/dev/block/mapper/extra-apk-0:extra-apk/0"),
+            ZipSource {
+                zip_file: "/dev/block/mapper/extra-apk-0".into(),
+                subdir: "extra-apk/0".into()
+            }
+        );
+        assert!(ZipSource::parse(":dir").is_err());
+
+        let matches = clap_command().get_matches_from(["zipfuse", "a.zip", "b.zip:b", "/mnt"]);
+        let sources: Vec<_> = matches.get_many::<ZipSource>("ZIPFILE").unwrap().collect();
+        assert_eq!(sources, [&parse("a.zip"), &parse("b.zip:b")]);
+        assert_eq!(matches.get_one::<PathBuf>("MOUNTPOINT").unwrap(), Path::new("/mnt"));
+    }
+
     #[test]
     fn verify_command() {
         // Check that the command parsing has been configured in a valid way.
@@ -0,0 +1,279 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Random access to zstd compressed zip entries.
+//!
+//! An entry in the [seekable format] consists of independent zstd frames followed by a seek
+//! table, so each frame is a chunk which can be decompressed on its own. Other entries are
+//! decompressed as a single chunk. As a chunk which doesn't fit in the cache would be decompressed
+//! again on every read, entries with a chunk larger than the cache can't be read.
+//!
+//! [seekable format]: https://github.com/facebook/zstd/blob/dev/contrib/seekable_format/zstd_seekable_compression_format.md
+
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+
+use crate::cache::{ChunkIndex, ChunkedEntry};
+
+const SKIPPABLE_FRAME_MAGIC: u32 = 0x184d2a5e;
+const SEEKABLE_MAGIC: u32 = 0x8f92eab1;
+/// Size of the frame header of the skippable frame holding the seek table.
+const SKIPPABLE_HEADER_SIZE: u64 = 8;
+/// Size of the seek table footer: number of frames, descriptor and magic number.
+const FOOTER_SIZE: u64 = 9;
+const CHECKSUM_FLAG: u8 = 1 << 7;
+const RESERVED_BITS: u8 = 0x7c;
+
+/// A frame of a zstd compressed entry.
+#[derive(Debug, Eq, PartialEq)]
+struct Frame {
+    /// Range of the compressed frame, relative to the start of the entry data.
+    compressed: Range<u64>,
+    /// Range of the decompressed content which the frame covers.
+    decompressed: Range<u64>,
+}
+
+/// A zstd compressed zip entry.
+pub struct ZstdEntry {
+    /// Offset of the compressed data in the zip archive.
+    data_start: u64,
+    frames: Vec<Frame>,
+}
+
+impl ZstdEntry {
+    /// Creates an entry with `compressed_size` bytes of zstd compressed data at `data_start` in
+    /// `file`, which decompress to `size` bytes. If the data ends with a valid seek table, its
+    /// frames are decompressed individually. Fails with `EFBIG` if a chunk would decompress to
+    /// more than `max_chunk_size` bytes.
+    pub fn new(
+        file: &File,
+        data_start: u64,
+        compressed_size: u64,
+        size: u64,
+        max_chunk_size: u64,
+    ) -> io::Result<Self> {
+        let frames = match read_seek_table(file, data_start, compressed_size, size)? {
+            Some(frames) => frames,
+            None => {
+                log::info!("zstd entry at {} has no seek table", data_start);
+                vec![Frame { compressed: 0..compressed_size, decompressed: 0..size }]
+            }
+        };
+        if let Some(frame) =
+            frames.iter().find(|f| f.decompressed.end - f.decompressed.start > max_chunk_size)
+        {
+            log::error!(
+                "zstd entry at {} has a frame of {} bytes, more than the cache size {}. \
+                Compress it in the seekable format with smaller frames",
+                data_start,
+                frame.decompressed.end - frame.decompressed.start,
+                max_chunk_size
+            );
+            return Err(io::Error::from_raw_os_error(libc::EFBIG));
+        }
+        Ok(Self { data_start, frames })
+    }
+}
+
+/// Reads the seek table at the end of the compressed data, and checks that the frames it
+/// describes cover the whole entry. Returns `None` if there is no valid seek table.
+fn read_seek_table(
+    file: &File,
+    data_start: u64,
+    compressed_size: u64,
+    size: u64,
+) -> io::Result<Option<Vec<Frame>>> {
+    if compressed_size < SKIPPABLE_HEADER_SIZE + FOOTER_SIZE {
+        return Ok(None);
+    }
+    let mut footer = [0; FOOTER_SIZE as usize];
+    file.read_exact_at(&mut footer, data_start + compressed_size - FOOTER_SIZE)?;
+    let num_frames = u32::from_le_bytes(footer[0..4].try_into().unwrap()) as u64;
+    let descriptor = footer[4];
+    let magic = u32::from_le_bytes(footer[5..9].try_into().unwrap());
+    if magic != SEEKABLE_MAGIC || descriptor & RESERVED_BITS != 0 {
+        return Ok(None);
+    }
+
+    let entry_size = if descriptor & CHECKSUM_FLAG != 0 { 12 } else { 8 };
+    let table_size = num_frames * entry_size + FOOTER_SIZE;
+    let Some(table_start) = compressed_size.checked_sub(SKIPPABLE_HEADER_SIZE + table_size) else {
+        return Ok(None);
+    };
+    let mut table = vec![0; (SKIPPABLE_HEADER_SIZE + table_size - FOOTER_SIZE) as usize];
+    file.read_exact_at(&mut table, data_start + table_start)?;
+    let read_u32 =
+        |offset: usize| u32::from_le_bytes(table[offset..offset + 4].try_into().unwrap());
+    if read_u32(0) != SKIPPABLE_FRAME_MAGIC || read_u32(4) as u64 != table_size {
+        return Ok(None);
+    }
+
+    let mut frames = Vec::with_capacity(num_frames as usize);
+    let (mut compressed, mut decompressed) = (0, 0);
+    for i in 0..num_frames {
+        let offset = (SKIPPABLE_HEADER_SIZE + i * entry_size) as usize;
+        let compressed_end = compressed + read_u32(offset) as u64;
+        let decompressed_end = decompressed + read_u32(offset + 4) as u64;
+        frames.push(Frame {
+            compressed: compressed..compressed_end,
+            decompressed: decompressed..decompressed_end,
+        });
+        (compressed, decompressed) = (compressed_end, decompressed_end);
+    }
+    if compressed != table_start || decompressed != size {
+        return Ok(None);
+    }
+    Ok(Some(frames))
+}
+
+impl ChunkedEntry for ZstdEntry {
+    fn chunk_at(&self, offset: u64) -> (ChunkIndex, Range<u64>) {
+        let index = self.frames.partition_point(|f| f.decompressed.end <= offset);
+        (index as ChunkIndex, self.frames[index].decompressed.clone())
+    }
+
+    fn decompress(
+        &mut self,
+        file: &File,
+        index: ChunkIndex,
+        sink: &mut dyn FnMut(ChunkIndex, Box<[u8]>),
+    ) -> io::Result<()> {
+        let frame = &self.frames[index as usize];
+        let mut compressed = vec![0; (frame.compressed.end - frame.compressed.start) as usize];
+        file.read_exact_at(&mut compressed, self.data_start + frame.compressed.start)?;
+        let size = (frame.decompressed.end - frame.decompressed.start) as usize;
+        let data = zstd::bulk::decompress(&compressed, size)?;
+        if data.len() != size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("zstd frame {} has {} bytes instead of {}", index, data.len(), size),
+            ));
+        }
+        sink(index, data.into_boxed_slice());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const FRAME_SIZE: usize = 1000;
+    const MAX_CHUNK_SIZE: u64 = 10000;
+
+    /// Compresses `data` in the seekable format, with frames of `FRAME_SIZE` bytes.
+    fn compress_seekable(data: &[u8], checksum: bool) -> Vec<u8> {
+        let mut out = vec![];
+        let mut table = vec![];
+        let chunks = data.chunks(FRAME_SIZE);
+        let num_frames = chunks.len() as u32;
+        for chunk in chunks {
+            let frame = zstd::bulk::compress(chunk, 3).unwrap();
+            table.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            table.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            if checksum {
+                table.extend_from_slice(&0u32.to_le_bytes());
+            }
+            out.extend_from_slice(&frame);
+        }
+        table.extend_from_slice(&num_frames.to_le_bytes());
+        table.push(if checksum { CHECKSUM_FLAG } else { 0 });
+        table.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+        out.extend_from_slice(&SKIPPABLE_FRAME_MAGIC.to_le_bytes());
+        out.extend_from_slice(&(table.len() as u32).to_le_bytes());
+        out.extend_from_slice(&table);
+        out
+    }
+
+    /// Writes `compressed` after some padding, and returns the file and the entry.
+    fn try_entry_for(
+        compressed: &[u8],
+        size: usize,
+        max_chunk_size: u64,
+    ) -> (File, io::Result<ZstdEntry>) {
+        const PADDING: usize = 30;
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&[0; PADDING]).unwrap();
+        file.write_all(compressed).unwrap();
+        let entry = ZstdEntry::new(
+            &file,
+            PADDING as u64,
+            compressed.len() as u64,
+            size as u64,
+            max_chunk_size,
+        );
+        (file, entry)
+    }
+
+    fn entry_for(compressed: &[u8], size: usize) -> (File, ZstdEntry) {
+        let (file, entry) = try_entry_for(compressed, size, MAX_CHUNK_SIZE);
+        (file, entry.unwrap())
+    }
+
+    fn decompress(entry: &mut ZstdEntry, file: &File, index: ChunkIndex) -> Box<[u8]> {
+        let mut chunk = None;
+        entry.decompress(file, index, &mut |_, data| chunk = Some(data)).unwrap();
+        chunk.unwrap()
+    }
+
+    fn test_data() -> Vec<u8> {
+        (0..5500u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn frames_of_seekable_entry_are_chunks() {
+        let data = test_data();
+        for checksum in [false, true] {
+            let (file, mut entry) = entry_for(&compress_seekable(&data, checksum), data.len());
+            assert_eq!(entry.frames.len(), 6);
+            assert_eq!(entry.chunk_at(4321), (4, 4000..5000));
+            assert_eq!(entry.chunk_at(5499), (5, 5000..5500));
+            assert_eq!(*decompress(&mut entry, &file, 4), data[4000..5000]);
+            assert_eq!(*decompress(&mut entry, &file, 0), data[0..1000]);
+        }
+    }
+
+    #[test]
+    fn entry_without_seek_table_is_one_chunk() {
+        let data = test_data();
+        let (file, mut entry) = entry_for(&zstd::bulk::compress(&data, 3).unwrap(), data.len());
+        assert_eq!(entry.chunk_at(4321), (0, 0..5500));
+        assert_eq!(*decompress(&mut entry, &file, 0), data[..]);
+    }
+
+    #[test]
+    fn entry_with_chunk_larger_than_cache_is_rejected() {
+        let data = test_data();
+        let compressed = zstd::bulk::compress(&data, 3).unwrap();
+        let (_, entry) = try_entry_for(&compressed, data.len(), data.len() as u64 - 1);
+        assert_eq!(entry.err().and_then(|e| e.raw_os_error()), Some(libc::EFBIG));
+
+        let compressed = compress_seekable(&data, false);
+        let (_, entry) = try_entry_for(&compressed, data.len(), FRAME_SIZE as u64 - 1);
+        assert_eq!(entry.err().and_then(|e| e.raw_os_error()), Some(libc::EFBIG));
+        assert!(try_entry_for(&compressed, data.len(), FRAME_SIZE as u64).1.is_ok());
+    }
+
+    #[test]
+    fn seek_table_not_matching_size_is_ignored() {
+        let data = test_data();
+        let (_, entry) = entry_for(&compress_seekable(&data, false), data.len() + 1);
+        assert_eq!(entry.frames.len(), 1);
+    }
+}
@@ -210,9 +210,18 @@ pub enum HashAlgorithm {
     #[default]
     /// SHA-256
     SHA256 = 1,
+    /// SHA-512. Not used by APK Signature Scheme v4, only for the hash trees of other files.
+    SHA512 = 2,
 }
 
 impl HashAlgorithm {
+    pub(crate) fn message_digest(&self) -> MessageDigest {
+        match self {
+            HashAlgorithm::SHA256 => MessageDigest::sha256(),
+            HashAlgorithm::SHA512 => MessageDigest::sha512(),
+        }
+    }
+
     pub(crate) fn from_read<R: Read>(read: &mut R) -> Result<Self> {
         let val = read.read_u32::<LittleEndian>()?;
         Self::from_u32(val).context(format!("Unsupported hash algorithm: {}", val))
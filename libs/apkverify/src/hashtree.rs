@@ -111,7 +111,7 @@ pub fn generate_hash_tree<R: Read>(
 
 /// Hash one block of input using the given hash algorithm and the salt. Input might be smaller
 /// than a block, in which case zero is padded.
-fn hash_one_block(
+pub(crate) fn hash_one_block(
     input: &[u8],
     salt: &[u8],
     block_size: usize,
@@ -125,10 +125,14 @@ fn hash_one_block(
     Ok(ctx.finish()?)
 }
 
-type Range = std::ops::Range<usize>;
+pub(crate) type Range = std::ops::Range<usize>;
 
 /// Calculate the ranges of hash for each level
-fn calc_hash_levels(input_size: usize, block_size: usize, digest_size: usize) -> Vec<Range> {
+pub(crate) fn calc_hash_levels(
+    input_size: usize,
+    block_size: usize,
+    digest_size: usize,
+) -> Vec<Range> {
     // The input is split into multiple blocks and each block is hashed, which becomes the input
     // for the next level. Size of a single hash is `digest_size`.
     let mut level_sizes = Vec::new();
@@ -167,7 +171,7 @@ fn calc_hash_levels(input_size: usize, block_size: usize, digest_size: usize) ->
 }
 
 /// Round `n` up to the nearest multiple of `unit`
-fn round_to_multiple(n: usize, unit: usize) -> usize {
+pub(crate) fn round_to_multiple(n: usize, unit: usize) -> usize {
     (n + unit - 1) & !(unit - 1)
 }
 
@@ -178,7 +182,7 @@ fn round_to_multiple(n: usize, unit: usize) -> usize {
 /// If a salt was specified, then it’s zero-padded to the closest multiple of the input size of the
 /// hash algorithm’s compression function, e.g. 64 bytes for SHA-256 or 128 bytes for SHA-512. The
 /// padded salt is prepended to every data or Merkle tree block that is hashed.
-fn zero_pad_salt(salt: &[u8], algorithm: MessageDigest) -> Vec<u8> {
+pub(crate) fn zero_pad_salt(salt: &[u8], algorithm: MessageDigest) -> Vec<u8> {
     if salt.is_empty() {
         salt.to_vec()
     } else {
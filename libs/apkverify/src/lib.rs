@@ -14,11 +14,13 @@
  * limitations under the License.
  */
 
-//! Verifies APK/APEX signing with v2/v3 scheme
+//! Verifies APK/APEX signing with v2/v3/v3.1 scheme
 
 mod algorithms;
 mod bytes_ext;
 mod hashtree;
+mod lineage;
+mod merkle;
 mod sigutil;
 #[allow(dead_code)]
 pub mod testing;
@@ -26,5 +28,9 @@ mod v3;
 mod v4;
 
 pub use algorithms::{HashAlgorithm, SignatureAlgorithmID};
-pub use v3::{extract_signed_data, verify, SignedData};
+pub use lineage::{LineageNode, SigningCertificateLineage};
+pub use merkle::{
+    write_hash_tree, HashTreeDescriptor, HashTreeDestination, HashTreeParams, HashTreeVerifier,
+};
+pub use v3::{extract_signed_data, verify, SignatureScheme, SignedData};
 pub use v4::{get_apk_digest, V4Signature};
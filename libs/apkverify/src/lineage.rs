@@ -0,0 +1,146 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Parses and verifies the [proof-of-rotation] attribute of APK Signature Scheme v3 and v3.1.
+//!
+//! [proof-of-rotation]: https://source.android.com/docs/security/features/apksigning/v3#key-rotation
+
+use anyhow::{ensure, Context, Result};
+use bytes::Bytes;
+use openssl::x509::X509;
+use std::collections::HashSet;
+
+use crate::algorithms::SignatureAlgorithmID;
+use crate::bytes_ext::{BytesExt, LengthPrefixed, ReadFromBytes};
+
+/// ID of the additional attribute of the v3 signed data holding the proof-of-rotation.
+pub(crate) const PROOF_OF_ROTATION_ATTR_ID: u32 = 0x3ba06f8c;
+
+const PROOF_OF_ROTATION_VERSION: u32 = 1;
+
+/// The signing certificates an APK has been signed with, from the oldest to the current one. Each
+/// certificate is signed by the key of the previous one.
+#[derive(Debug)]
+pub struct SigningCertificateLineage {
+    nodes: Vec<LineageNode>,
+}
+
+/// A signing certificate in a [`SigningCertificateLineage`].
+#[derive(Debug)]
+pub struct LineageNode {
+    /// The certificate and `parent_signature_algorithm_id`, signed by the previous node.
+    signed_data: LengthPrefixed<Bytes>,
+    certificate: X509Certificate,
+    parent_signature_algorithm_id: Option<SignatureAlgorithmID>,
+    flags: u32,
+    /// Algorithm with which this node signs the next node.
+    signature_algorithm_id: Option<SignatureAlgorithmID>,
+    /// Signature of `signed_data` by the previous node. Empty for the first node.
+    signature: LengthPrefixed<Bytes>,
+}
+
+type X509Certificate = Bytes;
+
+impl SigningCertificateLineage {
+    /// Returns the nodes of the lineage, from the oldest to the current signer.
+    pub fn nodes(&self) -> &[LineageNode] {
+        &self.nodes
+    }
+
+    /// Returns the certificate of the current signer, encoded in DER form.
+    pub(crate) fn last_certificate_der(&self) -> &[u8] {
+        // Parsing ensures that the lineage is not empty.
+        self.nodes.last().unwrap().certificate_der()
+    }
+
+    /// Verifies that each certificate is signed by the previous one and appears only once.
+    pub(crate) fn verify(&self) -> Result<()> {
+        let mut certificates = HashSet::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            ensure!(
+                certificates.insert(node.certificate_der()),
+                "Duplicate certificate #{} in proof-of-rotation",
+                i + 1
+            );
+            let Some(parent) = i.checked_sub(1).map(|parent| &self.nodes[parent]) else {
+                continue;
+            };
+            ensure!(
+                node.parent_signature_algorithm_id == parent.signature_algorithm_id,
+                "Signing algorithm ID mismatch for certificate #{} in proof-of-rotation",
+                i + 1
+            );
+            let algorithm = parent
+                .signature_algorithm_id
+                .context("Unsupported algorithm in proof-of-rotation")?;
+            let parent_key = X509::from_der(parent.certificate_der())?.public_key()?;
+            let mut verifier = algorithm.new_verifier(&parent_key)?;
+            verifier.update(&node.signed_data)?;
+            ensure!(
+                verifier.verify(&node.signature)?,
+                "Unable to verify signature of certificate #{} in proof-of-rotation",
+                i + 1
+            );
+        }
+        Ok(())
+    }
+}
+
+impl LineageNode {
+    /// Returns the signing certificate, encoded in DER form.
+    pub fn certificate_der(&self) -> &[u8] {
+        &self.certificate
+    }
+
+    /// Returns the capabilities granted by this signer to the next ones, as a bitmask of
+    /// installed data (1), shared UID (2), permission (4), rollback (8) and auth (16).
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+}
+
+// ReadFromBytes implementations
+
+impl ReadFromBytes for SigningCertificateLineage {
+    fn read_from_bytes(buf: &mut Bytes) -> Result<Self> {
+        let version: u32 = buf.read()?;
+        ensure!(
+            version == PROOF_OF_ROTATION_VERSION,
+            "Unsupported proof-of-rotation version: {}",
+            version
+        );
+        let nodes = buf.read::<Vec<LengthPrefixed<LineageNode>>>()?;
+        ensure!(!nodes.is_empty(), "Empty proof-of-rotation");
+        Ok(Self { nodes: nodes.into_iter().map(LengthPrefixed::into_inner).collect() })
+    }
+}
+
+impl ReadFromBytes for LineageNode {
+    fn read_from_bytes(buf: &mut Bytes) -> Result<Self> {
+        let signed_data: LengthPrefixed<Bytes> = buf.read()?;
+        let mut data = signed_data.slice(..);
+        let certificate = data.read::<LengthPrefixed<X509Certificate>>()?.into_inner();
+        let parent_signature_algorithm_id = data.read()?;
+        Ok(Self {
+            signed_data,
+            certificate,
+            parent_signature_algorithm_id,
+            flags: buf.read()?,
+            signature_algorithm_id: buf.read()?,
+            signature: buf.read()?,
+        })
+    }
+}
@@ -0,0 +1,369 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Merkle trees of arbitrary files, e.g. payload images which are not APKs.
+//!
+//! The trees have the same layout as the ones of APK Signature Scheme v4 and fs-verity, so they can
+//! also be used as the hash device of dm-verity.
+
+use anyhow::{ensure, Context, Result};
+use std::cmp::min;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use crate::algorithms::HashAlgorithm;
+use crate::hashtree::{
+    calc_hash_levels, hash_one_block, round_to_multiple, zero_pad_salt, HashTree,
+};
+
+/// Maximum size of a salt, as for fs-verity and APK Signature Scheme v4.
+const MAX_SALT_SIZE: usize = 32;
+
+/// Parameters of the Merkle tree of a file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HashTreeParams {
+    /// Algorithm used to hash the data and hash blocks.
+    pub algorithm: HashAlgorithm,
+    /// Size of the data and hash blocks. Must be a power of two.
+    pub block_size: usize,
+    /// Salt prepended to every hashed block. Up to 32 bytes.
+    pub salt: Vec<u8>,
+}
+
+impl Default for HashTreeParams {
+    fn default() -> Self {
+        Self { algorithm: HashAlgorithm::SHA256, block_size: 4096, salt: vec![] }
+    }
+}
+
+impl HashTreeParams {
+    pub(crate) fn check(&self) -> Result<()> {
+        ensure!(
+            self.block_size.is_power_of_two(),
+            "Block size {} is not a power of two",
+            self.block_size
+        );
+        ensure!(
+            self.block_size >= 2 * self.algorithm.message_digest().size(),
+            "Block size {} is too small for {:?}",
+            self.block_size,
+            self.algorithm
+        );
+        ensure!(
+            self.salt.len() <= MAX_SALT_SIZE,
+            "Salt is {} bytes, more than {}",
+            self.salt.len(),
+            MAX_SALT_SIZE
+        );
+        Ok(())
+    }
+}
+
+/// Where [`write_hash_tree`] writes the Merkle tree of a file.
+pub enum HashTreeDestination<'a> {
+    /// Into a separate writer, at its current position.
+    Separate(&'a mut dyn Write),
+    /// At the end of the file itself, after zero-padding the file to a multiple of the block size.
+    Appended,
+}
+
+/// Describes the Merkle tree of a file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HashTreeDescriptor {
+    /// Parameters the tree was built with.
+    pub params: HashTreeParams,
+    /// Size of the hashed data, which starts at the beginning of the file.
+    pub data_size: u64,
+    /// Root hash of the tree.
+    pub root_hash: Vec<u8>,
+    /// Offset of the tree in the file holding it. This is 0 for a tree written into a separate
+    /// writer.
+    pub tree_offset: u64,
+    /// Size of the tree. This is 0 if the data fits in a single block, in which case the root hash
+    /// is the hash of that block.
+    pub tree_size: u64,
+}
+
+/// Builds the Merkle tree of the whole `file` and writes it to `destination`.
+pub fn write_hash_tree<F: Read + Write + Seek>(
+    file: &mut F,
+    destination: HashTreeDestination,
+    params: &HashTreeParams,
+) -> Result<HashTreeDescriptor> {
+    params.check()?;
+    let data_size = file.seek(SeekFrom::End(0))?;
+    file.rewind()?;
+    let hash_tree = HashTree::from(
+        &mut Read::by_ref(file).take(data_size),
+        data_size.try_into()?,
+        &params.salt,
+        params.block_size,
+        params.algorithm.message_digest(),
+    )
+    .context("Failed to build the hash tree")?;
+
+    let tree_offset = match destination {
+        HashTreeDestination::Separate(writer) => {
+            writer.write_all(&hash_tree.tree)?;
+            0
+        }
+        HashTreeDestination::Appended => {
+            let tree_offset = round_to_multiple(data_size.try_into()?, params.block_size) as u64;
+            file.seek(SeekFrom::Start(data_size))?;
+            file.write_all(&vec![0; (tree_offset - data_size) as usize])?;
+            file.write_all(&hash_tree.tree)?;
+            tree_offset
+        }
+    };
+    Ok(HashTreeDescriptor {
+        params: params.clone(),
+        data_size,
+        root_hash: hash_tree.root_hash,
+        tree_offset,
+        tree_size: hash_tree.tree.len() as u64,
+    })
+}
+
+/// Verifies ranges of a file against its Merkle tree, without loading the file or the tree in
+/// memory. Only the data blocks of a range and the hash blocks on their paths to the root are
+/// read. The last verified hash block of each level is kept, so verifying consecutive ranges
+/// doesn't read the same hash blocks again.
+///
+/// If the tree is appended to the file, `data` and `tree` can be two handles of the same file, e.g.
+/// from [`std::fs::File::try_clone`].
+pub struct HashTreeVerifier<D, T> {
+    data: D,
+    tree: T,
+    descriptor: HashTreeDescriptor,
+    salt: Vec<u8>,
+    digest_size: usize,
+    /// Range of each level in the tree, level 0 (hashes of the data blocks) first.
+    levels: Vec<Range<usize>>,
+    /// Offset in the tree and content of the last verified hash block of each level.
+    verified: Vec<Option<(usize, Vec<u8>)>>,
+}
+
+impl<D: Read + Seek, T: Read + Seek> HashTreeVerifier<D, T> {
+    /// Creates a verifier of `data` against the tree in `tree` described by `descriptor`.
+    pub fn new(data: D, tree: T, descriptor: HashTreeDescriptor) -> Result<Self> {
+        let params = &descriptor.params;
+        params.check()?;
+        let algorithm = params.algorithm.message_digest();
+        let digest_size = algorithm.size();
+        ensure!(
+            descriptor.root_hash.len() == digest_size,
+            "Root hash is {} bytes, expected {}",
+            descriptor.root_hash.len(),
+            digest_size
+        );
+        let levels =
+            calc_hash_levels(descriptor.data_size.try_into()?, params.block_size, digest_size);
+        // Level 0 is stored at the end of the tree.
+        let tree_size = levels.first().map_or(0, |level| level.end) as u64;
+        ensure!(
+            tree_size == descriptor.tree_size,
+            "Tree of {} bytes of data is {} bytes, not {}",
+            descriptor.data_size,
+            tree_size,
+            descriptor.tree_size
+        );
+        let salt = zero_pad_salt(&params.salt, algorithm);
+        let verified = vec![None; levels.len()];
+        Ok(Self { data, tree, descriptor, salt, digest_size, levels, verified })
+    }
+
+    /// Verifies the bytes of the data in `range`, which must be within the data.
+    pub fn verify_range(&mut self, range: Range<u64>) -> Result<()> {
+        let data_size = self.descriptor.data_size;
+        ensure!(
+            range.start <= range.end && range.end <= data_size,
+            "Range {:?} is out of the {} bytes of data",
+            range,
+            data_size
+        );
+        if range.is_empty() {
+            return Ok(());
+        }
+        let block_size = self.descriptor.params.block_size as u64;
+        let mut block = vec![0; block_size as usize];
+        for index in range.start / block_size..=(range.end - 1) / block_size {
+            let start = index * block_size;
+            let len = min(block_size, data_size - start) as usize;
+            self.data.seek(SeekFrom::Start(start))?;
+            self.data.read_exact(&mut block[..len])?;
+            block[len..].fill(0);
+            let digest = self.hash(&block)?;
+            self.check_hash(0, index.try_into()?, &digest)
+                .with_context(|| format!("Data block {} is corrupted", index))?;
+        }
+        Ok(())
+    }
+
+    /// Verifies the whole data.
+    pub fn verify_all(&mut self) -> Result<()> {
+        self.verify_range(0..self.descriptor.data_size)
+    }
+
+    /// Checks that `digest` is the entry `index` of `level`, after verifying the hash block which
+    /// holds that entry. Above the top level, `digest` must be the root hash.
+    fn check_hash(&mut self, level: usize, index: usize, digest: &[u8]) -> Result<()> {
+        let Some(range) = self.levels.get(level).cloned() else {
+            ensure!(digest == self.descriptor.root_hash, "Root hash mismatch");
+            return Ok(());
+        };
+        let block_size = self.descriptor.params.block_size;
+        let entry = range.start + index * self.digest_size;
+        ensure!(entry < range.end, "Entry {} is out of level {}", index, level);
+        let block_offset = entry - entry % block_size;
+
+        let cached = matches!(&self.verified[level], Some((offset, _)) if *offset == block_offset);
+        if !cached {
+            let mut block = vec![0; block_size];
+            self.tree.seek(SeekFrom::Start(self.descriptor.tree_offset + block_offset as u64))?;
+            self.tree.read_exact(&mut block)?;
+            let block_digest = self.hash(&block)?;
+            self.check_hash(level + 1, (block_offset - range.start) / block_size, &block_digest)
+                .with_context(|| format!("Hash block at offset {} is corrupted", block_offset))?;
+            self.verified[level] = Some((block_offset, block));
+        }
+
+        let (_, block) = self.verified[level].as_ref().unwrap();
+        let expected = &block[entry - block_offset..][..self.digest_size];
+        ensure!(digest == expected, "Hash mismatch at level {}", level);
+        Ok(())
+    }
+
+    fn hash(&self, block: &[u8]) -> Result<Vec<u8>> {
+        let params = &self.descriptor.params;
+        let digest = hash_one_block(
+            block,
+            &self.salt,
+            params.block_size,
+            params.algorithm.message_digest(),
+        )?;
+        Ok(digest.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn data(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn separate_tree(data: &[u8], params: &HashTreeParams) -> (Vec<u8>, HashTreeDescriptor) {
+        let mut tree = vec![];
+        let descriptor = write_hash_tree(
+            &mut Cursor::new(data.to_vec()),
+            HashTreeDestination::Separate(&mut tree),
+            params,
+        )
+        .unwrap();
+        (tree, descriptor)
+    }
+
+    #[test]
+    fn appended_tree_is_same_as_separate_tree() -> Result<()> {
+        let params = HashTreeParams { salt: vec![1, 2, 3], ..Default::default() };
+        let data = data(1_000_000);
+        let (tree, separate) = separate_tree(&data, &params);
+
+        let mut file = Cursor::new(data.clone());
+        let appended = write_hash_tree(&mut file, HashTreeDestination::Appended, &params)?;
+        let file = file.into_inner();
+
+        assert_eq!(appended.root_hash, separate.root_hash);
+        assert_eq!(appended.tree_offset, 1_003_520);
+        assert_eq!(&file[..data.len()], data.as_slice());
+        assert!(file[data.len()..appended.tree_offset as usize].iter().all(|b| *b == 0));
+        assert_eq!(&file[appended.tree_offset as usize..], tree.as_slice());
+        Ok(())
+    }
+
+    #[test]
+    fn verify_ranges() -> Result<()> {
+        for algorithm in [HashAlgorithm::SHA256, HashAlgorithm::SHA512] {
+            for size in [0, 100, 4096, 4097, 1_000_000] {
+                let params = HashTreeParams { algorithm, salt: vec![42; 32], ..Default::default() };
+                let data = data(size);
+                let (tree, descriptor) = separate_tree(&data, &params);
+                let mut verifier =
+                    HashTreeVerifier::new(Cursor::new(&data), Cursor::new(&tree), descriptor)?;
+                verifier.verify_all()?;
+                verifier.verify_range(size as u64 / 3..size as u64 / 2)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn verify_appended_tree() -> Result<()> {
+        let mut file = Cursor::new(data(300_000));
+        let descriptor =
+            write_hash_tree(&mut file, HashTreeDestination::Appended, &Default::default())?;
+        let file = file.into_inner();
+        let mut verifier =
+            HashTreeVerifier::new(Cursor::new(&file), Cursor::new(&file), descriptor)?;
+        verifier.verify_all()
+    }
+
+    #[test]
+    fn corrupted_data_is_detected_only_in_its_block() -> Result<()> {
+        let data = data(1_000_000);
+        let (tree, descriptor) = separate_tree(&data, &Default::default());
+        let mut corrupted = data.clone();
+        corrupted[500_000] ^= 1;
+
+        let mut verifier =
+            HashTreeVerifier::new(Cursor::new(&corrupted), Cursor::new(&tree), descriptor)?;
+        verifier.verify_range(0..400_000)?;
+        let err = verifier.verify_range(499_000..501_000).unwrap_err();
+        assert!(format!("{:#}", err).contains("Data block 122 is corrupted"), "{:#}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_tree_is_detected() -> Result<()> {
+        let data = data(10_000_000);
+        let (mut tree, descriptor) = separate_tree(&data, &Default::default());
+        // Corrupt the top level, which is the first block of the tree.
+        tree[0] ^= 1;
+        let mut verifier =
+            HashTreeVerifier::new(Cursor::new(&data), Cursor::new(&tree), descriptor)?;
+        let err = verifier.verify_range(0..1).unwrap_err();
+        assert!(format!("{:#}", err).contains("Root hash mismatch"), "{:#}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_params_are_rejected() {
+        let mut file = Cursor::new(data(100));
+        for params in [
+            HashTreeParams { block_size: 4000, ..Default::default() },
+            HashTreeParams {
+                algorithm: HashAlgorithm::SHA512,
+                block_size: 64,
+                ..Default::default()
+            },
+            HashTreeParams { salt: vec![0; 33], ..Default::default() },
+        ] {
+            assert!(write_hash_tree(&mut file, HashTreeDestination::Appended, &params).is_err());
+        }
+    }
+}
@@ -14,29 +14,82 @@
  * limitations under the License.
  */
 
-//! Verifies APK Signature Scheme V3
+//! Verifies APK Signature Scheme V3 and V3.1, falling back to V2 for APKs without a V3 block.
 //!
 //! [v3 verification]: https://source.android.com/security/apksigning/v3#verification
+//! [v3.1]: https://source.android.com/docs/security/features/apksigning/v3-1
+//! [v2 verification]: https://source.android.com/security/apksigning/v2#verification
 
 use anyhow::{ensure, Context, Result};
 use bytes::Bytes;
 use openssl::pkey::{self, PKey};
 use openssl::x509::X509;
+use std::fmt;
 use std::fs::File;
-use std::io::{Read, Seek};
+use std::io::{self, ErrorKind, Read, Seek};
 use std::ops::RangeInclusive;
 use std::path::Path;
 
 use crate::algorithms::SignatureAlgorithmID;
 use crate::bytes_ext::{BytesExt, LengthPrefixed, ReadFromBytes};
+use crate::lineage::{SigningCertificateLineage, PROOF_OF_ROTATION_ATTR_ID};
 use crate::sigutil::ApkSections;
 
+pub const APK_SIGNATURE_SCHEME_V2_BLOCK_ID: u32 = 0x7109871a;
 pub const APK_SIGNATURE_SCHEME_V3_BLOCK_ID: u32 = 0xf05368c0;
+pub const APK_SIGNATURE_SCHEME_V31_BLOCK_ID: u32 = 0x1b93ad61;
+
+/// V2 attribute listing the newer schemes the APK is also signed with, to detect their stripping.
+const STRIPPING_PROTECTION_ATTR_ID: u32 = 0xbeeff00d;
+/// Value of the stripping protection attribute for APK Signature Scheme V3.
+const STRIPPING_PROTECTION_V3: u32 = 3;
+/// V3 attribute holding the minimum SDK version targeted by the V3.1 signers.
+const ROTATION_MIN_SDK_VERSION_ATTR_ID: u32 = 0x559f8b02;
+/// V3.1 attribute marking a signer whose minimum SDK version is a development release. Such a
+/// signer doesn't apply to the release of that SDK version.
+const ROTATION_ON_DEV_RELEASE_ATTR_ID: u32 = 0xc2a6b3ba;
+
+type Signers = LengthPrefixed<Vec<LengthPrefixed<Bytes>>>;
+
+/// The APK Signature Schemes which can be verified.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SignatureScheme {
+    /// APK Signature Scheme v2.
+    V2,
+    /// APK Signature Scheme v3.
+    V3,
+    /// APK Signature Scheme v3.1, which lets a rotated signing key target newer SDK versions.
+    V31,
+}
+
+impl SignatureScheme {
+    fn block_id(&self) -> u32 {
+        match self {
+            SignatureScheme::V2 => APK_SIGNATURE_SCHEME_V2_BLOCK_ID,
+            SignatureScheme::V3 => APK_SIGNATURE_SCHEME_V3_BLOCK_ID,
+            SignatureScheme::V31 => APK_SIGNATURE_SCHEME_V31_BLOCK_ID,
+        }
+    }
 
-type Signers = LengthPrefixed<Vec<LengthPrefixed<Signer>>>;
+    /// Whether the signers and the signed data of this scheme have min and max SDK versions.
+    fn has_sdk_range(&self) -> bool {
+        !matches!(self, SignatureScheme::V2)
+    }
+}
+
+impl fmt::Display for SignatureScheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureScheme::V2 => write!(f, "V2"),
+            SignatureScheme::V3 => write!(f, "V3"),
+            SignatureScheme::V31 => write!(f, "V3.1"),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub(crate) struct Signer {
+    scheme: SignatureScheme,
     signed_data: LengthPrefixed<Bytes>, // not verified yet
     min_sdk: u32,
     max_sdk: u32,
@@ -44,15 +97,16 @@ pub(crate) struct Signer {
     public_key: PKey<pkey::Public>,
 }
 
-/// Contains the signed data part of an APK v3 signature.
+/// Contains the signed data part of an APK v2, v3 or v3.1 signature.
 #[derive(Debug)]
 pub struct SignedData {
+    scheme: SignatureScheme,
     digests: LengthPrefixed<Vec<LengthPrefixed<Digest>>>,
     certificates: LengthPrefixed<Vec<LengthPrefixed<X509Certificate>>>,
     min_sdk: u32,
     max_sdk: u32,
-    #[allow(dead_code)]
-    additional_attributes: LengthPrefixed<Vec<LengthPrefixed<AdditionalAttributes>>>,
+    additional_attributes: LengthPrefixed<Vec<LengthPrefixed<AdditionalAttribute>>>,
+    lineage: Option<SigningCertificateLineage>,
 }
 
 #[derive(Debug)]
@@ -68,11 +122,16 @@ struct Digest {
     digest: LengthPrefixed<Bytes>,
 }
 
+#[derive(Debug)]
+struct AdditionalAttribute {
+    id: u32,
+    value: Bytes,
+}
+
 type X509Certificate = Bytes;
-type AdditionalAttributes = Bytes;
 
-/// Verifies APK Signature Scheme v3 signatures of the provided APK and returns the SignedData from
-/// the signature.
+/// Verifies the APK Signature Scheme v3.1, v3 or v2 signature of the provided APK which applies to
+/// `current_sdk`, and returns the SignedData from the signature.
 pub fn verify<P: AsRef<Path>>(apk_path: P, current_sdk: u32) -> Result<SignedData> {
     let apk = File::open(apk_path.as_ref())?;
     let (signer, mut sections) = extract_signer_and_apk_sections(apk, current_sdk)?;
@@ -91,25 +150,133 @@ pub(crate) fn extract_signer_and_apk_sections<R: Read + Seek>(
     current_sdk: u32,
 ) -> Result<(Signer, ApkSections<R>)> {
     let mut sections = ApkSections::new(apk)?;
-    let mut block = sections.find_signature(APK_SIGNATURE_SCHEME_V3_BLOCK_ID).context(
-        "Fallback to v2 when v3 block not found is not yet implemented.", // b/197052981
-    )?;
-    let signers = block.read::<Signers>()?.into_inner();
-    let mut supported =
-        signers.into_iter().filter(|s| s.sdk_range().contains(&current_sdk)).collect::<Vec<_>>();
+    let signer = select_signer(&mut sections, current_sdk)?;
+    Ok((signer, sections))
+}
+
+/// Selects the signer which applies to `current_sdk`, in the same order as the platform: a V3.1
+/// signer targeting `current_sdk` if there is one, otherwise the V3 signer, otherwise the V2
+/// signer if the APK has no V3 block.
+fn select_signer<R: Read + Seek>(
+    sections: &mut ApkSections<R>,
+    current_sdk: u32,
+) -> Result<Signer> {
+    let v31_block = find_block(sections, SignatureScheme::V31)?;
+    if let Some(block) = &v31_block {
+        let signers = read_signers(block.clone(), SignatureScheme::V31)?;
+        let signers = signers_applying_to(signers, current_sdk)?;
+        if !signers.is_empty() {
+            return single_signer(signers, SignatureScheme::V31);
+        }
+    }
+
+    if let Some(block) = find_block(sections, SignatureScheme::V3)? {
+        let signers = read_signers(block, SignatureScheme::V3)?;
+        if v31_block.is_none() {
+            for signer in &signers {
+                let Some(rotation_min_sdk) = signer.parse_signed_data()?.rotation_min_sdk()? else {
+                    continue;
+                };
+                ensure!(
+                    current_sdk < rotation_min_sdk,
+                    "V3 signer indicates a V3.1 signer from SDK {}, but no V3.1 block was found. \
+                     Signature stripped?",
+                    rotation_min_sdk
+                );
+            }
+        }
+        return single_signer(signers_applying_to(signers, current_sdk)?, SignatureScheme::V3);
+    }
+
+    let block = find_block(sections, SignatureScheme::V2)?
+        .context("No APK Signature Scheme V2 or V3 block found")?;
+    let signers = read_signers(block, SignatureScheme::V2)?;
+    let signer = single_signer(signers, SignatureScheme::V2)?;
     ensure!(
-        supported.len() == 1,
-        "APK Signature Scheme V3 only supports one signer: {} signers found.",
-        supported.len()
+        !signer.parse_signed_data()?.is_signed_with_v3()?,
+        "V2 signer indicates that the APK is signed with V3, but no V3 block was found. \
+         Signature stripped?"
     );
-    Ok((supported.pop().unwrap().into_inner(), sections))
+    Ok(signer)
+}
+
+/// Returns the block of the given scheme, or `None` if the APK isn't signed with that scheme.
+fn find_block<R: Read + Seek>(
+    sections: &mut ApkSections<R>,
+    scheme: SignatureScheme,
+) -> Result<Option<Bytes>> {
+    match sections.find_signature(scheme.block_id()) {
+        Ok(block) => Ok(Some(block)),
+        Err(e)
+            if e.downcast_ref::<io::Error>().map(io::Error::kind) == Some(ErrorKind::NotFound) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn read_signers(mut block: Bytes, scheme: SignatureScheme) -> Result<Vec<Signer>> {
+    block
+        .read::<Signers>()?
+        .into_inner()
+        .into_iter()
+        .map(|signer| Signer::from_bytes(signer.into_inner(), scheme))
+        .collect()
+}
+
+fn signers_applying_to(signers: Vec<Signer>, current_sdk: u32) -> Result<Vec<Signer>> {
+    let mut applying = vec![];
+    for signer in signers {
+        if signer.applies_to(current_sdk)? {
+            applying.push(signer);
+        }
+    }
+    Ok(applying)
+}
+
+fn single_signer(mut signers: Vec<Signer>, scheme: SignatureScheme) -> Result<Signer> {
+    ensure!(
+        signers.len() == 1,
+        "APK Signature Scheme {} only supports one signer: {} signers found.",
+        scheme,
+        signers.len()
+    );
+    Ok(signers.pop().unwrap())
 }
 
 impl Signer {
+    fn from_bytes(mut buf: Bytes, scheme: SignatureScheme) -> Result<Self> {
+        let signed_data = buf.read()?;
+        let (min_sdk, max_sdk) =
+            if scheme.has_sdk_range() { (buf.read()?, buf.read()?) } else { (0, u32::MAX) };
+        Ok(Self {
+            scheme,
+            signed_data,
+            min_sdk,
+            max_sdk,
+            signatures: buf.read()?,
+            public_key: buf.read()?,
+        })
+    }
+
     fn sdk_range(&self) -> RangeInclusive<u32> {
         self.min_sdk..=self.max_sdk
     }
 
+    /// Whether this signer applies to `current_sdk`. A V3.1 signer targeting a development
+    /// release doesn't apply to the release of its min SDK version.
+    fn applies_to(&self, current_sdk: u32) -> Result<bool> {
+        if !self.sdk_range().contains(&current_sdk) {
+            return Ok(false);
+        }
+        if self.scheme == SignatureScheme::V31 && current_sdk == self.min_sdk {
+            let signed_data = self.parse_signed_data()?;
+            return Ok(signed_data.find_attribute(ROTATION_ON_DEV_RELEASE_ATTR_ID).is_none());
+        }
+        Ok(true)
+    }
+
     /// Selects the signature that has the strongest supported `SignatureAlgorithmID`.
     /// The strongest signature is used in both v3 verification and v4 apk digest computation.
     pub(crate) fn strongest_signature(&self) -> Result<&Signature> {
@@ -125,7 +292,7 @@ impl Signer {
         &self,
         algorithm_id: SignatureAlgorithmID,
     ) -> Result<Box<[u8]>> {
-        let signed_data: SignedData = self.signed_data.slice(..).read()?;
+        let signed_data = self.parse_signed_data()?;
         let digest = signed_data.find_digest_by_algorithm(algorithm_id)?;
         Ok(digest.digest.as_ref().to_vec().into_boxed_slice())
     }
@@ -143,10 +310,12 @@ impl Signer {
 
     /// Returns the signed data, converted from bytes.
     fn parse_signed_data(&self) -> Result<SignedData> {
-        self.signed_data.slice(..).read()
+        SignedData::from_bytes(self.signed_data.slice(..), self.scheme)
     }
 
-    /// The steps in this method implements APK Signature Scheme v3 verification step 3.
+    /// The steps in this method implements APK Signature Scheme v3 verification step 3. V2 and
+    /// V3.1 signers are verified the same way, V2 signers having neither SDK versions nor
+    /// proof-of-rotation.
     fn verify<R: Read + Seek>(&self, sections: &mut ApkSections<R>) -> Result<SignedData> {
         // 1. Choose the strongest supported signature algorithm ID from signatures.
         let strongest = self.strongest_signature()?;
@@ -162,7 +331,8 @@ impl Signer {
         //    signer.
         ensure!(
             self.sdk_range() == verified_signed_data.sdk_range(),
-            "SDK versions mismatch between signed and unsigned in v3 signer block."
+            "SDK versions mismatch between signed and unsigned in {} signer block.",
+            self.scheme
         );
 
         // 4. Verify that the ordered list of signature algorithm IDs in digests and signatures is
@@ -198,15 +368,48 @@ impl Signer {
             "Public key mismatch between certificate and signature record"
         );
 
-        // TODO(b/245914104)
         // 8. If the proof-of-rotation attribute exists for the signer verify that the
         // struct is valid and this signer is the last certificate in the list.
+        if let Some(lineage) = &verified_signed_data.lineage {
+            lineage.verify()?;
+            ensure!(
+                lineage.last_certificate_der() == verified_signed_data.first_certificate_der()?,
+                "Signer is not the last certificate in the proof-of-rotation"
+            );
+        }
 
         Ok(verified_signed_data)
     }
 }
 
 impl SignedData {
+    fn from_bytes(mut buf: Bytes, scheme: SignatureScheme) -> Result<Self> {
+        let digests = buf.read()?;
+        let certificates = buf.read()?;
+        let (min_sdk, max_sdk) =
+            if scheme.has_sdk_range() { (buf.read()?, buf.read()?) } else { (0, u32::MAX) };
+        let mut signed_data = Self {
+            scheme,
+            digests,
+            certificates,
+            min_sdk,
+            max_sdk,
+            additional_attributes: buf.read()?,
+            lineage: None,
+        };
+        signed_data.lineage = signed_data
+            .find_attribute(PROOF_OF_ROTATION_ATTR_ID)
+            .map(|mut value| value.read())
+            .transpose()
+            .context("Invalid proof-of-rotation")?;
+        Ok(signed_data)
+    }
+
+    /// Returns the scheme of the signature this signed data is part of.
+    pub fn scheme(&self) -> SignatureScheme {
+        self.scheme
+    }
+
     /// Returns the first X.509 certificate in the signed data, encoded in DER form. (All other
     /// certificates are ignored for v3; this certificate describes the public key that was actually
     /// used to sign the APK.)
@@ -214,6 +417,22 @@ impl SignedData {
         Ok(self.certificates.first().context("No certificates listed")?)
     }
 
+    /// Returns the proof-of-rotation, if the signing key of the APK has been rotated.
+    pub fn lineage(&self) -> Option<&SigningCertificateLineage> {
+        self.lineage.as_ref()
+    }
+
+    /// Returns the certificates the APK has been signed with, encoded in DER form, from the oldest
+    /// to the current one. This is only the first certificate if the signing key was never rotated.
+    pub fn signer_history_der(&self) -> Result<Vec<&[u8]>> {
+        match &self.lineage {
+            Some(lineage) => {
+                Ok(lineage.nodes().iter().map(|node| node.certificate_der()).collect())
+            }
+            None => Ok(vec![self.first_certificate_der()?]),
+        }
+    }
+
     fn sdk_range(&self) -> RangeInclusive<u32> {
         self.min_sdk..=self.max_sdk
     }
@@ -225,35 +444,33 @@ impl SignedData {
             .find(|&dig| dig.signature_algorithm_id == Some(algorithm_id))
             .context(format!("Digest not found for algorithm: {:?}", algorithm_id))?)
     }
-}
 
-// ReadFromBytes implementations
-// TODO(b/190343842): add derive macro: #[derive(ReadFromBytes)]
+    fn find_attribute(&self, id: u32) -> Option<Bytes> {
+        self.additional_attributes.iter().find(|attr| attr.id == id).map(|attr| attr.value.clone())
+    }
 
-impl ReadFromBytes for Signer {
-    fn read_from_bytes(buf: &mut Bytes) -> Result<Self> {
-        Ok(Self {
-            signed_data: buf.read()?,
-            min_sdk: buf.read()?,
-            max_sdk: buf.read()?,
-            signatures: buf.read()?,
-            public_key: buf.read()?,
-        })
+    /// Returns the min SDK version targeted by the V3.1 signers, if the APK has any.
+    fn rotation_min_sdk(&self) -> Result<Option<u32>> {
+        self.find_attribute(ROTATION_MIN_SDK_VERSION_ATTR_ID).map(read_u32_attribute).transpose()
     }
-}
 
-impl ReadFromBytes for SignedData {
-    fn read_from_bytes(buf: &mut Bytes) -> Result<Self> {
-        Ok(Self {
-            digests: buf.read()?,
-            certificates: buf.read()?,
-            min_sdk: buf.read()?,
-            max_sdk: buf.read()?,
-            additional_attributes: buf.read()?,
-        })
+    /// Whether the stripping protection attribute says that the APK is also signed with V3.
+    fn is_signed_with_v3(&self) -> Result<bool> {
+        match self.find_attribute(STRIPPING_PROTECTION_ATTR_ID) {
+            Some(value) => Ok(read_u32_attribute(value)? == STRIPPING_PROTECTION_V3),
+            None => Ok(false),
+        }
     }
 }
 
+fn read_u32_attribute(mut value: Bytes) -> Result<u32> {
+    ensure!(value.len() == 4, "Invalid size of attribute value: {}", value.len());
+    value.read()
+}
+
+// ReadFromBytes implementations
+// TODO(b/190343842): add derive macro: #[derive(ReadFromBytes)]
+
 impl ReadFromBytes for Signature {
     fn read_from_bytes(buf: &mut Bytes) -> Result<Self> {
         Ok(Signature { signature_algorithm_id: buf.read()?, signature: buf.read()? })
@@ -266,6 +483,13 @@ impl ReadFromBytes for Digest {
     }
 }
 
+impl ReadFromBytes for AdditionalAttribute {
+    fn read_from_bytes(buf: &mut Bytes) -> Result<Self> {
+        ensure!(buf.len() >= 4, "Additional attribute too short: {}", buf.len());
+        Ok(Self { id: buf.read()?, value: buf.read()? })
+    }
+}
+
 impl ReadFromBytes for PKey<pkey::Public> {
     fn read_from_bytes(buf: &mut Bytes) -> Result<Self> {
         let raw_public_key = buf.read::<LengthPrefixed<Bytes>>()?;
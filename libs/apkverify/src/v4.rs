@@ -28,6 +28,7 @@ use std::path::Path;
 
 use crate::algorithms::{HashAlgorithm, SignatureAlgorithmID};
 use crate::hashtree::*;
+use crate::merkle::{HashTreeDescriptor, HashTreeParams};
 use crate::v3::extract_signer_and_apk_sections;
 
 /// Gets the v4 [apk_digest]. If `verify` is true, we verify that digest computed
@@ -148,22 +149,44 @@ impl<R: Read + Seek> V4Signature<R> {
     /// (see: https://bugzilla.kernel.org/show_bug.cgi?id=200043), which will result in this
     /// function OOMing.
     pub fn create(
-        mut apk: &mut R,
+        apk: &mut R,
         current_sdk: u32,
         block_size: usize,
         salt: &[u8],
         algorithm: HashAlgorithm,
     ) -> Result<V4Signature<Cursor<Vec<u8>>>> {
-        // Determine the size of the apk
         let start = apk.stream_position()?;
-        let size = apk.seek(SeekFrom::End(0))? as usize;
+        let mut ret = Self::create_for_file(apk, block_size, salt, algorithm)?;
+
         apk.seek(SeekFrom::Start(start))?;
+        let (signature_algorithm_id, apk_digest) =
+            get_apk_digest(apk, current_sdk, /*verify=*/ false)?;
+        ret.signing_info.signature_algorithm_id = signature_algorithm_id;
+        ret.signing_info.apk_digest = apk_digest;
+        // TODO(jiyong): add a signature to the signing_info struct
+
+        Ok(ret)
+    }
+
+    /// Read a stream for an arbitrary file, which is not necessarily an APK, and creates a
+    /// corresponding `V4Signature` struct that digests the file. The signing info is left empty.
+    /// The same precautions as for [`V4Signature::create`] apply to `input`.
+    pub fn create_for_file(
+        mut input: &mut R,
+        block_size: usize,
+        salt: &[u8],
+        algorithm: HashAlgorithm,
+    ) -> Result<V4Signature<Cursor<Vec<u8>>>> {
+        HashTreeParams { algorithm, block_size, salt: salt.to_vec() }.check()?;
+
+        // Determine the size of the input
+        let start = input.stream_position()?;
+        let size = input.seek(SeekFrom::End(0))? as usize;
+        input.seek(SeekFrom::Start(start))?;
 
         // Create hash tree (and root hash)
-        let algorithm = match algorithm {
-            HashAlgorithm::SHA256 => openssl::hash::MessageDigest::sha256(),
-        };
-        let hash_tree = HashTree::from(&mut apk, size, salt, block_size, algorithm)?;
+        let hash_tree =
+            HashTree::from(&mut input, size, salt, block_size, algorithm.message_digest())?;
 
         let mut ret = V4Signature {
             version: Version::default(),
@@ -173,19 +196,33 @@ impl<R: Read + Seek> V4Signature<R> {
             merkle_tree_offset: 0, // merkle tree starts from the beginning of `data`
             data: Cursor::new(hash_tree.tree),
         };
+        ret.hashing_info.hash_algorithm = algorithm;
+        ret.hashing_info.salt = salt.into();
         ret.hashing_info.raw_root_hash = hash_tree.root_hash.into_boxed_slice();
         ret.hashing_info.log2_blocksize = log2(block_size);
-
-        apk.seek(SeekFrom::Start(start))?;
-        let (signature_algorithm_id, apk_digest) =
-            get_apk_digest(apk, current_sdk, /*verify=*/ false)?;
-        ret.signing_info.signature_algorithm_id = signature_algorithm_id;
-        ret.signing_info.apk_digest = apk_digest;
-        // TODO(jiyong): add a signature to the signing_info struct
-
         Ok(ret)
     }
 
+    /// Returns the descriptor of the merkle tree in the idsig file, which digests `data_size`
+    /// bytes. It can be used to verify the file with a [`crate::HashTreeVerifier`] reading the merkle
+    /// tree from the idsig file.
+    pub fn hash_tree_descriptor(&self, data_size: u64) -> Result<HashTreeDescriptor> {
+        let block_size = 1usize
+            .checked_shl(self.hashing_info.log2_blocksize.into())
+            .context("Invalid log2 of block size")?;
+        Ok(HashTreeDescriptor {
+            params: HashTreeParams {
+                algorithm: self.hashing_info.hash_algorithm,
+                block_size,
+                salt: self.hashing_info.salt.to_vec(),
+            },
+            data_size,
+            root_hash: self.hashing_info.raw_root_hash.to_vec(),
+            tree_offset: self.merkle_tree_offset,
+            tree_size: self.merkle_tree_size.into(),
+        })
+    }
+
     /// Writes the data into a writer
     pub fn write_into<W: Write + Seek>(&mut self, mut w: &mut W) -> Result<()> {
         // Writes the header part
@@ -311,6 +348,7 @@ fn log2(n: usize) -> u8 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::merkle::HashTreeVerifier;
     use std::io::Cursor;
 
     const TEST_APK_PATH: &str = "tests/data/v4-digest-v3-Sha256withEC.apk";
@@ -383,4 +421,31 @@ mod tests {
             golden.merkle_tree().unwrap().as_slice()
         );
     }
+
+    /// Create an idsig file for a file which is not an APK, and verify the file against the
+    /// merkle tree in the idsig file.
+    #[test]
+    fn idsig_for_file() {
+        let data = vec![0xa5; 100_000];
+        let salt = [1, 2, 3, 4];
+        let mut created = V4Signature::create_for_file(
+            &mut Cursor::new(&data),
+            4096,
+            &salt,
+            HashAlgorithm::SHA512,
+        )
+        .unwrap();
+        let mut idsig = Cursor::new(Vec::new());
+        created.write_into(&mut idsig).unwrap();
+
+        idsig.set_position(0);
+        let parsed = V4Signature::from_idsig(idsig).unwrap();
+        assert_eq!(HashAlgorithm::SHA512, parsed.hashing_info.hash_algorithm);
+        assert_eq!(&salt, parsed.hashing_info.salt.as_ref());
+
+        let descriptor = parsed.hash_tree_descriptor(data.len() as u64).unwrap();
+        let mut verifier =
+            HashTreeVerifier::new(Cursor::new(&data), parsed.data, descriptor).unwrap();
+        verifier.verify_all().unwrap();
+    }
 }
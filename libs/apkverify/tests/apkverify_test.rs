@@ -17,6 +17,7 @@
 use anyhow::Result;
 use apkverify::{
     extract_signed_data, get_apk_digest, testing::assert_contains, verify, SignatureAlgorithmID,
+    SignatureScheme,
 };
 use apkzip::zip_sections;
 use byteorder::{LittleEndian, ReadBytesExt};
@@ -139,7 +140,7 @@ fn apks_signed_with_v3_rsa_pkcs1_sha512_are_valid() {
 #[test]
 fn test_verify_v3_sig_min_max_sdk() {
     setup();
-    // The Signer for this APK has min_sdk=24, max_sdk=32.
+    // The V3 Signer for this APK has min_sdk=24, max_sdk=32. From SDK 33, the V3.1 signer applies.
     let path = "tests/data/v31-rsa-2048_2-tgt-33-1-tgt-28.apk";
 
     let res = verify(path, 23);
@@ -147,14 +148,74 @@ fn test_verify_v3_sig_min_max_sdk() {
     assert_contains(&res.unwrap_err().to_string(), "0 signers found");
 
     let res = verify(path, 24);
-    assert!(res.is_ok());
+    assert_eq!(res.unwrap().scheme(), SignatureScheme::V3);
 
     let res = verify(path, 32);
-    assert!(res.is_ok());
+    assert_eq!(res.unwrap().scheme(), SignatureScheme::V3);
+
+    let res = verify(path, 33);
+    assert_eq!(res.unwrap().scheme(), SignatureScheme::V31);
+}
+
+#[test]
+fn test_verify_v31_rotated_signer_has_lineage() {
+    setup();
+    let path = "tests/data/v31-rsa-2048_2-tgt-33-1-tgt-28.apk";
+
+    let original = verify(path, 32).unwrap();
+    assert!(original.lineage().is_none());
+    assert_eq!(
+        original.signer_history_der().unwrap(),
+        vec![original.first_certificate_der().unwrap()]
+    );
+
+    let rotated = verify(path, 33).unwrap();
+    let history = rotated.signer_history_der().unwrap();
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[0], original.first_certificate_der().unwrap());
+    assert_eq!(history[1], rotated.first_certificate_der().unwrap());
+    assert_ne!(history[0], history[1]);
+
+    let unverified = extract_signed_data(path, 33).unwrap();
+    assert_eq!(unverified.signer_history_der().unwrap(), history);
+}
+
+#[test]
+fn test_verify_v31_block_stripped() {
+    setup();
+    // Same as v31-rsa-2048_2-tgt-33-1-tgt-28.apk, without its V3.1 block.
+    let path = "tests/data/v31-rsa-2048_2-tgt-33-1-tgt-28-v31-stripped.apk";
+
+    assert_eq!(verify(path, 24).unwrap().scheme(), SignatureScheme::V3);
 
     let res = verify(path, 33);
     assert!(res.is_err());
-    assert_contains(&res.unwrap_err().to_string(), "0 signers found");
+    assert_contains(&res.unwrap_err().to_string(), "Signature stripped");
+}
+
+#[test]
+fn test_verify_v3_block_stripped() {
+    setup();
+    // Same as v31-rsa-2048_2-tgt-33-1-tgt-28.apk, without its V3 and V3.1 blocks.
+    let res = verify("tests/data/v31-rsa-2048_2-tgt-33-1-tgt-28-v3-stripped.apk", SDK_INT);
+    assert!(res.is_err());
+    assert_contains(&res.unwrap_err().to_string(), "Signature stripped");
+}
+
+#[test]
+fn apk_signed_with_v2_rsa_pkcs1_sha256_is_valid() {
+    setup();
+    let path = "tests/data/v2-only-with-rsa-pkcs1-sha256-2048.apk";
+    validate_apk(path, SignatureAlgorithmID::RsaPkcs1V15WithSha256);
+    assert_eq!(verify(path, SDK_INT).unwrap().scheme(), SignatureScheme::V2);
+}
+
+#[test]
+fn test_verify_v2_two_signers() {
+    setup();
+    let res = verify("tests/data/v2-only-two-signers.apk", SDK_INT);
+    assert!(res.is_err());
+    assert_contains(&res.unwrap_err().to_string(), "2 signers found");
 }
 
 #[test]
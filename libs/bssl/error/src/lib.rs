@@ -61,6 +61,9 @@ impl fmt::Display for Error {
     }
 }
 
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
 impl From<coset::CoseError> for Error {
     fn from(e: coset::CoseError) -> Self {
         log::error!("Coset error: {e}");
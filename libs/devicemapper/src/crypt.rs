@@ -42,6 +42,16 @@ pub enum CipherType {
     /// supported by inline encryption hardware. Note that (status quo) `encryptedstore` in VMs
     /// is the only user of this module & inline encryption is not supported by guest kernel.
     AES256XTS,
+    /// AES256 with GCM mode. GCM is an authenticated encryption (AEAD) mode: reading a sector
+    /// which was modified by anyone without the key fails. It doesn't detect rollback: a sector,
+    /// or the whole device, replaced with an older version of itself along with its tags reads
+    /// fine. The authentication tag and the IV of each sector are stored by an integrity device
+    /// below the crypt device. See `DeviceMapper::create_authenticated_crypt_device`.
+    AES256GCM,
+    /// ChaCha20 with Poly1305 (RFC 7539). Like `AES256GCM`, this is an AEAD mode which requires an
+    /// integrity device below the crypt device. It is faster than AES-GCM on CPUs without AES
+    /// instructions.
+    CHACHA20POLY1305,
 }
 impl CipherType {
     fn get_kernel_crypto_name(&self) -> &str {
@@ -50,6 +60,10 @@ impl CipherType {
             // which basically is the sector number.
             CipherType::AES256HCTR2 => "aes-hctr2-plain64",
             CipherType::AES256XTS => "aes-xts-plain64",
+            // AEAD modes use a random IV for each write, which is stored along with the tag. A
+            // sector-derived IV would be reused when a sector is overwritten, breaking GCM.
+            CipherType::AES256GCM => "capi:gcm(aes)-random",
+            CipherType::CHACHA20POLY1305 => "capi:rfc7539(chacha20,poly1305)-random",
         }
     }
 
@@ -60,6 +74,18 @@ impl CipherType {
             // XTS requires key of twice the length of the underlying block cipher
             // i.e., 64B for AES256
             CipherType::AES256XTS => 64,
+            CipherType::AES256GCM => 32,
+            CipherType::CHACHA20POLY1305 => 32,
+        }
+    }
+
+    /// Returns the number of bytes the integrity device below the crypt device needs to store for
+    /// each sector, or `None` if the cipher doesn't need an integrity device.
+    pub fn integrity_tag_size(&self) -> Option<u32> {
+        match *self {
+            CipherType::AES256HCTR2 | CipherType::AES256XTS => None,
+            // 16-byte authentication tag and 12-byte random IV.
+            CipherType::AES256GCM | CipherType::CHACHA20POLY1305 => Some(16 + 12),
         }
     }
 
@@ -158,6 +184,12 @@ impl<'a> DmCryptTargetBuilder<'a> {
         );
         let key = hex::encode(self.key.unwrap());
 
+        // AEAD ciphers store their tags on the integrity device below.
+        let mut opt_params: Vec<String> = self.opt_params.iter().map(|p| p.to_string()).collect();
+        if let Some(tag_size) = self.cipher.integrity_tag_size() {
+            opt_params.push(format!("integrity:{}:aead", tag_size));
+        }
+
         // Step2: serialize the information according to the spec, which is ...
         // DmTargetSpec{...}
         // <cipher> <key> <iv_offset> <device path> \
@@ -169,7 +201,7 @@ impl<'a> DmCryptTargetBuilder<'a> {
         write!(&mut body, "{} ", self.iv_offset)?;
         write!(&mut body, "{} ", device_path)?;
         write!(&mut body, "{} ", self.offset)?;
-        write!(&mut body, "{} {} ", self.opt_params.len(), self.opt_params.join(" "))?;
+        write!(&mut body, "{} {} ", opt_params.len(), opt_params.join(" "))?;
         write!(&mut body, "\0")?; // null terminator
 
         let size = size_of::<DmTargetSpec>() + body.len();
@@ -0,0 +1,271 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// `integrity` module implements the "integrity" target in the device mapper framework.
+// Specifically, it provides `DmIntegrityTargetBuilder` struct which is used to construct a
+// `DmIntegrityTarget` struct which is then given to `DeviceMapper` to create a mapper device.
+//
+// An integrity device stores a tag next to each data block. The tag is either computed by the
+// target itself (`internal_hash`), or provided by the user of the device. The latter is how
+// dm-crypt stores the authentication tag and the IV of an AEAD cipher.
+
+use anyhow::{ensure, Context, Result};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::mem::size_of;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::Path;
+use zerocopy::AsBytes;
+
+use crate::DmTargetSpec;
+
+const SECTOR_SIZE: u64 = 512;
+
+// The UAPI for the integrity target is at:
+// Documentation/admin-guide/device-mapper/dm-integrity.rst
+
+/// Size of the area at the start of the data device holding the superblock.
+pub(crate) const SUPERBLOCK_SIZE: usize = 4096;
+const SUPERBLOCK_MAGIC: &[u8; 8] = b"integrt\0";
+
+/// How the integrity target keeps data and tags consistent when a write is interrupted.
+#[derive(Clone, Copy, Debug)]
+pub enum IntegrityMode {
+    /// Data and tags are written to a journal first. Writes are atomic, at the cost of writing
+    /// everything twice.
+    Journal,
+    /// Tags are written along with the data, and a bitmap tracks the dirty regions, whose tags are
+    /// recalculated after a crash. Only usable with `internal_hash`.
+    Bitmap,
+    /// Data and tags are written directly. An interrupted write may leave a block with a
+    /// mismatching tag.
+    Direct,
+}
+
+impl IntegrityMode {
+    fn as_str(&self) -> &str {
+        match *self {
+            IntegrityMode::Journal => "J",
+            IntegrityMode::Bitmap => "B",
+            IntegrityMode::Direct => "D",
+        }
+    }
+}
+
+pub struct DmIntegrityTarget(Box<[u8]>);
+
+impl DmIntegrityTarget {
+    /// Flatten into slice
+    pub fn as_slice(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+pub struct DmIntegrityTargetBuilder<'a> {
+    device_path: Option<&'a Path>,
+    offset: u64,
+    size: u64,
+    tag_size: Option<u32>,
+    mode: IntegrityMode,
+    block_size: u32,
+    internal_hash: Option<&'a str>,
+    opt_params: Vec<&'a str>,
+}
+
+impl<'a> Default for DmIntegrityTargetBuilder<'a> {
+    fn default() -> Self {
+        DmIntegrityTargetBuilder {
+            device_path: None,
+            offset: 0,
+            size: 0,
+            tag_size: None,
+            mode: IntegrityMode::Journal,
+            block_size: SECTOR_SIZE as u32,
+            internal_hash: None,
+            opt_params: Vec::new(),
+        }
+    }
+}
+
+impl<'a> DmIntegrityTargetBuilder<'a> {
+    /// Sets the device that will be used as the data device (i.e. providing the data and the
+    /// tags). `size` is the size of the data provided by the integrity device, which is smaller
+    /// than the data device. Once the data device is formatted, it can be read from the superblock
+    /// with [`read_provided_data_size`].
+    pub fn data_device(&mut self, p: &'a Path, size: u64) -> &mut Self {
+        self.device_path = Some(p);
+        self.size = size;
+        self
+    }
+
+    /// Starting sector within the device where the superblock is.
+    pub fn offset(&mut self, offset: u64) -> &mut Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Sets the size in bytes of the tag stored for each block. If not set, the size is derived
+    /// from the internal hash.
+    pub fn tag_size(&mut self, tag_size: u32) -> &mut Self {
+        self.tag_size = Some(tag_size);
+        self
+    }
+
+    /// Sets the journaling mode.
+    pub fn mode(&mut self, mode: IntegrityMode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets the size in bytes of the blocks which are protected by a tag. It must be a power of
+    /// two between 512 and 4096.
+    pub fn block_size(&mut self, block_size: u32) -> &mut Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Makes the target compute the tags itself with the given kernel crypto algorithm, e.g.
+    /// "crc32c" or "sha256". Without it, the tags are provided by the user of the device, e.g.
+    /// dm-crypt.
+    pub fn internal_hash(&mut self, algorithm: &'a str) -> &mut Self {
+        self.internal_hash = Some(algorithm);
+        self
+    }
+
+    /// Add additional optional parameter
+    pub fn opt_param(&mut self, param: &'a str) -> &mut Self {
+        self.opt_params.push(param);
+        self
+    }
+
+    /// Constructs a `DmIntegrityTarget`.
+    pub fn build(&self) -> Result<DmIntegrityTarget> {
+        // The `DmIntegrityTarget` struct actually is a flattened data consisting of a header and
+        // body. The format of the header is `dm_target_spec` as defined in
+        // include/uapi/linux/dm-ioctl.h.
+        let device_path = self
+            .device_path
+            .context("data device is not set")?
+            .to_str()
+            .context("data device path is not encoded in utf8")?;
+
+        ensure!(
+            self.block_size.is_power_of_two() && (512..=4096).contains(&self.block_size),
+            "Invalid block size:{}",
+            self.block_size
+        );
+        ensure!(
+            self.size % self.block_size as u64 == 0,
+            "Size {} is not a multiple of the block size {}",
+            self.size,
+            self.block_size
+        );
+        ensure!(
+            self.tag_size.is_some() || self.internal_hash.is_some(),
+            "Neither the tag size nor the internal hash is set"
+        );
+        ensure!(
+            self.internal_hash.is_some() || !matches!(self.mode, IntegrityMode::Bitmap),
+            "Bitmap mode requires an internal hash"
+        );
+
+        let mut opt_params = Vec::new();
+        if self.block_size != SECTOR_SIZE as u32 {
+            opt_params.push(format!("block_size:{}", self.block_size));
+        }
+        if let Some(internal_hash) = self.internal_hash {
+            opt_params.push(format!("internal_hash:{}", internal_hash));
+        }
+        opt_params.extend(self.opt_params.iter().map(|p| p.to_string()));
+
+        // Step2: serialize the information according to the spec, which is ...
+        // DmTargetSpec{...}
+        // <device path> <offset> <tag_size> <mode> \
+        // [<#opt_params> <opt_params>]
+        let mut body = String::new();
+        use std::fmt::Write;
+        write!(&mut body, "{} ", device_path)?;
+        write!(&mut body, "{} ", self.offset)?;
+        match self.tag_size {
+            Some(tag_size) => write!(&mut body, "{} ", tag_size)?,
+            None => write!(&mut body, "- ")?, // derived from the internal hash
+        }
+        write!(&mut body, "{} ", self.mode.as_str())?;
+        write!(&mut body, "{} {} ", opt_params.len(), opt_params.join(" "))?;
+        write!(&mut body, "\0")?; // null terminator
+
+        let size = size_of::<DmTargetSpec>() + body.len();
+        let aligned_size = (size + 7) & !7; // align to 8 byte boundaries
+        let padding = aligned_size - size;
+
+        let mut header = DmTargetSpec::new("integrity")?;
+        header.sector_start = 0;
+        header.length = self.size / SECTOR_SIZE; // number of 512-byte sectors
+        header.next = aligned_size as u32;
+
+        let mut buf = Vec::with_capacity(aligned_size);
+        buf.write_all(header.as_bytes())?;
+        buf.write_all(body.as_bytes())?;
+        buf.write_all(vec![0; padding].as_slice())?;
+
+        Ok(DmIntegrityTarget(buf.into_boxed_slice()))
+    }
+}
+
+/// The superblock is read and written with O_DIRECT, bypassing the page cache of the data device
+/// which the kernel doesn't update when it formats the device.
+#[repr(C, align(4096))]
+struct SuperblockBuf([u8; SUPERBLOCK_SIZE]);
+
+/// Reads the superblock of the integrity device on `data_device` at sector `offset`, and returns
+/// the size in bytes of the data it provides, and the size of its tags. Returns `None` if the
+/// device is not formatted.
+pub fn read_provided_data_size(data_device: &Path, offset: u64) -> Result<Option<(u64, u32)>> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(data_device)
+        .with_context(|| format!("Failed to open {:?}", data_device))?;
+    let mut buf = SuperblockBuf([0; SUPERBLOCK_SIZE]);
+    file.read_exact_at(&mut buf.0, offset * SECTOR_SIZE)
+        .with_context(|| format!("Failed to read the superblock of {:?}", data_device))?;
+    let sb = &buf.0;
+
+    // struct superblock in drivers/md/dm-integrity.c:
+    // magic[8] version log2_interleave_sectors integrity_tag_size:le16 journal_sections:le32
+    // provided_data_sectors:le64 ...
+    if &sb[0..8] != SUPERBLOCK_MAGIC {
+        return Ok(None);
+    }
+    let tag_size = u16::from_le_bytes(sb[10..12].try_into().unwrap()) as u32;
+    let provided_data_sectors = u64::from_le_bytes(sb[16..24].try_into().unwrap());
+    Ok(Some((provided_data_sectors * SECTOR_SIZE, tag_size)))
+}
+
+/// Zeroes the superblock of the integrity device on `data_device` at sector `offset`, so that the
+/// kernel formats the device when the target is next created. All the data on it is lost.
+pub(crate) fn wipe_superblock(data_device: &Path, offset: u64) -> Result<()> {
+    let file = OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(data_device)
+        .with_context(|| format!("Failed to open {:?}", data_device))?;
+    let buf = SuperblockBuf([0; SUPERBLOCK_SIZE]);
+    file.write_all_at(&buf.0, offset * SECTOR_SIZE)
+        .with_context(|| format!("Failed to wipe the superblock of {:?}", data_device))?;
+    file.sync_all()?;
+    Ok(())
+}
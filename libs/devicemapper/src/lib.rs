@@ -15,9 +15,9 @@
  */
 
 // `dm` module implements part of the `device-mapper` ioctl interfaces. It currently supports
-// creation and deletion of the mapper device. It doesn't support other operations like querying
-// the status of the mapper device. And there's no plan to extend the support unless it is
-// required.
+// creation and deletion of the mapper device, and querying the devices, their status and tables.
+// It doesn't support other operations like renaming a device or sending messages to a target.
+// And there's no plan to extend the support unless it is required.
 //
 // Why in-house development? [`devicemapper`](https://crates.io/crates/devicemapper) is a public
 // Rust implementation of the device mapper APIs. However, it doesn't provide any abstraction for
@@ -31,17 +31,21 @@
 #![allow(missing_docs)]
 #![cfg_attr(test, allow(unused))]
 
-use anyhow::{Context, Result};
+use anyhow::{ensure, Context, Result};
+use std::ffi::CStr;
 use std::fs::{File, OpenOptions};
 use std::io::Write;
 use std::mem::size_of;
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use zerocopy::AsBytes;
+use zerocopy::FromBytes;
 use zerocopy::FromZeroes;
 
 /// Exposes DmCryptTarget & related builder
 pub mod crypt;
+/// Exposes DmIntegrityTarget & related builder
+pub mod integrity;
 /// Expose util functions
 pub mod util;
 /// Exposes the DmVerityTarget & related builder
@@ -50,7 +54,10 @@ pub mod verity;
 pub mod loopdevice;
 
 mod sys;
-use crypt::DmCryptTarget;
+use crypt::{CipherType, DmCryptTarget, DmCryptTargetBuilder};
+use integrity::{
+    read_provided_data_size, wipe_superblock, DmIntegrityTarget, DmIntegrityTargetBuilder,
+};
 use sys::*;
 use util::*;
 use verity::DmVerityTarget;
@@ -59,6 +66,10 @@ nix::ioctl_readwrite!(_dm_dev_create, DM_IOCTL, Cmd::DM_DEV_CREATE, DmIoctl);
 nix::ioctl_readwrite!(_dm_dev_suspend, DM_IOCTL, Cmd::DM_DEV_SUSPEND, DmIoctl);
 nix::ioctl_readwrite!(_dm_table_load, DM_IOCTL, Cmd::DM_TABLE_LOAD, DmIoctl);
 nix::ioctl_readwrite!(_dm_dev_remove, DM_IOCTL, Cmd::DM_DEV_REMOVE, DmIoctl);
+nix::ioctl_readwrite!(_dm_list_devices, DM_IOCTL, Cmd::DM_LIST_DEVICES, DmIoctl);
+nix::ioctl_readwrite!(_dm_dev_status, DM_IOCTL, Cmd::DM_DEV_STATUS, DmIoctl);
+nix::ioctl_readwrite!(_dm_table_status, DM_IOCTL, Cmd::DM_TABLE_STATUS, DmIoctl);
+nix::ioctl_readwrite!(_dm_get_target_version, DM_IOCTL, Cmd::DM_GET_TARGET_VERSION, DmIoctl);
 
 /// Create a new (mapper) device
 fn dm_dev_create(dm: &DeviceMapper, ioctl: *mut DmIoctl) -> Result<i32> {
@@ -85,10 +96,34 @@ fn dm_dev_remove(dm: &DeviceMapper, ioctl: *mut DmIoctl) -> Result<i32> {
     Ok(unsafe { _dm_dev_remove(dm.0.as_raw_fd(), ioctl) }?)
 }
 
+fn dm_list_devices(dm: &DeviceMapper, ioctl: *mut DmIoctl) -> Result<i32> {
+    // SAFETY: `ioctl` is copied into the kernel, which writes the result back to it. The buffer
+    // behind `ioctl` is at least `data_size` bytes long.
+    Ok(unsafe { _dm_list_devices(dm.0.as_raw_fd(), ioctl) }?)
+}
+
+fn dm_dev_status(dm: &DeviceMapper, ioctl: *mut DmIoctl) -> Result<i32> {
+    // SAFETY: `ioctl` is copied into the kernel, which writes the result back to it. The buffer
+    // behind `ioctl` is at least `data_size` bytes long.
+    Ok(unsafe { _dm_dev_status(dm.0.as_raw_fd(), ioctl) }?)
+}
+
+fn dm_table_status(dm: &DeviceMapper, ioctl: *mut DmIoctl) -> Result<i32> {
+    // SAFETY: `ioctl` is copied into the kernel, which writes the result back to it. The buffer
+    // behind `ioctl` is at least `data_size` bytes long.
+    Ok(unsafe { _dm_table_status(dm.0.as_raw_fd(), ioctl) }?)
+}
+
+fn dm_get_target_version(dm: &DeviceMapper, ioctl: *mut DmIoctl) -> Result<i32> {
+    // SAFETY: `ioctl` is copied into the kernel, which writes the result back to it. The buffer
+    // behind `ioctl` is at least `data_size` bytes long.
+    Ok(unsafe { _dm_get_target_version(dm.0.as_raw_fd(), ioctl) }?)
+}
+
 // `DmTargetSpec` is the header of the data structure for a device-mapper target. When doing the
 // ioctl, one of more `DmTargetSpec` (and its body) are appened to the `DmIoctl` struct.
 #[repr(C)]
-#[derive(Copy, Clone, AsBytes, FromZeroes)]
+#[derive(Copy, Clone, AsBytes, FromBytes, FromZeroes)]
 struct DmTargetSpec {
     sector_start: u64,
     length: u64, // number of 512 sectors
@@ -125,6 +160,56 @@ impl DmIoctl {
     }
 }
 
+/// A mapper device, as listed by `DeviceMapper::list_devices`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DmDevice {
+    /// Name of the device, i.e. the device is at "/dev/mapper/<name>".
+    pub name: String,
+    /// Device number of the device.
+    pub dev: u64,
+}
+
+/// The state of a mapper device, as returned by `DeviceMapper::info`.
+#[derive(Clone, Debug)]
+pub struct DmDeviceInfo {
+    /// Name of the device.
+    pub name: String,
+    /// UUID given to the device when it was created.
+    pub uuid: String,
+    /// Device number of the device.
+    pub dev: u64,
+    /// Number of times the device is currently opened.
+    pub open_count: i32,
+    /// Number of targets in the active table.
+    pub target_count: u32,
+    /// Number of events the targets raised, e.g. when a verity target finds corrupted data.
+    pub event_nr: u32,
+    /// Whether the device is read-only.
+    pub read_only: bool,
+    /// Whether the device is suspended.
+    pub suspended: bool,
+    /// Whether the device has an active table.
+    pub active_table: bool,
+    /// Whether the device has a table which is loaded but not activated yet.
+    pub inactive_table: bool,
+}
+
+/// A target of the table of a mapper device, as returned by `DeviceMapper::status` and
+/// `DeviceMapper::table`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DmTargetInfo {
+    /// First sector of the device which the target maps.
+    pub sector_start: u64,
+    /// Number of 512-byte sectors which the target maps.
+    pub length: u64,
+    /// Type of the target, e.g. "crypt" or "verity".
+    pub target_type: String,
+    /// Parameters of the target, whose format depends on the target type. These are the
+    /// parameters of the table for `DeviceMapper::table`, and the status of the target for
+    /// `DeviceMapper::status`.
+    pub params: String,
+}
+
 /// `DeviceMapper` is the entry point for the device mapper framework. It essentially is a file
 /// handle to "/dev/mapper/control".
 pub struct DeviceMapper(File);
@@ -163,6 +248,112 @@ impl DeviceMapper {
         self.create_device(name, target.as_slice(), uuid("apkver".as_bytes())?, false)
     }
 
+    /// Creates an (integrity) device and configure it according to the `target` specification.
+    /// The path to the generated device is "/dev/mapper/<name>".
+    pub fn create_integrity_device(
+        &self,
+        name: &str,
+        target: &DmIntegrityTarget,
+    ) -> Result<PathBuf> {
+        self.create_device(name, target.as_slice(), uuid("integr".as_bytes())?, true)
+    }
+
+    /// Creates a crypt device named `name` using the AEAD `cipher`, stacked on an integrity device
+    /// named "<name>-integrity" over `data_device`, from its 512-byte sector `offset`. The
+    /// integrity device stores the tag of each encrypted sector of `sector_size` bytes. Unless this
+    /// fails, both devices have to be removed by the caller.
+    ///
+    /// If `format` is true, the integrity device is formatted, losing the data on `data_device`,
+    /// and the crypt device is filled with zeros so that all its sectors have a valid tag.
+    /// Otherwise `data_device` must have been formatted with the same cipher before.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_authenticated_crypt_device(
+        &self,
+        name: &str,
+        data_device: &Path,
+        offset: u64,
+        cipher: CipherType,
+        key: &[u8],
+        sector_size: u32,
+        format: bool,
+    ) -> Result<PathBuf> {
+        let tag_size = cipher
+            .integrity_tag_size()
+            .with_context(|| format!("{:?} is not an authenticated cipher", cipher))?;
+        let integrity_name = format!("{}-integrity", name);
+        let mut integrity = DmIntegrityTargetBuilder::default();
+        integrity.offset(offset).tag_size(tag_size).block_size(sector_size);
+
+        let integrity_device = if format {
+            wipe_superblock(data_device, offset)?;
+            // The kernel formats the device when the target is created, which tells the size of the
+            // data it provides. Until then, map a single block.
+            let target = integrity.data_device(data_device, sector_size as u64).build()?;
+            let integrity_device = self.create_integrity_device(&integrity_name, &target)?;
+            let (size, _) = read_provided_data_size(data_device, offset)?
+                .with_context(|| format!("Failed to format {:?}", data_device))?;
+            let size = size - size % sector_size as u64;
+            let target = integrity.data_device(data_device, size).build()?;
+            self.reload_table(&integrity_name, target.as_slice(), true)?;
+            integrity_device
+        } else {
+            let (size, formatted_tag_size) = read_provided_data_size(data_device, offset)?
+                .with_context(|| format!("{:?} has no integrity superblock", data_device))?;
+            ensure!(
+                formatted_tag_size == tag_size,
+                "{:?} was formatted with tags of {} bytes instead of {}",
+                data_device,
+                formatted_tag_size,
+                tag_size
+            );
+            let size = size - size % sector_size as u64;
+            let target = integrity.data_device(data_device, size).build()?;
+            self.create_integrity_device(&integrity_name, &target)?
+        };
+
+        let result = self.create_crypt_over_integrity_device(
+            name,
+            &integrity_device,
+            cipher,
+            key,
+            sector_size,
+            format,
+        );
+        if result.is_err() {
+            // Don't leave the integrity device mapped without the crypt device above it.
+            let _ignored = self.delete_device_deferred(&integrity_name);
+        }
+        result
+    }
+
+    fn create_crypt_over_integrity_device(
+        &self,
+        name: &str,
+        integrity_device: &Path,
+        cipher: CipherType,
+        key: &[u8],
+        sector_size: u32,
+        format: bool,
+    ) -> Result<PathBuf> {
+        let size = blkgetsize64(integrity_device)?;
+        let sector_size_param = format!("sector_size:{}", sector_size);
+        let mut crypt = DmCryptTargetBuilder::default();
+        crypt.data_device(integrity_device, size).cipher(cipher).key(key);
+        if sector_size != 512 {
+            crypt.opt_param(&sector_size_param);
+        }
+        let crypt_device = self.create_crypt_device(name, &crypt.build()?)?;
+
+        if format {
+            // A sector which was never written has no valid tag, so reading it would fail.
+            if let Err(e) = zero_device(&crypt_device, size) {
+                let _ignored = self.delete_device_deferred(name);
+                return Err(e.context(format!("Failed to wipe {:?}", crypt_device)));
+            }
+        }
+        Ok(crypt_device)
+    }
+
     /// Removes a mapper device.
     pub fn delete_device_deferred(&self, name: &str) -> Result<()> {
         let mut data = DmIoctl::new(name)?;
@@ -172,6 +363,156 @@ impl DeviceMapper {
         Ok(())
     }
 
+    /// Returns all the mapper devices.
+    pub fn list_devices(&self) -> Result<Vec<DmDevice>> {
+        let (_, out) = self
+            .ioctl_with_output("", Flag::empty(), dm_list_devices)
+            .context("failed to list devices")?;
+
+        // The output is a list of `struct dm_name_list`, as defined in
+        // include/uapi/linux/dm-ioctl.h, each of which links to the next one with the offset
+        // `next` from itself. The name is a null terminated string.
+        const NAME_OFFSET: usize = 12;
+        let mut devices = Vec::new();
+        let mut offset = 0;
+        while offset + NAME_OFFSET <= out.len() {
+            let entry = &out[offset..];
+            let dev = u64::from_ne_bytes(entry[0..8].try_into().unwrap());
+            if dev == 0 {
+                break; // no devices
+            }
+            let next = u32::from_ne_bytes(entry[8..12].try_into().unwrap()) as usize;
+            let name = CStr::from_bytes_until_nul(&entry[NAME_OFFSET..])
+                .context("device name is not null terminated")?;
+            devices.push(DmDevice { name: name.to_str()?.to_owned(), dev });
+            if next == 0 {
+                break;
+            }
+            offset += next;
+        }
+        Ok(devices)
+    }
+
+    /// Returns the state of the mapper device `name`, or `None` if there is no such device.
+    pub fn info(&self, name: &str) -> Result<Option<DmDeviceInfo>> {
+        let mut data = DmIoctl::new(name)?;
+        match dm_dev_status(self, &mut data) {
+            Ok(_) => {}
+            Err(e) if e.downcast_ref::<nix::Error>() == Some(&nix::Error::ENXIO) => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.context(format!("failed to get the status of {}", name))),
+        }
+        Ok(Some(DmDeviceInfo {
+            name: name.to_owned(),
+            uuid: CStr::from_bytes_until_nul(&data.uuid)?.to_str()?.to_owned(),
+            dev: data.dev,
+            open_count: data.open_count,
+            target_count: data.target_count,
+            event_nr: data.event_nr,
+            read_only: data.flags.contains(Flag::DM_READONLY_FLAG),
+            suspended: data.flags.contains(Flag::DM_SUSPEND_FLAG),
+            active_table: data.flags.contains(Flag::DM_ACTIVE_PRESENT_FLAG),
+            inactive_table: data.flags.contains(Flag::DM_INACTIVE_PRESENT_FLAG),
+        }))
+    }
+
+    /// Returns the status of each target of the active table of the mapper device `name`.
+    pub fn status(&self, name: &str) -> Result<Vec<DmTargetInfo>> {
+        self.table_status(name, Flag::empty())
+            .context(format!("failed to get the status of the table of {}", name))
+    }
+
+    /// Returns the active table of the mapper device `name`. Note that the table of a crypt
+    /// target contains its key.
+    pub fn table(&self, name: &str) -> Result<Vec<DmTargetInfo>> {
+        self.table_status(name, Flag::DM_STATUS_TABLE_FLAG)
+            .context(format!("failed to get the table of {}", name))
+    }
+
+    fn table_status(&self, name: &str, flags: Flag) -> Result<Vec<DmTargetInfo>> {
+        let (data, out) = self.ioctl_with_output(name, flags, dm_table_status)?;
+
+        // The output is a list of `DmTargetSpec`, each followed by the null terminated
+        // parameters. Unlike when loading a table, `next` is the offset of the next target from
+        // the start of the output.
+        let mut targets = Vec::with_capacity(data.target_count as usize);
+        let mut offset = 0;
+        for _ in 0..data.target_count {
+            let spec = out
+                .get(offset..)
+                .and_then(DmTargetSpec::read_from_prefix)
+                .context("target spec is truncated")?;
+            let params = CStr::from_bytes_until_nul(&out[offset + size_of::<DmTargetSpec>()..])
+                .context("target parameters are not null terminated")?;
+            targets.push(DmTargetInfo {
+                sector_start: spec.sector_start,
+                length: spec.length,
+                target_type: CStr::from_bytes_until_nul(&spec.target_type)
+                    .context("target type is not null terminated")?
+                    .to_str()?
+                    .to_owned(),
+                params: params.to_str()?.to_owned(),
+            });
+            offset = spec.next as usize;
+        }
+        Ok(targets)
+    }
+
+    /// Returns the version of the target type `target_type`, e.g. "integrity", or `None` if the
+    /// kernel doesn't support it. The kernel loads the module of the target type if needed, so
+    /// this tells whether a table using it can be loaded.
+    pub fn target_version(&self, target_type: &str) -> Result<Option<[u32; 3]>> {
+        let out = match self.ioctl_with_output(target_type, Flag::empty(), dm_get_target_version) {
+            Ok((_, out)) => out,
+            Err(e) if e.downcast_ref::<nix::Error>() == Some(&nix::Error::EINVAL) => {
+                return Ok(None)
+            }
+            Err(e) => {
+                return Err(e.context(format!("failed to get the version of {}", target_type)))
+            }
+        };
+
+        // The output is a `struct dm_target_versions`, as defined in include/uapi/linux/dm-ioctl.h:
+        // the offset `next`, the three numbers of the version and the name.
+        let bytes = out.get(4..16).context("target version is truncated")?;
+        let mut version = [0; 3];
+        for (number, bytes) in version.iter_mut().zip(bytes.chunks_exact(4)) {
+            *number = u32::from_ne_bytes(bytes.try_into().unwrap());
+        }
+        Ok(Some(version))
+    }
+
+    /// Issues `ioctl` for the device `name` with a buffer for its output, which is enlarged until
+    /// the output fits. Returns the header and the output written by the kernel.
+    fn ioctl_with_output(
+        &self,
+        name: &str,
+        flags: Flag,
+        ioctl: fn(&DeviceMapper, *mut DmIoctl) -> Result<i32>,
+    ) -> Result<(DmIoctl, Vec<u8>)> {
+        let mut buf_size = 16 * 1024;
+        loop {
+            let mut data = DmIoctl::new(name)?;
+            data.data_size = buf_size as u32;
+            data.data_start = size_of::<DmIoctl>() as u32;
+            data.flags = flags;
+
+            let mut buf = vec![0; buf_size];
+            buf[..size_of::<DmIoctl>()].copy_from_slice(data.as_bytes());
+            ioctl(self, buf.as_mut_ptr() as *mut DmIoctl)?;
+
+            let data = DmIoctl::read_from_prefix(&buf).unwrap(); // safe; buf is large enough
+            if data.flags.contains(Flag::DM_BUFFER_FULL_FLAG) {
+                buf_size *= 2;
+                continue;
+            }
+            let start = (data.data_start as usize).min(buf_size);
+            let end = (data.data_size as usize).clamp(start, buf_size);
+            return Ok((data, buf[start..end].to_vec()));
+        }
+    }
+
     fn create_device(
         &self,
         name: &str,
@@ -185,7 +526,19 @@ impl DeviceMapper {
         dm_dev_create(self, &mut data)
             .context(format!("failed to create an empty device with name {}", &name))?;
 
-        // Step 2: load table onto the device
+        // Step 2 & 3: load table onto the device, and activate it
+        self.reload_table(name, target, writable)?;
+
+        // Step 4: wait unti the device is created and return the device path
+        let path = Path::new(MAPPER_DEV_ROOT).join(name);
+        wait_for_path(&path)?;
+        Ok(path)
+    }
+
+    /// Loads `target` as the table of the existing device `name`, and activates it. If the device
+    /// is active, its old table is replaced.
+    fn reload_table(&self, name: &str, target: &[u8], writable: bool) -> Result<()> {
+        // Step 1: load table onto the device
         let payload_size = size_of::<DmIoctl>() + target.len();
 
         let mut data = DmIoctl::new(name)?;
@@ -203,16 +556,27 @@ impl DeviceMapper {
         dm_table_load(self, payload.as_mut_ptr() as *mut DmIoctl)
             .context("failed to load table")?;
 
-        // Step 3: activate the device (note: the term 'suspend' might be misleading, but it
+        // Step 2: activate the device (note: the term 'suspend' might be misleading, but it
         // actually activates the table. See include/uapi/linux/dm-ioctl.h
         let mut data = DmIoctl::new(name)?;
         dm_dev_suspend(self, &mut data).context("failed to activate")?;
+        Ok(())
+    }
+}
 
-        // Step 4: wait unti the device is created and return the device path
-        let path = Path::new(MAPPER_DEV_ROOT).join(name);
-        wait_for_path(&path)?;
-        Ok(path)
+/// Writes `size` bytes of zeros to the start of `device`.
+fn zero_device(device: &Path, size: u64) -> Result<()> {
+    const BUF_SIZE: u64 = 1024 * 1024;
+    let mut file = OpenOptions::new().write(true).open(device)?;
+    let zeros = vec![0; BUF_SIZE as usize];
+    let mut remaining = size;
+    while remaining > 0 {
+        let len = remaining.min(BUF_SIZE);
+        file.write_all(&zeros[..len as usize])?;
+        remaining -= len;
     }
+    file.sync_all()?;
+    Ok(())
 }
 
 /// Used to derive a UUID that uniquely identifies a device mapper device when creating it.
@@ -257,6 +621,11 @@ mod tests {
         key: b"thirtytwobyteslongreallylongword",
         different_key: b"drowgnolyllaergnolsetybowtytriht",
     };
+    const KEY_SET_GCM: KeySet = KeySet {
+        cipher: CipherType::AES256GCM,
+        key: b"thirtytwobyteslongreallylongword",
+        different_key: b"drowgnolyllaergnolsetybowtytriht",
+    };
 
     // Create a file in given temp directory with given size
     fn prepare_tmpfile(test_dir: &Path, filename: &str, sz: u64) -> PathBuf {
@@ -292,6 +661,11 @@ mod tests {
         }
     }
 
+    fn is_integrity_supported() -> bool {
+        // dm-integrity isn't enabled in every kernel, e.g. in Microdroid.
+        DeviceMapper::new().unwrap().target_version("integrity").unwrap().is_some()
+    }
+
     #[rdroidtest]
     fn mapping_again_keeps_data_xts() {
         mapping_again_keeps_data(&KEY_SET_XTS, "name1");
@@ -314,6 +688,88 @@ mod tests {
         data_inaccessible_with_diff_key(&KEY_SET_HCTR2, "name4");
     }
 
+    #[rdroidtest]
+    fn query_device() {
+        let dm = DeviceMapper::new().unwrap();
+        let sz = 8192;
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let backing_file = prepare_tmpfile(test_dir.path(), "storage", sz);
+        let data_device = loopdevice::attach(
+            backing_file,
+            0,
+            sz,
+            /* direct_io */ true,
+            /* writable */ true,
+        )
+        .unwrap();
+        let device = "name8";
+        scopeguard::defer! {
+            loopdevice::detach(&data_device).unwrap();
+            let _ignored = delete_device(&dm, device);
+        }
+
+        assert!(dm.info(device).unwrap().is_none());
+        assert!(!dm.list_devices().unwrap().iter().any(|d| d.name == device));
+
+        let target = DmCryptTargetBuilder::default()
+            .data_device(&data_device, sz)
+            .cipher(KEY_SET_XTS.cipher)
+            .key(KEY_SET_XTS.key)
+            .build()
+            .unwrap();
+        dm.create_crypt_device(device, &target).unwrap();
+
+        let info = dm.info(device).unwrap().unwrap();
+        assert_eq!(info.target_count, 1);
+        assert!(info.active_table);
+        assert!(!info.suspended);
+        assert!(!info.read_only);
+        assert_eq!(info.open_count, 0);
+        assert!(dm
+            .list_devices()
+            .unwrap()
+            .contains(&DmDevice { name: device.into(), dev: info.dev }));
+
+        let table = dm.table(device).unwrap();
+        assert_eq!(table.len(), 1);
+        assert_eq!(table[0].target_type, "crypt");
+        assert_eq!(table[0].sector_start, 0);
+        assert_eq!(table[0].length, sz / 512);
+        assert!(table[0].params.starts_with("aes-xts-plain64 "));
+        assert!(table[0].params.contains(&hex::encode(KEY_SET_XTS.key)));
+
+        let status = dm.status(device).unwrap();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].target_type, "crypt");
+    }
+
+    #[rdroidtest]
+    fn query_target_version() {
+        let dm = DeviceMapper::new().unwrap();
+        let version = dm.target_version("crypt").unwrap().unwrap();
+        assert!(version[0] >= 1);
+        assert_eq!(dm.target_version("no-such-target").unwrap(), None);
+    }
+
+    #[rdroidtest]
+    #[ignore_if(!is_integrity_supported())]
+    fn mapping_again_keeps_data_gcm() {
+        authenticated_mapping_again_keeps_data(&KEY_SET_GCM, "name5");
+    }
+
+    #[rdroidtest]
+    #[ignore_if(!is_integrity_supported())]
+    fn data_unreadable_with_diff_key_gcm() {
+        authenticated_data_unreadable_with_diff_key(&KEY_SET_GCM, "name6");
+    }
+
+    #[rdroidtest]
+    #[ignore_if(!is_integrity_supported())]
+    fn tampered_data_unreadable_gcm() {
+        authenticated_tampered_data_unreadable(&KEY_SET_GCM, "name7");
+    }
+
     fn mapping_again_keeps_data(keyset: &KeySet, device: &str) {
         // This test creates 2 different crypt devices using same key backed by same data_device
         // -> Write data on dev1 -> Check the data is visible & same on dev2
@@ -404,4 +860,174 @@ mod tests {
         let crypt = read(crypt_device).unwrap();
         assert_ne!(inputimg, crypt.as_slice());
     }
+
+    // The integrity device needs room for its superblock, journal and tags besides the data.
+    const AUTHENTICATED_STORAGE_SIZE: u64 = 16 * 1024 * 1024;
+
+    const AUTHENTICATED_DATA: &[u8] = include_bytes!("../testdata/rand8k");
+
+    fn delete_authenticated_device(dm: &DeviceMapper, name: &str) {
+        let _ignored1 = delete_device(dm, name);
+        let _ignored2 = delete_device(dm, &format!("{}-integrity", name));
+    }
+
+    fn authenticated_mapping_again_keeps_data(keyset: &KeySet, device: &str) {
+        // This test creates 2 authenticated crypt devices using the same key, backed by the same
+        // data_device, formatted by the first one -> Write data on dev1 -> Check the data is the
+        // same on dev2
+        let dm = DeviceMapper::new().unwrap();
+        let sz = AUTHENTICATED_STORAGE_SIZE;
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let backing_file = prepare_tmpfile(test_dir.path(), "storage", sz);
+        let data_device = loopdevice::attach(
+            backing_file,
+            0,
+            sz,
+            /* direct_io */ true,
+            /* writable */ true,
+        )
+        .unwrap();
+        let device_diff = device.to_owned() + "_diff";
+        scopeguard::defer! {
+            loopdevice::detach(&data_device).unwrap();
+            delete_authenticated_device(&dm, device);
+            delete_authenticated_device(&dm, &device_diff);
+        }
+
+        let crypt_device = dm
+            .create_authenticated_crypt_device(
+                device,
+                &data_device,
+                /* offset */ 0,
+                keyset.cipher,
+                keyset.key,
+                4096,
+                /* format */ true,
+            )
+            .unwrap();
+        // The formatted device is readable before anything is written to it.
+        assert!(read(&crypt_device).unwrap().iter().all(|b| *b == 0));
+        write_to_dev(&crypt_device, AUTHENTICATED_DATA);
+
+        let crypt_device = dm
+            .create_authenticated_crypt_device(
+                &device_diff,
+                &data_device,
+                /* offset */ 0,
+                keyset.cipher,
+                keyset.key,
+                4096,
+                /* format */ false,
+            )
+            .unwrap();
+        let crypt = read(crypt_device).unwrap();
+        assert_eq!(AUTHENTICATED_DATA, &crypt[..AUTHENTICATED_DATA.len()]);
+    }
+
+    fn authenticated_data_unreadable_with_diff_key(keyset: &KeySet, device: &str) {
+        // Unlike with the unauthenticated ciphers, reading data written with another key fails
+        // instead of returning garbage.
+        let dm = DeviceMapper::new().unwrap();
+        let sz = AUTHENTICATED_STORAGE_SIZE;
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let backing_file = prepare_tmpfile(test_dir.path(), "storage", sz);
+        let data_device = loopdevice::attach(
+            backing_file,
+            0,
+            sz,
+            /* direct_io */ true,
+            /* writable */ true,
+        )
+        .unwrap();
+        let device_diff = device.to_owned() + "_diff";
+        scopeguard::defer! {
+            loopdevice::detach(&data_device).unwrap();
+            delete_authenticated_device(&dm, device);
+            delete_authenticated_device(&dm, &device_diff);
+        }
+
+        let crypt_device = dm
+            .create_authenticated_crypt_device(
+                device,
+                &data_device,
+                /* offset */ 0,
+                keyset.cipher,
+                keyset.key,
+                4096,
+                /* format */ true,
+            )
+            .unwrap();
+        write_to_dev(&crypt_device, AUTHENTICATED_DATA);
+
+        let crypt_device = dm
+            .create_authenticated_crypt_device(
+                &device_diff,
+                &data_device,
+                /* offset */ 0,
+                keyset.cipher,
+                keyset.different_key,
+                4096,
+                /* format */ false,
+            )
+            .unwrap();
+        assert!(read(crypt_device).is_err());
+    }
+
+    fn authenticated_tampered_data_unreadable(keyset: &KeySet, device: &str) {
+        // This test modifies the data device below an authenticated crypt device, behind its back,
+        // and checks that reading the modified sectors fails.
+        let dm = DeviceMapper::new().unwrap();
+        let sz = AUTHENTICATED_STORAGE_SIZE;
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let backing_file = prepare_tmpfile(test_dir.path(), "storage", sz);
+        let data_device = loopdevice::attach(
+            &backing_file,
+            0,
+            sz,
+            /* direct_io */ true,
+            /* writable */ true,
+        )
+        .unwrap();
+        let device_diff = device.to_owned() + "_diff";
+        scopeguard::defer! {
+            loopdevice::detach(&data_device).unwrap();
+            delete_authenticated_device(&dm, device);
+            delete_authenticated_device(&dm, &device_diff);
+        }
+
+        let crypt_device = dm
+            .create_authenticated_crypt_device(
+                device,
+                &data_device,
+                /* offset */ 0,
+                keyset.cipher,
+                keyset.key,
+                4096,
+                /* format */ true,
+            )
+            .unwrap();
+        write_to_dev(&crypt_device, AUTHENTICATED_DATA);
+        delete_authenticated_device(&dm, device);
+
+        // Flip the bits of the whole backing file, except for the integrity superblock.
+        let mut storage = read(&backing_file).unwrap();
+        storage[integrity::SUPERBLOCK_SIZE..].iter_mut().for_each(|b| *b = !*b);
+        std::fs::write(&backing_file, storage).unwrap();
+
+        let crypt_device = dm
+            .create_authenticated_crypt_device(
+                &device_diff,
+                &data_device,
+                /* offset */ 0,
+                keyset.cipher,
+                keyset.key,
+                4096,
+                /* format */ false,
+            )
+            .unwrap();
+        assert!(read(crypt_device).is_err());
+    }
 }
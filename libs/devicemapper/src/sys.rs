@@ -16,6 +16,7 @@
 
 use bitflags::bitflags;
 use zerocopy::AsBytes;
+use zerocopy::FromBytes;
 use zerocopy::FromZeroes;
 
 // UAPI for device mapper can be found at include/uapi/linux/dm-ioctl.h
@@ -42,10 +43,12 @@ pub enum Cmd {
     DM_LIST_VERSIONS,
     DM_TARGET_MSG,
     DM_DEV_SET_GEOMETRY,
+    DM_DEV_ARM_POLL,
+    DM_GET_TARGET_VERSION,
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, AsBytes, FromZeroes)]
+#[derive(Copy, Clone, AsBytes, FromBytes, FromZeroes)]
 pub struct DmIoctl {
     pub version: [u32; 3],
     pub data_size: u32,
@@ -70,7 +73,9 @@ pub const DM_UUID_LEN: usize = 129;
 pub const DM_MAX_TYPE_NAME: usize = 16;
 
 #[repr(transparent)]
-#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, AsBytes, FromZeroes)]
+#[derive(
+    Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, AsBytes, FromBytes, FromZeroes,
+)]
 pub struct Flag(u32);
 
 bitflags! {
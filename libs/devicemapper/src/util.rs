@@ -37,13 +37,12 @@ pub fn wait_for_path<P: AsRef<Path>>(path: P) -> Result<()> {
     Ok(())
 }
 
-/// Wait for the path to disappear
-#[cfg(test)]
+/// Returns when the file on the given `path` disappears or timeout (1s) occurs.
 pub fn wait_for_path_disappears<P: AsRef<Path>>(path: P) -> Result<()> {
     const TIMEOUT: Duration = Duration::from_secs(1);
     const INTERVAL: Duration = Duration::from_millis(10);
     let begin = Instant::now();
-    while !path.as_ref().exists() {
+    while path.as_ref().exists() {
         if begin.elapsed() > TIMEOUT {
             bail!("{:?} not disappearing. TIMEOUT.", path.as_ref());
         }
@@ -25,7 +25,7 @@ use std::path::Path;
 use zerocopy::AsBytes;
 
 use crate::util::*;
-use crate::DmTargetSpec;
+use crate::{DmTargetInfo, DmTargetSpec};
 
 // The UAPI for the verity target is here.
 // https://www.kernel.org/doc/Documentation/device-mapper/verity.txt
@@ -201,3 +201,10 @@ impl<'a> DmVerityTargetBuilder<'a> {
         Ok(DmVerityTarget(buf.into_boxed_slice()))
     }
 }
+
+/// Returns whether `status`, the status of a verity target as returned by
+/// `DeviceMapper::status`, reports that a corrupted data block was found.
+pub fn is_corrupted(status: &DmTargetInfo) -> bool {
+    // The status is "V" if no corruption was detected, "C" otherwise.
+    status.target_type == "verity" && status.params.starts_with('C')
+}
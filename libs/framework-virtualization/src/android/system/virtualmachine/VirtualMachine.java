@@ -96,6 +96,7 @@ import java.io.IOException;
 import java.io.InputStream;
 import java.io.InputStreamReader;
 import java.io.OutputStream;
+import java.io.RandomAccessFile;
 import java.lang.annotation.Retention;
 import java.lang.annotation.RetentionPolicy;
 import java.nio.ByteBuffer;
@@ -592,7 +593,7 @@ public class VirtualMachine implements AutoCloseable {
                 try {
                     service.initializeWritablePartition(
                             ParcelFileDescriptor.open(vm.mEncryptedStoreFilePath, MODE_READ_WRITE),
-                            config.getEncryptedStorageBytes(),
+                            encryptedStorageImageBytes(config.getEncryptedStorageBytes()),
                             PartitionType.ENCRYPTEDSTORE);
                 } catch (FileNotFoundException e) {
                     throw new VirtualMachineException("encrypted storage image missing", e);
@@ -1929,13 +1930,16 @@ public class VirtualMachine implements AutoCloseable {
      * application to run on the virtual machine, etc.)
      *
      * <p>The new config must be {@linkplain VirtualMachineConfig#isCompatibleWith compatible with}
-     * the existing config.
+     * the existing config. If the new config has a larger {@linkplain
+     * VirtualMachineConfig#getEncryptedStorageBytes encrypted storage}, the storage is grown, and
+     * the filesystem on it is grown the next time the virtual machine is run. The encrypted storage
+     * can't be shrunk.
      *
      * <p>NOTE: This method may block and should not be called on the main thread.
      *
      * @return the old config
-     * @throws VirtualMachineException if the virtual machine is not stopped, or the new config is
-     *     incompatible.
+     * @throws VirtualMachineException if the virtual machine is not stopped, the new config is
+     *     incompatible, or it has a smaller encrypted storage.
      * @hide
      */
     @SystemApi
@@ -1950,6 +1954,9 @@ public class VirtualMachine implements AutoCloseable {
             }
             checkStopped();
 
+            if (newConfig.isEncryptedStorageEnabled()) {
+                growEncryptedStorage(newConfig.getEncryptedStorageBytes());
+            }
             if (oldConfig != newConfig) {
                 // Delete any existing file before recreating; that ensures any
                 // VirtualMachineDescriptor that refers to the old file does not see the new config.
@@ -1961,6 +1968,37 @@ public class VirtualMachine implements AutoCloseable {
         }
     }
 
+    @GuardedBy("mLock")
+    private void growEncryptedStorage(long storageBytes) throws VirtualMachineException {
+        // The image is created lazily when the VM is first run.
+        if (!mEncryptedStoreFilePath.exists()) {
+            return;
+        }
+        // The image keeps the size of a whole number of 4 KiB blocks, like when it was created.
+        long size = (encryptedStorageImageBytes(storageBytes) + 4095) & ~4095L;
+        try (RandomAccessFile file = new RandomAccessFile(mEncryptedStoreFilePath, "rw")) {
+            long currentSize = file.length();
+            if (size < currentSize) {
+                throw new VirtualMachineException(
+                        "encrypted storage can't shrink from "
+                                + currentSize
+                                + " to "
+                                + size
+                                + " bytes");
+            }
+            if (size > currentSize) {
+                file.setLength(size);
+            }
+        } catch (IOException e) {
+            throw new VirtualMachineException("failed to grow encrypted storage", e);
+        }
+    }
+
+    /** Returns the size of the image of an encrypted storage of {@code storageBytes}. */
+    private static long encryptedStorageImageBytes(long storageBytes) {
+        return storageBytes + IVirtualizationService.ENCRYPTEDSTORE_OVERHEAD_BYTES;
+    }
+
     @Nullable
     private static native IBinder nativeConnectToVsockServer(IBinder vmBinder, int port);
 
@@ -606,7 +606,9 @@ public final class VirtualMachineConfig {
      * can be interchangeably used for the same virtual machine; they do not change the VM identity
      * or secrets. Such changes include varying the number of CPUs or the size of the RAM. Changes
      * that would alter the identity of the VM (e.g. using a different payload or changing the debug
-     * mode) are considered incompatible.
+     * mode) are considered incompatible. Encrypted storage can be grown but not shrunk, so a config
+     * with less encrypted storage than this one is incompatible with it, but not the other way
+     * around.
      *
      * @see VirtualMachine#setConfig
      * @hide
@@ -618,7 +620,9 @@ public final class VirtualMachineConfig {
         }
         return this.mDebugLevel == other.mDebugLevel
                 && this.mProtectedVm == other.mProtectedVm
-                && this.mEncryptedStorageBytes == other.mEncryptedStorageBytes
+                && this.isEncryptedStorageEnabled() == other.isEncryptedStorageEnabled()
+                // Encrypted storage can be grown but not shrunk.
+                && other.mEncryptedStorageBytes >= this.mEncryptedStorageBytes
                 && this.mVmOutputCaptured == other.mVmOutputCaptured
                 && this.mVmConsoleInputSupported == other.mVmConsoleInputSupported
                 && this.mConnectVmConsole == other.mConnectVmConsole
@@ -1187,6 +1191,14 @@ public final class VirtualMachineConfig {
          *
          * <p>Deleting the VM will delete the encrypted data - there is no way to recover that data.
          *
+         * <p>The storage of an existing VM can be grown by {@linkplain VirtualMachine#setConfig
+         * setting} a config with a larger size, which takes effect the next time the VM is run. It
+         * can't be shrunk.
+         *
+         * <p>The file backing the storage is about 1 MiB larger than the given size, as it also
+         * holds metadata of the storage, such as a journal used to change its key. Storage created
+         * before that metadata was introduced gets it the first time it is grown.
+         *
          * @hide
          */
         @SystemApi
@@ -15,6 +15,7 @@
 //! VM Payload Config
 
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 /// VM payload config
 #[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
@@ -29,6 +30,11 @@ pub struct VmPayloadConfig {
     #[serde(default)]
     pub task: Option<Task>,
 
+    /// Tasks to run and supervise in a VM, instead of the single `task`. Each of them must have a
+    /// unique name, and exactly one of them must be the main task.
+    #[serde(default)]
+    pub tasks: Vec<Task>,
+
     /// APEXes to activate in a VM
     #[serde(default)]
     pub apexes: Vec<ApexConfig>,
@@ -56,6 +62,21 @@ pub struct VmPayloadConfig {
     /// https://docs.kernel.org/admin-guide/mm/transhuge.html
     #[serde(default)]
     pub hugepages: bool,
+
+    /// Whether the stdout and stderr of the task should be forwarded to the host, where they can
+    /// be read with `IVirtualMachine.getPayloadOutput`.
+    #[serde(default)]
+    pub forward_output: bool,
+    /// Whether the encrypted storage should use authenticated encryption (AES-GCM over
+    /// dm-integrity) when it is formatted, so that reading data modified outside the VM fails
+    /// instead of returning garbage. This has no effect on storage which is already formatted.
+    #[serde(default)]
+    pub authenticated_encrypted_storage: bool,
+
+    /// Version of the key the encrypted storage is encrypted with. Raising it re-encrypts the
+    /// storage with a new key the next time the VM boots. The default is the lowest version.
+    #[serde(default)]
+    pub encrypted_storage_key_version: Option<u32>,
 }
 
 /// OS config
@@ -84,8 +105,44 @@ pub enum TaskType {
     MicrodroidLauncher,
 }
 
+/// When a task is considered ready, i.e. when the tasks depending on it can be started.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, Default)]
+pub enum TaskReadiness {
+    /// The task is ready as soon as it is started.
+    #[serde(rename = "started")]
+    #[default]
+    Started,
+    /// The task is ready once it has exited successfully, e.g. for a setup task.
+    #[serde(rename = "exited")]
+    Exited,
+    /// The task is ready once the file at the given path exists.
+    #[serde(rename = "file")]
+    File(String),
+}
+
+/// Whether a task is restarted when it exits.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, Default)]
+pub enum TaskRestartPolicy {
+    /// The task is never restarted.
+    #[serde(rename = "never")]
+    #[default]
+    Never,
+    /// The task is restarted when it exits unsuccessfully.
+    #[serde(rename = "on_failure")]
+    OnFailure,
+    /// The task is restarted whenever it exits.
+    #[serde(rename = "always")]
+    Always,
+}
+
+/// The most tasks which a payload can have.
+pub const MAX_TASKS: usize = 32;
+
+/// The longest name of a task, in bytes.
+pub const MAX_TASK_NAME_LEN: usize = 128;
+
 /// Task to run in a VM
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Task {
     /// Decides how to execute the command: executable(default) | microdroid_launcher
     #[serde(default, rename = "type")]
@@ -95,6 +152,35 @@ pub struct Task {
     /// - For executable task, this is the path to the executable.
     /// - For microdroid_launcher task, this is the name of .so
     pub command: String,
+
+    /// Name of the task, which other tasks refer to it by. Required in `tasks`.
+    #[serde(default)]
+    pub name: String,
+
+    /// Arguments passed to the command
+    #[serde(default)]
+    pub args: Vec<String>,
+
+    /// Environment variables set for the command, on top of the ones of microdroid_manager
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+
+    /// Names of the tasks which must be ready before this task is started
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+
+    /// When the task is ready: started(default) | exited | {"file": path}
+    #[serde(default)]
+    pub ready: TaskReadiness,
+
+    /// Whether the task is restarted when it exits: never(default) | on_failure | always
+    #[serde(default)]
+    pub restart: TaskRestartPolicy,
+
+    /// Whether this is the main task, whose exit finishes the payload. The single `task` is
+    /// always the main task.
+    #[serde(default)]
+    pub main: bool,
 }
 
 /// APEX config
@@ -25,6 +25,7 @@ use std::io::Write;
 
 pub use microdroid_metadata::metadata::{
     metadata::Payload as PayloadMetadata, ApexPayload, ApkPayload, Metadata, PayloadConfig,
+    PayloadTaskOverrides,
 };
 
 /// Reads a metadata from a reader
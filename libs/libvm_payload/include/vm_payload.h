@@ -260,4 +260,55 @@ size_t AVmAttestationResult_getCertificateAt(const AVmAttestationResult* _Nonnul
                                              size_t index, void* _Nullable data, size_t size)
         __INTRODUCED_IN(__ANDROID_API_V__);
 
+/**
+ * Seals the given data, so that it can be stored outside the VM, e.g. passed to the host, and
+ * later be recovered with `AVmPayload_unseal` by this VM instance only.
+ *
+ * The data is encrypted and authenticated with a key derived from a secret bound to the VM
+ * instance, which no identifier passed to `AVmPayload_getVmInstanceSecret` returns, and from the
+ * supplied label, so that it can only be unsealed with the same label. The label can be used to bind the sealed data to a policy of the caller, e.g. to
+ * its purpose or to the version of the data, and does not need to be kept secret.
+ *
+ * The sealed data is versioned, and can be unsealed by future versions of this API. Sealing the
+ * same data twice produces different sealed data.
+ *
+ * \param label A pointer to the label (can be null if label_size is 0).
+ * \param label_size size of the label, which can be 0 if no label is needed.
+ * \param data A pointer to the data to seal (can be null if data_size is 0).
+ * \param data_size size of the data to seal.
+ * \param sealed A pointer to the memory where the sealed data will be written
+ * (can be null if size is 0).
+ * \param size The maximum number of bytes that can be written to the sealed buffer.
+ * If `size` is smaller than the size of the sealed data, nothing is written.
+ *
+ * \return The size of the sealed data, or the size needed if the supplied buffer is too small.
+ */
+size_t AVmPayload_seal(const void* _Nullable label, size_t label_size, const void* _Nullable data,
+                       size_t data_size, void* _Nullable sealed, size_t size)
+        __INTRODUCED_IN(36);
+
+/**
+ * Unseals data sealed with `AVmPayload_seal` by this VM instance, with the same label.
+ *
+ * The unsealed data is never larger than the sealed data, so a buffer of `sealed_size` bytes is
+ * always large enough.
+ *
+ * \param label A pointer to the label (can be null if label_size is 0).
+ * \param label_size size of the label.
+ * \param sealed A pointer to the sealed data (can be null if sealed_size is 0).
+ * \param sealed_size size of the sealed data.
+ * \param data A pointer to the memory where the unsealed data will be written
+ * (can be null if size is 0).
+ * \param size The maximum number of bytes that can be written to the data buffer.
+ * \param data_size Set to the size of the unsealed data on success, or to the size needed if
+ * the supplied buffer is too small. Otherwise set to 0.
+ *
+ * \return true if the data was unsealed. false if the supplied buffer is too small, or if the
+ * data cannot be unsealed because it is malformed, was sealed by another VM instance or with
+ * another label, or has been modified.
+ */
+bool AVmPayload_unseal(const void* _Nullable label, size_t label_size,
+                       const void* _Nullable sealed, size_t sealed_size, void* _Nullable data,
+                       size_t size, size_t* _Nonnull data_size) __INTRODUCED_IN(36);
+
 __END_DECLS
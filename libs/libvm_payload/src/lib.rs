@@ -14,8 +14,10 @@
 
 //! This module handles the interaction with virtual machine payload service.
 
+mod sealing;
+
 use android_system_virtualization_payload::aidl::android::system::virtualization::payload:: IVmPayloadService::{
-    IVmPayloadService, ENCRYPTEDSTORE_MOUNTPOINT, VM_APK_CONTENTS_PATH,
+    IVmPayloadService, ENCRYPTEDSTORE_MOUNTPOINT, PAYLOAD_SEALING_SECRET_SIZE, VM_APK_CONTENTS_PATH,
     VM_PAYLOAD_SERVICE_SOCKET_NAME, AttestationResult::AttestationResult,
 };
 use anyhow::{bail, ensure, Context, Result};
@@ -38,6 +40,7 @@ use std::sync::{
     Mutex,
 };
 use vm_payload_status_bindgen::AVmAttestationStatus;
+use zeroize::Zeroizing;
 
 /// Maximum size of an ECDSA signature for EC P-256 key is 72 bytes.
 const MAX_ECDSA_P256_SIGNATURE_SIZE: usize = 72;
@@ -206,6 +209,142 @@ fn try_get_vm_instance_secret(identifier: &[u8], size: usize) -> Result<Vec<u8>>
     Ok(vm_secret)
 }
 
+/// Seals the given data with a key bound to this VM instance and the given label, and returns the
+/// size of the sealed data.
+/// Panics on failure.
+///
+/// # Safety
+///
+/// Behavior is undefined if any of the following conditions are violated:
+///
+/// * `label` must be [valid] for reads of `label_size` bytes, if label_size > 0.
+/// * `data` must be [valid] for reads of `data_size` bytes, if data_size > 0.
+/// * `sealed` must be [valid] for writes of `size` bytes, if size > 0.
+///
+/// [valid]: ptr#safety
+#[no_mangle]
+pub unsafe extern "C" fn AVmPayload_seal(
+    label: *const u8,
+    label_size: usize,
+    data: *const u8,
+    data_size: usize,
+    sealed: *mut u8,
+    size: usize,
+) -> usize {
+    initialize_logging();
+
+    let sealed_size = sealing::sealed_size(data_size);
+    if size < sealed_size {
+        return sealed_size;
+    }
+    // SAFETY: See the requirements on `label` and `data` above.
+    let (label, data) =
+        unsafe { (slice_or_empty(label, label_size), slice_or_empty(data, data_size)) };
+    let sealed_data = unwrap_or_abort(try_seal(label, data));
+    // SAFETY: See the requirements on `sealed` above; `sealed_data` is known to have length
+    // `sealed_size`, which doesn't exceed `size`, and cannot overlap `sealed` because we just
+    // allocated it.
+    unsafe { ptr::copy_nonoverlapping(sealed_data.as_ptr(), sealed, sealed_size) };
+    sealed_size
+}
+
+fn try_get_sealing_secret() -> Result<Zeroizing<Vec<u8>>> {
+    let secret = Zeroizing::new(
+        get_vm_payload_service()?
+            .getPayloadSealingSecret()
+            .context("Cannot get the payload sealing secret")?,
+    );
+    ensure!(
+        secret.len() == PAYLOAD_SEALING_SECRET_SIZE as usize,
+        "Returned sealing secret has {} bytes, expected {}",
+        secret.len(),
+        PAYLOAD_SEALING_SECRET_SIZE
+    );
+    Ok(secret)
+}
+
+fn try_seal(label: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let secret = try_get_sealing_secret()?;
+    sealing::seal(&secret, label, data).context("Cannot seal data")
+}
+
+/// Unseals data sealed with `AVmPayload_seal` by this VM instance with the same label.
+/// Returns false if the data cannot be unsealed.
+///
+/// # Safety
+///
+/// Behavior is undefined if any of the following conditions are violated:
+///
+/// * `label` must be [valid] for reads of `label_size` bytes, if label_size > 0.
+/// * `sealed` must be [valid] for reads of `sealed_size` bytes, if sealed_size > 0.
+/// * `data` must be [valid] for writes of `size` bytes, if size > 0.
+///
+/// [valid]: ptr#safety
+#[no_mangle]
+pub unsafe extern "C" fn AVmPayload_unseal(
+    label: *const u8,
+    label_size: usize,
+    sealed: *const u8,
+    sealed_size: usize,
+    data: *mut u8,
+    size: usize,
+    data_size: &mut usize,
+) -> bool {
+    initialize_logging();
+
+    // SAFETY: See the requirements on `label` and `sealed` above.
+    let (label, sealed) =
+        unsafe { (slice_or_empty(label, label_size), slice_or_empty(sealed, sealed_size)) };
+    *data_size = 0;
+    let unsealed_size = match sealing::unsealed_size(sealed) {
+        Ok(unsealed_size) => unsealed_size,
+        Err(e) => {
+            error!("Cannot unseal data: {e:?}");
+            return false;
+        }
+    };
+    if size < unsealed_size {
+        *data_size = unsealed_size;
+        return false;
+    }
+    match try_unseal(label, sealed) {
+        Ok(unsealed) => {
+            // SAFETY: See the requirements on `data` above; `unsealed` doesn't exceed `size`
+            // bytes, and cannot overlap `data` because we just allocated it.
+            unsafe { ptr::copy_nonoverlapping(unsealed.as_ptr(), data, unsealed.len()) };
+            *data_size = unsealed.len();
+            true
+        }
+        Err(e) => {
+            error!("Cannot unseal data: {e:?}");
+            false
+        }
+    }
+}
+
+fn try_unseal(label: &[u8], sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    // Failing to get the secret is not an expected failure of unsealing.
+    let secret = unwrap_or_abort(try_get_sealing_secret());
+    sealing::unseal(&secret, label, sealed)
+}
+
+/// Returns the slice of `len` bytes at `data`, or an empty slice if `len` is 0, in which case
+/// `data` may be null.
+///
+/// # Safety
+///
+/// `data` must be [valid] for reads of `len` bytes, if len > 0.
+///
+/// [valid]: ptr#safety
+unsafe fn slice_or_empty<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        &[]
+    } else {
+        // SAFETY: The caller guarantees that `data` is valid for reads of `len` bytes.
+        unsafe { std::slice::from_raw_parts(data, len) }
+    }
+}
+
 /// Get the VM's attestation chain.
 /// Panics on failure.
 ///
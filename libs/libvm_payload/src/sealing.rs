@@ -0,0 +1,180 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sealing and unsealing of payload data with keys bound to the VM instance.
+//!
+//! A sealed blob is laid out as follows:
+//!
+//! | Offset | Size | Content                                      |
+//! |--------|------|----------------------------------------------|
+//! | 0      | 1    | Version of the format, currently 1           |
+//! | 1      | 32   | Random salt used to derive the sealing key   |
+//! | 33     | n+16 | Data encrypted with AES-256-GCM, and its tag |
+//!
+//! The sealing key is derived with HKDF-SHA512 from the sealing secret of the VM instance, the
+//! salt and the label supplied by the caller, so that a blob can only be unsealed by the same VM
+//! instance with the same label. The version and the salt are authenticated as additional data.
+//!
+//! The sealing secret is derived by microdroid_manager apart from the secrets returned by
+//! `AVmPayload_getVmInstanceSecret`, so that the payload can't get it through that API.
+
+use anyhow::{ensure, Context, Result};
+use bssl_avf::{hkdf, rand_bytes, Aead, AeadContext, Digester, AES_GCM_NONCE_LENGTH};
+use zeroize::Zeroizing;
+
+/// Version of the sealed blob format.
+const SEALED_VERSION_1: u8 = 1;
+
+/// Info used to derive the sealing key using HKDF, followed by the label supplied by the caller.
+const SEALING_KEY_INFO: &[u8] = b"vm_payload sealing key v1:";
+
+const SALT_SIZE: usize = 32;
+const HEADER_SIZE: usize = 1 + SALT_SIZE;
+
+/// An all-zero nonce is used, as each blob is encrypted with a distinct key derived from a random
+/// salt, which already guarantees the uniqueness of the key/nonce combination.
+const SEALING_NONCE: &[u8; AES_GCM_NONCE_LENGTH] = &[0; AES_GCM_NONCE_LENGTH];
+
+fn aead() -> Aead {
+    Aead::aes_256_gcm()
+}
+
+fn aead_ctx(secret: &[u8], salt: &[u8], label: &[u8]) -> Result<AeadContext> {
+    let info = [SEALING_KEY_INFO, label].concat();
+    let key = hkdf::<32>(secret, salt, &info, Digester::sha512())?;
+    let tag_len = None;
+    Ok(AeadContext::new(aead(), key.as_slice(), tag_len)?)
+}
+
+/// Returns the size of the blob that sealing `data_size` bytes produces.
+pub(crate) fn sealed_size(data_size: usize) -> usize {
+    HEADER_SIZE + data_size + aead().max_overhead()
+}
+
+/// Returns the size of the data which `sealed` unseals to, or an error if `sealed` isn't a
+/// well-formed sealed blob.
+pub(crate) fn unsealed_size(sealed: &[u8]) -> Result<usize> {
+    let version = *sealed.first().context("Sealed data is empty")?;
+    ensure!(version == SEALED_VERSION_1, "Unsupported sealed data version {version}");
+    sealed
+        .len()
+        .checked_sub(HEADER_SIZE + aead().max_overhead())
+        .with_context(|| format!("Sealed data is too short: {} bytes", sealed.len()))
+}
+
+/// Encrypts and authenticates `data` with a key derived from `secret` and `label`.
+pub(crate) fn seal(secret: &[u8], label: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let mut sealed = vec![0u8; sealed_size(data.len())];
+    sealed[0] = SEALED_VERSION_1;
+    rand_bytes(&mut sealed[1..HEADER_SIZE])?;
+
+    let (header, out) = sealed.split_at_mut(HEADER_SIZE);
+    let aead_ctx = aead_ctx(secret, &header[1..], label)?;
+    let ciphertext_size = aead_ctx.seal(data, SEALING_NONCE, header, out)?.len();
+    sealed.truncate(HEADER_SIZE + ciphertext_size);
+    Ok(sealed)
+}
+
+/// Authenticates and decrypts `sealed`, which must have been sealed with the same `secret` and
+/// `label`.
+pub(crate) fn unseal(secret: &[u8], label: &[u8], sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    let mut data = Zeroizing::new(vec![0u8; unsealed_size(sealed)?]);
+    let (header, ciphertext) = sealed.split_at(HEADER_SIZE);
+    let aead_ctx = aead_ctx(secret, &header[1..], label)?;
+    let data_size = aead_ctx
+        .open(ciphertext, SEALING_NONCE, header, &mut data)
+        .context("Failed to authenticate the sealed data")?
+        .len();
+    data.truncate(data_size);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Size of the sealing secret returned by microdroid_manager.
+    const SEALING_SECRET_SIZE: usize = 32;
+    const TEST_SECRET1: [u8; SEALING_SECRET_SIZE] = [1; SEALING_SECRET_SIZE];
+    const TEST_SECRET2: [u8; SEALING_SECRET_SIZE] = [2; SEALING_SECRET_SIZE];
+    const TEST_DATA: &[u8] = b"some data to be sealed";
+
+    #[test]
+    fn unsealing_succeeds_with_the_same_secret_and_label() -> Result<()> {
+        let sealed = seal(&TEST_SECRET1, b"label", TEST_DATA)?;
+
+        assert_eq!(sealed.len(), sealed_size(TEST_DATA.len()));
+        assert_eq!(unsealed_size(&sealed)?, TEST_DATA.len());
+        assert_eq!(unseal(&TEST_SECRET1, b"label", &sealed)?.as_slice(), TEST_DATA);
+        Ok(())
+    }
+
+    #[test]
+    fn sealing_empty_data_succeeds() -> Result<()> {
+        let sealed = seal(&TEST_SECRET1, &[], &[])?;
+
+        assert!(unseal(&TEST_SECRET1, &[], &sealed)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn sealing_twice_produces_different_blobs() -> Result<()> {
+        let sealed1 = seal(&TEST_SECRET1, &[], TEST_DATA)?;
+        let sealed2 = seal(&TEST_SECRET1, &[], TEST_DATA)?;
+
+        assert_ne!(sealed1, sealed2);
+        Ok(())
+    }
+
+    #[test]
+    fn unsealing_fails_with_a_different_secret() -> Result<()> {
+        let sealed = seal(&TEST_SECRET1, b"label", TEST_DATA)?;
+
+        assert!(unseal(&TEST_SECRET2, b"label", &sealed).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn unsealing_fails_with_a_different_label() -> Result<()> {
+        let sealed = seal(&TEST_SECRET1, b"label", TEST_DATA)?;
+
+        assert!(unseal(&TEST_SECRET1, b"other label", &sealed).is_err());
+        assert!(unseal(&TEST_SECRET1, &[], &sealed).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn unsealing_fails_with_tampered_data() -> Result<()> {
+        let sealed = seal(&TEST_SECRET1, &[], TEST_DATA)?;
+
+        for i in 1..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 1;
+            assert!(unseal(&TEST_SECRET1, &[], &tampered).is_err(), "Byte {i} not authenticated");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unsealing_fails_with_malformed_data() -> Result<()> {
+        let mut sealed = seal(&TEST_SECRET1, &[], TEST_DATA)?;
+
+        assert!(unsealed_size(&[]).is_err());
+        assert!(unsealed_size(&sealed[..HEADER_SIZE]).is_err());
+        sealed[0] = 2;
+        assert!(unsealed_size(&sealed).is_err());
+        assert!(unseal(&TEST_SECRET1, &[], &sealed).is_err());
+        Ok(())
+    }
+}
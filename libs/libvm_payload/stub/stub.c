@@ -13,3 +13,5 @@ void AVmAttestationResult_free() {}
 void AVmAttestationStatus_toString() {}
 void AVmAttestationResult_getCertificateCount() {}
 void AVmAttestationResult_getCertificateAt() {}
+void AVmPayload_seal() {}
+void AVmPayload_unseal() {}
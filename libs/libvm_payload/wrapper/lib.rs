@@ -21,10 +21,12 @@
 //! for more information on the VM Payload API.
 
 mod attestation;
+mod sealing;
 
 pub use attestation::{request_attestation, AttestationError, AttestationResult};
 use binder::unstable_api::AsNative;
 use binder::{FromIBinder, Strong};
+pub use sealing::{seal, unseal, UnsealError};
 use std::ffi::{c_void, CStr, OsStr};
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
@@ -0,0 +1,103 @@
+/*
+ * Copyright 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::error::Error;
+use std::ffi::c_void;
+use std::fmt::{self, Display};
+
+use vm_payload_bindgen::{AVmPayload_seal, AVmPayload_unseal};
+
+/// Error returned when data cannot be unsealed. See [`unseal`].
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct UnsealError;
+
+impl Error for UnsealError {}
+
+impl Display for UnsealError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        f.write_str("The data cannot be unsealed by this VM instance with this label.")
+    }
+}
+
+/// Seals the given data, so that it can be stored outside the VM, e.g. passed to the host, and
+/// later be recovered with [`unseal`] by this VM instance only.
+///
+/// The data is encrypted and authenticated with a key derived from the VM instance secret (see
+/// [`get_vm_instance_secret`](crate::get_vm_instance_secret)) and the supplied label, so that it
+/// can only be unsealed with the same label. The label can be used to bind the sealed data to a
+/// policy of the caller, e.g. to its purpose or to the version of the data, and does not need to
+/// be kept secret; it may be empty.
+///
+/// The sealed data is versioned, and can be unsealed by future versions of this API. Sealing the
+/// same data twice produces different sealed data.
+pub fn seal(label: &[u8], data: &[u8]) -> Vec<u8> {
+    // SAFETY: The function only reads from `[label]` and `[data]` within their bounds, and doesn't
+    // write anything if size is 0.
+    let size = unsafe {
+        AVmPayload_seal(
+            label.as_ptr() as *const c_void,
+            label.len(),
+            data.as_ptr() as *const c_void,
+            data.len(),
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    let mut sealed = vec![0u8; size];
+    // SAFETY: The function only reads from `[label]` and `[data]` within their bounds, and only
+    // writes to `[sealed]` within its bounds. No reference is retained.
+    let sealed_size = unsafe {
+        AVmPayload_seal(
+            label.as_ptr() as *const c_void,
+            label.len(),
+            data.as_ptr() as *const c_void,
+            data.len(),
+            sealed.as_mut_ptr() as *mut c_void,
+            sealed.len(),
+        )
+    };
+    assert_eq!(sealed_size, size, "Unexpected size of the sealed data");
+    sealed
+}
+
+/// Unseals data sealed with [`seal`] by this VM instance, with the same label.
+///
+/// Returns an error if the data cannot be unsealed because it is malformed, was sealed by another
+/// VM instance or with another label, or has been modified.
+pub fn unseal(label: &[u8], sealed: &[u8]) -> Result<Vec<u8>, UnsealError> {
+    // The unsealed data is never larger than the sealed data.
+    let mut data = vec![0u8; sealed.len()];
+    let mut data_size = 0;
+    // SAFETY: The function only reads from `[label]` and `[sealed]` within their bounds, and only
+    // writes to `[data]` within its bounds and to `data_size`. No reference is retained.
+    let unsealed = unsafe {
+        AVmPayload_unseal(
+            label.as_ptr() as *const c_void,
+            label.len(),
+            sealed.as_ptr() as *const c_void,
+            sealed.len(),
+            data.as_mut_ptr() as *mut c_void,
+            data.len(),
+            &mut data_size,
+        )
+    };
+    if unsealed {
+        data.truncate(data_size);
+        Ok(data)
+    } else {
+        Err(UnsealError)
+    }
+}
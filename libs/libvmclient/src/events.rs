@@ -0,0 +1,129 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{DeathReason, ErrorCode, VmState};
+use crate::sync::Monitor;
+use futures_core::Stream;
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+/// A notification of a significant change to the VM state, as passed to [`crate::VmCallback`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum VmEvent {
+    /// The payload has been started within the VM.
+    PayloadStarted,
+    /// The payload has notified Virtualization Service that it is ready to serve clients.
+    PayloadReady,
+    /// The payload has exited in the VM with the given exit code.
+    PayloadFinished {
+        /// The exit code of the payload process.
+        exit_code: i32,
+    },
+    /// An error has occurred in the VM.
+    Error {
+        /// The kind of the error.
+        error_code: ErrorCode,
+        /// Further details of the error.
+        message: String,
+    },
+    /// The VM or the VirtualizationService itself has died. This is always the last event.
+    Died {
+        /// Why the VM died.
+        reason: DeathReason,
+    },
+}
+
+/// A stream of the events of a VM, from when it was created until it died.
+///
+/// Each stream goes through all the events of the VM at its own pace, starting with the first,
+/// however late it was created. The stream ends after [`VmEvent::Died`].
+#[derive(Debug)]
+pub struct VmEvents {
+    state: Arc<Monitor<VmState>>,
+    next: usize,
+}
+
+impl VmEvents {
+    pub(crate) fn new(state: Arc<Monitor<VmState>>) -> Self {
+        Self { state, next: 0 }
+    }
+}
+
+impl Stream for VmEvents {
+    type Item = VmEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<VmEvent>> {
+        let this = self.get_mut();
+        let next = this.next;
+        let state = ready!(this.state.poll_while(cx, |state| {
+            next >= state.events.len() && state.death_reason.is_none()
+        }))
+        .unwrap();
+        let event = state.events.get(next).cloned();
+        if event.is_some() {
+            this.next += 1;
+        }
+        Poll::Ready(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VirtualMachineState;
+    use std::task::Waker;
+
+    fn poll_next(events: &mut VmEvents) -> Poll<Option<VmEvent>> {
+        Pin::new(events).poll_next(&mut Context::from_waker(Waker::noop()))
+    }
+
+    #[test]
+    fn stream_ends_after_death() {
+        let state = Arc::new(Monitor::new(VmState::default()));
+        let mut events = VmEvents::new(state.clone());
+        assert_eq!(poll_next(&mut events), Poll::Pending);
+
+        state.notify_state(VirtualMachineState::STARTED, VmEvent::PayloadStarted);
+        assert_eq!(poll_next(&mut events), Poll::Ready(Some(VmEvent::PayloadStarted)));
+        assert_eq!(poll_next(&mut events), Poll::Pending);
+
+        state.notify_death(DeathReason::Shutdown);
+        assert_eq!(
+            poll_next(&mut events),
+            Poll::Ready(Some(VmEvent::Died { reason: DeathReason::Shutdown }))
+        );
+        assert_eq!(poll_next(&mut events), Poll::Ready(None));
+        assert_eq!(poll_next(&mut events), Poll::Ready(None));
+    }
+
+    #[test]
+    fn late_stream_goes_through_all_events() {
+        let state = Arc::new(Monitor::new(VmState::default()));
+        state.notify_state(VirtualMachineState::STARTED, VmEvent::PayloadStarted);
+        state.notify_state(VirtualMachineState::READY, VmEvent::PayloadReady);
+        state.notify_death(DeathReason::Crash);
+
+        let mut events = VmEvents::new(state);
+        assert_eq!(poll_next(&mut events), Poll::Ready(Some(VmEvent::PayloadStarted)));
+        assert_eq!(poll_next(&mut events), Poll::Ready(Some(VmEvent::PayloadReady)));
+        assert_eq!(
+            poll_next(&mut events),
+            Poll::Ready(Some(VmEvent::Died { reason: DeathReason::Crash }))
+        );
+        assert_eq!(poll_next(&mut events), Poll::Ready(None));
+    }
+}
@@ -17,11 +17,13 @@
 mod death_reason;
 mod error_code;
 mod errors;
+mod events;
 mod sync;
 
 pub use crate::death_reason::DeathReason;
 pub use crate::error_code::ErrorCode;
 pub use crate::errors::VmWaitError;
+pub use crate::events::{VmEvent, VmEvents};
 use crate::sync::Monitor;
 use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::{
     DeathReason::DeathReason as AidlDeathReason, ErrorCode::ErrorCode as AidlErrorCode,
@@ -44,6 +46,7 @@ use log::warn;
 use rpcbinder::{FileDescriptorTransportMode, RpcSession};
 use shared_child::SharedChild;
 use std::ffi::{c_char, c_int, c_void, CString};
+use std::future::{poll_fn, Future};
 use std::io::{self, Read};
 use std::os::fd::RawFd;
 use std::process::Command;
@@ -164,6 +167,9 @@ impl VirtualizationService {
 }
 
 /// A virtual machine which has been started by the VirtualizationService.
+///
+/// Besides the blocking `wait_*` methods, it has methods returning futures and streams which can
+/// be awaited from any async runtime, without a thread per VM.
 pub struct VmInstance {
     /// The `IVirtualMachine` Binder object representing the VM.
     pub vm: Strong<dyn IVirtualMachine>,
@@ -282,6 +288,37 @@ impl VmInstance {
         }
     }
 
+    /// Returns a future which resolves when the VM or the VirtualizationService itself dies, to the
+    /// reason why it died.
+    pub fn death(&self) -> impl Future<Output = DeathReason> + Send + 'static {
+        death(self.state.clone())
+    }
+
+    /// Returns a future which resolves when the VM reports that it is ready.
+    ///
+    /// Resolves to an error if the VM dies or its payload finishes first. It never times out by
+    /// itself; use the timer of the async runtime for that, e.g. `tokio::time::timeout`.
+    pub fn ready(&self) -> impl Future<Output = Result<(), VmWaitError>> + Send + 'static {
+        ready(self.state.clone())
+    }
+
+    /// Returns a future which resolves when the state of the VM, as reported through its callbacks,
+    /// is no longer `from`, to the new state. The state is `DEAD` once the VM has died.
+    ///
+    /// Unlike [`Self::state`], this never reports `STARTING`, which has no callback.
+    pub fn state_change(
+        &self,
+        from: VirtualMachineState,
+    ) -> impl Future<Output = VirtualMachineState> + Send + 'static {
+        state_change(self.state.clone(), from)
+    }
+
+    /// Returns a stream of the events of the VM, the same as those passed to the [`VmCallback`] of
+    /// the VM, from when the VM was created until it died.
+    pub fn events(&self) -> VmEvents {
+        VmEvents::new(self.state.clone())
+    }
+
     /// Tries to connect to an RPC Binder service provided by the VM on the given vsock port.
     pub fn connect_service<T: FromIBinder + ?Sized>(
         &self,
@@ -308,6 +345,46 @@ impl Debug for VmInstance {
     }
 }
 
+fn death(state: Arc<Monitor<VmState>>) -> impl Future<Output = DeathReason> + Send + 'static {
+    poll_fn(move |cx| {
+        state
+            .poll_while(cx, |state| state.death_reason.is_none())
+            .map(|state| state.unwrap().death_reason.unwrap())
+    })
+}
+
+fn ready(
+    state: Arc<Monitor<VmState>>,
+) -> impl Future<Output = Result<(), VmWaitError>> + Send + 'static {
+    poll_fn(move |cx| {
+        state
+            .poll_while(cx, |state| {
+                state.reported_state < VirtualMachineState::READY && state.death_reason.is_none()
+            })
+            .map(|state| {
+                let state = state.unwrap();
+                if let Some(reason) = state.death_reason {
+                    Err(VmWaitError::Died { reason })
+                } else if state.reported_state != VirtualMachineState::READY {
+                    Err(VmWaitError::Finished)
+                } else {
+                    Ok(())
+                }
+            })
+    })
+}
+
+fn state_change(
+    state: Arc<Monitor<VmState>>,
+    from: VirtualMachineState,
+) -> impl Future<Output = VirtualMachineState> + Send + 'static {
+    poll_fn(move |cx| {
+        state
+            .poll_while(cx, |state| state.reported_state() == from)
+            .map(|state| state.unwrap().reported_state())
+    })
+}
+
 /// Notify the VmState when the given Binder object dies.
 ///
 /// If the returned DeathRecipient is dropped then this will no longer do anything.
@@ -327,6 +404,18 @@ fn wait_for_binder_death(
 struct VmState {
     death_reason: Option<DeathReason>,
     reported_state: VirtualMachineState,
+    /// All the events of the VM so far, for each `VmEvents` to go through. A VM only has a few.
+    events: Vec<VmEvent>,
+}
+
+impl VmState {
+    fn reported_state(&self) -> VirtualMachineState {
+        if self.death_reason.is_some() {
+            VirtualMachineState::DEAD
+        } else {
+            self.reported_state
+        }
+    }
 }
 
 impl Monitor<VmState> {
@@ -335,13 +424,16 @@ impl Monitor<VmState> {
         // In case this method is called more than once, ignore subsequent calls.
         if state.death_reason.is_none() {
             state.death_reason.replace(reason);
-            self.cv.notify_all();
+            state.events.push(VmEvent::Died { reason });
+            self.notify_all();
         }
     }
 
-    fn notify_state(&self, state: VirtualMachineState) {
-        self.state.lock().unwrap().reported_state = state;
-        self.cv.notify_all();
+    fn notify_state(&self, state: VirtualMachineState, event: VmEvent) {
+        let vm_state = &mut *self.state.lock().unwrap();
+        vm_state.reported_state = state;
+        vm_state.events.push(event);
+        self.notify_all();
     }
 }
 
@@ -366,7 +458,7 @@ impl Interface for VirtualMachineCallback {}
 
 impl IVirtualMachineCallback for VirtualMachineCallback {
     fn onPayloadStarted(&self, cid: i32) -> BinderResult<()> {
-        self.state.notify_state(VirtualMachineState::STARTED);
+        self.state.notify_state(VirtualMachineState::STARTED, VmEvent::PayloadStarted);
         if let Some(ref callback) = self.client_callback {
             callback.on_payload_started(cid);
         }
@@ -374,7 +466,7 @@ impl IVirtualMachineCallback for VirtualMachineCallback {
     }
 
     fn onPayloadReady(&self, cid: i32) -> BinderResult<()> {
-        self.state.notify_state(VirtualMachineState::READY);
+        self.state.notify_state(VirtualMachineState::READY, VmEvent::PayloadReady);
         if let Some(ref callback) = self.client_callback {
             callback.on_payload_ready(cid);
         }
@@ -382,7 +474,8 @@ impl IVirtualMachineCallback for VirtualMachineCallback {
     }
 
     fn onPayloadFinished(&self, cid: i32, exit_code: i32) -> BinderResult<()> {
-        self.state.notify_state(VirtualMachineState::FINISHED);
+        self.state
+            .notify_state(VirtualMachineState::FINISHED, VmEvent::PayloadFinished { exit_code });
         if let Some(ref callback) = self.client_callback {
             callback.on_payload_finished(cid, exit_code);
         }
@@ -390,9 +483,12 @@ impl IVirtualMachineCallback for VirtualMachineCallback {
     }
 
     fn onError(&self, cid: i32, error_code: AidlErrorCode, message: &str) -> BinderResult<()> {
-        self.state.notify_state(VirtualMachineState::FINISHED);
+        let error_code = error_code.into();
+        self.state.notify_state(
+            VirtualMachineState::FINISHED,
+            VmEvent::Error { error_code, message: message.to_owned() },
+        );
         if let Some(ref callback) = self.client_callback {
-            let error_code = error_code.into();
             callback.on_error(cid, error_code, message);
         }
         Ok(())
@@ -407,3 +503,73 @@ impl IVirtualMachineCallback for VirtualMachineCallback {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    fn poll<F: Future>(future: std::pin::Pin<&mut F>) -> Poll<F::Output> {
+        future.poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    #[test]
+    fn death_resolves_when_vm_dies() {
+        let state = Arc::new(Monitor::new(VmState::default()));
+        let mut death = pin!(death(state.clone()));
+        assert_eq!(poll(death.as_mut()), Poll::Pending);
+
+        state
+            .notify_state(VirtualMachineState::FINISHED, VmEvent::PayloadFinished { exit_code: 0 });
+        assert_eq!(poll(death.as_mut()), Poll::Pending);
+
+        state.notify_death(DeathReason::Shutdown);
+        assert_eq!(poll(death.as_mut()), Poll::Ready(DeathReason::Shutdown));
+    }
+
+    #[test]
+    fn ready_resolves_when_vm_is_ready() {
+        let state = Arc::new(Monitor::new(VmState::default()));
+        let mut ready = pin!(ready(state.clone()));
+        assert_eq!(poll(ready.as_mut()), Poll::Pending);
+
+        state.notify_state(VirtualMachineState::STARTED, VmEvent::PayloadStarted);
+        assert_eq!(poll(ready.as_mut()), Poll::Pending);
+
+        state.notify_state(VirtualMachineState::READY, VmEvent::PayloadReady);
+        assert_eq!(poll(ready.as_mut()), Poll::Ready(Ok(())));
+    }
+
+    #[test]
+    fn ready_fails_when_vm_finishes_or_dies_first() {
+        let state = Arc::new(Monitor::new(VmState::default()));
+        let mut ready_after_finish = pin!(ready(state.clone()));
+        state
+            .notify_state(VirtualMachineState::FINISHED, VmEvent::PayloadFinished { exit_code: 1 });
+        assert_eq!(poll(ready_after_finish.as_mut()), Poll::Ready(Err(VmWaitError::Finished)));
+
+        let state = Arc::new(Monitor::new(VmState::default()));
+        let mut ready_after_death = pin!(ready(state.clone()));
+        state.notify_death(DeathReason::Crash);
+        assert_eq!(
+            poll(ready_after_death.as_mut()),
+            Poll::Ready(Err(VmWaitError::Died { reason: DeathReason::Crash }))
+        );
+    }
+
+    #[test]
+    fn state_change_resolves_to_new_state() {
+        let state = Arc::new(Monitor::new(VmState::default()));
+        state.notify_state(VirtualMachineState::STARTED, VmEvent::PayloadStarted);
+        let mut change = pin!(state_change(state.clone(), VirtualMachineState::STARTED));
+        assert_eq!(poll(change.as_mut()), Poll::Pending);
+
+        state.notify_state(VirtualMachineState::READY, VmEvent::PayloadReady);
+        assert_eq!(poll(change.as_mut()), Poll::Ready(VirtualMachineState::READY));
+
+        let mut change = pin!(state_change(state.clone(), VirtualMachineState::READY));
+        state.notify_death(DeathReason::Killed);
+        assert_eq!(poll(change.as_mut()), Poll::Ready(VirtualMachineState::DEAD));
+    }
+}
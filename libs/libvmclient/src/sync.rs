@@ -13,21 +13,35 @@
 // limitations under the License.
 
 use std::{
+    mem,
     sync::{Condvar, LockResult, Mutex, MutexGuard, PoisonError, WaitTimeoutResult},
+    task::{Context, Poll, Waker},
     time::Duration,
 };
 
-/// A mutex with an associated condition variable.
+/// A mutex with an associated condition variable, and the wakers of the futures waiting on it.
 #[derive(Debug)]
 pub struct Monitor<T> {
     pub state: Mutex<T>,
     pub cv: Condvar,
+    wakers: Mutex<Vec<Waker>>,
 }
 
 impl<T> Monitor<T> {
     /// Creates a new mutex wrapping the given value, and a new condition variable to go with it.
     pub fn new(state: T) -> Self {
-        Self { state: Mutex::new(state), cv: Condvar::default() }
+        Self { state: Mutex::new(state), cv: Condvar::default(), wakers: Mutex::default() }
+    }
+
+    /// Wakes up all threads blocked on the condition variable and all futures waiting on it.
+    ///
+    /// This should be called after changing the contents of the mutex.
+    pub fn notify_all(&self) {
+        self.cv.notify_all();
+        let wakers = mem::take(&mut *self.wakers.lock().unwrap());
+        for waker in wakers {
+            waker.wake();
+        }
     }
 
     /// Waits on the condition variable while the given condition holds true on the contents of the
@@ -52,8 +66,83 @@ impl<T> Monitor<T> {
             .wait_timeout_while(self.state.lock()?, timeout, condition)
             .map_err(convert_poison_error)
     }
+
+    /// Checks the given condition on the contents of the mutex, for a future which waits while it
+    /// holds true.
+    ///
+    /// Returns `Poll::Pending` if the condition holds, in which case the task of `cx` is woken by
+    /// the next call to `notify_all`.
+    pub fn poll_while(
+        &self,
+        cx: &mut Context,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> Poll<LockResult<MutexGuard<T>>> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        if !condition(&mut state) {
+            return Poll::Ready(Ok(state));
+        }
+        // The waker is registered while the state is locked, so that it can't miss a change.
+        let mut wakers = self.wakers.lock().unwrap();
+        if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
 }
 
 fn convert_poison_error<T>(err: PoisonError<(T, WaitTimeoutResult)>) -> PoisonError<T> {
     PoisonError::new(err.into_inner().0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    /// A waker which counts how many times it was woken.
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn poll_while_is_woken_by_notify_all() {
+        let monitor = Monitor::new(0);
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(monitor.poll_while(&mut cx, |state| *state == 0).is_pending());
+        // Polling again doesn't register the same waker twice.
+        assert!(monitor.poll_while(&mut cx, |state| *state == 0).is_pending());
+        assert_eq!(monitor.wakers.lock().unwrap().len(), 1);
+        assert_eq!(counter.0.load(Ordering::SeqCst), 0);
+
+        *monitor.state.lock().unwrap() = 1;
+        monitor.notify_all();
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        assert!(monitor.wakers.lock().unwrap().is_empty());
+        let Poll::Ready(state) = monitor.poll_while(&mut cx, |state| *state == 0) else {
+            panic!("Condition no longer holds but still pending");
+        };
+        assert_eq!(*state.unwrap(), 1);
+    }
+
+    #[test]
+    fn poll_while_is_ready_without_waiting() {
+        let monitor = Monitor::new(1);
+        let mut cx = Context::from_waker(Waker::noop());
+
+        assert!(monitor.poll_while(&mut cx, |state| *state == 0).is_ready());
+        assert!(monitor.wakers.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Resolution of VM config files which extend other config files.
+//!
+//! A config file may name a base config file with the `"extends"` key. The path is relative to the
+//! directory of the extending file. The base is resolved first (it may itself extend another file)
+//! and the extending file is then merged onto it:
+//!
+//! - `params` of the two files are concatenated, separated by a space.
+//! - `disks` are appended, except that a disk with a `label` replaces the disk of the base with the
+//!   same label.
+//! - `devices` are appended, except for those already present in the base.
+//! - Any other key replaces the value of the base, including with `null`.
+
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+/// Key naming the config file which a config file extends.
+pub const EXTENDS_KEY: &str = "extends";
+
+/// Reads the JSON config file at `path` and merges it onto the config files it extends.
+pub fn resolve(path: &Path) -> Result<Value> {
+    resolve_chain(path, &mut vec![])
+}
+
+fn resolve_chain(path: &Path, chain: &mut Vec<PathBuf>) -> Result<Value> {
+    let canonical =
+        path.canonicalize().with_context(|| format!("Failed to resolve path {:?}", path))?;
+    if chain.contains(&canonical) {
+        bail!("Config file {:?} extends itself", path);
+    }
+    chain.push(canonical);
+
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let value: Value = serde_json::from_reader(BufReader::new(file))
+        .with_context(|| format!("Failed to parse {:?}", path))?;
+    let Value::Object(mut config) = value else {
+        bail!("Config file {:?} is not a JSON object", path);
+    };
+    let resolved = match config.remove(EXTENDS_KEY) {
+        None => config,
+        Some(Value::String(base)) => {
+            let base_path = path.parent().unwrap_or(Path::new("")).join(base);
+            let Value::Object(base) = resolve_chain(&base_path, chain)? else {
+                unreachable!("Resolved config is always an object");
+            };
+            merge(base, config).with_context(|| format!("Failed to merge {:?}", path))?
+        }
+        Some(other) => bail!("\"{}\" must be a path, not {}", EXTENDS_KEY, other),
+    };
+
+    chain.pop();
+    Ok(Value::Object(resolved))
+}
+
+/// Merges `overlay` onto `base`, following the rules described in the module documentation.
+pub fn merge(
+    mut base: Map<String, Value>,
+    overlay: Map<String, Value>,
+) -> Result<Map<String, Value>> {
+    for (key, value) in overlay {
+        let merged = match (key.as_str(), base.remove(&key)) {
+            ("params", Some(Value::String(base_params))) => match value {
+                Value::String(params) => Value::String(format!("{base_params} {params}")),
+                value => value,
+            },
+            ("disks", Some(Value::Array(base_disks))) => merge_disks(base_disks, value)?,
+            ("devices", Some(Value::Array(base_devices))) => merge_devices(base_devices, value)?,
+            _ => value,
+        };
+        base.insert(key, merged);
+    }
+    Ok(base)
+}
+
+fn merge_disks(mut disks: Vec<Value>, overlay: Value) -> Result<Value> {
+    let Value::Array(overlay) = overlay else {
+        bail!("\"disks\" must be an array, not {}", overlay);
+    };
+    for disk in overlay {
+        let label = disk.get("label").and_then(Value::as_str);
+        let existing = label.and_then(|label| {
+            disks.iter().position(|d| d.get("label").and_then(Value::as_str) == Some(label))
+        });
+        match existing {
+            Some(index) => disks[index] = disk,
+            None => disks.push(disk),
+        }
+    }
+    Ok(Value::Array(disks))
+}
+
+fn merge_devices(mut devices: Vec<Value>, overlay: Value) -> Result<Value> {
+    let Value::Array(overlay) = overlay else {
+        bail!("\"devices\" must be an array, not {}", overlay);
+    };
+    for device in overlay {
+        if !devices.contains(&device) {
+            devices.push(device);
+        }
+    }
+    Ok(Value::Array(devices))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs;
+
+    fn object(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => panic!("not an object"),
+        }
+    }
+
+    #[test]
+    fn scalars_override() -> Result<()> {
+        let base = object(json!({ "name": "base", "memory_mib": 256, "kernel": "/kernel" }));
+        let overlay = object(json!({ "memory_mib": 1024, "kernel": null }));
+        let merged = merge(base, overlay)?;
+        assert_eq!(
+            Value::Object(merged),
+            json!({ "name": "base", "memory_mib": 1024, "kernel": null })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn params_are_concatenated() -> Result<()> {
+        let merged = merge(
+            object(json!({ "params": "console=hvc0" })),
+            object(json!({ "params": "quiet" })),
+        )?;
+        assert_eq!(merged["params"], json!("console=hvc0 quiet"));
+        Ok(())
+    }
+
+    #[test]
+    fn disks_are_appended_or_replaced_by_label() -> Result<()> {
+        let base = object(json!({ "disks": [
+            { "label": "root", "image": "/root.img", "writable": false },
+            { "image": "/data.img", "writable": true },
+        ]}));
+        let overlay = object(json!({ "disks": [
+            { "label": "root", "image": "/other_root.img", "writable": false },
+            { "image": "/extra.img", "writable": false },
+        ]}));
+        let merged = merge(base, overlay)?;
+        assert_eq!(
+            merged["disks"],
+            json!([
+                { "label": "root", "image": "/other_root.img", "writable": false },
+                { "image": "/data.img", "writable": true },
+                { "image": "/extra.img", "writable": false },
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn devices_are_appended_without_duplicates() -> Result<()> {
+        let merged = merge(
+            object(json!({ "devices": ["/sys/a", "/sys/b"] })),
+            object(json!({ "devices": ["/sys/b", "/sys/c"] })),
+        )?;
+        assert_eq!(merged["devices"], json!(["/sys/a", "/sys/b", "/sys/c"]));
+        Ok(())
+    }
+
+    #[test]
+    fn resolves_chain_relative_to_extending_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::create_dir(dir.path().join("base"))?;
+        fs::write(
+            dir.path().join("base/base.json"),
+            r#"{ "kernel": "/kernel", "params": "a", "platform_version": "~1.0" }"#,
+        )?;
+        fs::write(
+            dir.path().join("mid.json"),
+            r#"{ "extends": "base/base.json", "params": "b" }"#,
+        )?;
+        fs::write(dir.path().join("vm.json"), r#"{ "extends": "mid.json", "memory_mib": 512 }"#)?;
+
+        let resolved = resolve(&dir.path().join("vm.json"))?;
+        assert_eq!(
+            resolved,
+            json!({
+                "kernel": "/kernel",
+                "params": "a b",
+                "platform_version": "~1.0",
+                "memory_mib": 512,
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn cycle_is_rejected() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("a.json"), r#"{ "extends": "b.json" }"#)?;
+        fs::write(dir.path().join("b.json"), r#"{ "extends": "a.json" }"#)?;
+        assert!(resolve(&dir.path().join("a.json")).is_err());
+        Ok(())
+    }
+}
@@ -14,10 +14,22 @@
 
 //! Struct for VM configuration with JSON (de)serialization and AIDL parcelables
 
+mod extends;
+mod validation;
+
+pub use validation::{parse_mac_address, ConfigProblem, PLATFORM_VERSION};
+
 use android_system_virtualizationservice::{
+    aidl::android::system::virtualizationservice::BalloonPolicy::BalloonPolicy as AidlBalloonPolicy,
     aidl::android::system::virtualizationservice::CpuTopology::CpuTopology,
     aidl::android::system::virtualizationservice::DiskImage::DiskImage as AidlDiskImage,
+    aidl::android::system::virtualizationservice::NetworkInterface::{
+        Mode::Mode as AidlNetworkMode, NetworkInterface as AidlNetworkInterface,
+    },
     aidl::android::system::virtualizationservice::Partition::Partition as AidlPartition,
+    aidl::android::system::virtualizationservice::RestartPolicy::{
+        Mode::Mode as AidlRestartMode, RestartPolicy as AidlRestartPolicy,
+    },
     aidl::android::system::virtualizationservice::UsbConfig::UsbConfig as AidlUsbConfig,
     aidl::android::system::virtualizationservice::VirtualMachineAppConfig::DebugLevel::DebugLevel,
     aidl::android::system::virtualizationservice::VirtualMachineConfig::VirtualMachineConfig,
@@ -71,30 +83,65 @@ pub struct VmConfig {
     pub console_input_device: Option<String>,
     /// The USB config of the VM.
     pub usb_config: Option<UsbConfig>,
+    /// The limits within which the memory balloon of the VM is resized automatically.
+    pub balloon_policy: Option<BalloonPolicy>,
+    /// When the VM is restarted after it exits.
+    pub restart_policy: Option<RestartPolicy>,
+    /// Network interfaces of the VM.
+    #[serde(default)]
+    pub network_interfaces: Vec<NetworkInterface>,
 }
 
 impl VmConfig {
-    /// Ensure that the configuration has a valid combination of fields set, or return an error if
-    /// not.
+    /// Ensure that the configuration is valid, or return an error listing every problem found
+    /// if not. The files it refers to are not accessed.
     pub fn validate(&self) -> Result<(), Error> {
-        if self.bootloader.is_none() && self.kernel.is_none() {
-            bail!("VM must have either a bootloader or a kernel image.");
-        }
-        if self.bootloader.is_some() && (self.kernel.is_some() || self.initrd.is_some()) {
-            bail!("Can't have both bootloader and kernel/initrd image.");
-        }
-        for disk in &self.disks {
-            if disk.image.is_none() == disk.partitions.is_empty() {
-                bail!("Exactly one of image and partitions must be specified. (Was {:?}.)", disk);
-            }
-        }
-        Ok(())
+        check_problems(self.problems())
+    }
+
+    /// Ensure that the configuration is valid and that the files it refers to exist, or return an
+    /// error listing every problem found if not.
+    ///
+    /// This is meant for the client which owns the config, as the paths in it may not be
+    /// accessible to other processes.
+    pub fn validate_with_files(&self) -> Result<(), Error> {
+        check_problems(self.problems_with_files())
+    }
+
+    /// Check the configuration, without accessing the files it refers to, and return every
+    /// problem found. The list is empty if the configuration is valid.
+    pub fn problems(&self) -> Vec<ConfigProblem> {
+        validation::find_problems(self, false)
+    }
+
+    /// Check the configuration, including that the files it refers to exist, and return every
+    /// problem found. The list is empty if the configuration is valid.
+    pub fn problems_with_files(&self) -> Vec<ConfigProblem> {
+        validation::find_problems(self, true)
     }
 
     /// Load the configuration for a VM from the given JSON file, and check that it is valid.
+    ///
+    /// The file must not extend another config file, as there is no path to resolve it against.
+    /// Use [`VmConfig::load_from_path`] for those.
     pub fn load(file: &File) -> Result<VmConfig, Error> {
         let buffered = BufReader::new(file);
-        let config: VmConfig = serde_json::from_reader(buffered)?;
+        let value: serde_json::Value = serde_json::from_reader(buffered)?;
+        if value.get(extends::EXTENDS_KEY).is_some() {
+            bail!("Config extending another config must be loaded from a path.");
+        }
+        let config: VmConfig = serde_json::from_value(value)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Load the configuration for a VM from the JSON file at the given path, and check that it is
+    /// valid. If the file extends another config file (with the `"extends"` key), the base config
+    /// is loaded first and the file is merged onto it.
+    pub fn load_from_path(path: &Path) -> Result<VmConfig, Error> {
+        let value = extends::resolve(path)?;
+        let config: VmConfig = serde_json::from_value(value)
+            .with_context(|| format!("Invalid resolved config {:?}", path))?;
         config.validate()?;
         Ok(config)
     }
@@ -107,13 +154,10 @@ impl VmConfig {
         } else {
             0
         };
-        let cpu_topology = match self.cpu_topology.as_deref() {
-            None => CpuTopology::ONE_CPU,
-            Some("one_cpu") => CpuTopology::ONE_CPU,
-            Some("match_host") => CpuTopology::MATCH_HOST,
-            Some(cpu_topology) => bail!("Invalid cpu topology {}", cpu_topology),
-        };
+        let cpu_topology = parse_cpu_topology(self.cpu_topology.as_deref())?;
         let usb_config = self.usb_config.clone().map(|x| x.to_parcelable()).transpose()?;
+        let balloon_policy = self.balloon_policy.as_ref().map(BalloonPolicy::to_parcelable);
+        let restart_policy = self.restart_policy.as_ref().map(|x| x.to_parcelable()).transpose()?;
         Ok(VirtualMachineRawConfig {
             kernel: maybe_open_parcel_file(&self.kernel, false)?,
             initrd: maybe_open_parcel_file(&self.initrd, false)?,
@@ -133,11 +177,36 @@ impl VmConfig {
                 .collect::<Result<_>>()?,
             consoleInputDevice: self.console_input_device.clone(),
             usbConfig: usb_config,
+            balloonPolicy: balloon_policy,
+            restartPolicy: restart_policy,
+            networkInterfaces: self
+                .network_interfaces
+                .iter()
+                .map(NetworkInterface::to_parcelable)
+                .collect::<Result<_>>()?,
             ..Default::default()
         })
     }
 }
 
+fn check_problems(problems: Vec<ConfigProblem>) -> Result<(), Error> {
+    if !problems.is_empty() {
+        let problems: Vec<_> = problems.iter().map(ConfigProblem::to_string).collect();
+        bail!("Invalid VM config:\n  {}", problems.join("\n  "));
+    }
+    Ok(())
+}
+
+/// Parses the `cpu_topology` field of a [`VmConfig`].
+fn parse_cpu_topology(cpu_topology: Option<&str>) -> Result<CpuTopology> {
+    match cpu_topology {
+        None => Ok(CpuTopology::ONE_CPU),
+        Some("one_cpu") => Ok(CpuTopology::ONE_CPU),
+        Some("match_host") => Ok(CpuTopology::MATCH_HOST),
+        Some(cpu_topology) => bail!("Invalid cpu topology {}", cpu_topology),
+    }
+}
+
 /// Returns the debug level of the VM from its configuration.
 pub fn get_debug_level(config: &VirtualMachineConfig) -> Option<DebugLevel> {
     match config {
@@ -149,6 +218,11 @@ pub fn get_debug_level(config: &VirtualMachineConfig) -> Option<DebugLevel> {
 /// A disk image to be made available to the VM.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct DiskImage {
+    /// A label identifying the disk within the config. A config extending another config can
+    /// replace a disk of the base config by using the same label. The label also identifies the
+    /// disk of the running VM, e.g. to resize it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
     /// The filename of the disk image, if it already exists. Exactly one of this and `partitions`
     /// must be specified.
     #[serde(default)]
@@ -168,6 +242,7 @@ impl DiskImage {
             image: maybe_open_parcel_file(&self.image, self.writable)?,
             writable: self.writable,
             partitions,
+            label: self.label.clone(),
         })
     }
 }
@@ -211,6 +286,103 @@ impl UsbConfig {
     }
 }
 
+/// Limits of the memory left to the guest when its memory balloon is resized automatically.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct BalloonPolicy {
+    /// The least memory left to the guest, in MiB.
+    pub floor_mib: NonZeroU32,
+    /// The most memory left to the guest, in MiB.
+    pub ceiling_mib: NonZeroU32,
+}
+
+impl BalloonPolicy {
+    fn to_parcelable(&self) -> AidlBalloonPolicy {
+        AidlBalloonPolicy {
+            floorMib: self.floor_mib.get() as i32,
+            ceilingMib: self.ceiling_mib.get() as i32,
+        }
+    }
+}
+
+/// When a VM is restarted after it exits.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RestartPolicy {
+    /// Whether the VM is restarted never, only when it doesn't shut down cleanly, or always.
+    pub mode: RestartMode,
+    /// How many times the VM is restarted at most, required for the `on_failure` mode.
+    pub max_retries: Option<NonZeroU32>,
+    /// The delay before the first restart, in milliseconds, doubled with each restart.
+    pub backoff_ms: Option<u32>,
+}
+
+/// When a VM is restarted.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartMode {
+    Never,
+    OnFailure,
+    Always,
+}
+
+impl RestartPolicy {
+    fn to_parcelable(&self) -> Result<AidlRestartPolicy> {
+        let mut policy = AidlRestartPolicy {
+            mode: match self.mode {
+                RestartMode::Never => AidlRestartMode::NEVER,
+                RestartMode::OnFailure => AidlRestartMode::ON_FAILURE,
+                RestartMode::Always => AidlRestartMode::ALWAYS,
+            },
+            ..Default::default()
+        };
+        if let Some(max_retries) = self.max_retries {
+            policy.maxRetries = max_retries.get().try_into().context("Invalid max_retries")?;
+        }
+        if let Some(backoff_ms) = self.backoff_ms {
+            policy.backoffMillis = backoff_ms.try_into().context("Invalid backoff_ms")?;
+        }
+        Ok(policy)
+    }
+}
+
+/// A network interface of a VM, backed by a TAP interface on the host.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NetworkInterface {
+    /// What the interface is connected to.
+    #[serde(default)]
+    pub mode: NetworkMode,
+    /// The MAC address of the device in the guest, as six colon-separated hexadecimal octets.
+    pub mac: Option<String>,
+    /// The MTU of the TAP interface, in bytes.
+    pub mtu: Option<NonZeroU32>,
+}
+
+/// What a network interface of a VM is connected to.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkMode {
+    /// Nothing, so that the VM can't reach any network.
+    Isolated,
+    /// A bridge shared with the other host-only interfaces, on which the host has an address.
+    HostOnly,
+    /// A bridge tethered to the external network of the device.
+    #[default]
+    Nat,
+}
+
+impl NetworkInterface {
+    fn to_parcelable(&self) -> Result<AidlNetworkInterface> {
+        Ok(AidlNetworkInterface {
+            mode: match self.mode {
+                NetworkMode::Isolated => AidlNetworkMode::ISOLATED,
+                NetworkMode::HostOnly => AidlNetworkMode::HOST_ONLY,
+                NetworkMode::Nat => AidlNetworkMode::NAT,
+            },
+            macAddress: self.mac.clone(),
+            mtu: self.mtu.map_or(Ok(0), |mtu| mtu.get().try_into()).context("Invalid mtu")?,
+        })
+    }
+}
+
 /// Try to open the given file and wrap it in a [`ParcelFileDescriptor`].
 pub fn open_parcel_file(filename: &Path, writable: bool) -> Result<ParcelFileDescriptor> {
     Ok(ParcelFileDescriptor::new(
@@ -0,0 +1,405 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Semantic validation of a [`VmConfig`].
+
+use crate::{parse_cpu_topology, RestartMode, VmConfig};
+use anyhow::{anyhow, ensure, Context, Result};
+use semver::Version;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+/// Version of the platform that crosvm currently implements. The format follows SemVer. This
+/// should be updated when there is a platform change in the crosvm side. Having this value here is
+/// fine because virtualizationservice, crosvm and the `vm` tool are supposed to be updated together
+/// in the virt APEX.
+pub const PLATFORM_VERSION: &str = "1.0.0";
+
+/// The range of MTUs which a network interface can have, from the minimum of IPv4 to the maximum
+/// of a TAP interface.
+const MTU_RANGE: RangeInclusive<u32> = 68..=65535;
+
+/// A problem found in a [`VmConfig`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConfigProblem {
+    /// JSON path of the offending value, e.g. `$.disks[0].partitions[1].label`.
+    pub path: String,
+    /// What is wrong with the value.
+    pub message: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Collects the problems found in a config, in the order in which they are found.
+struct Problems {
+    problems: Vec<ConfigProblem>,
+    /// Whether to check the files the config refers to.
+    check_files: bool,
+}
+
+impl Problems {
+    fn add(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.problems.push(ConfigProblem { path: path.into(), message: message.into() });
+    }
+
+    fn check_exists(&mut self, path: impl Into<String>, file: &Path) {
+        if self.check_files && !file.exists() {
+            self.add(path, format!("{:?} does not exist", file));
+        }
+    }
+}
+
+/// Returns every problem found in the config, or an empty list if it is valid. If `check_files` is
+/// false, the files the config refers to are not accessed, and only its structure is checked.
+pub fn find_problems(config: &VmConfig, check_files: bool) -> Vec<ConfigProblem> {
+    let mut problems = Problems { problems: Vec::new(), check_files };
+
+    if config.bootloader.is_none() && config.kernel.is_none() {
+        problems.add("$", "VM must have either a bootloader or a kernel image");
+    }
+    if config.bootloader.is_some() && (config.kernel.is_some() || config.initrd.is_some()) {
+        problems.add("$.bootloader", "Can't have both bootloader and kernel/initrd image");
+    }
+    for (key, file) in
+        [("kernel", &config.kernel), ("initrd", &config.initrd), ("bootloader", &config.bootloader)]
+    {
+        if let Some(file) = file {
+            problems.check_exists(format!("$.{key}"), file);
+        }
+    }
+
+    // Label of each partition, and whether each file is writable, with the path where first seen.
+    let mut labels: HashMap<&str, String> = HashMap::new();
+    let mut files: HashMap<PathBuf, (bool, String)> = HashMap::new();
+    let mut check_access = |problems: &mut Problems, path: String, file: &Path, writable| {
+        let key = if problems.check_files {
+            file.canonicalize().unwrap_or_else(|_| file.to_owned())
+        } else {
+            file.to_owned()
+        };
+        match files.get(&key) {
+            Some((other_writable, other_path)) if *other_writable != writable => {
+                let (rw, ro) = if writable { (&path, other_path) } else { (other_path, &path) };
+                problems.add(
+                    path.clone(),
+                    format!("{:?} is writable at {} but read-only at {}", file, rw, ro),
+                );
+            }
+            Some(_) => {}
+            None => {
+                files.insert(key, (writable, path));
+            }
+        }
+    };
+    for (i, disk) in config.disks.iter().enumerate() {
+        let disk_path = format!("$.disks[{i}]");
+        if disk.image.is_none() == disk.partitions.is_empty() {
+            problems.add(&disk_path, "Exactly one of image and partitions must be specified");
+        }
+        if let Some(image) = &disk.image {
+            let path = format!("{disk_path}.image");
+            problems.check_exists(&path, image);
+            check_access(&mut problems, path, image, disk.writable);
+        }
+        for (j, partition) in disk.partitions.iter().enumerate() {
+            let partition_path = format!("{disk_path}.partitions[{j}]");
+            let label_path = format!("{partition_path}.label");
+            if partition.label.is_empty() {
+                problems.add(&label_path, "Partition label must not be empty");
+            } else if let Some(other) = labels.get(partition.label.as_str()) {
+                problems.add(
+                    &label_path,
+                    format!("Partition label {:?} is already used at {}", partition.label, other),
+                );
+            } else {
+                labels.insert(&partition.label, label_path);
+            }
+            let path = format!("{partition_path}.path");
+            problems.check_exists(&path, &partition.path);
+            check_access(&mut problems, path, &partition.path, partition.writable);
+        }
+    }
+
+    if let Err(e) = parse_cpu_topology(config.cpu_topology.as_deref()) {
+        problems.add("$.cpu_topology", e.to_string());
+    }
+
+    let platform_version = Version::parse(PLATFORM_VERSION).unwrap();
+    if !config.platform_version.matches(&platform_version) {
+        problems.add(
+            "$.platform_version",
+            format!(
+                "{} does not match the supported platform version {}",
+                config.platform_version, platform_version
+            ),
+        );
+    }
+
+    if !config.devices.is_empty() && !config.protected {
+        problems.add("$.devices", "Devices can only be assigned to a protected VM");
+    }
+    for (i, device) in config.devices.iter().enumerate() {
+        problems.check_exists(format!("$.devices[{i}]"), device);
+    }
+
+    if let Some(policy) = &config.balloon_policy {
+        if policy.floor_mib > policy.ceiling_mib {
+            problems.add(
+                "$.balloon_policy.floor_mib",
+                format!("{} is above the ceiling {}", policy.floor_mib, policy.ceiling_mib),
+            );
+        }
+        if let Some(memory_mib) = config.memory_mib {
+            if policy.ceiling_mib > memory_mib {
+                problems.add(
+                    "$.balloon_policy.ceiling_mib",
+                    format!("{} is above the memory of the VM {}", policy.ceiling_mib, memory_mib),
+                );
+            }
+        }
+    }
+
+    if let Some(policy) = &config.restart_policy {
+        if policy.mode == RestartMode::OnFailure && policy.max_retries.is_none() {
+            problems.add("$.restart_policy.max_retries", "Required for the on_failure mode");
+        }
+    }
+
+    if !config.network_interfaces.is_empty() && config.protected {
+        problems.add("$.network_interfaces", "Network is not supported for a protected VM yet");
+    }
+    let mut macs: HashMap<String, String> = HashMap::new();
+    for (i, interface) in config.network_interfaces.iter().enumerate() {
+        let interface_path = format!("$.network_interfaces[{i}]");
+        if let Some(mac) = &interface.mac {
+            let path = format!("{interface_path}.mac");
+            if let Err(e) = parse_mac_address(mac) {
+                problems.add(path, format!("{e:#}"));
+            } else if let Some(other) = macs.get(&mac.to_ascii_lowercase()) {
+                problems.add(path, format!("MAC address {mac} is already used at {other}"));
+            } else {
+                macs.insert(mac.to_ascii_lowercase(), path);
+            }
+        }
+        if let Some(mtu) = interface.mtu {
+            if !MTU_RANGE.contains(&mtu.get()) {
+                problems.add(
+                    format!("{interface_path}.mtu"),
+                    format!("{mtu} is not between {} and {}", MTU_RANGE.start(), MTU_RANGE.end()),
+                );
+            }
+        }
+    }
+
+    problems.problems
+}
+
+/// Parses a MAC address written as six colon-separated hexadecimal octets. Only unicast addresses
+/// can be given to a network device.
+pub fn parse_mac_address(mac_address: &str) -> Result<[u8; 6]> {
+    let octets = mac_address
+        .split(':')
+        .map(|octet| {
+            ensure!(
+                octet.len() == 2 && octet.chars().all(|c| c.is_ascii_hexdigit()),
+                "Invalid octet {octet:?}"
+            );
+            Ok(u8::from_str_radix(octet, 16)?)
+        })
+        .collect::<Result<Vec<_>>>()
+        .with_context(|| format!("Invalid MAC address {mac_address:?}"))?;
+    let octets: [u8; 6] = octets
+        .try_into()
+        .map_err(|_| anyhow!("MAC address {mac_address:?} doesn't have six octets"))?;
+    ensure!(octets[0] & 1 == 0, "MAC address {mac_address:?} is a multicast address");
+    Ok(octets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    fn config_from_json(json: &str) -> VmConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn paths(problems: &[ConfigProblem]) -> Vec<&str> {
+        problems.iter().map(|p| p.path.as_str()).collect()
+    }
+
+    #[test]
+    fn valid_config_has_no_problems() {
+        let dir = tempfile::tempdir().unwrap();
+        let kernel = dir.path().join("kernel");
+        let disk = dir.path().join("disk.img");
+        File::create(&kernel).unwrap();
+        File::create(&disk).unwrap();
+        let config = config_from_json(&format!(
+            r#"{{
+                "kernel": {kernel:?},
+                "disks": [{{ "image": {disk:?}, "writable": true }}],
+                "cpu_topology": "match_host",
+                "platform_version": "~1.0"
+            }}"#
+        ));
+        assert_eq!(find_problems(&config, true), vec![]);
+    }
+
+    #[test]
+    fn all_problems_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("shared.img");
+        File::create(&image).unwrap();
+        let config = config_from_json(&format!(
+            r#"{{
+                "kernel": "/nonexistent/kernel",
+                "disks": [
+                    {{
+                        "partitions": [
+                            {{ "label": "a", "path": {image:?}, "writable": true }},
+                            {{ "label": "a", "path": {image:?} }}
+                        ],
+                        "writable": true
+                    }}
+                ],
+                "cpu_topology": "two_cpus",
+                "platform_version": "~2.0",
+                "devices": ["/nonexistent/device"]
+            }}"#
+        ));
+        assert_eq!(
+            paths(&find_problems(&config, true)),
+            vec![
+                "$.kernel",
+                "$.disks[0].partitions[1].label",
+                "$.disks[0].partitions[1].path",
+                "$.cpu_topology",
+                "$.platform_version",
+                "$.devices",
+                "$.devices[0]",
+            ]
+        );
+    }
+
+    #[test]
+    fn files_are_not_checked_for_structural_problems() {
+        let config = config_from_json(
+            r#"{
+                "kernel": "/nonexistent/kernel",
+                "disks": [{ "image": "/nonexistent/disk.img", "writable": false }],
+                "platform_version": "~1.0"
+            }"#,
+        );
+        assert_eq!(paths(&find_problems(&config, true)), vec!["$.kernel", "$.disks[0].image"]);
+        assert_eq!(find_problems(&config, false), vec![]);
+    }
+
+    #[test]
+    fn bootloader_and_kernel_are_exclusive() {
+        let config =
+            config_from_json(r#"{ "bootloader": "/", "kernel": "/", "platform_version": "~1.0" }"#);
+        assert_eq!(paths(&find_problems(&config, false)), vec!["$.bootloader"]);
+    }
+
+    #[test]
+    fn balloon_policy_must_fit_in_memory() {
+        let config = config_from_json(
+            r#"{
+                "bootloader": "/",
+                "platform_version": "~1.0",
+                "memory_mib": 1024,
+                "balloon_policy": { "floor_mib": 512, "ceiling_mib": 2048 }
+            }"#,
+        );
+        assert_eq!(paths(&find_problems(&config, false)), vec!["$.balloon_policy.ceiling_mib"]);
+
+        let config = config_from_json(
+            r#"{
+                "bootloader": "/",
+                "platform_version": "~1.0",
+                "balloon_policy": { "floor_mib": 512, "ceiling_mib": 256 }
+            }"#,
+        );
+        assert_eq!(paths(&find_problems(&config, false)), vec!["$.balloon_policy.floor_mib"]);
+    }
+
+    #[test]
+    fn restart_on_failure_needs_max_retries() {
+        let config = config_from_json(
+            r#"{
+                "bootloader": "/",
+                "platform_version": "~1.0",
+                "restart_policy": { "mode": "on_failure" }
+            }"#,
+        );
+        assert_eq!(paths(&find_problems(&config, false)), vec!["$.restart_policy.max_retries"]);
+
+        let config = config_from_json(
+            r#"{
+                "bootloader": "/",
+                "platform_version": "~1.0",
+                "restart_policy": { "mode": "always", "backoff_ms": 500 }
+            }"#,
+        );
+        assert!(find_problems(&config, false).is_empty());
+    }
+
+    #[test]
+    fn network_interfaces_are_checked() {
+        let config = config_from_json(
+            r#"{
+                "bootloader": "/",
+                "platform_version": "~1.0",
+                "network_interfaces": [
+                    { "mac": "02:00:00:00:00:01" },
+                    { "mode": "host_only", "mac": "02:00:00:00:00:01", "mtu": 9000 },
+                    { "mode": "isolated", "mac": "01:00:5e:00:00:01" },
+                    { "mac": "02:00:00:00:01", "mtu": 70000 }
+                ]
+            }"#,
+        );
+        assert_eq!(
+            paths(&find_problems(&config, false)),
+            vec![
+                "$.network_interfaces[1].mac",
+                "$.network_interfaces[2].mac",
+                "$.network_interfaces[3].mac",
+                "$.network_interfaces[3].mtu",
+            ]
+        );
+    }
+
+    #[test]
+    fn mac_addresses_are_parsed() {
+        assert_eq!(parse_mac_address("02:00:5e:10:00:0a").unwrap(), [2, 0, 0x5e, 0x10, 0, 0xa]);
+        assert_eq!(
+            parse_mac_address("AA:BB:CC:DD:EE:FF").unwrap(),
+            [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]
+        );
+        // Multicast
+        assert!(parse_mac_address("01:00:5e:00:00:01").is_err());
+        assert!(parse_mac_address("02:00:5e:10:00").is_err());
+        assert!(parse_mac_address("02:00:5e:10:00:0a:0b").is_err());
+        assert!(parse_mac_address("02:00:5e:10:00:a").is_err());
+        assert!(parse_mac_address("02-00-5e-10-00-0a").is_err());
+        assert!(parse_mac_address("02:00:5e:10:00:+a").is_err());
+    }
+}
@@ -705,6 +705,12 @@ public class MicrodroidTests extends MicrodroidDeviceTestBase {
         assertConfigCompatible(baseline, newBaselineBuilder().setEncryptedStorageBytes(100_000))
                 .isFalse();
 
+        VirtualMachineConfig.Builder storageBuilder =
+                newBaselineBuilder().setEncryptedStorageBytes(100_000);
+        VirtualMachineConfig storage = storageBuilder.build();
+        assertConfigCompatible(storage, storageBuilder.setEncryptedStorageBytes(200_000)).isTrue();
+        assertConfigCompatible(storage, storageBuilder.setEncryptedStorageBytes(50_000)).isFalse();
+
         VirtualMachineConfig.Builder debuggableBuilder =
                 newBaselineBuilder().setDebugLevel(DEBUG_LEVEL_FULL);
         VirtualMachineConfig debuggable = debuggableBuilder.build();
@@ -746,7 +752,8 @@ public class MicrodroidTests extends MicrodroidDeviceTestBase {
 
     private BooleanSubject assertConfigCompatible(
             VirtualMachineConfig baseline, VirtualMachineConfig.Builder builder) {
-        return assertThat(builder.build().isCompatibleWith(baseline));
+        // The same way as VirtualMachine#setConfig checks the new config against the old one.
+        return assertThat(baseline.isCompatibleWith(builder.build()));
     }
 
     @Test